@@ -0,0 +1,172 @@
+//! Peak heap while downloading and decoding a large PNG source, for the
+//! memory figures in the README.
+//!
+//! The source is served from an in-process stand-in for S3, so no bucket or
+//! network is needed, and a counting allocator records the highest heap use
+//! above what was allocated before each step. The stand-in keeps its copy of
+//! the object outside the measured amount. The previous download, collecting
+//! the body and then joining the chunks, is measured alongside for comparison.
+//!
+//! ```text
+//! cargo bench --bench download
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use aws_config::{BehaviorVersion, Region};
+use aws_credential_types::Credentials;
+use bytes::Bytes;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ImageEncoder, RgbImage};
+
+use image_resizer::s3::{S3Client, S3FileConfig};
+
+/// 24 megapixels of noise, which PNG can't compress: about 72 MB either way.
+const WIDTH: u32 = 4000;
+const HEIGHT: u32 = 6000;
+
+const BUCKET: &str = "bench";
+const KEY: &str = "large.png";
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grew(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+            grew(new_size);
+        }
+        new_ptr
+    }
+}
+
+fn grew(size: usize) {
+    let now = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(now, Ordering::Relaxed);
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `step` and returns its result with the peak heap it added, in bytes.
+async fn peak_of<T>(step: impl std::future::Future<Output = T>) -> (T, usize) {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let result = step.await;
+    (result, PEAK.load(Ordering::Relaxed) - before)
+}
+
+fn megabytes(bytes: usize) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Noise from a cheap hash, the same bytes on every run.
+fn source() -> Bytes {
+    let img = RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let hash =
+            (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)).wrapping_mul(2_654_435_761);
+        image::Rgb([(hash >> 8) as u8, (hash >> 16) as u8, (hash >> 24) as u8])
+    });
+    let mut buffer = Vec::new();
+    PngEncoder::new_with_quality(
+        Cursor::new(&mut buffer),
+        CompressionType::Fast,
+        FilterType::NoFilter,
+    )
+    .write_image(img.as_raw(), WIDTH, HEIGHT, image::ExtendedColorType::Rgb8)
+    .expect("encoding the benchmark source");
+    Bytes::from(buffer)
+}
+
+/// Answers every GET with the object, as path-style S3 would.
+async fn serve(object: Bytes) -> String {
+    let app = axum::Router::new().fallback(move || {
+        let object = object.clone();
+        async move { ([(axum::http::header::CONTENT_TYPE, "image/png")], object) }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    endpoint
+}
+
+/// The download as it was before it read into one preallocated buffer.
+async fn collect_and_join(endpoint: &str) -> Bytes {
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("bench", "bench", None, None, "bench"))
+        .load()
+        .await;
+    let s3_config = aws_sdk_s3::config::Builder::from(&config)
+        .endpoint_url(endpoint)
+        .force_path_style(true)
+        .build();
+    let client = aws_sdk_s3::Client::from_conf(s3_config);
+
+    let (data, peak) = peak_of(async {
+        let response = client
+            .get_object()
+            .bucket(BUCKET)
+            .key(KEY)
+            .send()
+            .await
+            .unwrap();
+        response.body.collect().await.unwrap().into_bytes()
+    })
+    .await;
+    println!("collect and join:     peak {}", megabytes(peak));
+    data
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let object = source();
+    println!(
+        "source: {}x{} PNG, {}",
+        WIDTH,
+        HEIGHT,
+        megabytes(object.len())
+    );
+    let endpoint = serve(object.clone()).await;
+
+    let file_config = S3FileConfig {
+        region: "us-east-1".to_string(),
+        access_key_id: "bench".to_string(),
+        secret_access_key: "bench".to_string(),
+        session_token: None,
+        endpoint: Some(endpoint.clone()),
+        force_path_style: true,
+    };
+    let client = S3Client::new(Some(&file_config), "us-east-1").await;
+    let url = format!("s3://{}/{}", BUCKET, KEY);
+
+    drop(collect_and_join(&endpoint).await);
+
+    let (data, peak) = peak_of(client.download_image(&url)).await;
+    let data = data.expect("downloading the benchmark source");
+    println!("preallocated buffer:  peak {}", megabytes(peak));
+
+    let (decoded, peak) = peak_of(async { image::load_from_memory(&data) }).await;
+    let decoded = decoded.expect("decoding the benchmark source");
+    assert_eq!((decoded.width(), decoded.height()), (WIDTH, HEIGHT));
+    println!("decode:               peak {}", megabytes(peak));
+}
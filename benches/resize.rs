@@ -0,0 +1,185 @@
+//! Decode + resize + encode throughput, for sizing deployments.
+//!
+//! Sources are generated in memory so runs are reproducible without fixture
+//! files: a smooth gradient with fine detail on top, which compresses like a
+//! photo rather than a flat fill. Each case is measured for a small thumbnail
+//! and for a large output, since the two are bound by different stages.
+//!
+//! ```text
+//! cargo bench --bench resize
+//! cargo bench --bench resize --features avif -- output_format
+//! RESIZE_THREADS=8 cargo bench --bench resize -- large_downscale
+//! ```
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+use std::time::Duration;
+
+use image_resizer::image_processor::{DecodeLimits, EncodeOptions, ImageProcessor, ResizeOptions};
+use image_resizer::models::{ColorProfile, ObjectMode, OutputFormat, ResizeFilter};
+use image_resizer::resample;
+
+/// `(name, source width, source height)`: a typical camera upload and a
+/// smaller web-sized original.
+const SOURCES: [(&str, u32, u32); 2] = [("12mp", 4000, 3000), ("1mp", 1200, 900)];
+
+/// `(name, width, height)` of the requested output.
+const OUTPUTS: [(&str, u32, u32); 2] = [("thumbnail", 200, 200), ("large", 2048, 1536)];
+
+const OBJECT_MODES: [(&str, ObjectMode); 5] = [
+    ("cover", ObjectMode::Cover),
+    ("contain", ObjectMode::Contain),
+    ("fill", ObjectMode::Fill),
+    ("scale_down", ObjectMode::ScaleDown),
+    ("outside", ObjectMode::Outside),
+];
+
+fn output_formats() -> Vec<OutputFormat> {
+    let mut formats = vec![OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp];
+    if cfg!(feature = "avif") {
+        formats.push(OutputFormat::Avif);
+    }
+    formats
+}
+
+fn limits() -> DecodeLimits {
+    DecodeLimits {
+        max_dimension: 16_384,
+        max_alloc_bytes: 512 * 1024 * 1024,
+        format_retry: false,
+    }
+}
+
+/// A JPEG source of the given size, the same bytes on every run.
+fn source(width: u32, height: u32) -> Bytes {
+    let img = RgbImage::from_fn(width, height, |x, y| {
+        // A cheap hash gives per-pixel detail without an RNG dependency.
+        let noise = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) % 24;
+        Rgb([
+            (x * 255 / width) as u8 ^ noise as u8,
+            (y * 255 / height) as u8 ^ noise as u8,
+            ((x + y) * 127 / (width + height)) as u8 + noise as u8,
+        ])
+    });
+
+    let mut buffer = Vec::new();
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Jpeg)
+        .expect("encoding the benchmark source");
+    Bytes::from(buffer)
+}
+
+fn options(
+    width: u32,
+    height: u32,
+    object_mode: ObjectMode,
+    format: OutputFormat,
+) -> ResizeOptions {
+    ResizeOptions {
+        width,
+        height,
+        object_mode,
+        filter: ResizeFilter::default(),
+        min_source_dimension: None,
+        crop: None,
+        distortion_guard: None,
+        canvas: None,
+        watermark: None,
+        color_profile: ColorProfile::default(),
+        encode: EncodeOptions {
+            format,
+            ..EncodeOptions::default()
+        },
+        auto_formats: Vec::new(),
+        avif_fallback: Vec::new(),
+        jpeg_fallback: false,
+        face_crop: false,
+        phash: false,
+        blurhash: false,
+        frame_index: None,
+        operations: None,
+        reject_if_larger: false,
+    }
+}
+
+/// Runs each case for every source and output size, reporting throughput in
+/// source pixels per second.
+fn bench_outputs(
+    c: &mut Criterion,
+    group_name: &str,
+    cases: &[(String, ObjectMode, OutputFormat)],
+) {
+    let limits = limits();
+    let mut group = c.benchmark_group(group_name);
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+
+    for (source_name, source_width, source_height) in SOURCES {
+        let data = source(source_width, source_height);
+        group.throughput(Throughput::Elements(
+            source_width as u64 * source_height as u64,
+        ));
+
+        for (output_name, width, height) in OUTPUTS {
+            for (case, object_mode, format) in cases {
+                let options = options(width, height, *object_mode, *format);
+                let id = BenchmarkId::new(format!("{}/{}", case, output_name), source_name);
+                group.bench_with_input(id, &data, |b, data| {
+                    b.iter(|| {
+                        ImageProcessor::resize(data.clone(), &options, &limits)
+                            .expect("benchmark resize")
+                    })
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+/// Every object mode, encoded as JPEG.
+fn object_modes(c: &mut Criterion) {
+    let cases: Vec<_> = OBJECT_MODES
+        .iter()
+        .map(|(name, mode)| (name.to_string(), *mode, OutputFormat::Jpeg))
+        .collect();
+    bench_outputs(c, "object_mode", &cases);
+}
+
+/// Every output format this build can encode, from a `cover` resize.
+fn output_formats_bench(c: &mut Criterion) {
+    let cases: Vec<_> = output_formats()
+        .into_iter()
+        .map(|format| (format.as_str().to_string(), ObjectMode::Cover, format))
+        .collect();
+    bench_outputs(c, "output_format", &cases);
+}
+
+/// The Lanczos3 resample alone of an 8000x6000 source down to 2048x1536, on
+/// as many threads as `RESIZE_THREADS` gives it.
+fn large_downscale(c: &mut Criterion) {
+    let threads = std::env::var("RESIZE_THREADS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+    resample::set_threads(threads);
+
+    let data = source(8000, 6000);
+    let img = image::load_from_memory(&data).expect("decoding the benchmark source");
+    let mut group = c.benchmark_group("large_downscale");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(20))
+        .throughput(Throughput::Elements(8000 * 6000));
+    group.bench_function(
+        BenchmarkId::new("lanczos3", format!("{}_threads", threads)),
+        |b| b.iter(|| resample::resize_exact(&img, 2048, 1536, FilterType::Lanczos3)),
+    );
+    group.finish();
+}
+
+criterion_group!(benches, object_modes, output_formats_bench, large_downscale);
+criterion_main!(benches);
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    first_failure_at: Option<Instant>,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+/// Fails fast while a dependency is down. After `failure_threshold` consecutive
+/// failures within `window` the breaker opens and rejects calls for `cooldown`,
+/// then lets a single trial call through to decide whether to close again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        name: &'static str,
+        failure_threshold: u32,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            name,
+            failure_threshold: failure_threshold.max(1),
+            window,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                first_failure_at: None,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Returns an error without calling the dependency when the breaker is open.
+    pub fn check(&self) -> Result<(), AppError> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::HalfOpen if !inner.trial_in_flight => {
+                inner.trial_in_flight = true;
+                Ok(())
+            }
+            _ => Err(AppError::ServiceUnavailable(format!(
+                "{} is unavailable, failing fast while the circuit breaker is open",
+                self.name
+            ))),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != BreakerState::Closed {
+            tracing::info!("{} circuit breaker closed", self.name);
+        }
+
+        inner.state = BreakerState::Closed;
+        inner.consecutive_failures = 0;
+        inner.first_failure_at = None;
+        inner.opened_at = None;
+        inner.trial_in_flight = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        if inner.state == BreakerState::HalfOpen {
+            self.open(&mut inner, now);
+            return;
+        }
+
+        match inner.first_failure_at {
+            Some(first) if now.duration_since(first) <= self.window => {
+                inner.consecutive_failures += 1;
+            }
+            _ => {
+                inner.first_failure_at = Some(now);
+                inner.consecutive_failures = 1;
+            }
+        }
+
+        if inner.consecutive_failures >= self.failure_threshold {
+            self.open(&mut inner, now);
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, now: Instant) {
+        tracing::warn!(
+            "{} circuit breaker opened after {} consecutive failures, cooling down for {:?}",
+            self.name,
+            inner.consecutive_failures,
+            self.cooldown
+        );
+
+        inner.state = BreakerState::Open;
+        inner.opened_at = Some(now);
+        inner.trial_in_flight = false;
+    }
+
+    fn refresh(&self, inner: &mut Inner) {
+        if inner.state == BreakerState::Open
+            && inner
+                .opened_at
+                .is_some_and(|opened| opened.elapsed() >= self.cooldown)
+        {
+            tracing::info!(
+                "{} circuit breaker half-open, allowing a trial request",
+                self.name
+            );
+            inner.state = BreakerState::HalfOpen;
+            inner.trial_in_flight = false;
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration;
+
+use crate::image_processor::{DecodeLimits, JpegEncoder};
+use crate::models::{ObjectAcl, ObjectMode, OutputFormat, StorageClass};
+use crate::s3::{parse_s3_url, S3FileConfig};
+use crate::storage::UploadChecksum;
+
+/// Each `auto` candidate is a full encode of the output, so the list is kept short.
+const MAX_AUTO_FORMATS: usize = 3;
+
+/// How the device pixel ratio is expressed in derivative keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DprKeyStyle {
+    /// `photo_800x600@2x.jpg`: CSS dimensions plus an `@{dpr}x` suffix.
+    Suffix,
+    /// `photo_1600x1200.jpg`: dimensions multiplied by the DPR.
+    Dimensions,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dpr_key_style: DprKeyStyle,
+    /// Used when a request doesn't name an `object_mode`.
+    pub default_object_mode: ObjectMode,
+    pub content_hash_length: usize,
+    /// Tag folded into every derived key as `_v{version}`; changing it moves
+    /// all derivatives to new keys.
+    pub cache_version: Option<String>,
+    pub decode_limits: DecodeLimits,
+    pub min_source_dimension: Option<u32>,
+    pub max_upload_bytes: usize,
+    /// Cap on the RGBA8 buffer `GET /resize/raw` returns.
+    pub max_raw_output_bytes: u64,
+    pub strict_tagging: bool,
+    /// Make concurrent requests for the same derivative wait for the first one.
+    pub single_flight: bool,
+    pub breaker_failure_threshold: u32,
+    pub breaker_window: Duration,
+    pub breaker_cooldown: Duration,
+    pub log_sample_rate: u64,
+    pub slow_request_threshold: Duration,
+    pub default_acl: Option<ObjectAcl>,
+    pub default_storage_class: Option<StorageClass>,
+    pub upload_checksum: UploadChecksum,
+    pub job_workers: usize,
+    /// Per-request cap on estimated resize work in megapixel-passes; `None`
+    /// disables the check.
+    pub max_request_work: Option<f64>,
+    /// Endpoint, region and keys from `S3_CONFIG_FILE`, replacing the
+    /// `TT_AWS_*` variables.
+    pub s3_file: Option<S3FileConfig>,
+    /// Custom domains serving a bucket, e.g. `cdn.example.com` -> `my-bucket`,
+    /// so `https://cdn.example.com/{key}` source URLs resolve to it.
+    pub custom_domain_buckets: BTreeMap<String, String>,
+    /// Formats `output_format: "auto"` encodes and compares, in tie-break order.
+    pub auto_formats: Vec<OutputFormat>,
+    /// Formats tried in order when an `avif` encode fails, WebP then JPEG
+    /// unless configured; empty fails the request instead.
+    pub avif_fallback: Vec<OutputFormat>,
+    /// Tokio worker threads running the async handlers.
+    pub worker_threads: usize,
+    /// Cap on Tokio's blocking pool, which runs the CPU-bound resizes.
+    pub max_blocking_threads: usize,
+    /// Threads one resample is split across, shared by all requests; 1
+    /// resamples on the request's own blocking thread.
+    pub resize_threads: usize,
+    /// Decodes and resizes allowed to run at once; `None` leaves only the
+    /// blocking pool size as the bound.
+    pub max_concurrent_decodes: Option<usize>,
+    pub job_queue_capacity: usize,
+    pub job_retention: Duration,
+    /// Budget for `POST /resize` when the caller sends no `X-Deadline-Ms`.
+    pub request_timeout: Duration,
+    /// TrueType/OpenType font for text watermarks; the bundled DejaVu Sans Bold
+    /// is used when unset.
+    pub watermark_font_path: Option<String>,
+    /// ffmpeg binary used to grab frames from video sources (`video` feature).
+    pub ffmpeg_path: String,
+    /// Image `GET /resize` renders in place of a source that doesn't exist;
+    /// unset returns the `404`.
+    pub fallback_image_s3_url: Option<String>,
+    /// Bucket `/ready` checks access to; the probe always passes when unset.
+    pub readiness_bucket: Option<String>,
+    /// Write and delete a test object in `readiness_bucket` once at startup.
+    pub readiness_write_check: bool,
+    /// Sustained requests per second allowed per API key or client IP;
+    /// `None` disables rate limiting.
+    pub rate_limit_rps: Option<f64>,
+    /// Requests a client may send at once before `rate_limit_rps` applies.
+    pub rate_limit_burst: u32,
+    /// Key unauthenticated clients by the first `X-Forwarded-For` address
+    /// rather than the peer address, for deployments behind a proxy.
+    pub rate_limit_trust_forwarded: bool,
+    /// Fill in the size and other settings of resize requests that give no
+    /// size from the source object's `resize-*` user metadata.
+    pub source_metadata_defaults: bool,
+    /// Read sources as the requester, for requester-pays buckets; requests
+    /// can override it with `requester_pays`.
+    pub requester_pays: bool,
+    /// Library that writes JPEG output; mozjpeg needs the `mozjpeg` feature.
+    pub jpeg_encoder: JpegEncoder,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let dpr_key_style = match env::var("DPR_KEY_STYLE")
+            .unwrap_or_else(|_| "suffix".to_string())
+            .as_str()
+        {
+            "suffix" => DprKeyStyle::Suffix,
+            "dimensions" => DprKeyStyle::Dimensions,
+            other => panic!(
+                "DPR_KEY_STYLE must be 'suffix' or 'dimensions', got '{}'",
+                other
+            ),
+        };
+
+        let default_object_mode = env::var("DEFAULT_OBJECT_MODE")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                ObjectMode::deserialize(v.as_str().into_deserializer()).unwrap_or_else(
+                    |e: serde::de::value::Error| {
+                        panic!("DEFAULT_OBJECT_MODE is not a valid object mode: {}", e)
+                    },
+                )
+            })
+            .unwrap_or(ObjectMode::Cover);
+
+        let content_hash_length = env::var("CONTENT_HASH_LENGTH")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("CONTENT_HASH_LENGTH must be a number")
+            })
+            .unwrap_or(8)
+            .clamp(1, 64);
+
+        let cache_version = env::var("CACHE_VERSION")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        if let Some(version) = &cache_version {
+            if version.len() > 32
+                || !version
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                panic!(
+                    "CACHE_VERSION must be at most 32 letters, digits or dashes, got '{}'",
+                    version
+                );
+            }
+        }
+
+        let max_dimension = env::var("MAX_IMAGE_DIMENSION")
+            .ok()
+            .map(|v| {
+                v.parse::<u32>()
+                    .expect("MAX_IMAGE_DIMENSION must be a number")
+            })
+            .unwrap_or(16_384);
+        let max_alloc_mb = env::var("MAX_DECODE_ALLOC_MB")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("MAX_DECODE_ALLOC_MB must be a number")
+            })
+            .unwrap_or(512);
+        let decode_format_retry = env::var("DECODE_FORMAT_RETRY")
+            .map(|v| v != "false" && v != "0")
+            .unwrap_or(true);
+
+        let min_source_dimension = env::var("MIN_SOURCE_DIMENSION")
+            .ok()
+            .map(|v| {
+                v.parse::<u32>()
+                    .expect("MIN_SOURCE_DIMENSION must be a number")
+            })
+            .filter(|&v| v > 0);
+
+        let max_upload_bytes = env::var("MAX_UPLOAD_BYTES")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("MAX_UPLOAD_BYTES must be a number")
+            })
+            .unwrap_or(20 * 1024 * 1024);
+
+        let max_raw_output_bytes = env::var("MAX_RAW_OUTPUT_BYTES")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("MAX_RAW_OUTPUT_BYTES must be a number")
+            })
+            .unwrap_or(64 * 1024 * 1024);
+
+        let strict_tagging = env::var("STRICT_TAGGING")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let single_flight = env::var("SINGLE_FLIGHT")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(true);
+
+        let breaker_failure_threshold = env::var("S3_BREAKER_FAILURE_THRESHOLD")
+            .ok()
+            .map(|v| {
+                v.parse::<u32>()
+                    .expect("S3_BREAKER_FAILURE_THRESHOLD must be a number")
+            })
+            .unwrap_or(5);
+        let breaker_window_secs = env::var("S3_BREAKER_WINDOW_SECS")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("S3_BREAKER_WINDOW_SECS must be a number")
+            })
+            .unwrap_or(30);
+        let breaker_cooldown_secs = env::var("S3_BREAKER_COOLDOWN_SECS")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("S3_BREAKER_COOLDOWN_SECS must be a number")
+            })
+            .unwrap_or(30);
+
+        let log_sample_rate = env::var("LOG_SAMPLE_RATE")
+            .ok()
+            .map(|v| v.parse::<u64>().expect("LOG_SAMPLE_RATE must be a number"))
+            .unwrap_or(1)
+            .max(1);
+        let slow_request_ms = env::var("SLOW_REQUEST_MS")
+            .ok()
+            .map(|v| v.parse::<u64>().expect("SLOW_REQUEST_MS must be a number"))
+            .unwrap_or(2_000);
+
+        let default_acl = env::var("UPLOAD_ACL")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                ObjectAcl::deserialize(v.as_str().into_deserializer()).unwrap_or_else(
+                    |e: serde::de::value::Error| panic!("UPLOAD_ACL is not a canned ACL: {}", e),
+                )
+            });
+
+        let default_storage_class = env::var("STORAGE_CLASS")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                StorageClass::deserialize(v.as_str().into_deserializer()).unwrap_or_else(
+                    |e: serde::de::value::Error| {
+                        panic!("STORAGE_CLASS is not a supported storage class: {}", e)
+                    },
+                )
+            });
+
+        let upload_checksum = match env::var("UPLOAD_CHECKSUM")
+            .unwrap_or_else(|_| "crc32".to_string())
+            .as_str()
+        {
+            "crc32" => UploadChecksum::Crc32,
+            "sha256" => UploadChecksum::Sha256,
+            other => panic!(
+                "UPLOAD_CHECKSUM must be 'crc32' or 'sha256', got '{}'",
+                other
+            ),
+        };
+
+        let job_workers = env::var("JOB_WORKERS")
+            .ok()
+            .map(|v| v.parse::<usize>().expect("JOB_WORKERS must be a number"))
+            .unwrap_or(4)
+            .max(1);
+        let max_request_work = env::var("MAX_REQUEST_WORK")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f64>().expect("MAX_REQUEST_WORK must be a number"))
+            .filter(|&v| v > 0.0);
+        let s3_file = env::var("S3_CONFIG_FILE")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|path| {
+                S3FileConfig::load(&path).unwrap_or_else(|e| panic!("S3_CONFIG_FILE: {}", e))
+            });
+        let auto_formats = parse_auto_formats(
+            &env::var("AUTO_FORMAT_CANDIDATES").unwrap_or_else(|_| "webp,jpeg".to_string()),
+        );
+        let avif_fallback = parse_avif_fallback(
+            &env::var("AVIF_FALLBACK_FORMATS").unwrap_or_else(|_| "webp,jpeg".to_string()),
+        );
+        let custom_domain_buckets = env::var("CUSTOM_DOMAIN_BUCKET_MAP")
+            .map(|value| parse_custom_domains(&value))
+            .unwrap_or_default();
+        let worker_threads = env::var("TOKIO_WORKER_THREADS")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("TOKIO_WORKER_THREADS must be a number")
+            })
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1);
+        let max_blocking_threads = env::var("TOKIO_MAX_BLOCKING_THREADS")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("TOKIO_MAX_BLOCKING_THREADS must be a number")
+            })
+            .unwrap_or(512)
+            .max(1);
+        let resize_threads = env::var("RESIZE_THREADS")
+            .ok()
+            .map(|v| v.parse::<usize>().expect("RESIZE_THREADS must be a number"))
+            .unwrap_or(1)
+            .max(1);
+        let max_concurrent_decodes = env::var("MAX_CONCURRENT_DECODES")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("MAX_CONCURRENT_DECODES must be a number")
+            })
+            .filter(|&v| v > 0);
+        let job_queue_capacity = env::var("JOB_QUEUE_CAPACITY")
+            .ok()
+            .map(|v| {
+                v.parse::<usize>()
+                    .expect("JOB_QUEUE_CAPACITY must be a number")
+            })
+            .unwrap_or(1_000)
+            .max(1);
+        let job_retention_secs = env::var("JOB_RETENTION_SECS")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("JOB_RETENTION_SECS must be a number")
+            })
+            .unwrap_or(3_600);
+
+        let request_timeout_ms = env::var("REQUEST_TIMEOUT_MS")
+            .ok()
+            .map(|v| {
+                v.parse::<u64>()
+                    .expect("REQUEST_TIMEOUT_MS must be a number")
+            })
+            .unwrap_or(30_000);
+
+        let watermark_font_path = env::var("WATERMARK_FONT_PATH")
+            .ok()
+            .filter(|v| !v.is_empty());
+
+        let ffmpeg_path = env::var("FFMPEG_PATH")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "ffmpeg".to_string());
+
+        let fallback_image_s3_url = env::var("FALLBACK_IMAGE_S3_URL")
+            .ok()
+            .filter(|v| !v.is_empty());
+        if let Some(url) = &fallback_image_s3_url {
+            if let Err(e) = parse_s3_url(url) {
+                panic!("FALLBACK_IMAGE_S3_URL is invalid: {}", e);
+            }
+        }
+
+        let readiness_bucket = env::var("READINESS_BUCKET").ok().filter(|v| !v.is_empty());
+
+        let readiness_write_check = env::var("READINESS_WRITE_CHECK")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        if readiness_write_check && readiness_bucket.is_none() {
+            panic!("READINESS_WRITE_CHECK requires READINESS_BUCKET to be set");
+        }
+
+        let rate_limit_rps = env::var("RATE_LIMIT_RPS")
+            .ok()
+            .map(|v| v.parse::<f64>().expect("RATE_LIMIT_RPS must be a number"))
+            .filter(|&v| v > 0.0);
+        let rate_limit_burst = env::var("RATE_LIMIT_BURST")
+            .ok()
+            .map(|v| v.parse::<u32>().expect("RATE_LIMIT_BURST must be a number"))
+            .unwrap_or_else(|| rate_limit_rps.map_or(1, |rps| rps.ceil() as u32))
+            .max(1);
+        let rate_limit_trust_forwarded = env::var("RATE_LIMIT_TRUST_FORWARDED")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let source_metadata_defaults = env::var("SOURCE_METADATA_DEFAULTS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let requester_pays = env::var("S3_REQUESTER_PAYS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let jpeg_encoder = match env::var("JPEG_ENCODER")
+            .unwrap_or_else(|_| "builtin".to_string())
+            .as_str()
+        {
+            "builtin" => JpegEncoder::Builtin,
+            "mozjpeg" if cfg!(feature = "mozjpeg") => JpegEncoder::Mozjpeg,
+            "mozjpeg" => {
+                tracing::warn!(
+                    "JPEG_ENCODER=mozjpeg needs the `mozjpeg` feature; using the built-in encoder"
+                );
+                JpegEncoder::Builtin
+            }
+            other => panic!(
+                "JPEG_ENCODER must be 'builtin' or 'mozjpeg', got '{}'",
+                other
+            ),
+        };
+
+        Self {
+            dpr_key_style,
+            default_object_mode,
+            content_hash_length,
+            cache_version,
+            decode_limits: DecodeLimits {
+                max_dimension,
+                max_alloc_bytes: max_alloc_mb * 1024 * 1024,
+                format_retry: decode_format_retry,
+            },
+            min_source_dimension,
+            max_upload_bytes,
+            max_raw_output_bytes,
+            strict_tagging,
+            single_flight,
+            breaker_failure_threshold,
+            breaker_window: Duration::from_secs(breaker_window_secs),
+            breaker_cooldown: Duration::from_secs(breaker_cooldown_secs),
+            log_sample_rate,
+            slow_request_threshold: Duration::from_millis(slow_request_ms),
+            default_acl,
+            default_storage_class,
+            upload_checksum,
+            job_workers,
+            max_request_work,
+            s3_file,
+            custom_domain_buckets,
+            auto_formats,
+            avif_fallback,
+            worker_threads,
+            max_blocking_threads,
+            resize_threads,
+            max_concurrent_decodes,
+            job_queue_capacity,
+            job_retention: Duration::from_secs(job_retention_secs),
+            request_timeout: Duration::from_millis(request_timeout_ms),
+            watermark_font_path,
+            ffmpeg_path,
+            fallback_image_s3_url,
+            readiness_bucket,
+            readiness_write_check,
+            rate_limit_rps,
+            rate_limit_burst,
+            rate_limit_trust_forwarded,
+            source_metadata_defaults,
+            requester_pays,
+            jpeg_encoder,
+        }
+    }
+}
+
+fn parse_auto_formats(value: &str) -> Vec<OutputFormat> {
+    let mut formats = Vec::new();
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let format = OutputFormat::deserialize(name.into_deserializer()).unwrap_or_else(
+            |e: serde::de::value::Error| {
+                panic!("AUTO_FORMAT_CANDIDATES has an unknown format: {}", e)
+            },
+        );
+        match format {
+            OutputFormat::Auto => panic!("AUTO_FORMAT_CANDIDATES can't contain 'auto'"),
+            OutputFormat::Avif if !cfg!(feature = "avif") => {
+                panic!("AUTO_FORMAT_CANDIDATES contains 'avif' but the service was built without the `avif` feature")
+            }
+            _ => {}
+        }
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+
+    if formats.is_empty() || formats.len() > MAX_AUTO_FORMATS {
+        panic!(
+            "AUTO_FORMAT_CANDIDATES must name between 1 and {} formats",
+            MAX_AUTO_FORMATS
+        );
+    }
+    formats
+}
+
+/// `host=bucket` pairs separated by commas, e.g.
+/// `cdn.example.com=my-bucket,img.example.org=other-bucket`.
+fn parse_custom_domains(value: &str) -> BTreeMap<String, String> {
+    let mut domains = BTreeMap::new();
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (host, bucket) = entry
+            .split_once('=')
+            .map(|(host, bucket)| (host.trim().to_ascii_lowercase(), bucket.trim()))
+            .filter(|(host, bucket)| !host.is_empty() && !bucket.is_empty())
+            .unwrap_or_else(|| {
+                panic!(
+                    "CUSTOM_DOMAIN_BUCKET_MAP entries must be host=bucket, got '{}'",
+                    entry
+                )
+            });
+        if host.contains('/') || host.contains(':') {
+            panic!("CUSTOM_DOMAIN_BUCKET_MAP hosts must be bare domains without a scheme, port or path, got '{}'", host);
+        }
+        domains.insert(host, bucket.to_string());
+    }
+    domains
+}
+
+fn parse_avif_fallback(value: &str) -> Vec<OutputFormat> {
+    let mut formats = Vec::new();
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let format = OutputFormat::deserialize(name.into_deserializer()).unwrap_or_else(
+            |e: serde::de::value::Error| {
+                panic!("AVIF_FALLBACK_FORMATS has an unknown format: {}", e)
+            },
+        );
+        if matches!(format, OutputFormat::Auto | OutputFormat::Avif) {
+            panic!("AVIF_FALLBACK_FORMATS can only contain webp, png and jpeg");
+        }
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    formats
+}
@@ -0,0 +1,63 @@
+use axum::http::HeaderMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::error::AppError;
+
+/// Remaining time budget of the caller, in milliseconds.
+pub const DEADLINE_HEADER: &str = "x-deadline-ms";
+
+/// The point after which the caller has given up on the request, so any work
+/// still running would produce a result nobody reads.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            at: Instant::now() + budget,
+        }
+    }
+
+    /// Uses the `X-Deadline-Ms` budget when the caller sent one, otherwise
+    /// `default` (the global request timeout).
+    pub fn from_headers(headers: &HeaderMap, default: Duration) -> Result<Self, AppError> {
+        let Some(value) = headers.get(DEADLINE_HEADER) else {
+            return Ok(Self::after(default));
+        };
+
+        let budget_ms = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .ok_or_else(|| {
+                AppError::InvalidParameter(
+                    "X-Deadline-Ms must be a number of milliseconds".to_string(),
+                )
+            })?;
+
+        Ok(Self::after(Duration::from_millis(budget_ms)))
+    }
+
+    /// Runs one stage of a request, abandoning it with a 504 once the deadline
+    /// passes. A stage that starts after the deadline is not started at all.
+    pub async fn run<T, F>(&self, stage: &str, work: F) -> Result<T, AppError>
+    where
+        F: Future<Output = Result<T, AppError>>,
+    {
+        if Instant::now() >= self.at {
+            return Err(Self::exceeded(stage));
+        }
+
+        tokio::time::timeout_at(self.at, work)
+            .await
+            .unwrap_or_else(|_| Err(Self::exceeded(stage)))
+    }
+
+    fn exceeded(stage: &str) -> AppError {
+        AppError::DeadlineExceeded(format!("Request deadline exceeded during {}", stage))
+    }
+}
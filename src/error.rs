@@ -1,32 +1,148 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use serde::Serialize;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// JSON body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Each problem on its own, for requests rejected by validation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<String>>,
+}
 
 #[derive(Debug)]
 pub enum AppError {
     InvalidS3Url(String),
+    InvalidParameter(String),
+    /// Every problem found while validating a request, so clients can fix
+    /// them in one go. Rendered as a 400 with an `errors` list.
+    ValidationFailed(Vec<String>),
+    /// The request is valid but needs something this build or this input
+    /// can't provide, e.g. a format behind a disabled cargo feature.
+    UnsupportedOperation(String),
+    NotFound(String),
     S3Error(String),
+    #[cfg(feature = "gcs")]
+    GcsError(String),
     ImageProcessingError(String),
+    DimensionTooLarge(String),
+    SourceTooSmall(String),
+    /// The source object exists but has no image in it, typically a
+    /// botched upload that left zero bytes behind.
+    EmptySource(String),
+    PayloadTooLarge(String),
+    ServiceUnavailable(String),
+    DeadlineExceeded(String),
+    /// The client is over its rate limit; rendered as a 429 whose
+    /// `Retry-After` is the wait until its next request is allowed.
+    RateLimited(String, Duration),
     InternalError(String),
 }
 
+/// `Ok` when `problems` is empty, otherwise one error listing all of them.
+pub fn reject_problems(problems: Vec<String>) -> Result<(), AppError> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::ValidationFailed(problems))
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            AppError::ValidationFailed(problems) => return f.write_str(&problems.join("; ")),
+            AppError::InvalidS3Url(msg)
+            | AppError::InvalidParameter(msg)
+            | AppError::UnsupportedOperation(msg)
+            | AppError::NotFound(msg)
+            | AppError::S3Error(msg)
+            | AppError::ImageProcessingError(msg)
+            | AppError::DimensionTooLarge(msg)
+            | AppError::SourceTooSmall(msg)
+            | AppError::EmptySource(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::ServiceUnavailable(msg)
+            | AppError::DeadlineExceeded(msg)
+            | AppError::RateLimited(msg, _)
+            | AppError::InternalError(msg) => msg,
+            #[cfg(feature = "gcs")]
+            AppError::GcsError(msg) => msg,
+        };
+
+        f.write_str(msg)
+    }
+}
+
+impl AppError {
+    /// HTTP status the error is rendered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::ValidationFailed(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidS3Url(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+            AppError::UnsupportedOperation(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::S3Error(_) => StatusCode::BAD_GATEWAY,
+            #[cfg(feature = "gcs")]
+            AppError::GcsError(_) => StatusCode::BAD_GATEWAY,
+            AppError::ImageProcessingError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::DimensionTooLarge(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::SourceTooSmall(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::EmptySource(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError::InvalidS3Url(msg) => (StatusCode::BAD_REQUEST, msg),
-            AppError::S3Error(msg) => (StatusCode::BAD_GATEWAY, msg),
-            AppError::ImageProcessingError(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
-            AppError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        let errors = match &self {
+            AppError::ValidationFailed(problems) => Some(problems.clone()),
+            _ => None,
+        };
+        // Whole seconds, rounded up so a client honouring it isn't rejected again.
+        let retry_after = match &self {
+            AppError::RateLimited(_, wait) => Some(wait.as_secs_f64().ceil() as u64),
+            _ => None,
         };
+        let status = self.status();
+        let error_message = self.to_string();
+
+        // Errors are never sampled away, unlike routine success logs.
+        if status.is_server_error() {
+            tracing::error!(
+                status = status.as_u16(),
+                "Request failed: {}",
+                error_message
+            );
+        } else {
+            tracing::warn!(
+                status = status.as_u16(),
+                "Request rejected: {}",
+                error_message
+            );
+        }
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+        let body = Json(ErrorBody {
+            error: error_message,
+            errors,
+        });
 
-        (status, body).into_response()
+        match retry_after {
+            Some(secs) => (status, [(header::RETRY_AFTER, secs.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
     }
 }
 
@@ -0,0 +1,85 @@
+use crate::error::AppError;
+
+pub fn unsupported() -> AppError {
+    AppError::UnsupportedOperation(
+        "face_crop requires the service to be built with the `face-detect` feature".to_string(),
+    )
+}
+
+#[cfg(feature = "face-detect")]
+mod detect {
+    use std::cell::RefCell;
+
+    use image::{DynamicImage, GenericImageView};
+    use rustface::{Detector, ImageData};
+
+    /// SeetaFace frontal face model, as shipped with rustface.
+    const MODEL: &[u8] = include_bytes!("../assets/models/seeta_fd_frontal_v1.0.bin");
+
+    /// Detection runs on a copy no larger than this per side; faces in
+    /// avatars and portraits are big enough to survive the downscale, and
+    /// the cascade's cost grows with the pixel count.
+    const DETECTION_SIZE: u32 = 640;
+
+    thread_local! {
+        /// Detection needs `&mut` access, so each blocking thread parses the
+        /// model once and keeps its own detector.
+        static DETECTOR: RefCell<Box<dyn Detector>> = RefCell::new(detector());
+    }
+
+    fn detector() -> Box<dyn Detector> {
+        let model = rustface::read_model(MODEL).expect("bundled face model is valid");
+        let mut detector = rustface::create_detector_with_model(model);
+        detector.set_min_face_size(20);
+        detector.set_score_thresh(2.0);
+        detector.set_pyramid_scale_factor(0.8);
+        detector.set_slide_window_step(4, 4);
+        detector
+    }
+
+    /// Center of the largest detected face as fractions of the image width
+    /// and height, or `None` when there is no face.
+    pub fn largest_face_center(img: &DynamicImage) -> Option<(f32, f32)> {
+        let (width, height) = img.dimensions();
+        let gray = img.thumbnail(DETECTION_SIZE, DETECTION_SIZE).to_luma8();
+        let (gray_width, gray_height) = gray.dimensions();
+        if gray_width == 0 || gray_height == 0 {
+            return None;
+        }
+
+        let image = ImageData::new(gray.as_raw(), gray_width, gray_height);
+        let faces = DETECTOR.with(|detector| detector.borrow_mut().detect(&image));
+        let face = faces
+            .iter()
+            .map(|face| face.bbox())
+            .max_by_key(|bbox| bbox.width() as u64 * bbox.height() as u64)?;
+        tracing::debug!(
+            "Found {} face(s), largest {}x{} at {},{} in a {}x{} detection copy of {}x{}",
+            faces.len(),
+            face.width(),
+            face.height(),
+            face.x(),
+            face.y(),
+            gray_width,
+            gray_height,
+            width,
+            height
+        );
+
+        let center_x = face.x() as f32 + face.width() as f32 / 2.0;
+        let center_y = face.y() as f32 + face.height() as f32 / 2.0;
+        Some((
+            (center_x / gray_width as f32).clamp(0.0, 1.0),
+            (center_y / gray_height as f32).clamp(0.0, 1.0),
+        ))
+    }
+}
+
+#[cfg(feature = "face-detect")]
+pub use detect::largest_face_center;
+
+/// Handlers reject `face_crop` up front in builds without the detector.
+#[cfg(not(feature = "face-detect"))]
+pub fn largest_face_center(_img: &image::DynamicImage) -> Option<(f32, f32)> {
+    None
+}
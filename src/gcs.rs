@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::object_access_controls::PredefinedObjectAcl;
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::Error as GcsHttpError;
+
+use crate::error::AppError;
+use crate::models::ObjectAcl;
+use crate::storage::{Storage, UploadOptions};
+
+pub struct GcsClient {
+    client: Client,
+}
+
+impl GcsClient {
+    /// Authenticates via the standard GCP chain: `GOOGLE_APPLICATION_CREDENTIALS`,
+    /// gcloud application-default credentials, or the metadata server.
+    pub async fn new() -> Result<Self, AppError> {
+        let config = ClientConfig::default()
+            .with_auth()
+            .await
+            .map_err(|e| AppError::GcsError(format!("Failed to load GCP credentials: {}", e)))?;
+
+        Ok(Self {
+            client: Client::new(config),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for GcsClient {
+    async fn check_object_exists(&self, bucket: &str, key: &str) -> bool {
+        tracing::debug!(
+            "Checking if GCS object exists: bucket={}, key={}",
+            bucket,
+            key
+        );
+
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        match self.client.get_object(&request).await {
+            Ok(_) => {
+                tracing::debug!("GCS object exists: bucket={}, key={}", bucket, key);
+                true
+            }
+            Err(_) => {
+                tracing::debug!("GCS object does not exist: bucket={}, key={}", bucket, key);
+                false
+            }
+        }
+    }
+
+    async fn download_object(&self, bucket: &str, key: &str) -> Result<Bytes, AppError> {
+        tracing::debug!("Downloading from GCS: bucket={}, key={}", bucket, key);
+
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let data = self
+            .client
+            .download_object(&request, &Range::default())
+            .await
+            .map_err(|e| AppError::GcsError(format!("Failed to download from GCS: {}", e)))?;
+
+        Ok(Bytes::from(data))
+    }
+
+    async fn upload_image(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        content_type: &str,
+        options: &UploadOptions,
+    ) -> Result<String, AppError> {
+        tracing::debug!("Uploading to GCS: bucket={}, key={}", bucket, key);
+
+        let predefined_acl = options.acl.map(predefined_acl).transpose()?;
+
+        let mut media = Media::new(key.to_string());
+        media.content_type = content_type.to_string().into();
+
+        // Generation 0 matches only a missing object.
+        let request = UploadObjectRequest {
+            bucket: bucket.to_string(),
+            predefined_acl,
+            if_generation_match: options.if_absent.then_some(0),
+            ..Default::default()
+        };
+
+        let result = self
+            .client
+            .upload_object(&request, data.to_vec(), &UploadType::Simple(media))
+            .await;
+        if let Err(GcsHttpError::Response(response)) = &result {
+            if options.if_absent && response.code == 412 {
+                tracing::debug!(
+                    "gs://{}/{} was already written by a concurrent request, keeping it",
+                    bucket,
+                    key
+                );
+                return Ok(format!("gs://{}/{}", bucket, key));
+            }
+        }
+
+        result.map_err(|e| {
+                let message = e.to_string();
+                if request.predefined_acl.is_some()
+                    && message.to_lowercase().contains("uniform bucket-level access")
+                {
+                    AppError::InvalidParameter(format!(
+                        "Bucket {} uses uniform bucket-level access and rejects object ACLs; remove the acl option or UPLOAD_ACL",
+                        bucket
+                    ))
+                } else {
+                    AppError::GcsError(format!("Failed to upload to GCS: {}", message))
+                }
+            })?;
+
+        Ok(format!("gs://{}/{}", bucket, key))
+    }
+}
+
+fn predefined_acl(acl: ObjectAcl) -> Result<PredefinedObjectAcl, AppError> {
+    match acl {
+        ObjectAcl::Private => Ok(PredefinedObjectAcl::Private),
+        ObjectAcl::PublicRead => Ok(PredefinedObjectAcl::PublicRead),
+        ObjectAcl::AuthenticatedRead => Ok(PredefinedObjectAcl::AuthenticatedRead),
+        ObjectAcl::BucketOwnerRead => Ok(PredefinedObjectAcl::BucketOwnerRead),
+        ObjectAcl::BucketOwnerFullControl => Ok(PredefinedObjectAcl::BucketOwnerFullControl),
+        ObjectAcl::PublicReadWrite => Err(AppError::InvalidParameter(
+            "GCS has no public-read-write object ACL".to_string(),
+        )),
+    }
+}
@@ -1,65 +1,3630 @@
-use axum::Json;
-use crate::error::AppError;
-use crate::models::{ResizeRequest, ResizeResponse};
-use crate::s3::{S3Client, parse_s3_url, generate_resized_key};
-use crate::image_processor::ImageProcessor;
+use crate::config::{Config, DprKeyStyle};
+use crate::deadline::Deadline;
+use crate::error::{reject_problems, AppError, ErrorBody};
+use crate::face;
+use crate::image_processor::{
+    DecodeLimits, DistortionGuard, EncodeOptions, ImageProcessor, ProcessedImage, ResizeOptions,
+    Step, Watermark, DEFAULT_JPEG_QUALITY, PHASH_BITS,
+};
+use crate::log_sampling::log_sampled;
+use crate::models::{
+    default_alpha_background, AspectCropRequest, AspectCropResponse, CanvasOptions,
+    ChromaSubsampling, CollageRequest, CollageResponse, ColorProfile, ConvertRequest,
+    ConvertResponse, DestinationResult, HealthResponse, HexColor, InlineResizeQuery, JobResponse,
+    JobStatus, LqipOptions, MontageRequest, MontageResponse, MultiDestResizeRequest,
+    MultiSizeResizeRequest, ObjectMode, Operation, OutputFormat, ParseUrlRequest, ParseUrlResponse,
+    PhashRequest, PhashResponse, RawResizeQuery, ReadyResponse, RequestedSize, ResizeFilter,
+    ResizeQuery, ResizeRequest, ResizeResponse, ResizeSidecar, ResizeStats, ResizeTimings,
+    SizeResult, SpriteCell, SpriteRequest, SpriteResponse, SrcsetOptions, StatsResponse,
+    StorageClass, TextWatermark, TileRequest, TileResponse, UploadResizeResponse, VerifyRequest,
+    VerifyResponse,
+};
+use crate::openapi::{ApiDoc, ImageBytes};
+use crate::readiness;
+use crate::s3::{
+    append_content_hash, append_key_suffix, generate_aspect_crop_key, generate_collage_key,
+    generate_convert_key, generate_dpr_key, generate_montage_key, generate_resized_key,
+    generate_sidecar_key, generate_sprite_key, generate_tile_key, generate_tile_manifest_key,
+    parse_s3_url, replace_extension, replace_key_stem, tiles_prefix, S3Client,
+};
+use crate::state::AppState;
+use crate::storage::{
+    self, parse_output_bucket, OutputLocation, Storage, StorageScheme, UploadOptions,
+};
+use crate::svg;
+use crate::video;
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::join_all;
+use futures_util::stream::{self, StreamExt};
+use serde::{de::IntoDeserializer, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use utoipa::OpenApi;
+use uuid::Uuid;
 
+const DEFAULT_VERIFY_THRESHOLD: f64 = 0.99;
+const MAX_COLLAGE_CELLS: u64 = 16;
+const MAX_MONTAGE_SOURCES: usize = 100;
+const MAX_DESTINATIONS: usize = 5;
+const MAX_SIZES: usize = 10;
+const MAX_SPRITE_CELLS: u32 = 100;
+const MIN_TILE_SIZE: u32 = 64;
+const MAX_TILE_SIZE: u32 = 2048;
+const MAX_TILE_OVERLAP: u32 = 8;
+/// Tile uploads in flight at once for one pyramid.
+const TILE_UPLOAD_CONCURRENCY: usize = 16;
+
+#[utoipa::path(
+    post,
+    path = "/resize",
+    params(ResizeQuery),
+    request_body = ResizeRequest,
+    responses(
+        (status = 200, description = "Derivative stored, or found in the cache", body = ResizeResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+        (status = 504, description = "Request deadline passed", body = ErrorBody),
+    )
+)]
 pub async fn resize_image(
+    State(state): State<AppState>,
+    Query(query): Query<ResizeQuery>,
+    headers: HeaderMap,
     Json(payload): Json<ResizeRequest>,
 ) -> Result<Json<ResizeResponse>, AppError> {
-    tracing::info!(
-        "Resize request: url={}, width={}, height={}, mode={:?}",
+    resize_to_destinations(state, headers, payload, None, None, query.debug)
+        .await
+        .map(Json)
+}
+
+/// Resizes once and stores the identical bytes in every destination bucket,
+/// e.g. one per CDN origin region. Each destination is reported separately;
+/// the request only fails when none of them could be written.
+#[utoipa::path(
+    post,
+    path = "/resize-multi-dest",
+    request_body = MultiDestResizeRequest,
+    responses(
+        (status = 200, description = "Stored in at least one destination; see `destinations`", body = ResizeResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+        (status = 504, description = "Request deadline passed", body = ErrorBody),
+    )
+)]
+pub async fn resize_multi_dest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<MultiDestResizeRequest>,
+) -> Result<Json<ResizeResponse>, AppError> {
+    if payload.request.output_bucket.is_some() {
+        return Err(AppError::InvalidParameter(
+            "output_bucket cannot be combined with destinations".to_string(),
+        ));
+    }
+    if payload.destinations.is_empty() || payload.destinations.len() > MAX_DESTINATIONS {
+        return Err(AppError::InvalidParameter(format!(
+            "destinations must list between 1 and {} buckets",
+            MAX_DESTINATIONS
+        )));
+    }
+
+    resize_to_destinations(
+        state,
+        headers,
+        payload.request,
+        Some(payload.destinations),
+        None,
+        false,
+    )
+    .await
+    .map(Json)
+}
+
+/// Resizes to every entry of `sizes`, or every width of `srcset`,
+/// concurrently. A size that fails is reported in its place without failing
+/// the others; the response is a `207` when any did.
+#[utoipa::path(
+    post,
+    path = "/resize-multi-size",
+    request_body = MultiSizeResizeRequest,
+    responses(
+        (status = 200, description = "Every size was stored, or found in the cache", body = Vec<SizeResult>),
+        (status = 207, description = "Some sizes failed; see each entry's `status`", body = Vec<SizeResult>),
+        (status = 400, description = "Invalid `sizes` or `srcset`", body = ErrorBody),
+    )
+)]
+pub async fn resize_multi_size(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<MultiSizeResizeRequest>,
+) -> Result<(StatusCode, Json<Vec<SizeResult>>), AppError> {
+    if payload.request.width.is_some()
+        || payload.request.height.is_some()
+        || payload.request.square.is_some()
+    {
+        return Err(AppError::InvalidParameter(
+            "width, height and square are set per entry of sizes".to_string(),
+        ));
+    }
+    if payload.request.operations.is_some() {
+        return Err(AppError::InvalidParameter(
+            "operations can't be combined with sizes; each size is its own resize".to_string(),
+        ));
+    }
+    let (sizes, source) = match (&payload.srcset, payload.sizes.is_empty()) {
+        (None, false) => (payload.sizes, None),
+        (Some(srcset), true) => srcset_sizes(&state, &headers, &payload.request, srcset).await?,
+        _ => {
+            return Err(AppError::InvalidParameter(
+                "Provide either sizes or srcset".to_string(),
+            ))
+        }
+    };
+    if sizes.len() > MAX_SIZES {
+        return Err(AppError::InvalidParameter(format!(
+            "sizes and srcset.widths must list between 1 and {} sizes",
+            MAX_SIZES
+        )));
+    }
+
+    // Each size is its own `/resize`, so one that can't be served (e.g. under
+    // `min_source_dimension`) fails alone. A source srcset already read is
+    // shared instead of being downloaded again for every size.
+    let results = join_all(sizes.iter().map(|size| {
+        let request = ResizeRequest {
+            width: Some(size.width),
+            height: Some(size.height),
+            ..payload.request.clone()
+        };
+        resize_to_destinations(
+            state.clone(),
+            headers.clone(),
+            request,
+            None,
+            source.clone(),
+            false,
+        )
+    }))
+    .await;
+
+    let results: Vec<SizeResult> = sizes
+        .iter()
+        .zip(results)
+        .map(|(size, result)| match result {
+            Ok(response) => SizeResult::Ok(Box::new(response)),
+            Err(e) => {
+                tracing::warn!(
+                    "Size {}x{} of {} failed: {}",
+                    size.width,
+                    size.height,
+                    payload.request.s3_url,
+                    e
+                );
+                SizeResult::Error {
+                    width: size.width,
+                    height: size.height,
+                    code: e.status().as_u16(),
+                    message: e.to_string(),
+                }
+            }
+        })
+        .collect();
+
+    let status = if results
+        .iter()
+        .all(|result| matches!(result, SizeResult::Ok(_)))
+    {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok((status, Json(results)))
+}
+
+/// The sizes `srcset` stands for. Without an `aspect_ratio` the source is
+/// downloaded once up front to read its dimensions, and returned for the
+/// resizes to reuse.
+async fn srcset_sizes(
+    state: &AppState,
+    headers: &HeaderMap,
+    request: &ResizeRequest,
+    srcset: &SrcsetOptions,
+) -> Result<(Vec<RequestedSize>, Option<Source>), AppError> {
+    if srcset.widths.is_empty() || srcset.widths.contains(&0) {
+        return Err(AppError::InvalidParameter(
+            "srcset.widths must list widths greater than 0".to_string(),
+        ));
+    }
+    if srcset.widths.len() > MAX_SIZES {
+        return Err(AppError::InvalidParameter(format!(
+            "sizes and srcset.widths must list between 1 and {} sizes",
+            MAX_SIZES
+        )));
+    }
+
+    let (ratio_width, ratio_height, source) = match &srcset.aspect_ratio {
+        Some(aspect_ratio) => {
+            let (ratio_width, ratio_height) = parse_aspect_ratio(aspect_ratio)?;
+            (ratio_width, ratio_height, None)
+        }
+        None => {
+            let deadline = Deadline::from_headers(headers, state.config.request_timeout)?;
+            let s3_client = state
+                .source_client(&request.s3_url, request.requester_pays)
+                .await;
+            let source = download_source(state, &deadline, &s3_client, request).await?;
+            let (width, height) = ImageProcessor::dimensions(&source.data)?;
+            (width, height, Some(source))
+        }
+    };
+
+    Ok((srcset.sizes(ratio_width, ratio_height), source))
+}
+
+/// The `/resize` pipeline. `destinations` replaces `output_bucket` and adds a
+/// per-destination report to the response; `source`, when the caller already
+/// downloaded it, is used instead of downloading it again; `debug` adds the
+/// stage timings.
+async fn resize_to_destinations(
+    state: AppState,
+    headers: HeaderMap,
+    payload: ResizeRequest,
+    destinations: Option<Vec<String>>,
+    source: Option<Source>,
+    debug: bool,
+) -> Result<ResizeResponse, AppError> {
+    let started = Instant::now();
+    let deadline = Deadline::from_headers(&headers, state.config.request_timeout)?;
+    let payload = deadline
+        .run("metadata lookup", with_source_metadata(&state, payload))
+        .await?;
+    let requested_mode = payload
+        .object_mode
+        .unwrap_or(state.config.default_object_mode);
+    tracing::debug!(
+        "Resize request: url={}, width={:?}, height={:?}, max_megapixels={:?}, max_width={:?}, max_height={:?}, mode={:?}, dpr={:?}",
         payload.s3_url,
         payload.width,
         payload.height,
-        payload.object_mode
+        payload.max_megapixels,
+        payload.max_width,
+        payload.max_height,
+        requested_mode,
+        payload.dpr
     );
 
-    if payload.width == 0 || payload.height == 0 {
-        return Err(AppError::InvalidS3Url(
-            "Width and height must be greater than 0".to_string(),
-        ));
+    check_resize_request(&payload, &state.config)?;
+    let payload = payload.expand_square();
+
+    let dpr = payload.dpr.unwrap_or(1);
+    let filter = payload.resolved_filter();
+    let quality = payload.resolved_quality(DEFAULT_JPEG_QUALITY);
+    // A preset's progressive flag only means something for JPEG.
+    let progressive = payload.resolved_progressive() && payload.output_format == OutputFormat::Jpeg;
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+
+    let canvas = payload.canvas.clone().map(|canvas| CanvasOptions {
+        width: canvas.width.saturating_mul(dpr),
+        height: canvas.height.saturating_mul(dpr),
+        x: canvas.x.map(|x| x * dpr as i64),
+        y: canvas.y.map(|y| y * dpr as i64),
+        ..canvas
+    });
+
+    if let Some(canvas) = &canvas {
+        if canvas.width > max_dimension || canvas.height > max_dimension {
+            return Err(AppError::DimensionTooLarge(format!(
+                "Requested canvas {}x{} exceeds the maximum of {}px per side",
+                canvas.width, canvas.height, max_dimension
+            )));
+        }
     }
 
     let (bucket, original_key) = parse_s3_url(&payload.s3_url)?;
 
-    let s3_client = S3Client::new().await;
+    let multi_dest = destinations.is_some();
+    let outputs = match &destinations {
+        Some(destinations) => {
+            let outputs = destinations
+                .iter()
+                .map(|destination| parse_output_bucket(destination))
+                .collect::<Result<Vec<_>, _>>()?;
+            let urls: HashSet<String> = outputs.iter().map(OutputLocation::bucket_url).collect();
+            if urls.len() < outputs.len() {
+                return Err(AppError::InvalidParameter(
+                    "destinations must not repeat a bucket".to_string(),
+                ));
+            }
+            outputs
+        }
+        None => vec![match &payload.output_bucket {
+            Some(output_url) => parse_output_bucket(output_url)?,
+            None => OutputLocation::s3(&bucket),
+        }],
+    };
+
+    // The source's bucket is reached in the region its URL names; other
+    // buckets are only named as `s3://`, so they use the configured region.
+    let s3_client = state
+        .source_client(&payload.s3_url, payload.requester_pays)
+        .await;
+    let default_s3_client = state.s3_client().await;
+    let gcs_client = if outputs
+        .iter()
+        .any(|output| output.scheme == StorageScheme::Gcs)
+    {
+        Some(storage::gcs_storage().await?)
+    } else {
+        None
+    };
+    let targets: Vec<(OutputLocation, &dyn Storage)> = outputs
+        .into_iter()
+        .map(|output| {
+            let storage: &dyn Storage = match (output.scheme, gcs_client.as_deref()) {
+                (StorageScheme::Gcs, Some(gcs)) => gcs,
+                _ if output.bucket == bucket => &s3_client,
+                _ => &default_s3_client,
+            };
+            (output, storage)
+        })
+        .collect();
+    // Cached derivatives are read back from the first destination.
+    let (output, output_storage) = (&targets[0].0, targets[0].1);
+
+    // Per-stage timings, reported on the success log (at warn when slow) and,
+    // with `debug`, in the response.
+    let mut download_time = Duration::ZERO;
+
+    // Megapixel caps and bounding boxes need the source dimensions before the
+    // key is known, so the source is fetched up front and reused below.
+    let mut prefetched_source = source;
+    let size = (
+        payload.width,
+        payload.height,
+        payload.max_megapixels,
+        payload.max_width,
+        payload.max_height,
+    );
+    let (css_width, css_height, object_mode) = match (payload.operations_size(), size) {
+        // Validation makes operations resize, so they always name a size.
+        (Some((width, height)), _) => (
+            width,
+            height,
+            payload
+                .operations_object_mode()
+                .unwrap_or(state.config.default_object_mode),
+        ),
+        (None, (Some(width), Some(height), None, None, None)) => (width, height, requested_mode),
+        (None, (None, None, Some(max_megapixels), None, None)) => {
+            let source = match prefetched_source.take() {
+                Some(source) => source,
+                None => {
+                    let download_started = Instant::now();
+                    let source = download_source(&state, &deadline, &s3_client, &payload).await?;
+                    download_time += download_started.elapsed();
+                    source
+                }
+            };
+            let (source_width, source_height) = ImageProcessor::dimensions(&source.data)?;
+            let (width, height) =
+                ImageProcessor::fit_megapixels(source_width, source_height, max_megapixels);
+            tracing::debug!(
+                "Capped {}x{} source at {} megapixels: {}x{}",
+                source_width,
+                source_height,
+                max_megapixels,
+                width,
+                height
+            );
+
+            prefetched_source = Some(source);
+            (width, height, ObjectMode::Fill)
+        }
+        (None, (None, None, None, max_width, max_height)) => {
+            let source = match prefetched_source.take() {
+                Some(source) => source,
+                None => {
+                    let download_started = Instant::now();
+                    let source = download_source(&state, &deadline, &s3_client, &payload).await?;
+                    download_time += download_started.elapsed();
+                    source
+                }
+            };
+            let (source_width, source_height) = ImageProcessor::dimensions(&source.data)?;
+            let (width, height) =
+                ImageProcessor::fit_within(source_width, source_height, max_width, max_height);
+            tracing::debug!(
+                "Fit {}x{} source within {:?}x{:?}: {}x{}",
+                source_width,
+                source_height,
+                max_width,
+                max_height,
+                width,
+                height
+            );
+
+            prefetched_source = Some(source);
+            (width, height, ObjectMode::Fill)
+        }
+        _ => {
+            return Err(AppError::InternalError(
+                "size parameters were not validated".to_string(),
+            ));
+        }
+    };
+
+    // Before the key is generated, so nearby sizes share one variant.
+    let (css_width, css_height) = payload.snapped(css_width, css_height);
+    let width = css_width.saturating_mul(dpr);
+    let height = css_height.saturating_mul(dpr);
+
+    if width > max_dimension || height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested output {}x{} exceeds the maximum of {}px per side",
+            width, height, max_dimension
+        )));
+    }
+    for operation in payload.operations.iter().flatten() {
+        if let Operation::Resize(resize) = operation {
+            if resize.width > max_dimension || resize.height > max_dimension {
+                return Err(AppError::DimensionTooLarge(format!(
+                    "Requested resize to {}x{} exceeds the maximum of {}px per side",
+                    resize.width, resize.height, max_dimension
+                )));
+            }
+        }
+    }
+
+    // The derivative stays next to its source under the requested name.
+    let named_key = match payload.output_stem() {
+        Some(stem) => replace_key_stem(&original_key, &stem),
+        None => original_key.clone(),
+    };
+    let cache_version = state.config.cache_version.as_deref();
+    let resized_key = match state.config.dpr_key_style {
+        DprKeyStyle::Suffix if dpr > 1 => {
+            generate_dpr_key(&named_key, css_width, css_height, dpr, cache_version)
+        }
+        _ => generate_resized_key(&named_key, width, height, cache_version),
+    };
+
+    // Rasterized videos and SVGs are stored as images, never under the source's
+    // extension unless the caller asks to keep it.
+    let resized_key = if !payload.preserve_extension
+        && (video::is_video_key(&original_key) || svg::is_svg_key(&original_key))
+    {
+        replace_extension(&resized_key, "jpg")
+    } else {
+        resized_key
+    };
+
+    let resized_key = match payload.video_timestamp {
+        Some(timestamp) => {
+            let millis = (timestamp * 1000.0).round() as u64;
+            append_key_suffix(&resized_key, &format!("t{}ms", millis))
+        }
+        None => resized_key,
+    };
+
+    let resized_key = match payload.frame_index {
+        Some(index) => append_key_suffix(&resized_key, &format!("f{}", index)),
+        None => resized_key,
+    };
+
+    let resized_key = match &payload.crop {
+        Some(crop) => append_key_suffix(&resized_key, &crop.key_suffix()),
+        None => resized_key,
+    };
+
+    let resized_key = match &payload.operations {
+        Some(operations) => append_key_suffix(&resized_key, &operations_key_suffix(operations)),
+        None => resized_key,
+    };
+
+    let resized_key = match &canvas {
+        Some(canvas) => append_key_suffix(&resized_key, &canvas_key_suffix(canvas)),
+        None => resized_key,
+    };
+
+    let resized_key = match encoding_key_suffix(filter, quality, progressive, payload.dpi) {
+        Some(suffix) => append_key_suffix(&resized_key, &suffix),
+        None => resized_key,
+    };
+
+    let resized_key = match payload.chroma_subsampling.key_suffix() {
+        Some(suffix) => append_key_suffix(&resized_key, suffix),
+        None => resized_key,
+    };
+
+    let resized_key = match object_mode.key_suffix() {
+        Some(suffix) => append_key_suffix(&resized_key, suffix),
+        None => resized_key,
+    };
+
+    let resized_key = match &payload.text_watermark {
+        Some(watermark) => append_key_suffix(&resized_key, &text_watermark_key_suffix(watermark)),
+        None => resized_key,
+    };
+
+    let resized_key = match payload.color_profile.key_suffix() {
+        Some(suffix) => append_key_suffix(&resized_key, suffix),
+        None => resized_key,
+    };
+
+    let resized_key = match alpha_background_key_suffix(payload.alpha_background) {
+        Some(suffix) => append_key_suffix(&resized_key, &suffix),
+        None => resized_key,
+    };
+
+    let resized_key = if payload.face_crop {
+        append_key_suffix(&resized_key, "face")
+    } else {
+        resized_key
+    };
+
+    // The stored object may be the source at its own size, which a plain
+    // request for this size mustn't be served from the cache.
+    let resized_key = if payload.reject_if_larger {
+        append_key_suffix(&resized_key, "nogrow")
+    } else {
+        resized_key
+    };
+
+    // With `auto` the extension depends on which candidate wins, and with an
+    // AVIF fallback on which format could be encoded, so any of them counts
+    // as cached.
+    let output_formats = match payload.output_format {
+        OutputFormat::Auto => state.config.auto_formats.clone(),
+        OutputFormat::Avif => [OutputFormat::Avif]
+            .into_iter()
+            .chain(state.config.avif_fallback.iter().copied())
+            .collect(),
+        format => vec![format],
+    };
 
-    let resized_key = generate_resized_key(&original_key, payload.width, payload.height);
+    // Identical requests arriving together wait for the first and then take the
+    // cache shortcut below. Forced and hashed requests never read the cache, so
+    // waiting would gain them nothing.
+    let flight = if state.config.single_flight && !payload.force && !payload.content_hash {
+        let flight_key = targets
+            .iter()
+            .map(|(output, _)| {
+                output.object_url(&output_key(
+                    &resized_key,
+                    payload.output_format,
+                    payload.preserve_extension,
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        deadline
+            .run("waiting for an identical request", async {
+                Ok(state.flights.join(&flight_key).await)
+            })
+            .await?
+    } else {
+        None
+    };
 
-    if s3_client.check_object_exists(&bucket, &resized_key).await {
-        let resized_url = format!("s3://{}/{}", bucket, resized_key);
-        tracing::info!("Resized image already exists at {}, returning cached URL", resized_url);
-        
-        return Ok(Json(ResizeResponse {
+    // Hashed keys are only known after encoding, so they can't take the cache
+    // shortcut. With several destinations, the derivative counts as cached only
+    // when every one of them has it.
+    let cached = if payload.force || payload.content_hash {
+        None
+    } else {
+        deadline
+            .run("cache check", async {
+                for &format in &output_formats {
+                    let key = output_key(&resized_key, format, payload.preserve_extension);
+                    if stored_everywhere(&targets, &key, payload.write_sidecar).await {
+                        return Ok(Some((key, format)));
+                    }
+                }
+                Ok(None)
+            })
+            .await?
+    };
+
+    // The JPEG fallback has its own key and is cache-checked on its own; the
+    // shortcut needs both. When only one is stored, both are encoded again but
+    // only the missing one is uploaded.
+    let fallback_key = payload.with_fallback.then(|| {
+        if payload.preserve_extension {
+            resized_key.clone()
+        } else {
+            replace_extension(&resized_key, "jpg")
+        }
+    });
+    let fallback_cached = match &fallback_key {
+        Some(key) if !payload.force && !payload.content_hash => {
+            deadline
+                .run("cache check", async {
+                    Ok(stored_everywhere(&targets, key, false).await)
+                })
+                .await?
+        }
+        _ => false,
+    };
+    let cached = cached.filter(|_| fallback_key.is_none() || fallback_cached);
+
+    if let Some((resized_key, output_format)) = cached {
+        if let Some(flight) = flight {
+            flight.succeeded();
+        }
+        state.stats.cache_hit();
+        let resized_url = output.object_url(&resized_key);
+        let lqip = match &payload.lqip {
+            Some(options) => {
+                let cached = deadline
+                    .run(
+                        "download",
+                        output_storage.download_object(&output.bucket, &resized_key),
+                    )
+                    .await?;
+                Some(lqip_data_uri(
+                    &cached,
+                    options,
+                    &state.config.decode_limits,
+                )?)
+            }
+            None => None,
+        };
+        // The hashes describe the source, which a cache hit otherwise never reads.
+        let source = match prefetched_source {
+            Some(source) => Some(source),
+            None if payload.include_phash || payload.include_blurhash => {
+                Some(download_source(&state, &deadline, &s3_client, &payload).await?)
+            }
+            None => None,
+        };
+        let source_content_type = source
+            .as_ref()
+            .and_then(|source| source.content_type.clone());
+        let warnings = source
+            .as_ref()
+            .and_then(|source| source.mislabeled.clone())
+            .into_iter()
+            .collect();
+        let phash = match source.clone().filter(|_| payload.include_phash) {
+            Some(source) => {
+                let hash = deadline
+                    .run("perceptual hash", phash_blocking(&state, source.data))
+                    .await?;
+                Some(phash_hex(hash))
+            }
+            None => None,
+        };
+        let blurhash = match source.filter(|_| payload.include_blurhash) {
+            Some(source) => Some(
+                deadline
+                    .run("blurhash", blurhash_blocking(&state, source.data))
+                    .await?,
+            ),
+            None => None,
+        };
+        log_sampled!(
+            state.log_sampler,
+            started.elapsed(),
+            "Resized image already exists at {}, returning cached URL",
+            resized_url
+        );
+
+        let destinations = if multi_dest {
+            targets
+                .iter()
+                .map(|(output, _)| DestinationResult {
+                    bucket: output.bucket_url(),
+                    ok: true,
+                    resized_url: Some(output.object_url(&resized_key)),
+                    sidecar_url: payload
+                        .write_sidecar
+                        .then(|| output.object_url(&generate_sidecar_key(&resized_key))),
+                    fallback_url: fallback_key.as_ref().map(|key| output.object_url(key)),
+                    error: None,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        return Ok(ResizeResponse {
             original_url: payload.s3_url,
             resized_url,
-            width: payload.width,
-            height: payload.height,
-            object_mode: payload.object_mode,
+            width,
+            height,
+            object_mode,
+            dpr,
+            output_format,
+            stats: None,
+            sidecar_url: payload
+                .write_sidecar
+                .then(|| output.object_url(&generate_sidecar_key(&resized_key))),
+            fallback_url: fallback_key.as_ref().map(|key| output.object_url(key)),
+            face_detected: None,
+            source_kept: None,
+            phash,
+            blurhash,
+            lqip,
+            warnings,
+            source_content_type,
+            destinations,
+            timings: debug.then(|| ResizeTimings {
+                download_ms: download_time.as_millis() as u64,
+                decode_ms: 0,
+                resize_ms: 0,
+                encode_ms: 0,
+                upload_ms: 0,
+                total_ms: started.elapsed().as_millis() as u64,
+            }),
+        });
+    }
+
+    if payload.force {
+        tracing::debug!(
+            "Force refresh requested, regenerating and overwriting {}",
+            resized_key
+        );
+    }
+
+    let Source {
+        data: image_data,
+        content_type: source_content_type,
+        mislabeled,
+    } = match prefetched_source {
+        Some(source) => source,
+        None => {
+            let download_started = Instant::now();
+            let source = download_source(&state, &deadline, &s3_client, &payload).await?;
+            download_time += download_started.elapsed();
+            source
+        }
+    };
+    // Every `auto` candidate and the fallback is a separate encode of the
+    // output; AVIF fallback formats are only encoded when AVIF fails.
+    let candidates = match payload.output_format {
+        OutputFormat::Auto => output_formats.len(),
+        _ => 1,
+    };
+    let encodes = candidates + fallback_key.is_some() as usize;
+    check_work_budget(&state, &[&image_data], encodes as u32, filter)?;
+    let source_bytes = image_data.len();
+
+    let watermark = payload.text_watermark.clone().map(|options| Watermark {
+        options: TextWatermark {
+            size: options.size.map(|size| size * dpr as f32),
+            ..options
+        },
+        font: state.watermark_font.clone(),
+    });
+
+    let operations = payload.operations.as_ref().map(|operations| {
+        operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Rotate(degrees) => Some(Step::Rotate(*degrees)),
+                Operation::Crop(crop) => Some(Step::Crop(*crop)),
+                Operation::Resize(resize) => Some(Step::Resize {
+                    width: resize.width,
+                    height: resize.height,
+                    object_mode: resize
+                        .object_mode
+                        .unwrap_or(state.config.default_object_mode),
+                }),
+                Operation::Grayscale(true) => Some(Step::Grayscale),
+                Operation::Grayscale(false) => None,
+                Operation::Watermark(options) => Some(Step::Watermark(Watermark {
+                    options: options.clone(),
+                    font: state.watermark_font.clone(),
+                })),
+            })
+            .collect()
+    });
+
+    let options = ResizeOptions {
+        width,
+        height,
+        object_mode,
+        filter,
+        min_source_dimension: payload
+            .min_source_dimension
+            .or(state.config.min_source_dimension)
+            .filter(|&v| v > 0),
+        crop: payload.crop,
+        distortion_guard: distortion_guard(&payload),
+        canvas,
+        watermark,
+        color_profile: payload.color_profile,
+        encode: EncodeOptions {
+            format: payload.output_format,
+            chroma_subsampling: payload.chroma_subsampling,
+            quality,
+            progressive,
+            dpi: payload.dpi,
+            alpha_background: payload.alpha_background,
+            jpeg_encoder: state.config.jpeg_encoder,
+        },
+        auto_formats: state.config.auto_formats.clone(),
+        avif_fallback: state.config.avif_fallback.clone(),
+        jpeg_fallback: fallback_key.is_some(),
+        face_crop: payload.face_crop,
+        phash: payload.include_phash,
+        blurhash: payload.include_blurhash,
+        frame_index: payload.frame_index,
+        operations,
+        reject_if_larger: payload.reject_if_larger,
+    };
+
+    // Resizing is CPU-bound, so it runs on the blocking pool where the deadline
+    // can stop waiting for it. The decode/encode itself can't be interrupted and
+    // finishes in the background, but nothing is uploaded afterwards.
+    let resize_started = Instant::now();
+    let processed = deadline
+        .run("resize", resize_blocking(&state, image_data, options))
+        .await?;
+    let resize_time = resize_started.elapsed();
+    state.stats.resized(processed.format.as_str(), resize_time);
+    state
+        .stats
+        .encoded(processed.format.as_str(), processed.timings.encode);
+
+    let stats = ResizeStats::new(
+        source_bytes,
+        processed.data.len(),
+        processed.width,
+        processed.height,
+    );
+
+    let lqip = match &payload.lqip {
+        Some(options) => Some(lqip_data_uri(
+            &processed.data,
+            options,
+            &state.config.decode_limits,
+        )?),
+        None => None,
+    };
+
+    let resized_key = output_key(&resized_key, processed.format, payload.preserve_extension);
+    if payload.preserve_extension && !extension_matches(&resized_key, processed.format) {
+        tracing::warn!(
+            "Storing {} bytes under {} because preserve_extension is set; the extension doesn't match the content",
+            processed.content_type,
+            resized_key
+        );
+    }
+    let resized_key = if payload.content_hash {
+        append_content_hash(
+            &resized_key,
+            &processed.data,
+            state.config.content_hash_length,
+        )
+    } else {
+        resized_key
+    };
+    let fallback = fallback_key
+        .zip(processed.fallback.as_deref())
+        .map(|(key, fallback)| {
+            let key = if payload.content_hash {
+                append_content_hash(&key, &fallback.data, state.config.content_hash_length)
+            } else {
+                key
+            };
+            (key, fallback)
+        });
+
+    let upload_options = UploadOptions {
+        acl: payload.acl.or(state.config.default_acl),
+        storage_class: payload.storage_class.or(state.config.default_storage_class),
+        // Without `force` the derivative was missing a moment ago; if an
+        // identical request on another instance stores it first, theirs is kept.
+        if_absent: !payload.force,
+        ..UploadOptions::from_config(&state.config)
+    };
+
+    // Filled in with each destination's URL when it is written.
+    let sidecar = payload.write_sidecar.then(|| ResizeSidecar {
+        source_url: payload.s3_url.clone(),
+        resized_url: String::new(),
+        width,
+        height,
+        output_width: processed.width,
+        output_height: processed.height,
+        object_mode,
+        dpr,
+        content_type: processed.content_type.clone(),
+        stats: stats.clone(),
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    });
+
+    let upload_started = Instant::now();
+    let results = join_all(targets.iter().map(|(output, storage)| async {
+        let derivative = store_derivative(
+            &deadline,
+            *storage,
+            output,
+            &resized_key,
+            &processed,
+            sidecar.as_ref(),
+            &upload_options,
+            &payload,
+        )
+        .await?;
+        let fallback = match &fallback {
+            Some((key, fallback)) => Some(
+                store_derivative(
+                    &deadline,
+                    *storage,
+                    output,
+                    key,
+                    fallback,
+                    None,
+                    &upload_options,
+                    &payload,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+        Ok::<_, AppError>((derivative, fallback))
+    }))
+    .await;
+    let upload_time = upload_started.elapsed();
+    let stored_bytes = processed.data.len()
+        + fallback
+            .as_ref()
+            .map_or(0, |(_, fallback)| fallback.data.len());
+
+    let mut stored = None;
+    let mut first_error = None;
+    let mut warnings: Vec<String> = mislabeled.into_iter().collect();
+    let mut destinations = Vec::new();
+    for ((output, _), result) in targets.iter().zip(results) {
+        match result {
+            Ok((derivative, fallback)) => {
+                state.stats.uploaded(stored_bytes);
+                warnings.extend(derivative.warnings.iter().cloned());
+                let fallback_url = fallback.map(|fallback| {
+                    warnings.extend(fallback.warnings);
+                    fallback.url
+                });
+                destinations.push(DestinationResult {
+                    bucket: output.bucket_url(),
+                    ok: true,
+                    resized_url: Some(derivative.url.clone()),
+                    sidecar_url: derivative.sidecar_url.clone(),
+                    fallback_url: fallback_url.clone(),
+                    error: None,
+                });
+                stored.get_or_insert((derivative, fallback_url));
+            }
+            Err(e) => {
+                if multi_dest {
+                    tracing::warn!(
+                        "Failed to store {} in {}: {}",
+                        resized_key,
+                        output.bucket_url(),
+                        e
+                    );
+                }
+                destinations.push(DestinationResult {
+                    bucket: output.bucket_url(),
+                    ok: false,
+                    resized_url: None,
+                    sidecar_url: None,
+                    fallback_url: None,
+                    error: Some(e.to_string()),
+                });
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    // A single destination fails the request as before; several only fail
+    // when nothing was stored.
+    let stored = match (stored, first_error) {
+        (Some(stored), None) => {
+            if let Some(flight) = flight {
+                flight.succeeded();
+            }
+            stored
+        }
+        (Some(stored), Some(_)) if multi_dest => stored,
+        (_, Some(e)) => return Err(e),
+        (None, None) => {
+            return Err(AppError::InternalError(
+                "No destination to store the derivative in".to_string(),
+            ))
+        }
+    };
+    let (stored, fallback_url) = stored;
+    let resized_url = stored.url;
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        source = %payload.s3_url,
+        object_mode = ?object_mode,
+        content_type = %processed.content_type,
+        output_width = processed.width,
+        output_height = processed.height,
+        download_ms = download_time.as_millis() as u64,
+        resize_ms = resize_time.as_millis() as u64,
+        upload_ms = upload_time.as_millis() as u64,
+        source_bytes = stats.source_bytes,
+        output_bytes = stats.output_bytes,
+        compression_ratio = stats.compression_ratio,
+        pixels_per_byte = stats.pixels_per_byte,
+        "Successfully resized and uploaded image to {}",
+        resized_url
+    );
+
+    Ok(ResizeResponse {
+        original_url: payload.s3_url,
+        resized_url,
+        width,
+        height,
+        object_mode,
+        dpr,
+        output_format: processed.format,
+        stats: payload.include_stats.then_some(stats),
+        sidecar_url: stored.sidecar_url,
+        fallback_url,
+        face_detected: processed.face_detected,
+        source_kept: payload.reject_if_larger.then_some(processed.source_kept),
+        phash: processed.phash.map(phash_hex),
+        blurhash: processed.blurhash,
+        lqip,
+        warnings,
+        source_content_type,
+        destinations: if multi_dest { destinations } else { Vec::new() },
+        timings: debug.then(|| ResizeTimings {
+            download_ms: download_time.as_millis() as u64,
+            decode_ms: processed.timings.decode.as_millis() as u64,
+            resize_ms: processed.timings.resize.as_millis() as u64,
+            encode_ms: processed.timings.encode.as_millis() as u64,
+            upload_ms: upload_time.as_millis() as u64,
+            total_ms: started.elapsed().as_millis() as u64,
+        }),
+    })
+}
+
+/// True when every destination has `key`, and its sidecar when `with_sidecar`.
+async fn stored_everywhere(
+    targets: &[(OutputLocation, &dyn Storage)],
+    key: &str,
+    with_sidecar: bool,
+) -> bool {
+    for (output, storage) in targets {
+        if !(storage.check_object_exists(&output.bucket, key).await
+            && (!with_sidecar
+                || storage
+                    .check_object_exists(&output.bucket, &generate_sidecar_key(key))
+                    .await))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Size of a cached derivative, from the metadata its upload stored. Objects
+/// stored before uploads recorded it are downloaded and their header read.
+async fn cached_dimensions(s3_client: &S3Client, s3_url: &str) -> Result<(u32, u32), AppError> {
+    if let Some(dimensions) = s3_client.stored_dimensions(s3_url).await? {
+        return Ok(dimensions);
+    }
+    let cached_data = s3_client.download_image(s3_url).await?;
+    ImageProcessor::dimensions(&cached_data)
+}
+
+/// Where one destination's copy of the derivative ended up.
+struct StoredDerivative {
+    url: String,
+    sidecar_url: Option<String>,
+    warnings: Vec<String>,
+}
+
+/// Uploads the derivative, and its sidecar when one is given, to one
+/// destination. Hashed keys already present there are not uploaded again, and
+/// neither is either half of a `with_fallback` pair that was already cached.
+#[allow(clippy::too_many_arguments)]
+async fn store_derivative(
+    deadline: &Deadline,
+    storage: &dyn Storage,
+    output: &OutputLocation,
+    resized_key: &str,
+    processed: &ProcessedImage,
+    sidecar: Option<&ResizeSidecar>,
+    upload_options: &UploadOptions,
+    payload: &ResizeRequest,
+) -> Result<StoredDerivative, AppError> {
+    let mut warnings = Vec::new();
+    let already_stored = !payload.force
+        && (payload.content_hash || payload.with_fallback)
+        && deadline
+            .run("upload", async {
+                Ok(storage
+                    .check_object_exists(&output.bucket, resized_key)
+                    .await)
+            })
+            .await?;
+    let url = if already_stored {
+        tracing::debug!(
+            "Identical content already stored at {}, skipping upload",
+            resized_key
+        );
+        output.object_url(resized_key)
+    } else {
+        let outcome = deadline
+            .run(
+                "upload",
+                storage.upload_image_with_tags(
+                    &output.bucket,
+                    resized_key,
+                    processed.data.clone(),
+                    &processed.content_type,
+                    upload_options,
+                    &payload.tags,
+                ),
+            )
+            .await?;
+        warnings.extend(outcome.warnings);
+        outcome.url
+    };
+
+    let sidecar_url = match sidecar {
+        Some(sidecar) => {
+            let sidecar = ResizeSidecar {
+                resized_url: url.clone(),
+                ..sidecar.clone()
+            };
+            let body = serde_json::to_vec(&sidecar).map_err(|e| {
+                AppError::InternalError(format!("Failed to serialize sidecar: {}", e))
+            })?;
+
+            let sidecar_url = deadline
+                .run(
+                    "upload",
+                    storage.upload_image(
+                        &output.bucket,
+                        &generate_sidecar_key(resized_key),
+                        Bytes::from(body),
+                        "application/json",
+                        upload_options,
+                    ),
+                )
+                .await?;
+            tracing::debug!("Wrote metadata sidecar to {}", sidecar_url);
+
+            Some(sidecar_url)
+        }
+        None => None,
+    };
+
+    Ok(StoredDerivative {
+        url,
+        sidecar_url,
+        warnings,
+    })
+}
+
+/// Perceptual hash of the source for near-duplicate detection: compare two
+/// hashes by Hamming distance.
+#[utoipa::path(
+    post,
+    path = "/phash",
+    request_body = PhashRequest,
+    responses(
+        (status = 200, description = "Perceptual hash of the source", body = PhashResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn phash(
+    State(state): State<AppState>,
+    Json(payload): Json<PhashRequest>,
+) -> Result<Json<PhashResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!("Perceptual hash request: url={}", payload.s3_url);
+
+    parse_s3_url(&payload.s3_url)?;
+
+    let s3_client = state.s3_client_for(&payload.s3_url).await;
+    let image_data = s3_client.download_image(&payload.s3_url).await?;
+    let hash = phash_blocking(&state, image_data).await?;
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Computed perceptual hash of {}",
+        payload.s3_url
+    );
+
+    Ok(Json(PhashResponse {
+        original_url: payload.s3_url,
+        phash: phash_hex(hash),
+        algorithm: "dhash",
+        bits: PHASH_BITS,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/aspect-crop",
+    request_body = AspectCropRequest,
+    responses(
+        (status = 200, description = "Cropped derivative stored, or found in the cache", body = AspectCropResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn aspect_crop(
+    State(state): State<AppState>,
+    Json(payload): Json<AspectCropRequest>,
+) -> Result<Json<AspectCropResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Aspect crop request: url={}, aspect_ratio={}, max_width={:?}",
+        payload.s3_url,
+        payload.aspect_ratio,
+        payload.max_width
+    );
+
+    let (ratio_width, ratio_height) = parse_aspect_ratio(&payload.aspect_ratio)?;
+
+    if payload.max_width == Some(0) {
+        return Err(AppError::InvalidParameter(
+            "max_width must be greater than 0".to_string(),
+        ));
+    }
+
+    let (bucket, original_key) = parse_s3_url(&payload.s3_url)?;
+
+    let s3_client = state.s3_client_for(&payload.s3_url).await;
+
+    let cropped_key = generate_aspect_crop_key(
+        &original_key,
+        ratio_width,
+        ratio_height,
+        payload.max_width,
+        state.config.cache_version.as_deref(),
+    );
+    let cropped_url = format!("s3://{}/{}", bucket, cropped_key);
+
+    if s3_client.check_object_exists(&bucket, &cropped_key).await {
+        let (width, height) = cached_dimensions(&s3_client, &cropped_url).await?;
+
+        log_sampled!(
+            state.log_sampler,
+            started.elapsed(),
+            "Cropped image already exists at {}, returning cached URL",
+            cropped_url
+        );
+
+        return Ok(Json(AspectCropResponse {
+            original_url: payload.s3_url,
+            cropped_url,
+            aspect_ratio: payload.aspect_ratio,
+            width,
+            height,
         }));
     }
 
     let image_data = s3_client.download_image(&payload.s3_url).await?;
 
-    let (resized_data, content_type) = ImageProcessor::resize(
-        image_data,
+    let (max_width, limits) = (payload.max_width, state.config.decode_limits);
+    let processed = run_blocking(&state, "Aspect crop", move || {
+        ImageProcessor::aspect_crop(image_data, ratio_width, ratio_height, max_width, &limits)
+    })
+    .await?;
+    let (width, height) = (processed.width, processed.height);
+
+    let cropped_url = s3_client
+        .upload_image(
+            &bucket,
+            &cropped_key,
+            processed.data,
+            &processed.content_type,
+            &UploadOptions {
+                storage_class: payload.storage_class.or(state.config.default_storage_class),
+                dimensions: Some((width, height)),
+                ..UploadOptions::from_config(&state.config)
+            },
+        )
+        .await?;
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Successfully cropped and uploaded image to {}",
+        cropped_url
+    );
+
+    Ok(Json(AspectCropResponse {
+        original_url: payload.s3_url,
+        cropped_url,
+        aspect_ratio: payload.aspect_ratio,
+        width,
+        height,
+    }))
+}
+
+/// Re-encodes the source in another format without resizing, e.g. to migrate
+/// PNGs to WebP. Stored next to the source as `{stem}_converted.{format}`.
+#[utoipa::path(
+    post,
+    path = "/convert",
+    request_body = ConvertRequest,
+    responses(
+        (status = 200, description = "Converted image stored, or found in the cache", body = ConvertResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 404, description = "The source doesn't exist", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn convert(
+    State(state): State<AppState>,
+    Json(payload): Json<ConvertRequest>,
+) -> Result<Json<ConvertResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Convert request: url={}, format={:?}, quality={:?}",
+        payload.s3_url,
+        payload.output_format,
+        payload.quality
+    );
+
+    let quality = payload.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    let mut problems = Vec::new();
+    match payload.output_format {
+        OutputFormat::Auto => problems
+            .push("output_format must name a format; auto isn't supported by /convert".to_string()),
+        OutputFormat::Avif if !cfg!(feature = "avif") && state.config.avif_fallback.is_empty() => {
+            problems.push(
+                "AVIF output requires the service to be built with the `avif` feature".to_string(),
+            )
+        }
+        _ => {}
+    }
+    if !(1..=100).contains(&quality) {
+        problems.push("quality must be between 1 and 100".to_string());
+    }
+    if payload.color_profile != ColorProfile::default()
+        && payload.output_format != OutputFormat::Jpeg
+    {
+        problems.push("color_profile=preserve only applies to JPEG output".to_string());
+    }
+    reject_problems(problems)?;
+
+    let (bucket, original_key) = parse_s3_url(&payload.s3_url)?;
+    let s3_client = state.s3_client_for(&payload.s3_url).await;
+
+    let encoding_suffix = encoding_key_suffix(ResizeFilter::default(), quality, false, None);
+    let converted_key = |format: OutputFormat| {
+        let extension = match format {
+            OutputFormat::Jpeg => "jpg",
+            format => format.as_str(),
+        };
+        let key = generate_convert_key(
+            &original_key,
+            extension,
+            encoding_suffix.as_deref(),
+            state.config.cache_version.as_deref(),
+        );
+        let key = match payload.color_profile.key_suffix() {
+            Some(suffix) => append_key_suffix(&key, suffix),
+            None => key,
+        };
+        match alpha_background_key_suffix(payload.alpha_background) {
+            Some(suffix) => append_key_suffix(&key, &suffix),
+            None => key,
+        }
+    };
+
+    // An earlier AVIF request may have been stored as one of its fallbacks.
+    let mut cache_formats = vec![payload.output_format];
+    if payload.output_format == OutputFormat::Avif {
+        cache_formats.extend(&state.config.avif_fallback);
+    }
+    if !payload.force {
+        for format in cache_formats {
+            let key = converted_key(format);
+            if !s3_client.check_object_exists(&bucket, &key).await {
+                continue;
+            }
+            let converted_url = format!("s3://{}/{}", bucket, key);
+            state.stats.cache_hit();
+            let dimensions = cached_dimensions(&s3_client, &converted_url).await.ok();
+
+            log_sampled!(
+                state.log_sampler,
+                started.elapsed(),
+                "Converted image already exists at {}, returning cached URL",
+                converted_url
+            );
+
+            return Ok(Json(ConvertResponse {
+                original_url: payload.s3_url,
+                converted_url,
+                output_format: format,
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+            }));
+        }
+    }
+
+    let image_data = s3_client.download_image(&payload.s3_url).await?;
+    state.stats.downloaded(image_data.len());
+    check_work_budget(&state, &[&image_data], 1, ResizeFilter::default())?;
+
+    let encode = EncodeOptions {
+        format: payload.output_format,
+        quality,
+        alpha_background: payload.alpha_background,
+        ..EncodeOptions::from_config(&state.config)
+    };
+    let color_profile = payload.color_profile;
+    let avif_fallback = state.config.avif_fallback.clone();
+    let limits = state.config.decode_limits;
+    let convert_started = Instant::now();
+    let processed = run_blocking(&state, "Convert", move || {
+        ImageProcessor::convert(&image_data, &encode, color_profile, &avif_fallback, &limits)
+    })
+    .await?;
+    state
+        .stats
+        .resized(processed.format.as_str(), convert_started.elapsed());
+    state
+        .stats
+        .encoded(processed.format.as_str(), processed.timings.encode);
+
+    let (width, height, format) = (processed.width, processed.height, processed.format);
+    let upload_bytes = processed.data.len();
+    let converted_url = s3_client
+        .upload_image(
+            &bucket,
+            &converted_key(format),
+            processed.data,
+            &processed.content_type,
+            &UploadOptions {
+                storage_class: payload.storage_class.or(state.config.default_storage_class),
+                dimensions: Some((width, height)),
+                ..UploadOptions::from_config(&state.config)
+            },
+        )
+        .await?;
+    state.stats.uploaded(upload_bytes);
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Successfully converted and uploaded image to {}",
+        converted_url
+    );
+
+    Ok(Json(ConvertResponse {
+        original_url: payload.s3_url,
+        converted_url,
+        output_format: format,
+        width: Some(width),
+        height: Some(height),
+    }))
+}
+
+/// Cuts the source into a Deep Zoom tile pyramid for pan-and-zoom viewers.
+/// The tiles are uploaded first and the `.dzi` manifest last, so a manifest
+/// in the bucket means the pyramid under it is complete.
+#[utoipa::path(
+    post,
+    path = "/tiles",
+    request_body = TileRequest,
+    responses(
+        (status = 200, description = "Pyramid stored, or found in the cache", body = TileResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 404, description = "The source doesn't exist", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn tiles(
+    State(state): State<AppState>,
+    Json(payload): Json<TileRequest>,
+) -> Result<Json<TileResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Tile request: url={}, tile_size={}, overlap={}, format={:?}",
+        payload.s3_url,
+        payload.tile_size,
+        payload.overlap,
+        payload.output_format
+    );
+
+    let quality = payload.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    let mut problems = Vec::new();
+    if !(MIN_TILE_SIZE..=MAX_TILE_SIZE).contains(&payload.tile_size) {
+        problems.push(format!(
+            "tile_size must be between {} and {}",
+            MIN_TILE_SIZE, MAX_TILE_SIZE
+        ));
+    }
+    if payload.overlap > MAX_TILE_OVERLAP {
+        problems.push(format!("overlap must be at most {}", MAX_TILE_OVERLAP));
+    }
+    if !matches!(
+        payload.output_format,
+        OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::Webp
+    ) {
+        problems.push("output_format must be jpeg, png or webp for tiles".to_string());
+    }
+    if !(1..=100).contains(&quality) {
+        problems.push("quality must be between 1 and 100".to_string());
+    }
+    reject_problems(problems)?;
+
+    let (bucket, original_key) = parse_s3_url(&payload.s3_url)?;
+    let s3_client = state.s3_client_for(&payload.s3_url).await;
+
+    let extension = match payload.output_format {
+        OutputFormat::Jpeg => "jpg",
+        format => format.as_str(),
+    };
+    let encoding_suffix = encoding_key_suffix(ResizeFilter::default(), quality, false, None);
+    let manifest_key = generate_tile_manifest_key(
+        &original_key,
+        payload.tile_size,
+        payload.overlap,
+        extension,
+        encoding_suffix.as_deref(),
+        state.config.cache_version.as_deref(),
+    );
+    let manifest_url = format!("s3://{}/{}", bucket, manifest_key);
+    let tiles_url = format!("s3://{}/{}/", bucket, tiles_prefix(&manifest_key));
+    let response = |width: u32, height: u32| {
+        let levels = ImageProcessor::pyramid_levels(width, height, payload.tile_size);
+        TileResponse {
+            original_url: payload.s3_url.clone(),
+            manifest_url: manifest_url.clone(),
+            tiles_url: tiles_url.clone(),
+            width,
+            height,
+            tile_size: payload.tile_size,
+            overlap: payload.overlap,
+            output_format: payload.output_format,
+            tile_count: levels.iter().map(|level| level.columns * level.rows).sum(),
+            levels,
+        }
+    };
+
+    if !payload.force && s3_client.check_object_exists(&bucket, &manifest_key).await {
+        let manifest = s3_client.download_image(&manifest_url).await?;
+        if let Some((width, height)) = ImageProcessor::dzi_size(&String::from_utf8_lossy(&manifest))
+        {
+            state.stats.cache_hit();
+            log_sampled!(
+                state.log_sampler,
+                started.elapsed(),
+                "Tile pyramid already exists at {}, returning cached URL",
+                manifest_url
+            );
+            return Ok(Json(response(width, height)));
+        }
+        tracing::warn!("Unreadable tile manifest at {}, regenerating", manifest_url);
+    }
+
+    let image_data = s3_client.download_image(&payload.s3_url).await?;
+    state.stats.downloaded(image_data.len());
+    // Every level below the top adds a third of the source's pixels between
+    // them, and encoding reads them all once more.
+    check_work_budget(&state, &[&image_data], 2, ResizeFilter::default())?;
+
+    let encode = EncodeOptions {
+        format: payload.output_format,
+        quality,
+        ..EncodeOptions::from_config(&state.config)
+    };
+    let (tile_size, overlap) = (payload.tile_size, payload.overlap);
+    let limits = state.config.decode_limits;
+    let tile_started = Instant::now();
+    let pyramid = run_blocking(&state, "Tile", move || {
+        ImageProcessor::tile_pyramid(&image_data, tile_size, overlap, &encode, &limits)
+    })
+    .await?;
+    state
+        .stats
+        .resized(payload.output_format.as_str(), tile_started.elapsed());
+
+    let upload_options = UploadOptions {
+        storage_class: payload.storage_class.or(state.config.default_storage_class),
+        ..UploadOptions::from_config(&state.config)
+    };
+    let content_type = payload.output_format.content_type();
+    let uploads = stream::iter(pyramid.tiles)
+        .map(|tile| {
+            let key =
+                generate_tile_key(&manifest_key, tile.level, tile.column, tile.row, extension);
+            let (s3_client, bucket, upload_options) = (&s3_client, &bucket, &upload_options);
+            async move {
+                let bytes = tile.data.len();
+                s3_client
+                    .upload_image(bucket, &key, tile.data, content_type, upload_options)
+                    .await?;
+                Ok::<_, AppError>(bytes)
+            }
+        })
+        .buffer_unordered(TILE_UPLOAD_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    for bytes in uploads {
+        state.stats.uploaded(bytes?);
+    }
+
+    let manifest =
+        ImageProcessor::dzi_manifest(pyramid.width, pyramid.height, tile_size, overlap, extension);
+    let manifest_bytes = manifest.len();
+    s3_client
+        .upload_image(
+            &bucket,
+            &manifest_key,
+            Bytes::from(manifest),
+            "application/xml",
+            &upload_options,
+        )
+        .await?;
+    state.stats.uploaded(manifest_bytes);
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Successfully uploaded a {}x{} tile pyramid to {}",
+        pyramid.width,
+        pyramid.height,
+        manifest_url
+    );
+
+    Ok(Json(response(pyramid.width, pyramid.height)))
+}
+
+/// Builds one grid image from several sources. Sources are downloaded in
+/// parallel and the collage is written next to the first one.
+#[utoipa::path(
+    post,
+    path = "/collage",
+    request_body = CollageRequest,
+    responses(
+        (status = 200, description = "Collage stored, or found in the cache", body = CollageResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn collage(
+    State(state): State<AppState>,
+    Json(payload): Json<CollageRequest>,
+) -> Result<Json<CollageResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Collage request: sources={}, grid={}x{}, size={}x{}",
+        payload.s3_urls.len(),
+        payload.columns,
+        payload.rows,
+        payload.width,
+        payload.height
+    );
+
+    if payload.columns == 0 || payload.rows == 0 {
+        return Err(AppError::InvalidParameter(
+            "columns and rows must be greater than 0".to_string(),
+        ));
+    }
+    let cells = payload.columns as u64 * payload.rows as u64;
+    if cells > MAX_COLLAGE_CELLS {
+        return Err(AppError::InvalidParameter(format!(
+            "A collage can have at most {} cells",
+            MAX_COLLAGE_CELLS
+        )));
+    }
+    if payload.s3_urls.len() as u64 != cells {
+        return Err(AppError::InvalidParameter(format!(
+            "A {}x{} grid needs exactly {} source URLs, got {}",
+            payload.columns,
+            payload.rows,
+            cells,
+            payload.s3_urls.len()
+        )));
+    }
+    if payload.width < payload.columns || payload.height < payload.rows {
+        return Err(AppError::InvalidParameter(
+            "width and height must leave at least one pixel per cell".to_string(),
+        ));
+    }
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if payload.width > max_dimension || payload.height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested collage {}x{} exceeds the maximum of {}px per side",
+            payload.width, payload.height, max_dimension
+        )));
+    }
+
+    let sources = payload
+        .s3_urls
+        .iter()
+        .map(|url| parse_s3_url(url))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (bucket, first_key) = &sources[0];
+
+    let collage_key = generate_collage_key(
+        first_key,
+        payload.columns,
+        payload.rows,
         payload.width,
         payload.height,
-        payload.object_mode,
-    )?;
+        &payload.s3_urls,
+        state.config.cache_version.as_deref(),
+    );
+
+    let s3_client = state.s3_client_for(&payload.s3_urls[0]).await;
+
+    if s3_client.check_object_exists(bucket, &collage_key).await {
+        let collage_url = format!("s3://{}/{}", bucket, collage_key);
+        state.stats.cache_hit();
+        log_sampled!(
+            state.log_sampler,
+            started.elapsed(),
+            "Collage already exists at {}, returning cached URL",
+            collage_url
+        );
+
+        return Ok(Json(CollageResponse {
+            source_urls: payload.s3_urls,
+            collage_url,
+            width: payload.width,
+            height: payload.height,
+        }));
+    }
+
+    let images = download_all(&state, &payload.s3_urls)
+        .await
+        .into_iter()
+        .map(|result| result.map(Some))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sources: Vec<_> = images.iter().flatten().collect();
+    for source in &sources {
+        state.stats.downloaded(source.len());
+    }
+    check_work_budget(&state, &sources, 1, ResizeFilter::default())?;
+
+    let (columns, rows, width, height) =
+        (payload.columns, payload.rows, payload.width, payload.height);
+    let limits = state.config.decode_limits;
+    let collage_started = Instant::now();
+    let processed = run_blocking(&state, "Collage", move || {
+        ImageProcessor::collage(&images, columns, rows, width, height, &limits)
+    })
+    .await?;
+    state
+        .stats
+        .resized(processed.format.as_str(), collage_started.elapsed());
+
+    let (width, height) = (processed.width, processed.height);
+    let collage_bytes = processed.data.len();
+    let collage_url = s3_client
+        .upload_image(
+            bucket,
+            &collage_key,
+            processed.data,
+            &processed.content_type,
+            &UploadOptions {
+                storage_class: payload.storage_class.or(state.config.default_storage_class),
+                ..UploadOptions::from_config(&state.config)
+            },
+        )
+        .await?;
+    state.stats.uploaded(collage_bytes);
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Successfully built and uploaded collage to {}",
+        collage_url
+    );
+
+    Ok(Json(CollageResponse {
+        source_urls: payload.s3_urls,
+        collage_url,
+        width,
+        height,
+    }))
+}
+
+/// Builds a contact sheet of many sources at a fixed cell size. Unlike
+/// `collage`, a source that can't be fetched or read doesn't fail the request;
+/// its cell becomes a placeholder and the URL is listed in `failed_sources`.
+/// Montages are always regenerated, so a placeholder never gets cached.
+#[utoipa::path(
+    post,
+    path = "/montage",
+    request_body = MontageRequest,
+    responses(
+        (status = 200, description = "Montage stored", body = MontageResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn montage(
+    State(state): State<AppState>,
+    Json(payload): Json<MontageRequest>,
+) -> Result<Json<MontageResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Montage request: sources={}, columns={}, rows={:?}, cell={}x{}",
+        payload.s3_urls.len(),
+        payload.columns,
+        payload.rows,
+        payload.cell_width,
+        payload.cell_height
+    );
+
+    if payload.s3_urls.is_empty() || payload.s3_urls.len() > MAX_MONTAGE_SOURCES {
+        return Err(AppError::InvalidParameter(format!(
+            "A montage needs between 1 and {} source URLs",
+            MAX_MONTAGE_SOURCES
+        )));
+    }
+    if payload.columns == 0 || payload.cell_width == 0 || payload.cell_height == 0 {
+        return Err(AppError::InvalidParameter(
+            "columns, cell_width and cell_height must be greater than 0".to_string(),
+        ));
+    }
+
+    let needed_rows = payload.s3_urls.len().div_ceil(payload.columns as usize) as u32;
+    let rows = payload.rows.unwrap_or(needed_rows);
+    if rows < needed_rows {
+        return Err(AppError::InvalidParameter(format!(
+            "{} sources don't fit in a {}x{} grid",
+            payload.s3_urls.len(),
+            payload.columns,
+            rows
+        )));
+    }
+
+    let width = payload.columns as u64 * payload.cell_width as u64;
+    let height = rows as u64 * payload.cell_height as u64;
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if width > max_dimension as u64 || height > max_dimension as u64 {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested montage {}x{} exceeds the maximum of {}px per side",
+            width, height, max_dimension
+        )));
+    }
+    let (width, height) = (width as u32, height as u32);
+
+    let sources = payload
+        .s3_urls
+        .iter()
+        .map(|url| parse_s3_url(url))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (bucket, first_key) = &sources[0];
+
+    let montage_key = generate_montage_key(
+        first_key,
+        payload.columns,
+        rows,
+        payload.cell_width,
+        payload.cell_height,
+        &payload.s3_urls,
+        state.config.cache_version.as_deref(),
+    );
+
+    let s3_client = state.s3_client_for(&payload.s3_urls[0]).await;
+
+    let mut failed_sources = Vec::new();
+    let images: Vec<_> = download_all(&state, &payload.s3_urls)
+        .await
+        .into_iter()
+        .zip(&payload.s3_urls)
+        .map(|(result, url)| {
+            // A header that doesn't parse means the cell would fail to decode.
+            match result.and_then(|data| ImageProcessor::dimensions(&data).map(|_| data)) {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    tracing::warn!("Montage source {} failed, using a placeholder: {}", url, e);
+                    failed_sources.push(url.clone());
+                    None
+                }
+            }
+        })
+        .collect();
+    let sources: Vec<_> = images.iter().flatten().collect();
+    for source in &sources {
+        state.stats.downloaded(source.len());
+    }
+    check_work_budget(&state, &sources, 1, ResizeFilter::default())?;
+
+    let (columns, limits) = (payload.columns, state.config.decode_limits);
+    let montage_started = Instant::now();
+    let processed = run_blocking(&state, "Montage", move || {
+        ImageProcessor::collage(&images, columns, rows, width, height, &limits)
+    })
+    .await?;
+    state
+        .stats
+        .resized(processed.format.as_str(), montage_started.elapsed());
+
+    let (width, height) = (processed.width, processed.height);
+    let montage_bytes = processed.data.len();
+    let montage_url = s3_client
+        .upload_image(
+            bucket,
+            &montage_key,
+            processed.data,
+            &processed.content_type,
+            &UploadOptions {
+                storage_class: payload.storage_class.or(state.config.default_storage_class),
+                ..UploadOptions::from_config(&state.config)
+            },
+        )
+        .await?;
+    state.stats.uploaded(montage_bytes);
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        failed_sources = failed_sources.len(),
+        "Successfully built and uploaded montage to {}",
+        montage_url
+    );
+
+    Ok(Json(MontageResponse {
+        montage_url,
+        width,
+        height,
+        failed_sources,
+    }))
+}
+
+/// Thumbnails a list of frames, or the frames of one animated source, onto a
+/// single sheet and returns where each cell sits. The map is stored next to
+/// the sheet as `{sheet}.json`, written last, and serves as the cache entry.
+#[utoipa::path(
+    post,
+    path = "/sprite",
+    request_body = SpriteRequest,
+    responses(
+        (status = 200, description = "Sprite sheet stored, or found in the cache", body = SpriteResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 404, description = "A source doesn't exist", body = ErrorBody),
+        (status = 422, description = "A source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn sprite(
+    State(state): State<AppState>,
+    Json(payload): Json<SpriteRequest>,
+) -> Result<Json<SpriteResponse>, AppError> {
+    let started = Instant::now();
+    tracing::debug!(
+        "Sprite request: sources={}, animated={:?}, frames={:?}, thumb={}x{}, columns={}",
+        payload.s3_urls.len(),
+        payload.s3_url,
+        payload.frames,
+        payload.thumb_width,
+        payload.thumb_height,
+        payload.columns
+    );
+
+    let quality = payload.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    let mut problems = Vec::new();
+    match (&payload.s3_url, payload.s3_urls.len()) {
+        (None, 0) | (Some(_), 1..) => {
+            problems.push("Provide either s3_urls or an animated s3_url".to_string())
+        }
+        (None, sources) if sources > MAX_SPRITE_CELLS as usize => problems.push(format!(
+            "A sprite sheet takes at most {} source URLs",
+            MAX_SPRITE_CELLS
+        )),
+        _ => {}
+    }
+    match payload.frames {
+        Some(_) if payload.s3_url.is_none() => {
+            problems.push("frames only applies to an animated s3_url".to_string())
+        }
+        Some(frames) if frames == 0 || frames > MAX_SPRITE_CELLS => {
+            problems.push(format!("frames must be between 1 and {}", MAX_SPRITE_CELLS))
+        }
+        _ => {}
+    }
+    if payload.columns == 0 || payload.thumb_width == 0 || payload.thumb_height == 0 {
+        problems.push("columns, thumb_width and thumb_height must be greater than 0".to_string());
+    }
+    if !matches!(
+        payload.output_format,
+        OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::Webp
+    ) {
+        problems.push("output_format must be jpeg, png or webp for sprite sheets".to_string());
+    }
+    if !(1..=100).contains(&quality) {
+        problems.push("quality must be between 1 and 100".to_string());
+    }
+    reject_problems(problems)?;
+
+    let source_urls = match &payload.s3_url {
+        Some(url) => vec![url.clone()],
+        None => payload.s3_urls.clone(),
+    };
+    let (bucket, first_key) = parse_s3_url(&source_urls[0])?;
+    for url in &source_urls[1..] {
+        parse_s3_url(url)?;
+    }
+    let s3_client = state.s3_client_for(&source_urls[0]).await;
+
+    let extension = match payload.output_format {
+        OutputFormat::Jpeg => "jpg",
+        format => format.as_str(),
+    };
+    let sprite_key = generate_sprite_key(
+        &first_key,
+        payload.columns,
+        payload.thumb_width,
+        payload.thumb_height,
+        payload.frames,
+        &source_urls,
+        state.config.cache_version.as_deref(),
+    );
+    let sprite_key = match encoding_key_suffix(ResizeFilter::default(), quality, false, None) {
+        Some(suffix) => append_key_suffix(&sprite_key, &suffix),
+        None => sprite_key,
+    };
+    let sprite_key = replace_extension(&sprite_key, extension);
+    let map_key = generate_sidecar_key(&sprite_key);
+
+    if !payload.force && s3_client.check_object_exists(&bucket, &map_key).await {
+        let map_url = format!("s3://{}/{}", bucket, map_key);
+        let map = s3_client.download_image(&map_url).await?;
+        match serde_json::from_slice::<SpriteResponse>(&map) {
+            Ok(cached) => {
+                state.stats.cache_hit();
+                log_sampled!(
+                    state.log_sampler,
+                    started.elapsed(),
+                    "Sprite sheet already exists at {}, returning cached URL",
+                    cached.sprite_url
+                );
+                return Ok(Json(cached));
+            }
+            Err(e) => tracing::warn!("Unreadable sprite map at {}, regenerating: {}", map_url, e),
+        }
+    }
+
+    let sources = download_all(&state, &source_urls)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    for source in &sources {
+        state.stats.downloaded(source.len());
+    }
+    let budget_sources: Vec<_> = sources.iter().collect();
+    check_work_budget(&state, &budget_sources, 1, ResizeFilter::default())?;
+
+    let encode = EncodeOptions {
+        format: payload.output_format,
+        quality,
+        ..EncodeOptions::from_config(&state.config)
+    };
+    let (thumb_width, thumb_height, columns) =
+        (payload.thumb_width, payload.thumb_height, payload.columns);
+    let (animated, frames) = (payload.s3_url.is_some(), payload.frames);
+    let limits = state.config.decode_limits;
+    let sprite_started = Instant::now();
+    let (processed, picked_frames) = run_blocking(&state, "Sprite", move || {
+        if animated {
+            let (processed, picked) = ImageProcessor::animation_sprite_sheet(
+                &sources[0],
+                frames,
+                MAX_SPRITE_CELLS,
+                thumb_width,
+                thumb_height,
+                columns,
+                &encode,
+                &limits,
+            )?;
+            Ok((processed, Some(picked)))
+        } else {
+            let processed = ImageProcessor::sprite_sheet(
+                &sources,
+                thumb_width,
+                thumb_height,
+                columns,
+                &encode,
+                &limits,
+            )?;
+            Ok((processed, None))
+        }
+    })
+    .await?;
+    state
+        .stats
+        .resized(processed.format.as_str(), sprite_started.elapsed());
+
+    let cell_count = match &picked_frames {
+        Some(picked) => picked.len(),
+        None => source_urls.len(),
+    };
+    let columns = columns.min(cell_count as u32);
+    let cells = (0..cell_count)
+        .map(|index| SpriteCell {
+            x: index as u32 % columns * thumb_width,
+            y: index as u32 / columns * thumb_height,
+            width: thumb_width,
+            height: thumb_height,
+            source_url: picked_frames.is_none().then(|| source_urls[index].clone()),
+            frame: picked_frames.as_ref().map(|picked| picked[index]),
+        })
+        .collect();
+
+    let upload_options = UploadOptions {
+        storage_class: payload.storage_class.or(state.config.default_storage_class),
+        ..UploadOptions::from_config(&state.config)
+    };
+    let (width, height) = (processed.width, processed.height);
+    let sprite_bytes = processed.data.len();
+    let sprite_url = s3_client
+        .upload_image(
+            &bucket,
+            &sprite_key,
+            processed.data,
+            &processed.content_type,
+            &upload_options,
+        )
+        .await?;
+    state.stats.uploaded(sprite_bytes);
+
+    let response = SpriteResponse {
+        sprite_url,
+        map_url: format!("s3://{}/{}", bucket, map_key),
+        width,
+        height,
+        thumb_width,
+        thumb_height,
+        columns,
+        rows: (cell_count as u32).div_ceil(columns),
+        output_format: processed.format,
+        cells,
+    };
+    let map = serde_json::to_vec(&response).map_err(|e| {
+        AppError::InternalError(format!("Failed to serialize the sprite map: {}", e))
+    })?;
+    s3_client
+        .upload_image(
+            &bucket,
+            &map_key,
+            Bytes::from(map),
+            "application/json",
+            &upload_options,
+        )
+        .await?;
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        cells = cell_count,
+        "Successfully built and uploaded sprite sheet to {}",
+        response.sprite_url
+    );
+
+    Ok(Json(response))
+}
+
+/// Downloads every URL concurrently, each in the region its URL names,
+/// returning the results in input order.
+async fn download_all(state: &AppState, urls: &[String]) -> Vec<Result<Bytes, AppError>> {
+    let mut downloads = Vec::with_capacity(urls.len());
+    for url in urls {
+        let s3_client = state.s3_client_for(url).await;
+        let url = url.clone();
+        downloads.push(tokio::spawn(
+            async move { s3_client.download_image(&url).await },
+        ));
+    }
+
+    let mut results = Vec::with_capacity(downloads.len());
+    for download in downloads {
+        results.push(download.await.unwrap_or_else(|e| {
+            Err(AppError::InternalError(format!(
+                "Download task failed: {}",
+                e
+            )))
+        }));
+    }
+
+    results
+}
+
+/// Re-runs a resize in memory with the current settings and compares the result
+/// against the stored variant, to find derivatives produced by older settings or
+/// encoder versions.
+#[utoipa::path(
+    post,
+    path = "/verify",
+    request_body = VerifyRequest,
+    responses(
+        (status = 200, description = "Comparison of the stored variant with a fresh resize", body = VerifyResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn verify(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyRequest>,
+) -> Result<Json<VerifyResponse>, AppError> {
+    let started = Instant::now();
+    let request = &payload.resize;
+    let object_mode = request
+        .object_mode
+        .unwrap_or(state.config.default_object_mode);
+    tracing::debug!(
+        "Verify request: url={}, width={:?}, height={:?}, mode={:?}, variant={:?}",
+        request.s3_url,
+        request.width,
+        request.height,
+        object_mode,
+        payload.variant_url
+    );
+
+    if request.max_megapixels.is_some()
+        || request.max_width.is_some()
+        || request.max_height.is_some()
+        || request.square.is_some()
+        || request.crop.is_some()
+        || request.canvas.is_some()
+        || request.text_watermark.is_some()
+        || request.video_timestamp.is_some()
+        || request.frame_index.is_some()
+        || request.face_crop
+        || request.dpr.unwrap_or(1) != 1
+        || request.content_hash
+    {
+        return Err(AppError::InvalidParameter(
+            "verify supports width/height resizes only; max_megapixels, max_width/max_height, square, crop, canvas, text_watermark, video_timestamp, frame_index, face_crop, dpr and content_hash are not supported".to_string(),
+        ));
+    }
+
+    request.validate()?;
+
+    let (width, height) = match (request.width, request.height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+        _ => {
+            return Err(AppError::InvalidParameter(
+                "Width and height must be greater than 0".to_string(),
+            ));
+        }
+    };
+    let (width, height) = request.snapped(width, height);
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if width > max_dimension || height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested output {}x{} exceeds the maximum of {}px per side",
+            width, height, max_dimension
+        )));
+    }
+
+    if request.output_format != OutputFormat::Jpeg {
+        return Err(AppError::UnsupportedOperation(format!(
+            "verify compares decoded pixels and can't decode {} variants; only JPEG variants can be verified",
+            request.output_format.as_str().to_uppercase()
+        )));
+    }
+
+    let threshold = payload.threshold.unwrap_or(DEFAULT_VERIFY_THRESHOLD);
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(AppError::InvalidParameter(
+            "threshold must be between 0 and 1".to_string(),
+        ));
+    }
+
+    let filter = request.resolved_filter();
+    let quality = request.resolved_quality(DEFAULT_JPEG_QUALITY);
+    let progressive = request.resolved_progressive();
+
+    if !(1..=100).contains(&quality) {
+        return Err(AppError::InvalidParameter(
+            "quality must be between 1 and 100".to_string(),
+        ));
+    }
+
+    let (bucket, original_key) = parse_s3_url(&request.s3_url)?;
+
+    let variant_url = match &payload.variant_url {
+        Some(variant_url) => variant_url.clone(),
+        None => {
+            let output = match &request.output_bucket {
+                Some(output_bucket) => parse_output_bucket(output_bucket)?,
+                None => OutputLocation::s3(&bucket),
+            };
+            if output.scheme != StorageScheme::S3 {
+                return Err(AppError::InvalidParameter(
+                    "verify can only read variants stored in S3".to_string(),
+                ));
+            }
+
+            let named_key = match request.output_stem() {
+                Some(stem) => replace_key_stem(&original_key, &stem),
+                None => original_key.clone(),
+            };
+            let key = generate_resized_key(
+                &named_key,
+                width,
+                height,
+                state.config.cache_version.as_deref(),
+            );
+            let key = match encoding_key_suffix(filter, quality, progressive, request.dpi) {
+                Some(suffix) => append_key_suffix(&key, &suffix),
+                None => key,
+            };
+            let key = match request.chroma_subsampling.key_suffix() {
+                Some(suffix) => append_key_suffix(&key, suffix),
+                None => key,
+            };
+            let key = match object_mode.key_suffix() {
+                Some(suffix) => append_key_suffix(&key, suffix),
+                None => key,
+            };
+            let key = match request.color_profile.key_suffix() {
+                Some(suffix) => append_key_suffix(&key, suffix),
+                None => key,
+            };
+            let key = match alpha_background_key_suffix(request.alpha_background) {
+                Some(suffix) => append_key_suffix(&key, &suffix),
+                None => key,
+            };
+            let key = if request.reject_if_larger {
+                append_key_suffix(&key, "nogrow")
+            } else {
+                key
+            };
+            output.object_url(&key)
+        }
+    };
+    let (variant_bucket, variant_key) = parse_s3_url(&variant_url)?;
+
+    let s3_client = state
+        .source_client(&request.s3_url, request.requester_pays)
+        .await;
+    let variant_client = if variant_bucket == bucket {
+        s3_client.clone()
+    } else {
+        state.s3_client_for(&variant_url).await
+    };
+
+    let image_data = s3_client.download_image(&request.s3_url).await?;
+    let options = ResizeOptions {
+        width,
+        height,
+        object_mode,
+        filter,
+        min_source_dimension: request
+            .min_source_dimension
+            .or(state.config.min_source_dimension)
+            .filter(|&v| v > 0),
+        crop: None,
+        distortion_guard: None,
+        canvas: None,
+        watermark: None,
+        color_profile: request.color_profile,
+        encode: EncodeOptions {
+            format: OutputFormat::Jpeg,
+            chroma_subsampling: request.chroma_subsampling,
+            quality,
+            progressive,
+            dpi: request.dpi,
+            alpha_background: request.alpha_background,
+            jpeg_encoder: state.config.jpeg_encoder,
+        },
+        auto_formats: Vec::new(),
+        avif_fallback: Vec::new(),
+        jpeg_fallback: false,
+        face_crop: false,
+        phash: false,
+        blurhash: false,
+        frame_index: None,
+        operations: None,
+        reject_if_larger: request.reject_if_larger,
+    };
+    let expected = resize_blocking(&state, image_data, options).await?;
+
+    let mut response = VerifyResponse {
+        original_url: request.s3_url.clone(),
+        variant_url,
+        exists: false,
+        similarity: None,
+        threshold,
+        matches: false,
+        identical_bytes: false,
+        expected_width: expected.width,
+        expected_height: expected.height,
+        stored_width: None,
+        stored_height: None,
+    };
+
+    if !variant_client
+        .check_object_exists(&variant_bucket, &variant_key)
+        .await
+    {
+        tracing::debug!(
+            "Variant {} does not exist, nothing to verify",
+            response.variant_url
+        );
+        return Ok(Json(response));
+    }
+
+    let stored = variant_client.download_image(&response.variant_url).await?;
+    let (stored_width, stored_height) = ImageProcessor::dimensions(&stored)?;
+    let (expected_data, stored_data, limits) = (
+        expected.data.clone(),
+        stored.clone(),
+        state.config.decode_limits,
+    );
+    let similarity = run_blocking(&state, "Similarity", move || {
+        ImageProcessor::similarity(&expected_data, &stored_data, &limits)
+    })
+    .await?;
+
+    response.exists = true;
+    response.similarity = Some(similarity);
+    response.matches = similarity >= threshold;
+    response.identical_bytes = stored == expected.data;
+    response.stored_width = Some(stored_width);
+    response.stored_height = Some(stored_height);
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        similarity,
+        matches = response.matches,
+        "Verified variant {}",
+        response.variant_url
+    );
+
+    Ok(Json(response))
+}
+
+/// The bytes `GET /resize` serves, and the derivative they were cached under.
+struct InlineDerivative {
+    data: Bytes,
+    content_type: String,
+    bucket: String,
+    key: String,
+}
+
+/// Serves the cached derivative of `s3_url`, or renders and caches it.
+async fn inline_derivative(
+    state: &AppState,
+    query: &InlineResizeQuery,
+    s3_url: &str,
+    object_mode: ObjectMode,
+) -> Result<InlineDerivative, AppError> {
+    let (bucket, original_key) = parse_s3_url(s3_url)?;
+    // The fallback image is the service's own, so only the requested source
+    // is read with the caller's `requester_pays`.
+    let requester_pays = query.requester_pays.filter(|_| s3_url == query.s3_url);
+    let s3_client = state.source_client(s3_url, requester_pays).await;
+
+    let resized_key = generate_resized_key(
+        &original_key,
+        query.width,
+        query.height,
+        state.config.cache_version.as_deref(),
+    );
+    let resized_key = match query.chroma_subsampling.key_suffix() {
+        Some(suffix) => append_key_suffix(&resized_key, suffix),
+        None => resized_key,
+    };
+    let resized_key = match object_mode.key_suffix() {
+        Some(suffix) => append_key_suffix(&resized_key, suffix),
+        None => resized_key,
+    };
+
+    let (data, content_type) = if s3_client.check_object_exists(&bucket, &resized_key).await {
+        tracing::debug!("Serving cached derivative s3://{}/{}", bucket, resized_key);
+        state.stats.cache_hit();
+        let data = s3_client
+            .download_image(&format!("s3://{}/{}", bucket, resized_key))
+            .await?;
+        (data, "image/jpeg".to_string())
+    } else {
+        let image_data = s3_client.download_image(s3_url).await?;
+        state.stats.downloaded(image_data.len());
+
+        let options = ResizeOptions {
+            width: query.width,
+            height: query.height,
+            object_mode,
+            filter: ResizeFilter::default(),
+            min_source_dimension: state.config.min_source_dimension,
+            crop: None,
+            distortion_guard: None,
+            canvas: None,
+            watermark: None,
+            color_profile: ColorProfile::default(),
+            encode: EncodeOptions {
+                chroma_subsampling: query.chroma_subsampling,
+                ..EncodeOptions::from_config(&state.config)
+            },
+            auto_formats: Vec::new(),
+            avif_fallback: Vec::new(),
+            jpeg_fallback: false,
+            face_crop: false,
+            phash: false,
+            blurhash: false,
+            frame_index: None,
+            operations: None,
+            reject_if_larger: false,
+        };
+        check_work_budget(state, &[&image_data], 1, ResizeFilter::default())?;
+        let resize_started = Instant::now();
+        let processed = resize_blocking(state, image_data, options).await?;
+        state
+            .stats
+            .resized(processed.format.as_str(), resize_started.elapsed());
+        state
+            .stats
+            .encoded(processed.format.as_str(), processed.timings.encode);
+
+        // The caller gets the bytes either way; a failed cache write only costs a rerender.
+        if let Err(e) = s3_client
+            .upload_image(
+                &bucket,
+                &resized_key,
+                processed.data.clone(),
+                &processed.content_type,
+                &UploadOptions {
+                    storage_class: query.storage_class.or(state.config.default_storage_class),
+                    if_absent: true,
+                    ..UploadOptions::from_config(&state.config)
+                },
+            )
+            .await
+        {
+            tracing::warn!("Failed to cache inline derivative {}: {}", resized_key, e);
+        } else {
+            state.stats.uploaded(processed.data.len());
+        }
+
+        (processed.data, processed.content_type)
+    };
+
+    Ok(InlineDerivative {
+        data,
+        content_type,
+        bucket,
+        key: resized_key,
+    })
+}
+
+/// Resizes and returns the image bytes directly, e.g. as a CDN origin or an
+/// `<img src>`. Derivatives are cached in S3 exactly like `POST /resize`.
+#[utoipa::path(
+    get,
+    path = "/resize",
+    params(InlineResizeQuery),
+    responses(
+        (status = 200, description = "The derivative's bytes", content_type = "image/jpeg", body = ImageBytes, headers(
+            ("X-Fallback-Image" = String, description = "`true` when the source was missing and `FALLBACK_IMAGE_S3_URL` was served instead"),
+        )),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 404, description = "The source doesn't exist and no fallback image is configured", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn resize_inline(
+    State(state): State<AppState>,
+    Query(query): Query<InlineResizeQuery>,
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let object_mode = query
+        .object_mode
+        .unwrap_or(state.config.default_object_mode);
+    tracing::debug!(
+        "Inline resize request: url={}, width={}, height={}, mode={:?}, download={:?}",
+        query.s3_url,
+        query.width,
+        query.height,
+        object_mode,
+        query.download
+    );
+
+    if query.width == 0 || query.height == 0 {
+        return Err(AppError::InvalidParameter(
+            "Width and height must be greater than 0".to_string(),
+        ));
+    }
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if query.width > max_dimension || query.height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested output {}x{} exceeds the maximum of {}px per side",
+            query.width, query.height, max_dimension
+        )));
+    }
+
+    // A missing source renders FALLBACK_IMAGE_S3_URL instead. Its derivative is
+    // cached under the fallback's own key, so the real image shows up once uploaded.
+    let (derivative, fallback) =
+        match inline_derivative(&state, &query, &query.s3_url, object_mode).await {
+            Err(AppError::NotFound(message)) => match &state.config.fallback_image_s3_url {
+                Some(fallback_url) => {
+                    tracing::warn!("{}, serving the fallback image {}", message, fallback_url);
+                    let derivative =
+                        inline_derivative(&state, &query, fallback_url, object_mode).await?;
+                    (derivative, true)
+                }
+                None => return Err(AppError::NotFound(message)),
+            },
+            result => (result?, false),
+        };
+    let InlineDerivative {
+        data,
+        content_type,
+        bucket,
+        key: resized_key,
+    } = derivative;
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        bytes = data.len(),
+        "Served inline derivative s3://{}/{}",
+        bucket,
+        resized_key
+    );
 
+    let mut response = ([(header::CONTENT_TYPE, content_type)], data).into_response();
+    if fallback {
+        // Keep CDNs from holding on to the placeholder after the source arrives.
+        let headers = response.headers_mut();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        headers.insert(
+            HeaderName::from_static("x-fallback-image"),
+            HeaderValue::from_static("true"),
+        );
+    }
+
+    let download = match query.download.as_deref().map(str::trim) {
+        None | Some("false" | "0") => None,
+        Some("" | "true" | "1") => Some(query.filename.as_deref()),
+        Some(filename) => Some(Some(filename)),
+    };
+    if let Some(filename) = download {
+        let filename = filename
+            .map(sanitize_filename)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| {
+                sanitize_filename(resized_key.rsplit('/').next().unwrap_or(&resized_key))
+            });
+
+        let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+            .map_err(|e| AppError::InternalError(format!("Invalid Content-Disposition: {}", e)))?;
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok(response)
+}
+
+/// Resizes and returns uncompressed RGBA8 pixels, row by row, for consumers
+/// that would only decode an encoded image again. Nothing is cached.
+#[utoipa::path(
+    get,
+    path = "/resize/raw",
+    params(RawResizeQuery),
+    responses(
+        (status = 200, description = "`X-Image-Width` x `X-Image-Height` RGBA8 pixels", content_type = "application/octet-stream", body = ImageBytes, headers(
+            ("X-Image-Width" = u32, description = "Output width in pixels"),
+            ("X-Image-Height" = u32, description = "Output height in pixels"),
+            ("X-Pixel-Format" = String, description = "Always `rgba8`"),
+        )),
+        (status = 400, description = "Invalid parameters", body = ErrorBody),
+        (status = 422, description = "The source can't be processed, or the output exceeds `MAX_RAW_OUTPUT_BYTES`", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+        (status = 503, description = "S3 circuit breaker is open", body = ErrorBody),
+    )
+)]
+pub async fn resize_raw(
+    State(state): State<AppState>,
+    Query(query): Query<RawResizeQuery>,
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let object_mode = query
+        .object_mode
+        .unwrap_or(state.config.default_object_mode);
+    tracing::debug!(
+        "Raw resize request: url={}, width={}, height={}, mode={:?}",
+        query.s3_url,
+        query.width,
+        query.height,
+        object_mode
+    );
+
+    if query.width == 0 || query.height == 0 {
+        return Err(AppError::InvalidParameter(
+            "Width and height must be greater than 0".to_string(),
+        ));
+    }
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if query.width > max_dimension || query.height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested output {}x{} exceeds the maximum of {}px per side",
+            query.width, query.height, max_dimension
+        )));
+    }
+
+    // Checked again on the actual output, which `outside` can make larger
+    // than the requested box.
+    let max_bytes = state.config.max_raw_output_bytes;
+    let requested_bytes = query.width as u64 * query.height as u64 * 4;
+    if requested_bytes > max_bytes {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Raw output {}x{} is {} bytes, over the maximum of {}",
+            query.width, query.height, requested_bytes, max_bytes
+        )));
+    }
+
+    let image_data = state
+        .s3_client_for(&query.s3_url)
+        .await
+        .download_image(&query.s3_url)
+        .await?;
+    state.stats.downloaded(image_data.len());
+
+    let options = ResizeOptions {
+        width: query.width,
+        height: query.height,
+        object_mode,
+        filter: query.filter,
+        min_source_dimension: state.config.min_source_dimension,
+        crop: None,
+        distortion_guard: None,
+        canvas: None,
+        watermark: None,
+        color_profile: ColorProfile::default(),
+        encode: EncodeOptions::default(),
+        auto_formats: Vec::new(),
+        avif_fallback: Vec::new(),
+        jpeg_fallback: false,
+        face_crop: false,
+        phash: false,
+        blurhash: false,
+        frame_index: None,
+        operations: None,
+        reject_if_larger: false,
+    };
+    check_work_budget(&state, &[&image_data], 1, query.filter)?;
+    let limits = state.config.decode_limits;
+    let resize_started = Instant::now();
+    let raw = run_blocking(&state, "Resize", move || {
+        ImageProcessor::resize_raw(image_data, &options, &limits, max_bytes)
+    })
+    .await?;
+    state.stats.resized("raw", resize_started.elapsed());
+
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        bytes = raw.data.len(),
+        "Served {}x{} raw pixels for {}",
+        raw.width,
+        raw.height,
+        query.s3_url
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (
+                HeaderName::from_static("x-image-width"),
+                raw.width.to_string(),
+            ),
+            (
+                HeaderName::from_static("x-image-height"),
+                raw.height.to_string(),
+            ),
+            (
+                HeaderName::from_static("x-pixel-format"),
+                "rgba8".to_string(),
+            ),
+        ],
+        raw.data,
+    )
+        .into_response())
+}
+
+/// Keeps a filename safe to embed in a quoted header value: printable ASCII
+/// only, with quotes, backslashes and path separators removed.
+fn sanitize_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .filter(|c| !matches!(c, '"' | '\\' | '/'))
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Queues a resize and returns its ID immediately; poll `GET /jobs/{id}`.
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = ResizeRequest,
+    responses(
+        (status = 202, description = "Job queued", body = JobResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 503, description = "The job queue is full", body = ErrorBody),
+    )
+)]
+pub async fn create_job(
+    State(state): State<AppState>,
+    Json(payload): Json<ResizeRequest>,
+) -> Result<(StatusCode, Json<JobResponse>), AppError> {
+    // Fail invalid or unsupported requests now rather than as a failed job later.
+    let payload = with_source_metadata(&state, payload).await?;
+    check_resize_request(&payload, &state.config)?;
+
+    let id = state.jobs.enqueue(payload)?;
+    tracing::debug!("Queued job {}", id);
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(JobResponse {
+            id,
+            status: JobStatus::Pending,
+            result: None,
+            error: None,
+        }),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = Uuid, Path, description = "ID returned by `POST /jobs`")),
+    responses(
+        (status = 200, description = "Job status, with the resize response once done", body = JobResponse),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 404, description = "Unknown or expired job ID", body = ErrorBody),
+    )
+)]
+pub async fn get_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, AppError> {
+    let id = Uuid::parse_str(&id)
+        .map_err(|e| AppError::InvalidParameter(format!("Invalid job ID: {}", e)))?;
+
+    state
+        .jobs
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("Job {} not found", id)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "The service is up", body = HealthResponse),
+    )
+)]
+pub async fn health(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        s3_circuit: state.s3_breaker.state(),
+    })
+}
+
+/// Readiness probe. Checks read access to `READINESS_BUCKET` on every call and
+/// reports the startup write check when `READINESS_WRITE_CHECK` is on, retrying
+/// it while it fails. Failing checks name the missing permission and return `503`.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    responses(
+        (status = 200, description = "Every permission check passed", body = ReadyResponse),
+        (status = 503, description = "A permission check failed", body = ReadyResponse),
+    )
+)]
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    let Some(bucket) = &state.config.readiness_bucket else {
+        return (
+            StatusCode::OK,
+            Json(ReadyResponse {
+                status: "ready",
+                checks: Vec::new(),
+            }),
+        );
+    };
+
+    let s3_client = state.s3_client().await;
+    let mut checks = vec![readiness::check_read(&s3_client, bucket).await];
+    let mut pending = false;
+    if state.config.readiness_write_check {
+        match state.readiness.write_checks() {
+            Some(write_checks) if write_checks.iter().all(|check| check.ok) => {
+                checks.extend(write_checks)
+            }
+            Some(_) => checks.extend(state.readiness.run_write_check(&s3_client, bucket).await),
+            None => pending = true,
+        }
+    }
+
+    let (status_code, status) = if checks.iter().any(|check| !check.ok) {
+        (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+    } else if pending {
+        (StatusCode::SERVICE_UNAVAILABLE, "starting")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+
+    (status_code, Json(ReadyResponse { status, checks }))
+}
+
+/// Resize counters since startup, as a quick JSON snapshot for on-call.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses((status = 200, description = "Counters since startup", body = StatsResponse))
+)]
+pub async fn stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    Json(state.stats.snapshot())
+}
+
+/// The OpenAPI description of this service, generated from the handlers and
+/// model types so it can't drift from the code.
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Runs `parse_s3_url` on its own so integrators can see how a URL is
+/// interpreted, or why it is rejected, without attempting a resize.
+#[utoipa::path(
+    post,
+    path = "/parse-url",
+    request_body = ParseUrlRequest,
+    responses(
+        (status = 200, description = "Bucket and key the URL resolves to", body = ParseUrlResponse),
+        (status = 400, description = "The URL isn't a supported S3 URL", body = ErrorBody),
+    )
+)]
+pub async fn parse_url(
+    Json(payload): Json<ParseUrlRequest>,
+) -> Result<Json<ParseUrlResponse>, AppError> {
+    let (bucket, key) = parse_s3_url(&payload.url)?;
+
+    Ok(Json(ParseUrlResponse {
+        url: payload.url,
+        bucket,
+        key,
+    }))
+}
+
+/// Resizes an image posted as `multipart/form-data`.
+///
+/// Expects an `image` file part plus `width`, `height` and optional `object_mode`
+/// fields. With a `destination` S3 URL the result is uploaded there and described
+/// as JSON; otherwise the resized bytes are returned directly.
+#[utoipa::path(
+    post,
+    path = "/resize/upload",
+    request_body(content_type = "multipart/form-data", description = "`image` file part plus `width`, `height` and optional `object_mode`, `chroma_subsampling`, `storage_class` and `destination` fields"),
+    responses(
+        (status = 200, description = "The resized bytes, or where they were uploaded when `destination` is set", content(
+            (ImageBytes = "image/jpeg"),
+            (UploadResizeResponse = "application/json"),
+        )),
+        (status = 400, description = "Invalid or unsupported parameters", body = ErrorBody),
+        (status = 413, description = "The image exceeds `MAX_UPLOAD_BYTES`", body = ErrorBody),
+        (status = 422, description = "The source can't be processed or exceeds the limits", body = ErrorBody),
+        (status = 502, description = "Storage request failed", body = ErrorBody),
+    )
+)]
+pub async fn resize_upload(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let mut image_data: Option<Bytes> = None;
+    let mut width: Option<u32> = None;
+    let mut height: Option<u32> = None;
+    let mut object_mode = state.config.default_object_mode;
+    let mut chroma_subsampling = ChromaSubsampling::default();
+    let mut storage_class: Option<StorageClass> = None;
+    let mut destination: Option<String> = None;
+
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::InvalidParameter(format!("Invalid multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+
+        if name == "image" {
+            let mut buffer = BytesMut::new();
+            while let Some(chunk) = field.chunk().await.map_err(|e| {
+                AppError::InvalidParameter(format!("Failed to read image part: {}", e))
+            })? {
+                if buffer.len() + chunk.len() > state.config.max_upload_bytes {
+                    return Err(AppError::PayloadTooLarge(format!(
+                        "Uploaded image exceeds the maximum of {} bytes",
+                        state.config.max_upload_bytes
+                    )));
+                }
+                buffer.extend_from_slice(&chunk);
+            }
+            image_data = Some(buffer.freeze());
+            continue;
+        }
+
+        let value = field.text().await.map_err(|e| {
+            AppError::InvalidParameter(format!("Failed to read field '{}': {}", name, e))
+        })?;
+
+        match name.as_str() {
+            "width" => width = Some(parse_form_dimension("width", &value)?),
+            "height" => height = Some(parse_form_dimension("height", &value)?),
+            "object_mode" => {
+                object_mode = ObjectMode::deserialize(value.trim().into_deserializer()).map_err(
+                    |e: serde::de::value::Error| {
+                        AppError::InvalidParameter(format!("Invalid object_mode: {}", e))
+                    },
+                )?
+            }
+            "chroma_subsampling" => {
+                chroma_subsampling = ChromaSubsampling::deserialize(
+                    value.trim().into_deserializer(),
+                )
+                .map_err(|e: serde::de::value::Error| {
+                    AppError::InvalidParameter(format!("Invalid chroma_subsampling: {}", e))
+                })?
+            }
+            "storage_class" => {
+                storage_class = Some(
+                    StorageClass::deserialize(value.trim().into_deserializer()).map_err(
+                        |e: serde::de::value::Error| {
+                            AppError::InvalidParameter(format!("Invalid storage_class: {}", e))
+                        },
+                    )?,
+                )
+            }
+            "destination" => destination = Some(value),
+            _ => tracing::debug!("Ignoring unknown multipart field '{}'", name),
+        }
+    }
+
+    let image_data = image_data
+        .ok_or_else(|| AppError::InvalidParameter("Missing 'image' file part".to_string()))?;
+    let width =
+        width.ok_or_else(|| AppError::InvalidParameter("Missing 'width' field".to_string()))?;
+    let height =
+        height.ok_or_else(|| AppError::InvalidParameter("Missing 'height' field".to_string()))?;
+
+    tracing::debug!(
+        "Upload resize request: bytes={}, width={}, height={}, mode={:?}, destination={:?}",
+        image_data.len(),
+        width,
+        height,
+        object_mode,
+        destination
+    );
+
+    let max_dimension = state.config.decode_limits.max_dimension;
+    if width > max_dimension || height > max_dimension {
+        return Err(AppError::DimensionTooLarge(format!(
+            "Requested output {}x{} exceeds the maximum of {}px per side",
+            width, height, max_dimension
+        )));
+    }
+
+    let options = ResizeOptions {
+        width,
+        height,
+        object_mode,
+        filter: ResizeFilter::default(),
+        min_source_dimension: state.config.min_source_dimension,
+        crop: None,
+        distortion_guard: None,
+        canvas: None,
+        watermark: None,
+        color_profile: ColorProfile::default(),
+        encode: EncodeOptions {
+            chroma_subsampling,
+            ..EncodeOptions::from_config(&state.config)
+        },
+        auto_formats: Vec::new(),
+        avif_fallback: Vec::new(),
+        jpeg_fallback: false,
+        face_crop: false,
+        phash: false,
+        blurhash: false,
+        frame_index: None,
+        operations: None,
+        reject_if_larger: false,
+    };
+
+    check_work_budget(&state, &[&image_data], 1, ResizeFilter::default())?;
+    let resize_started = Instant::now();
+    let processed = resize_blocking(&state, image_data, options).await?;
+    state
+        .stats
+        .resized(processed.format.as_str(), resize_started.elapsed());
+    state
+        .stats
+        .encoded(processed.format.as_str(), processed.timings.encode);
+
+    let Some(destination) = destination else {
+        log_sampled!(
+            state.log_sampler,
+            started.elapsed(),
+            bytes = processed.data.len(),
+            "Successfully resized upload and returned it inline"
+        );
+        return Ok((
+            [(header::CONTENT_TYPE, processed.content_type)],
+            processed.data,
+        )
+            .into_response());
+    };
+
+    let (bucket, key) = parse_s3_url(&destination)?;
+
+    let s3_client = state.s3_client_for(&destination).await;
+    let output_bytes = processed.data.len();
     let resized_url = s3_client
-        .upload_image(&bucket, &resized_key, resized_data, &content_type)
+        .upload_image(
+            &bucket,
+            &key,
+            processed.data,
+            &processed.content_type,
+            &UploadOptions {
+                storage_class: storage_class.or(state.config.default_storage_class),
+                ..UploadOptions::from_config(&state.config)
+            },
+        )
         .await?;
+    state.stats.uploaded(output_bytes);
 
-    tracing::info!("Successfully resized and uploaded image to {}", resized_url);
+    log_sampled!(
+        state.log_sampler,
+        started.elapsed(),
+        "Successfully resized upload and stored it at {}",
+        resized_url
+    );
 
-    Ok(Json(ResizeResponse {
-        original_url: payload.s3_url,
+    Ok(Json(UploadResizeResponse {
         resized_url,
-        width: payload.width,
-        height: payload.height,
-        object_mode: payload.object_mode,
-    }))
+        width,
+        height,
+        object_mode,
+    })
+    .into_response())
+}
+
+/// With `SOURCE_METADATA_DEFAULTS`, completes a request that gives no size
+/// from the source's `resize-*` user metadata; other requests are returned
+/// unchanged without touching S3.
+async fn with_source_metadata(
+    state: &AppState,
+    request: ResizeRequest,
+) -> Result<ResizeRequest, AppError> {
+    if !state.config.source_metadata_defaults || !request.omits_size() {
+        return Ok(request);
+    }
+
+    let metadata = state
+        .source_client(&request.s3_url, request.requester_pays)
+        .await
+        .object_metadata(&request.s3_url)
+        .await?;
+    tracing::debug!(
+        "Taking resize defaults for {} from its metadata",
+        request.s3_url
+    );
+    request.with_metadata_defaults(&metadata)
+}
+
+/// Everything about a resize request that can be checked before anything is
+/// downloaded: the request's own problems plus what this build and
+/// configuration can't do, reported together.
+fn check_resize_request(request: &ResizeRequest, config: &Config) -> Result<(), AppError> {
+    let mut problems = request.problems();
+    check_source_support(request, &mut problems);
+    check_output_support(request, config, &mut problems);
+    check_face_crop(request, config.default_object_mode, &mut problems);
+
+    reject_problems(problems)
+}
+
+/// Video and SVG sources this build can't rasterize, recognised by the key's
+/// extension. Videos only recognisable by their bytes fail after the download.
+fn check_source_support(request: &ResizeRequest, problems: &mut Vec<String>) {
+    if video::is_video_key(&request.s3_url) && !cfg!(feature = "video") {
+        problems.push(video::unsupported().to_string());
+    }
+    if svg::is_svg_key(&request.s3_url) && !cfg!(feature = "svg") {
+        problems.push(svg::unsupported().to_string());
+    }
+}
+
+/// Face-aware cropping positions a `cover` crop, so it needs a build with the
+/// detector and, when `object_mode` is left out, a `cover` default.
+fn check_face_crop(request: &ResizeRequest, default_mode: ObjectMode, problems: &mut Vec<String>) {
+    if !request.face_crop {
+        return;
+    }
+    if !cfg!(feature = "face-detect") {
+        problems.push(face::unsupported().to_string());
+    }
+    if request.object_mode.is_none() && !matches!(default_mode, ObjectMode::Cover) {
+        problems.push(
+            "face_crop only applies to object_mode cover; DEFAULT_OBJECT_MODE is not cover"
+                .to_string(),
+        );
+    }
+}
+
+/// Output settings this build can't produce, or that don't apply to the
+/// requested format. AVIF without the `avif` feature is served by
+/// `AVIF_FALLBACK_FORMATS` when one is configured.
+fn check_output_support(request: &ResizeRequest, config: &Config, problems: &mut Vec<String>) {
+    if request.output_format == OutputFormat::Avif
+        && !cfg!(feature = "avif")
+        && config.avif_fallback.is_empty()
+    {
+        problems.push(
+            "AVIF output requires the service to be built with the `avif` feature".to_string(),
+        );
+    }
+    let may_be_avif = cfg!(feature = "avif")
+        && match request.output_format {
+            OutputFormat::Auto => config.auto_formats.contains(&OutputFormat::Avif),
+            format => format == OutputFormat::Avif,
+        };
+    if may_be_avif && request.lqip.is_some() {
+        problems.push(
+            "lqip is built by decoding the derivative, which isn't possible for AVIF output; request lqip with JPEG output".to_string(),
+        );
+    }
+}
+
+/// Rejects a request whose estimated work exceeds `MAX_REQUEST_WORK` before
+/// anything is decoded, so one huge request can't hold a blocking thread for
+/// long. Only image headers are read; sources whose header doesn't parse are
+/// left to fail in the decoder.
+fn check_work_budget(
+    state: &AppState,
+    sources: &[&Bytes],
+    outputs: u32,
+    filter: ResizeFilter,
+) -> Result<(), AppError> {
+    let Some(budget) = state.config.max_request_work else {
+        return Ok(());
+    };
+
+    let source_pixels = sources
+        .iter()
+        .filter_map(|source| ImageProcessor::dimensions(source).ok())
+        .map(|(width, height)| width as u64 * height as u64)
+        .sum();
+    let work = ImageProcessor::estimate_work(source_pixels, outputs, filter);
+    if work > budget {
+        return Err(AppError::InvalidParameter(format!(
+            "Estimated work of {:.1} megapixel-passes exceeds the per-request budget of {}; use smaller sources or fewer outputs",
+            work, budget
+        )));
+    }
+
+    Ok(())
+}
+
+/// Runs CPU-bound image work on Tokio's blocking pool, so decodes never stall
+/// the async workers. With `MAX_CONCURRENT_DECODES` set, it first waits for a
+/// decode permit, held until the work finishes even if the caller stops
+/// waiting, so requests downloading or uploading don't count against it.
+async fn run_blocking<T, F>(state: &AppState, task: &str, work: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, AppError> + Send + 'static,
+{
+    let permit =
+        match &state.decode_permits {
+            Some(permits) => Some(permits.clone().acquire_owned().await.map_err(|e| {
+                AppError::InternalError(format!("Decode permits unavailable: {}", e))
+            })?),
+            None => None,
+        };
+
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        work()
+    })
+    .await
+    .map_err(|e| AppError::InternalError(format!("{} task failed: {}", task, e)))?
+}
+
+async fn resize_blocking(
+    state: &AppState,
+    image_data: Bytes,
+    options: ResizeOptions,
+) -> Result<ProcessedImage, AppError> {
+    let limits = state.config.decode_limits;
+    run_blocking(state, "Resize", move || {
+        ImageProcessor::resize(image_data, &options, &limits)
+    })
+    .await
+}
+
+/// Hashes on the blocking pool, like `resize_blocking`, as it decodes the
+/// whole source.
+async fn phash_blocking(state: &AppState, image_data: Bytes) -> Result<u64, AppError> {
+    let limits = state.config.decode_limits;
+    run_blocking(state, "Hash", move || {
+        ImageProcessor::perceptual_hash(&image_data, &limits)
+    })
+    .await
+}
+
+/// Like `phash_blocking`, for `include_blurhash` on a cache hit.
+async fn blurhash_blocking(state: &AppState, image_data: Bytes) -> Result<String, AppError> {
+    let limits = state.config.decode_limits;
+    run_blocking(state, "BlurHash", move || {
+        ImageProcessor::blurhash(&image_data, &limits)
+    })
+    .await
+}
+
+fn phash_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// A downloaded source, with the `Content-Type` S3 reported for it.
+#[derive(Clone)]
+struct Source {
+    data: Bytes,
+    content_type: Option<String>,
+    /// Set when `content_type` contradicts the bytes, which win.
+    mislabeled: Option<String>,
+}
+
+/// Downloads the request's source, swapping a video for its still frame.
+async fn download_source(
+    state: &AppState,
+    deadline: &Deadline,
+    s3_client: &S3Client,
+    payload: &ResizeRequest,
+) -> Result<Source, AppError> {
+    let (source, content_type) = deadline
+        .run(
+            "download",
+            s3_client.download_with_content_type(&payload.s3_url),
+        )
+        .await?;
+    state.stats.downloaded(source.len());
+
+    let mislabeled = ImageProcessor::content_type_mismatch(content_type.as_deref(), &source);
+    if let Some(mismatch) = &mislabeled {
+        tracing::warn!("{}: {}", payload.s3_url, mismatch);
+    }
+
+    Ok(Source {
+        data: video_frame(state, deadline, source, payload.video_timestamp).await?,
+        content_type,
+        mislabeled,
+    })
+}
+
+/// Swaps a video source for its still frame; image sources pass through.
+async fn video_frame(
+    state: &AppState,
+    deadline: &Deadline,
+    source: Bytes,
+    timestamp: Option<f64>,
+) -> Result<Bytes, AppError> {
+    if !video::is_video_data(&source) {
+        if timestamp.is_some() {
+            return Err(AppError::InvalidParameter(
+                "video_timestamp only applies to video sources".to_string(),
+            ));
+        }
+        return Ok(source);
+    }
+
+    deadline
+        .run(
+            "frame extraction",
+            video::extract_frame(source, timestamp, &state.config.ffmpeg_path),
+        )
+        .await
+}
+
+/// Builds the placeholder from the derivative itself, so it matches the
+/// derivative's crop whether or not it was just generated.
+fn lqip_data_uri(
+    image_data: &Bytes,
+    options: &LqipOptions,
+    limits: &DecodeLimits,
+) -> Result<String, AppError> {
+    let placeholder = ImageProcessor::lqip(image_data, options, limits)?;
+
+    Ok(format!(
+        "data:{};base64,{}",
+        placeholder.content_type,
+        BASE64_STANDARD.encode(&placeholder.data)
+    ))
+}
+
+/// Describes filter/encoder settings that differ from the defaults, so default
+/// requests keep their historical keys.
+fn encoding_key_suffix(
+    filter: ResizeFilter,
+    quality: u8,
+    progressive: bool,
+    dpi: Option<u16>,
+) -> Option<String> {
+    let mut parts = Vec::new();
+    if filter != ResizeFilter::default() {
+        parts.push(filter.as_str().to_string());
+    }
+    if quality != DEFAULT_JPEG_QUALITY {
+        parts.push(format!("q{}", quality));
+    }
+    if progressive {
+        parts.push("progressive".to_string());
+    }
+    if let Some(dpi) = dpi {
+        parts.push(format!("{}dpi", dpi));
+    }
+
+    (!parts.is_empty()).then(|| parts.join("-"))
+}
+
+/// The `warn_on_distortion` guard of a validated request; it only ever
+/// accompanies `width`/`height` sizes.
+fn distortion_guard(request: &ResizeRequest) -> Option<DistortionGuard> {
+    request.warn_on_distortion.map(|threshold| DistortionGuard {
+        threshold,
+        reject: request.reject_distortion,
+    })
+}
+
+fn canvas_key_suffix(canvas: &CanvasOptions) -> String {
+    let mut suffix = format!(
+        "canvas{}x{}-{}",
+        canvas.width,
+        canvas.height,
+        format!("{:?}", canvas.gravity).to_lowercase()
+    );
+    if let Some(x) = canvas.x {
+        suffix.push_str(&format!("-x{}", x));
+    }
+    if let Some(y) = canvas.y {
+        suffix.push_str(&format!("-y{}", y));
+    }
+    suffix.push_str(&format!("-{}", canvas.background.to_hex()));
+
+    suffix
+}
+
+/// JPEG keeps the source's extension, as it always has; other formats swap it,
+/// or with `preserve_extension` are tagged `_{format}` so they never share a
+/// key with the JPEG variant.
+fn output_key(key: &str, format: OutputFormat, preserve_extension: bool) -> String {
+    match format {
+        OutputFormat::Jpeg => key.to_string(),
+        format if preserve_extension => append_key_suffix(key, format.as_str()),
+        format => replace_extension(key, format.as_str()),
+    }
+}
+
+/// Whether the key's extension is one clients would expect for `format`.
+fn extension_matches(key: &str, format: OutputFormat) -> bool {
+    let Some((_, extension)) = key.rsplit_once('.').filter(|(_, e)| !e.contains('/')) else {
+        return false;
+    };
+    let extension = extension.to_ascii_lowercase();
+    match format {
+        OutputFormat::Jpeg => extension == "jpg" || extension == "jpeg",
+        format => extension == format.as_str(),
+    }
+}
+
+/// White is the default and keeps the historical key. Only the RGB channels
+/// affect the output, so the background's alpha is left out.
+fn alpha_background_key_suffix(background: HexColor) -> Option<String> {
+    let [r, g, b, _] = background.0;
+    (background.0[..3] != default_alpha_background().0[..3])
+        .then(|| format!("bg{:02x}{:02x}{:02x}", r, g, b))
+}
+
+/// The watermark's text and styling are free-form, so they go into the key as
+/// a short digest rather than verbatim.
+fn text_watermark_key_suffix(watermark: &TextWatermark) -> String {
+    let description = format!(
+        "{}|{:?}|{}|{}|{:?}",
+        watermark.text,
+        watermark.size,
+        watermark.color.to_hex(),
+        watermark.opacity,
+        watermark.angle
+    );
+    let digest = format!("{:x}", Sha256::digest(description.as_bytes()));
+
+    format!("wm{}", &digest[..8])
+}
+
+/// Hash of the whole `operations` list, e.g. `ops1a2b3c4d`, as any step or
+/// their order can change the output.
+fn operations_key_suffix(operations: &[Operation]) -> String {
+    let description = serde_json::to_vec(operations).unwrap_or_default();
+    let digest = format!("{:x}", Sha256::digest(&description));
+
+    format!("ops{}", &digest[..8])
+}
+
+fn parse_form_dimension(name: &str, value: &str) -> Result<u32, AppError> {
+    match value.trim().parse::<u32>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(AppError::InvalidParameter(format!(
+            "{} must be an integer greater than 0",
+            name
+        ))),
+    }
+}
+
+fn parse_aspect_ratio(aspect_ratio: &str) -> Result<(u32, u32), AppError> {
+    let invalid = || {
+        AppError::InvalidParameter(format!(
+            "Invalid aspect_ratio '{}', expected the form W:H (e.g. 16:9)",
+            aspect_ratio
+        ))
+    };
+
+    let (width, height) = aspect_ratio.split_once(':').ok_or_else(invalid)?;
+    let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+
+    Ok((width, height))
 }
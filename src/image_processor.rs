@@ -1,84 +1,3419 @@
+use ab_glyph::{Font, FontArc, PxScale, ScaleFont};
 use bytes::Bytes;
-use image::{DynamicImage, ImageFormat, GenericImageView};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::error::{ImageError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, DynamicImage, Frames, GenericImageView, GrayImage, ImageDecoder, ImageFormat,
+    ImageReader, Limits, Luma, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use imageproc::drawing::{draw_text_mut, text_size};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use jpeg_encoder::{ColorType, Encoder, PixelDensity, SamplingFactor};
+use moxcms::{Layout, TransformOptions};
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::time::{Duration, Instant};
 
+use crate::config::Config;
 use crate::error::AppError;
-use crate::models::ObjectMode;
+use crate::face;
+use crate::jpeg;
+use crate::models::{
+    default_alpha_background, CanvasOptions, ChromaSubsampling, ColorProfile, CropRect, Gravity,
+    HexColor, LqipOptions, ObjectMode, OutputFormat, ResizeFilter, TextWatermark, TileLevel,
+};
+use crate::resample;
+use crate::svg;
+
+/// Longest side of the grayscale copy used to score crop windows.
+const ENERGY_SAMPLE_SIZE: u32 = 256;
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// Side of the dHash grid; the hash has this many bits squared.
+const PHASH_GRID: u32 = 8;
+pub const PHASH_BITS: u32 = PHASH_GRID * PHASH_GRID;
+/// BlurHash components across and down; 4x3 suits the usual landscape photo
+/// and gives a 28-character string.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// BlurHash only keeps a few low frequencies, so it is computed from a copy
+/// no larger than this per side.
+const BLURHASH_SIZE: u32 = 32;
+/// Outputs with at most this many colors are written as indexed PNGs.
+const PNG_PALETTE_SIZE: usize = 256;
+
+/// rav1e speed, from 1 (smallest files) to 10 (fastest). Slower settings cost
+/// seconds per image, which is too much for a synchronous request.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+const JPEG_EOI: [u8; 2] = [0xFF, 0xD9];
+
+/// How far into the source a retried decode looks for a format signature,
+/// past whatever junk a mislabeled upload starts with.
+const FORMAT_SNIFF_WINDOW: usize = 1024;
+
+/// Signatures specific enough to trust away from the start of the file.
+const FORMAT_SIGNATURES: [(&[u8], ImageFormat); 7] = [
+    (b"\x89PNG\r\n\x1a\n", ImageFormat::Png),
+    (&[0xFF, 0xD8, 0xFF], ImageFormat::Jpeg),
+    (b"GIF87a", ImageFormat::Gif),
+    (b"GIF89a", ImageFormat::Gif),
+    (b"RIFF", ImageFormat::WebP),
+    (b"II*\0", ImageFormat::Tiff),
+    (b"MM\0*", ImageFormat::Tiff),
+];
+
+/// Font used for text watermarks unless `WATERMARK_FONT_PATH` points elsewhere.
+const DEFAULT_WATERMARK_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// Fill for grid cells whose source couldn't be fetched.
+const PLACEHOLDER_CELL: Rgba<u8> = Rgba([208, 208, 208, 255]);
+
+/// Downscale ratio from which the `auto` filter box-filters before Lanczos3;
+/// below it the box pass would save little and `auto` is plain Lanczos3.
+pub const AUTO_PRESCALE_RATIO: f64 = 4.0;
+
+/// How many times the target size the `auto` box pass stops at, leaving
+/// Lanczos3 enough pixels to antialias the final step.
+const AUTO_PRESCALE_MARGIN: u32 = 2;
+
+/// Share of the image diagonal covered by a watermark without an explicit size.
+const WATERMARK_DIAGONAL_SHARE: f32 = 0.6;
+
+/// Upper bounds enforced by the decoder before it allocates the pixel buffer,
+/// and how hard it tries with input it doesn't recognise at first.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    pub max_dimension: u32,
+    pub max_alloc_bytes: u64,
+    /// After a failed decode, look for a known signature near the start and
+    /// decode again with that format's decoder.
+    pub format_retry: bool,
+}
+
+pub struct ResizeOptions {
+    pub width: u32,
+    pub height: u32,
+    pub object_mode: ObjectMode,
+    pub filter: ResizeFilter,
+    /// Rejects sources whose shorter side is below this many pixels.
+    pub min_source_dimension: Option<u32>,
+    /// Cut out of the source before anything else looks at it.
+    pub crop: Option<CropRect>,
+    /// Flags `Fill` resizes that stretch the source.
+    pub distortion_guard: Option<DistortionGuard>,
+    pub canvas: Option<CanvasOptions>,
+    pub watermark: Option<Watermark>,
+    pub color_profile: ColorProfile,
+    pub encode: EncodeOptions,
+    /// Formats tried when `encode.format` is `Auto`; the smallest output wins.
+    pub auto_formats: Vec<OutputFormat>,
+    /// Formats tried in order when an `Avif` encode fails, instead of failing.
+    pub avif_fallback: Vec<OutputFormat>,
+    /// Also encode the same pixels as JPEG, for clients without the primary
+    /// format.
+    pub jpeg_fallback: bool,
+    /// Center `Cover` crops on the largest detected face.
+    pub face_crop: bool,
+    /// Also hash the decoded source with `perceptual_hash`.
+    pub phash: bool,
+    /// Also compute the source's BlurHash, as `blurhash` does.
+    pub blurhash: bool,
+    /// Resize this frame of an animated GIF, PNG or WebP instead of the first.
+    pub frame_index: Option<u32>,
+    /// Applied in order in place of the `object_mode` resize, `canvas` and
+    /// `watermark`, which are then unset.
+    pub operations: Option<Vec<Step>>,
+    /// Hand back the source bytes instead of an output that encodes larger,
+    /// when the source is already in the output format.
+    pub reject_if_larger: bool,
+}
+
+/// One step of `ResizeOptions::operations`.
+pub enum Step {
+    /// Clockwise: 90, 180 or 270 degrees.
+    Rotate(u32),
+    Crop(CropRect),
+    Resize {
+        width: u32,
+        height: u32,
+        object_mode: ObjectMode,
+    },
+    Grayscale,
+    Watermark(Watermark),
+}
+
+/// How much a `Fill` resize may stretch the source before it's logged, or
+/// rejected with `reject`.
+#[derive(Debug, Clone, Copy)]
+pub struct DistortionGuard {
+    /// Largest tolerated `aspect_distortion`.
+    pub threshold: f64,
+    pub reject: bool,
+}
+
+/// A text watermark with its size already in output pixels, and the font to
+/// draw it with.
+#[derive(Clone)]
+pub struct Watermark {
+    pub options: TextWatermark,
+    pub font: FontArc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub format: OutputFormat,
+    /// JPEG only.
+    pub chroma_subsampling: ChromaSubsampling,
+    pub quality: u8,
+    /// JPEG only.
+    pub progressive: bool,
+    /// JPEG only. Written as the JFIF density in dots per inch; `None` leaves the
+    /// encoder's unitless 1:1 aspect.
+    pub dpi: Option<u16>,
+    /// Transparent pixels are flattened onto this color, as neither output
+    /// format carries alpha.
+    pub alpha_background: HexColor,
+    /// JPEG only.
+    pub jpeg_encoder: JpegEncoder,
+}
+
+/// Library that writes JPEG output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JpegEncoder {
+    /// The pure-Rust `jpeg-encoder` crate: fast, baseline Huffman tables.
+    #[default]
+    Builtin,
+    /// mozjpeg, when built with the `mozjpeg` feature: smaller files at the
+    /// same quality, for several times the encode time.
+    Mozjpeg,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            chroma_subsampling: ChromaSubsampling::default(),
+            quality: DEFAULT_JPEG_QUALITY,
+            progressive: false,
+            dpi: None,
+            alpha_background: default_alpha_background(),
+            jpeg_encoder: JpegEncoder::default(),
+        }
+    }
+}
+
+impl EncodeOptions {
+    /// Defaults with the configured `JPEG_ENCODER`.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            jpeg_encoder: config.jpeg_encoder,
+            ..Self::default()
+        }
+    }
+}
+
+pub struct ProcessedImage {
+    pub data: Bytes,
+    pub content_type: String,
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The JPEG requested with `jpeg_fallback`.
+    pub fallback: Option<Box<ProcessedImage>>,
+    /// Whether a face positioned the crop, when `face_crop` was requested.
+    pub face_detected: Option<bool>,
+    /// The source's perceptual hash, when `phash` was requested.
+    pub phash: Option<u64>,
+    /// The source's BlurHash, when `blurhash` was requested.
+    pub blurhash: Option<String>,
+    /// Where `resize` spent its time; zero for stages it skipped.
+    pub timings: StageTimings,
+    /// Whether `reject_if_larger` replaced the output with the source bytes.
+    pub source_kept: bool,
+}
+
+/// Time spent in each stage of `resize`. Resizing covers everything between
+/// decoding and encoding, such as the canvas, watermark and hashes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StageTimings {
+    pub decode: Duration,
+    pub resize: Duration,
+    pub encode: Duration,
+}
+
+fn frame_decode_error(e: ImageError) -> AppError {
+    AppError::ImageProcessingError(format!("Failed to decode frame: {}", e))
+}
+
+/// Encoded tiles of a Deep Zoom pyramid, from `tile_pyramid`.
+pub struct TilePyramid {
+    pub width: u32,
+    pub height: u32,
+    /// Smallest first, as `pyramid_levels` returns them.
+    pub levels: Vec<TileLevel>,
+    pub tiles: Vec<Tile>,
+}
+
+pub struct Tile {
+    pub level: u32,
+    pub column: u32,
+    pub row: u32,
+    pub data: Bytes,
+}
+
+/// Uncompressed output from `resize_raw`: `width * height` RGBA8 pixels, row
+/// by row.
+pub struct RawImage {
+    pub data: Bytes,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Pixels ready to encode, with what `resize` reports alongside them.
+struct Rendered {
+    image: DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+    face_center: Option<(f32, f32)>,
+    phash: Option<u64>,
+    blurhash: Option<String>,
+    decode_time: Duration,
+}
 
 pub struct ImageProcessor;
 
 impl ImageProcessor {
     pub fn resize(
         image_data: Bytes,
-        width: u32,
-        height: u32,
-        object_mode: ObjectMode,
-    ) -> Result<(Bytes, String), AppError> {
-        let img = image::load_from_memory(&image_data)
-            .map_err(|e| AppError::ImageProcessingError(format!("Failed to decode image: {}", e)))?;
+        options: &ResizeOptions,
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        if Self::is_identity(&image_data, options) {
+            tracing::debug!(
+                "Requested {}x{} matches the source, passing the original bytes through",
+                options.width,
+                options.height
+            );
+            let phash = if options.phash {
+                Some(Self::perceptual_hash(&image_data, limits)?)
+            } else {
+                None
+            };
+            let blurhash = if options.blurhash {
+                Some(Self::blurhash(&image_data, limits)?)
+            } else {
+                None
+            };
+            return Ok(ProcessedImage {
+                data: image_data,
+                content_type: "image/jpeg".to_string(),
+                format: OutputFormat::Jpeg,
+                width: options.width,
+                height: options.height,
+                fallback: None,
+                face_detected: options.face_crop.then_some(false),
+                phash,
+                blurhash,
+                timings: StageTimings::default(),
+                source_kept: false,
+            });
+        }
+
+        let render_started = Instant::now();
+        let rendered = Self::render(&image_data, options, limits)?;
+        let render_time = render_started.elapsed();
+        let output = rendered.image;
 
-        let resized = match object_mode {
-            ObjectMode::Cover => Self::resize_cover(img, width, height),
-            ObjectMode::Contain => Self::resize_contain(img, width, height),
-            ObjectMode::Fill => Self::resize_fill(img, width, height),
-            ObjectMode::ScaleDown => Self::resize_scale_down(img, width, height),
+        let encode_started = Instant::now();
+
+        let mut processed = match options.encode.format {
+            OutputFormat::Auto => {
+                Self::encode_smallest(&output, &options.encode, &options.auto_formats)?
+            }
+            OutputFormat::Avif if !options.avif_fallback.is_empty() => Self::encode_with_fallback(
+                &output,
+                &options.encode,
+                &options.avif_fallback,
+                rendered.icc_profile.as_deref(),
+            )?,
+            _ => Self::encode_with_profile(
+                &output,
+                &options.encode,
+                rendered.icc_profile.as_deref(),
+            )?,
         };
 
-        let format = ImageFormat::Jpeg;
-        let content_type = "image/jpeg";
+        if options.jpeg_fallback && processed.format != OutputFormat::Jpeg {
+            let jpeg = EncodeOptions {
+                format: OutputFormat::Jpeg,
+                ..options.encode
+            };
+            processed.fallback = Some(Box::new(Self::encode(&output, &jpeg)?));
+        }
+        processed.face_detected = options.face_crop.then_some(rendered.face_center.is_some());
+        processed.phash = rendered.phash;
+        processed.blurhash = rendered.blurhash;
+        processed.timings = StageTimings {
+            decode: rendered.decode_time,
+            resize: render_time.saturating_sub(rendered.decode_time),
+            encode: encode_started.elapsed(),
+        };
 
-        let mut buffer = Vec::new();
-        resized
-            .write_to(&mut Cursor::new(&mut buffer), format)
-            .map_err(|e| AppError::ImageProcessingError(format!("Failed to encode image: {}", e)))?;
+        if options.reject_if_larger
+            && processed.data.len() > image_data.len()
+            && image::guess_format(&image_data).ok()
+                == ImageFormat::from_mime_type(&processed.content_type)
+        {
+            tracing::debug!(
+                "Encoded output is {} bytes, over the {}-byte source; keeping the source",
+                processed.data.len(),
+                image_data.len()
+            );
+            let (width, height) = Self::dimensions(&image_data)?;
+            processed.data = image_data;
+            processed.width = width;
+            processed.height = height;
+            processed.source_kept = true;
+        }
 
-        Ok((Bytes::from(buffer), content_type.to_string()))
+        Ok(processed)
     }
 
-    fn resize_cover(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
-        let (img_width, img_height) = img.dimensions();
-        let img_aspect = img_width as f64 / img_height as f64;
-        let target_aspect = width as f64 / height as f64;
+    /// Runs the `resize` pipeline but hands back the output as uncompressed
+    /// RGBA8 rows instead of encoding it. The encode options and fallback are
+    /// ignored, and alpha is kept rather than flattened.
+    pub fn resize_raw(
+        image_data: Bytes,
+        options: &ResizeOptions,
+        limits: &DecodeLimits,
+        max_bytes: u64,
+    ) -> Result<RawImage, AppError> {
+        let output = Self::render(&image_data, options, limits)?.image;
+        let (width, height) = output.dimensions();
+        let bytes = width as u64 * height as u64 * 4;
+        if bytes > max_bytes {
+            return Err(AppError::DimensionTooLarge(format!(
+                "Raw output {}x{} is {} bytes, over the maximum of {}",
+                width, height, bytes, max_bytes
+            )));
+        }
 
-        let (scale_width, scale_height) = if img_aspect > target_aspect {
-            (((height as f64) * img_aspect) as u32, height)
+        Ok(RawImage {
+            data: Bytes::from(output.to_rgba8().into_raw()),
+            width,
+            height,
+        })
+    }
+
+    /// Decodes (or rasterizes) the source and applies the resize, canvas and
+    /// watermark; everything `resize` does short of encoding.
+    fn render(
+        image_data: &Bytes,
+        options: &ResizeOptions,
+        limits: &DecodeLimits,
+    ) -> Result<Rendered, AppError> {
+        // Vector sources are rendered straight at the output size instead of
+        // being rasterized and then resampled; that counts as decoding.
+        let decode_started = Instant::now();
+        let is_svg = svg::is_svg_data(image_data);
+        let (img, icc_profile) = if is_svg {
+            let img = svg::rasterize(
+                image_data,
+                options.width,
+                options.height,
+                options.object_mode,
+                limits,
+            )?;
+            (img, None)
         } else {
-            (width, ((width as f64) / img_aspect) as u32)
+            let frame = match options.frame_index {
+                Some(index) => Self::decode_frame(image_data, index, limits)?,
+                None => None,
+            };
+            match frame {
+                Some(frame) => (frame, None),
+                None => Self::decode_with_profile(image_data, limits, options.color_profile)?,
+            }
         };
+        let decode_time = decode_started.elapsed();
 
-        let scaled = img.resize_exact(
-            scale_width,
-            scale_height,
-            image::imageops::FilterType::Lanczos3,
-        );
+        if is_svg && options.operations.is_some() {
+            return Err(AppError::UnsupportedOperation(
+                "operations aren't supported for SVG sources".to_string(),
+            ));
+        }
+
+        let img = match options.crop {
+            Some(_) if is_svg => {
+                return Err(AppError::UnsupportedOperation(
+                    "crop isn't supported for SVG sources".to_string(),
+                ))
+            }
+            Some(crop) => {
+                let (img_width, img_height) = img.dimensions();
+                match crop.resolve(img_width, img_height)? {
+                    Some((x, y, width, height)) => img.crop_imm(x, y, width, height),
+                    None => {
+                        tracing::warn!(
+                            "crop {}x{} at ({}, {}) reaches past the {}x{} source, resizing the whole source",
+                            crop.width,
+                            crop.height,
+                            crop.x,
+                            crop.y,
+                            img_width,
+                            img_height
+                        );
+                        img
+                    }
+                }
+            }
+            None => img,
+        };
+
+        // An SVG has no native resolution to be too small for.
+        if let Some(min_dimension) = options.min_source_dimension.filter(|_| !is_svg) {
+            let (img_width, img_height) = img.dimensions();
+            if img_width.min(img_height) < min_dimension {
+                return Err(AppError::SourceTooSmall(format!(
+                    "Source image is {}x{}, shorter side must be at least {}px",
+                    img_width, img_height, min_dimension
+                )));
+            }
+        }
+
+        // A rasterized SVG already has the output size, so there is nothing
+        // left to compare it with.
+        if let (ObjectMode::Fill, Some(guard)) = (options.object_mode, options.distortion_guard) {
+            if !is_svg {
+                Self::check_distortion(img.dimensions(), (options.width, options.height), guard)?;
+            }
+        }
+
+        let phash = options.phash.then(|| Self::dhash(&img));
+        let blurhash = if options.blurhash {
+            Some(Self::encode_blurhash(&img)?)
+        } else {
+            None
+        };
+
+        // Only cover crops, so only cover has a crop to position. The source is
+        // already upright, as decoding applies the EXIF orientation.
+        let positions_crop = matches!(options.object_mode, ObjectMode::Cover) && !is_svg;
+        let face_center = if options.face_crop && positions_crop {
+            face::largest_face_center(&img)
+        } else {
+            None
+        };
+
+        let (width, height) = (options.width, options.height);
+        let filter = options.filter;
+        let resized = match (&options.operations, options.object_mode) {
+            (Some(steps), _) => Self::apply_steps(img, steps, filter, limits)?,
+            (None, ObjectMode::Cover) => {
+                Self::resize_cover(img, width, height, filter, face_center)
+            }
+            (None, ObjectMode::Contain) => Self::resize_contain(img, width, height, filter),
+            (None, ObjectMode::Fill) => Self::resize_fill(img, width, height, filter),
+            (None, ObjectMode::ScaleDown) => Self::resize_scale_down(img, width, height, filter),
+            (None, ObjectMode::Outside) => {
+                Self::resize_outside(img, width, height, filter, limits)?
+            }
+        };
+
+        let output = match &options.canvas {
+            Some(canvas) => Self::place_on_canvas(resized, canvas),
+            None => resized,
+        };
+
+        let output = match &options.watermark {
+            Some(watermark) => Self::draw_text_watermark(output, watermark),
+            None => output,
+        };
+
+        Ok(Rendered {
+            image: output,
+            icc_profile,
+            face_center,
+            phash,
+            blurhash,
+            decode_time,
+        })
+    }
+
+    /// Runs an `operations` pipeline. Crops are measured on the image as the
+    /// steps before them left it.
+    fn apply_steps(
+        img: DynamicImage,
+        steps: &[Step],
+        filter: ResizeFilter,
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, AppError> {
+        steps.iter().try_fold(img, |img, step| {
+            Ok(match step {
+                Step::Rotate(90) => img.rotate90(),
+                Step::Rotate(180) => img.rotate180(),
+                Step::Rotate(270) => img.rotate270(),
+                Step::Rotate(degrees) => {
+                    return Err(AppError::InvalidParameter(format!(
+                        "Can't rotate by {} degrees",
+                        degrees
+                    )))
+                }
+                Step::Crop(crop) => {
+                    let (img_width, img_height) = img.dimensions();
+                    match crop.resolve(img_width, img_height)? {
+                        Some((x, y, width, height)) => img.crop_imm(x, y, width, height),
+                        None => img,
+                    }
+                }
+                Step::Resize {
+                    width,
+                    height,
+                    object_mode,
+                } => {
+                    let (width, height) = (*width, *height);
+                    match object_mode {
+                        ObjectMode::Cover => Self::resize_cover(img, width, height, filter, None),
+                        ObjectMode::Contain => Self::resize_contain(img, width, height, filter),
+                        ObjectMode::Fill => Self::resize_fill(img, width, height, filter),
+                        ObjectMode::ScaleDown => {
+                            Self::resize_scale_down(img, width, height, filter)
+                        }
+                        ObjectMode::Outside => {
+                            Self::resize_outside(img, width, height, filter, limits)?
+                        }
+                    }
+                }
+                Step::Grayscale => DynamicImage::ImageRgba8(img.grayscale().into_rgba8()),
+                Step::Watermark(watermark) => Self::draw_text_watermark(img, watermark),
+            })
+        })
+    }
+
+    /// True when `resize` would hand back the source unchanged: a complete JPEG
+    /// with no EXIF rotation, already at the requested size, with no canvas or
+    /// watermark and default encoder settings. A source with an ICC profile
+    /// only qualifies when the profile is being preserved. Every object mode
+    /// is the identity at equal size, so re-encoding would only cost CPU and
+    /// quality.
+    fn is_identity(image_data: &Bytes, options: &ResizeOptions) -> bool {
+        if options.canvas.is_some()
+            || options.crop.is_some()
+            || options.operations.is_some()
+            || options.watermark.is_some()
+            || (EncodeOptions {
+                jpeg_encoder: JpegEncoder::default(),
+                ..options.encode
+            }) != EncodeOptions::default()
+        {
+            return false;
+        }
+        if image::guess_format(image_data).ok() != Some(ImageFormat::Jpeg)
+            || !image_data.ends_with(&JPEG_EOI)
+        {
+            return false;
+        }
+
+        let Ok(mut decoder) = ImageReader::new(Cursor::new(image_data.as_ref()))
+            .with_guessed_format()
+            .map_err(ImageError::IoError)
+            .and_then(|reader| reader.into_decoder())
+        else {
+            return false;
+        };
+
+        let needs_conversion = options.color_profile == ColorProfile::Srgb
+            && !matches!(decoder.icc_profile(), Ok(None));
+
+        let (width, height) = decoder.dimensions();
+        let too_small = options
+            .min_source_dimension
+            .is_some_and(|min_dimension| width.min(height) < min_dimension);
+
+        !too_small
+            && !needs_conversion
+            && (width, height) == (options.width, options.height)
+            && matches!(decoder.orientation(), Ok(Orientation::NoTransforms))
+    }
+
+    /// Crops the source to `ratio_width:ratio_height` around the highest-energy
+    /// window, keeping native resolution unless the crop is wider than `max_width`.
+    pub fn aspect_crop(
+        image_data: Bytes,
+        ratio_width: u32,
+        ratio_height: u32,
+        max_width: Option<u32>,
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        let img = Self::decode(&image_data, limits)?;
+        let (img_width, img_height) = img.dimensions();
+
+        let (crop_width, crop_height) =
+            if img_width as u64 * ratio_height as u64 > img_height as u64 * ratio_width as u64 {
+                let w = (img_height as u64 * ratio_width as u64 / ratio_height as u64) as u32;
+                (w.max(1), img_height)
+            } else {
+                let h = (img_width as u64 * ratio_height as u64 / ratio_width as u64) as u32;
+                (img_width, h.max(1))
+            };
+
+        let (x_offset, y_offset) = Self::smart_crop_window(&img, crop_width, crop_height);
+        let mut cropped = img.crop_imm(x_offset, y_offset, crop_width, crop_height);
+
+        if let Some(max_width) = max_width {
+            if crop_width > max_width {
+                let scaled_height =
+                    ((max_width as f64) * crop_height as f64 / crop_width as f64).round() as u32;
+                cropped = resample::resize_exact(
+                    &cropped,
+                    max_width,
+                    scaled_height.max(1),
+                    FilterType::Lanczos3,
+                );
+            }
+        }
+
+        Self::encode(&cropped, &EncodeOptions::default())
+    }
+
+    /// Re-encodes the source at its own resolution. AVIF output falls back
+    /// through `avif_fallback` like `resize`.
+    pub fn convert(
+        image_data: &Bytes,
+        encode: &EncodeOptions,
+        color_profile: ColorProfile,
+        avif_fallback: &[OutputFormat],
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        let decode_started = Instant::now();
+        let (img, icc_profile) = Self::decode_with_profile(image_data, limits, color_profile)?;
+        let decode = decode_started.elapsed();
+        let fallback = match encode.format {
+            OutputFormat::Avif => avif_fallback,
+            _ => &[],
+        };
+
+        let encode_started = Instant::now();
+        let mut processed =
+            Self::encode_with_fallback(&img, encode, fallback, icc_profile.as_deref())?;
+        processed.timings = StageTimings {
+            decode,
+            resize: Duration::ZERO,
+            encode: encode_started.elapsed(),
+        };
+        Ok(processed)
+    }
 
-        let x_offset = (scale_width.saturating_sub(width)) / 2;
-        let y_offset = (scale_height.saturating_sub(height)) / 2;
+    /// Cuts the source into a Deep Zoom pyramid of `tile_size` square tiles.
+    /// Level `max` is the source at full size and every level below halves
+    /// it, rounding up, down to a single pixel at level 0. Each level is
+    /// resampled from the one above, so the whole pyramid costs about a third
+    /// of a pass over the source on top of encoding the tiles.
+    pub fn tile_pyramid(
+        image_data: &Bytes,
+        tile_size: u32,
+        overlap: u32,
+        encode: &EncodeOptions,
+        limits: &DecodeLimits,
+    ) -> Result<TilePyramid, AppError> {
+        let img = Self::decode(image_data, limits)?;
+        let (width, height) = img.dimensions();
+        let levels = Self::pyramid_levels(width, height, tile_size);
 
-        DynamicImage::ImageRgba8(image::imageops::crop_imm(
-            &scaled.to_rgba8(),
-            x_offset,
-            y_offset,
+        let mut tiles = Vec::new();
+        let mut level_image = img;
+        for level in levels.iter().rev() {
+            if level_image.dimensions() != (level.width, level.height) {
+                level_image = Self::resample_exact(
+                    level_image,
+                    level.width,
+                    level.height,
+                    ResizeFilter::default(),
+                );
+            }
+            for row in 0..level.rows {
+                for column in 0..level.columns {
+                    let (x, tile_width) = Self::tile_span(column, tile_size, overlap, level.width);
+                    let (y, tile_height) = Self::tile_span(row, tile_size, overlap, level.height);
+                    let tile = level_image.crop_imm(x, y, tile_width, tile_height);
+                    tiles.push(Tile {
+                        level: level.level,
+                        column,
+                        row,
+                        data: Self::encode(&tile, encode)?.data,
+                    });
+                }
+            }
+        }
+
+        Ok(TilePyramid {
             width,
             height,
-        ).to_image())
+            levels,
+            tiles,
+        })
     }
 
-    fn resize_contain(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
-        img.resize(width, height, image::imageops::FilterType::Lanczos3)
+    /// The Deep Zoom levels of a `width x height` image, smallest first: level
+    /// `n` is the image scaled by `2^(n - max)`, rounded up, where `max` is
+    /// the first level at which the longer side reaches full size.
+    pub fn pyramid_levels(width: u32, height: u32, tile_size: u32) -> Vec<TileLevel> {
+        let longest = width.max(height).max(1);
+        let max_level = u32::BITS - (longest - 1).leading_zeros();
+
+        (0..=max_level)
+            .map(|level| {
+                let shift = max_level - level;
+                let scaled = |side: u32| ((side as u64 + (1 << shift) - 1) >> shift).max(1) as u32;
+                let (level_width, level_height) = (scaled(width), scaled(height));
+                TileLevel {
+                    level,
+                    width: level_width,
+                    height: level_height,
+                    columns: level_width.div_ceil(tile_size),
+                    rows: level_height.div_ceil(tile_size),
+                }
+            })
+            .collect()
     }
 
-    fn resize_fill(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
-        img.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+    /// Start and length along one axis of tile `index`, widened by `overlap`
+    /// on each side that has a neighbour.
+    fn tile_span(index: u32, tile_size: u32, overlap: u32, extent: u32) -> (u32, u32) {
+        let start = (index * tile_size).saturating_sub(if index > 0 { overlap } else { 0 });
+        let end = ((index + 1) * tile_size + overlap).min(extent);
+        (start, end - start)
     }
 
-    fn resize_scale_down(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
-        let (img_width, img_height) = img.dimensions();
-        
-        if img_width <= width && img_height <= height {
-            return img;
+    /// The `.dzi` descriptor Deep Zoom viewers load to find the tiles.
+    pub fn dzi_manifest(
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        overlap: u32,
+        extension: &str,
+    ) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">",
+                "<Size Width=\"{}\" Height=\"{}\"/></Image>\n"
+            ),
+            extension, overlap, tile_size, width, height
+        )
+    }
+
+    /// The image size recorded in a descriptor written by `dzi_manifest`.
+    pub fn dzi_size(manifest: &str) -> Option<(u32, u32)> {
+        let attribute = |name: &str| -> Option<u32> {
+            let start = manifest.find(&format!(" {}=\"", name))? + name.len() + 3;
+            let end = start + manifest[start..].find('"')?;
+            manifest[start..end].parse().ok()
+        };
+        Some((attribute("Width")?, attribute("Height")?))
+    }
+
+    pub fn default_watermark_font() -> FontArc {
+        FontArc::try_from_slice(DEFAULT_WATERMARK_FONT).expect("bundled watermark font must parse")
+    }
+
+    /// Tiles `sources` row by row into a `columns x rows` grid covering
+    /// `width x height`. Each source is cover-cropped to its cell; cells split
+    /// the remainder pixels so the grid fills the output exactly. `None` cells
+    /// are drawn as a gray placeholder and cells past the last source stay white.
+    pub fn collage(
+        sources: &[Option<Bytes>],
+        columns: u32,
+        rows: u32,
+        width: u32,
+        height: u32,
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        let edge =
+            |index: u32, cells: u32, size: u32| (index as u64 * size as u64 / cells as u64) as u32;
+
+        let mut base = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+        for (index, source) in sources.iter().enumerate() {
+            let (column, row) = (index as u32 % columns, index as u32 / columns);
+            let (left, right) = (
+                edge(column, columns, width),
+                edge(column + 1, columns, width),
+            );
+            let (top, bottom) = (edge(row, rows, height), edge(row + 1, rows, height));
+
+            let cell = match source {
+                Some(source) => Self::resize_cover(
+                    Self::decode(source, limits)?,
+                    right - left,
+                    bottom - top,
+                    ResizeFilter::Lanczos3,
+                    None,
+                )
+                .to_rgba8(),
+                None => RgbaImage::from_pixel(right - left, bottom - top, PLACEHOLDER_CELL),
+            };
+            image::imageops::overlay(&mut base, &cell, left as i64, top as i64);
+        }
+
+        Self::encode(&DynamicImage::ImageRgba8(base), &EncodeOptions::default())
+    }
+
+    /// Cover-crops each source to `thumb_width x thumb_height` and lays the
+    /// thumbnails out row by row, `columns` wide, with no gaps between them.
+    pub fn sprite_sheet(
+        sources: &[Bytes],
+        thumb_width: u32,
+        thumb_height: u32,
+        columns: u32,
+        encode: &EncodeOptions,
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        let thumbnails = sources
+            .iter()
+            .map(|source| {
+                Ok(Self::sprite_thumbnail(
+                    Self::decode(source, limits)?,
+                    thumb_width,
+                    thumb_height,
+                ))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        let sheet = Self::sprite_layout(&thumbnails, columns, limits)?;
+        Self::encode(&sheet, encode)
+    }
+
+    /// `sprite_sheet` for `count` evenly spaced frames of one animated source,
+    /// every frame when `None`, at most `max_cells`. Also returns the
+    /// zero-based frame in each cell. Every frame is thumbnailed as it is
+    /// decoded, so only the thumbnails are held at once.
+    #[allow(clippy::too_many_arguments)]
+    pub fn animation_sprite_sheet(
+        source: &Bytes,
+        count: Option<u32>,
+        max_cells: u32,
+        thumb_width: u32,
+        thumb_height: u32,
+        columns: u32,
+        encode: &EncodeOptions,
+        limits: &DecodeLimits,
+    ) -> Result<(ProcessedImage, Vec<u32>), AppError> {
+        let Some(frames) = Self::animation_frames(source, limits)? else {
+            return Err(AppError::InvalidParameter(
+                "s3_url must be an animated GIF, PNG or WebP; list still images in s3_urls"
+                    .to_string(),
+            ));
+        };
+        let mut thumbnails = Vec::new();
+        for frame in frames {
+            let frame = DynamicImage::ImageRgba8(frame.map_err(frame_decode_error)?.into_buffer());
+            thumbnails.push(Self::sprite_thumbnail(frame, thumb_width, thumb_height));
+        }
+
+        let total = thumbnails.len() as u64;
+        let count = count.unwrap_or(max_cells).min(max_cells) as u64;
+        let count = count.min(total);
+        let picked: Vec<u32> = (0..count)
+            .map(|cell| (cell * total / count) as u32)
+            .collect();
+        let selected: Vec<DynamicImage> = picked
+            .iter()
+            .map(|&frame| thumbnails[frame as usize].clone())
+            .collect();
+
+        let sheet = Self::sprite_layout(&selected, columns, limits)?;
+        Ok((Self::encode(&sheet, encode)?, picked))
+    }
+
+    fn sprite_thumbnail(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        Self::resize_cover(img, width, height, ResizeFilter::default(), None)
+    }
+
+    fn sprite_layout(
+        thumbnails: &[DynamicImage],
+        columns: u32,
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, AppError> {
+        let Some((thumb_width, thumb_height)) = thumbnails.first().map(|thumb| thumb.dimensions())
+        else {
+            return Err(AppError::ImageProcessingError(
+                "No thumbnails to lay out".to_string(),
+            ));
+        };
+        let columns = columns.min(thumbnails.len() as u32);
+        let rows = (thumbnails.len() as u32).div_ceil(columns);
+        let (width, height) = (
+            columns as u64 * thumb_width as u64,
+            rows as u64 * thumb_height as u64,
+        );
+        if width > limits.max_dimension as u64 || height > limits.max_dimension as u64 {
+            return Err(AppError::DimensionTooLarge(format!(
+                "Sprite sheet {}x{} exceeds the maximum of {}px per side",
+                width, height, limits.max_dimension
+            )));
+        }
+
+        let mut sheet =
+            RgbaImage::from_pixel(width as u32, height as u32, Rgba([255, 255, 255, 255]));
+        for (index, thumbnail) in thumbnails.iter().enumerate() {
+            let (column, row) = (index as u32 % columns, index as u32 / columns);
+            image::imageops::overlay(
+                &mut sheet,
+                &thumbnail.to_rgba8(),
+                (column * thumb_width) as i64,
+                (row * thumb_height) as i64,
+            );
+        }
+
+        Ok(DynamicImage::ImageRgba8(sheet))
+    }
+
+    /// Rough CPU work of a request in megapixel-passes: one unit is resampling
+    /// one source megapixel with Lanczos3. Decoding and encoding scale with the
+    /// same pixel count, so they are covered by the same estimate.
+    pub fn estimate_work(source_pixels: u64, outputs: u32, filter: ResizeFilter) -> f64 {
+        source_pixels as f64 / 1_000_000.0 * outputs as f64 * filter.relative_cost()
+    }
+
+    /// Largest size within `max_megapixels` that keeps the source aspect ratio.
+    /// Sources already under the cap keep their dimensions.
+    pub fn fit_megapixels(
+        source_width: u32,
+        source_height: u32,
+        max_megapixels: f64,
+    ) -> (u32, u32) {
+        let budget = max_megapixels * 1_000_000.0;
+        let pixels = source_width as f64 * source_height as f64;
+        if pixels <= budget {
+            return (source_width, source_height);
+        }
+
+        let scale = (budget / pixels).sqrt();
+        let width = ((source_width as f64 * scale).floor() as u32).max(1);
+        let height = ((source_height as f64 * scale).floor() as u32).max(1);
+
+        (width, height)
+    }
+
+    /// Largest size within `max_width` x `max_height` that keeps the source
+    /// aspect ratio; an open side doesn't constrain. Sources already inside the
+    /// box keep their dimensions.
+    pub fn fit_within(
+        source_width: u32,
+        source_height: u32,
+        max_width: Option<u32>,
+        max_height: Option<u32>,
+    ) -> (u32, u32) {
+        let scale_x = max_width.map_or(1.0, |max| max as f64 / source_width as f64);
+        let scale_y = max_height.map_or(1.0, |max| max as f64 / source_height as f64);
+        let scale = scale_x.min(scale_y);
+        if scale >= 1.0 {
+            return (source_width, source_height);
+        }
+
+        let width =
+            ((source_width as f64 * scale).round() as u32).clamp(1, max_width.unwrap_or(u32::MAX));
+        let height = ((source_height as f64 * scale).round() as u32)
+            .clamp(1, max_height.unwrap_or(u32::MAX));
+
+        (width, height)
+    }
+
+    /// 64-bit difference hash (dHash) of the decoded image, for spotting
+    /// near-duplicates by Hamming distance. Re-encoding, resizing and small
+    /// edits flip only a few bits.
+    pub fn perceptual_hash(image_data: &Bytes, limits: &DecodeLimits) -> Result<u64, AppError> {
+        Ok(Self::dhash(&Self::decode(image_data, limits)?))
+    }
+
+    /// Shrinks to a 9x8 grayscale grid and sets one bit per pair of
+    /// horizontal neighbours, row by row from the top left, when the left
+    /// pixel is brighter.
+    fn dhash(img: &DynamicImage) -> u64 {
+        let grid = img.thumbnail_exact(PHASH_GRID + 1, PHASH_GRID).to_luma8();
+        let mut hash = 0u64;
+        for y in 0..PHASH_GRID {
+            for x in 0..PHASH_GRID {
+                let brighter = grid.get_pixel(x, y).0[0] > grid.get_pixel(x + 1, y).0[0];
+                hash = (hash << 1) | brighter as u64;
+            }
+        }
+        hash
+    }
+
+    /// BlurHash of the decoded image: a short string that clients decode into
+    /// a blurred placeholder without fetching anything.
+    pub fn blurhash(image_data: &Bytes, limits: &DecodeLimits) -> Result<String, AppError> {
+        Self::encode_blurhash(&Self::decode(image_data, limits)?)
+    }
+
+    fn encode_blurhash(img: &DynamicImage) -> Result<String, AppError> {
+        let small = img.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE).to_rgba8();
+        let (components_x, components_y) = BLURHASH_COMPONENTS;
+        blurhash::encode(
+            components_x,
+            components_y,
+            small.width(),
+            small.height(),
+            small.as_raw(),
+        )
+        .map_err(|e| AppError::ImageProcessingError(format!("Failed to compute BlurHash: {}", e)))
+    }
+
+    /// Shrinks an image to a tiny, blurred, low-quality JPEG for use as a
+    /// placeholder while the real image loads.
+    pub fn lqip(
+        image_data: &Bytes,
+        options: &LqipOptions,
+        limits: &DecodeLimits,
+    ) -> Result<ProcessedImage, AppError> {
+        let img = Self::decode(image_data, limits)?;
+        let mut placeholder = img.thumbnail(options.size, options.size);
+        if options.blur > 0.0 {
+            placeholder = placeholder.blur(options.blur);
+        }
+
+        Self::encode(
+            &placeholder,
+            &EncodeOptions {
+                quality: options.quality,
+                ..EncodeOptions::default()
+            },
+        )
+    }
+
+    /// Mean per-channel similarity of two images in `[0, 1]`, where 1.0 means
+    /// pixel-identical. Images with different dimensions score 0.
+    pub fn similarity(
+        expected: &Bytes,
+        actual: &Bytes,
+        limits: &DecodeLimits,
+    ) -> Result<f64, AppError> {
+        let expected = Self::decode(expected, limits)?.to_rgb8();
+        let actual = Self::decode(actual, limits)?.to_rgb8();
+
+        if expected.dimensions() != actual.dimensions() {
+            return Ok(0.0);
+        }
+
+        let samples = expected.as_raw().len();
+        if samples == 0 {
+            return Ok(1.0);
+        }
+
+        let total_diff: u64 = expected
+            .as_raw()
+            .iter()
+            .zip(actual.as_raw())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+
+        Ok(1.0 - total_diff as f64 / (samples as f64 * 255.0))
+    }
+
+    /// Describes how `content_type`, as reported by storage, disagrees with
+    /// the format sniffed from the bytes, which is what decoding goes by.
+    /// Missing and generic types such as `application/octet-stream` don't
+    /// count, and neither do sources whose bytes aren't a known image
+    /// format, e.g. videos and SVGs.
+    pub fn content_type_mismatch(content_type: Option<&str>, image_data: &[u8]) -> Option<String> {
+        let reported = content_type?.split(';').next()?.trim().to_ascii_lowercase();
+        if reported.is_empty() || reported.ends_with("/octet-stream") {
+            return None;
+        }
+        let sniffed = image::guess_format(image_data).ok()?;
+        if ImageFormat::from_mime_type(&reported) == Some(sniffed) {
+            return None;
+        }
+
+        Some(format!(
+            "Source is labeled {} but its bytes are {}; decoding as {:?}",
+            reported,
+            sniffed.to_mime_type(),
+            sniffed
+        ))
+    }
+
+    /// Reads the image dimensions from the header without decoding pixels. The
+    /// EXIF orientation is taken into account, so a 90/270 rotated source reports
+    /// the same dimensions `resize` will see after decoding.
+    pub fn dimensions(image_data: &Bytes) -> Result<(u32, u32), AppError> {
+        if svg::is_svg_data(image_data) {
+            return svg::size(image_data);
+        }
+
+        let mut decoder = ImageReader::new(Cursor::new(image_data.as_ref()))
+            .with_guessed_format()
+            .map_err(|e| AppError::ImageProcessingError(format!("Failed to read image: {}", e)))?
+            .into_decoder()
+            .map_err(|e| {
+                AppError::ImageProcessingError(format!("Failed to read image dimensions: {}", e))
+            })?;
+
+        let (width, height) = decoder.dimensions();
+        match decoder.orientation() {
+            Ok(
+                Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH,
+            ) => Ok((height, width)),
+            _ => Ok((width, height)),
+        }
+    }
+
+    /// Decodes to sRGB pixels.
+    fn decode(image_data: &Bytes, limits: &DecodeLimits) -> Result<DynamicImage, AppError> {
+        Self::decode_with_profile(image_data, limits, ColorProfile::Srgb).map(|(img, _)| img)
+    }
+
+    /// Decodes the source and applies `color_profile` to its embedded ICC
+    /// profile. Returns the profile when it should be embedded in the output.
+    fn decode_with_profile(
+        image_data: &Bytes,
+        limits: &DecodeLimits,
+        color_profile: ColorProfile,
+    ) -> Result<(DynamicImage, Option<Vec<u8>>), AppError> {
+        let (img, icc_profile) = Self::decode_pixels(image_data, limits)?;
+
+        Ok(match (color_profile, icc_profile) {
+            (_, None) => (img, None),
+            (ColorProfile::Srgb, Some(icc_profile)) => {
+                (Self::convert_to_srgb(img, &icc_profile), None)
+            }
+            (ColorProfile::Preserve, Some(icc_profile)) => (img, Some(icc_profile)),
+        })
+    }
+
+    fn decode_pixels(
+        image_data: &Bytes,
+        limits: &DecodeLimits,
+    ) -> Result<(DynamicImage, Option<Vec<u8>>), AppError> {
+        // Endpoints that work on decoded pixels see an SVG at its own size.
+        if svg::is_svg_data(image_data) {
+            let (width, height) = svg::size(image_data)?;
+            let img = svg::rasterize(image_data, width, height, ObjectMode::Fill, limits)?;
+            return Ok((img, None));
+        }
+
+        let is_jpeg = image::guess_format(image_data).ok() == Some(ImageFormat::Jpeg);
+
+        let error = match Self::decode_with_limits(image_data, None, limits) {
+            Ok(decoded) => {
+                if is_jpeg && !image_data.ends_with(&JPEG_EOI) {
+                    tracing::warn!("Source JPEG is missing its end-of-image marker");
+                }
+                return Ok(decoded);
+            }
+            Err(e) => e,
+        };
+
+        // The jpeg decoder is already lenient about truncation, but some files cut
+        // off mid-marker only decode once the stream is closed properly.
+        if is_jpeg && !matches!(error, ImageError::Limits(_)) {
+            tracing::warn!("JPEG decode failed ({}), retrying in recovery mode", error);
+
+            let repaired = Self::repair_truncated_jpeg(image_data);
+            if let Ok(decoded) = Self::decode_with_limits(&repaired, None, limits) {
+                tracing::warn!("Recovered malformed JPEG by repairing its end-of-image marker");
+                return Ok(decoded);
+            }
+        }
+
+        let mut over_limits = Self::is_over_limits(&error);
+        let mut detail = error.to_string();
+
+        // A mislabeled or prefixed upload can defeat the signature check at
+        // offset 0; a signature further in names the decoder to force.
+        let retry = Self::sniff_format(image_data).filter(|&(offset, format)| {
+            offset > 0 || image::guess_format(image_data).ok() != Some(format)
+        });
+        if let (true, false, Some((offset, format))) = (limits.format_retry, over_limits, retry) {
+            tracing::warn!(
+                "Decode failed ({}), retrying as {:?} from its signature at byte {}",
+                error,
+                format,
+                offset
+            );
+            match Self::decode_with_limits(&image_data[offset..], Some(format), limits) {
+                Ok(decoded) => {
+                    tracing::warn!(
+                        "Decoded source as {:?} after sniffing its signature",
+                        format
+                    );
+                    return Ok(decoded);
+                }
+                Err(retry_error) => {
+                    over_limits = Self::is_over_limits(&retry_error);
+                    detail = format!(
+                        "{}; retried as {:?} from byte {}: {}",
+                        detail, format, offset, retry_error
+                    );
+                }
+            }
+        }
+
+        Err(if over_limits {
+            AppError::DimensionTooLarge(format!(
+                "Image exceeds decode limits (max {}px per side, {} bytes): {}",
+                limits.max_dimension, limits.max_alloc_bytes, detail
+            ))
+        } else {
+            AppError::ImageProcessingError(format!("Failed to decode image: {}", detail))
+        })
+    }
+
+    fn is_over_limits(error: &ImageError) -> bool {
+        matches!(
+            error,
+            ImageError::Limits(limit) if matches!(
+                limit.kind(),
+                LimitErrorKind::DimensionError | LimitErrorKind::InsufficientMemory
+            )
+        )
+    }
+
+    /// The first `FORMAT_SIGNATURES` match within `FORMAT_SNIFF_WINDOW`
+    /// bytes, with its offset. A RIFF container only counts when it holds WebP.
+    fn sniff_format(image_data: &[u8]) -> Option<(usize, ImageFormat)> {
+        let window = &image_data[..image_data.len().min(FORMAT_SNIFF_WINDOW)];
+        (0..window.len()).find_map(|offset| {
+            let rest = &image_data[offset..];
+            FORMAT_SIGNATURES
+                .iter()
+                .find(|(signature, format)| {
+                    rest.starts_with(signature)
+                        && (*format != ImageFormat::WebP || rest.get(8..12) == Some(b"WEBP"))
+                })
+                .map(|&(_, format)| (offset, format))
+        })
+    }
+
+    /// Frame `index` of an animated GIF, PNG or WebP, composited onto the full
+    /// canvas like a viewer shows it. An index past the end selects the last
+    /// frame. `None` for sources that aren't animated, which decode as usual.
+    fn decode_frame(
+        image_data: &[u8],
+        index: u32,
+        limits: &DecodeLimits,
+    ) -> Result<Option<DynamicImage>, AppError> {
+        let Some(frames) = Self::animation_frames(image_data, limits)? else {
+            return Ok(None);
+        };
+
+        let mut selected = None;
+        for (position, frame) in frames.take(index as usize + 1).enumerate() {
+            selected = Some((position, frame.map_err(frame_decode_error)?));
+        }
+        let Some((position, frame)) = selected else {
+            return Ok(None);
+        };
+        if position < index as usize {
+            tracing::debug!(
+                "Animation has {} frames, using the last instead of frame {}",
+                position + 1,
+                index
+            );
+        }
+
+        Ok(Some(DynamicImage::ImageRgba8(frame.into_buffer())))
+    }
+
+    /// The frames of an animated GIF, PNG or WebP, decoded as they are
+    /// iterated; `None` for still images and other formats.
+    fn animation_frames<'a>(
+        image_data: &'a [u8],
+        limits: &DecodeLimits,
+    ) -> Result<Option<Frames<'a>>, AppError> {
+        let decode_error = frame_decode_error;
+        let cursor = Cursor::new(image_data);
+
+        let frames = match image::guess_format(image_data).ok() {
+            Some(ImageFormat::Gif) => {
+                let mut decoder = GifDecoder::new(cursor).map_err(decode_error)?;
+                decoder
+                    .set_limits(Self::decoder_limits(limits))
+                    .map_err(decode_error)?;
+                decoder.into_frames()
+            }
+            Some(ImageFormat::Png) => {
+                let decoder = PngDecoder::with_limits(cursor, Self::decoder_limits(limits))
+                    .map_err(decode_error)?;
+                if !decoder.is_apng().map_err(decode_error)? {
+                    return Ok(None);
+                }
+                decoder.apng().map_err(decode_error)?.into_frames()
+            }
+            Some(ImageFormat::WebP) => {
+                let mut decoder = WebPDecoder::new(cursor).map_err(decode_error)?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                decoder
+                    .set_limits(Self::decoder_limits(limits))
+                    .map_err(decode_error)?;
+                decoder.into_frames()
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(frames))
+    }
+
+    fn decoder_limits(limits: &DecodeLimits) -> Limits {
+        let mut decoder_limits = Limits::default();
+        decoder_limits.max_image_width = Some(limits.max_dimension);
+        decoder_limits.max_image_height = Some(limits.max_dimension);
+        decoder_limits.max_alloc = Some(limits.max_alloc_bytes);
+        decoder_limits
+    }
+
+    /// Decodes as `format`, or as the format its leading bytes suggest.
+    fn decode_with_limits(
+        image_data: &[u8],
+        format: Option<ImageFormat>,
+        limits: &DecodeLimits,
+    ) -> Result<(DynamicImage, Option<Vec<u8>>), ImageError> {
+        let mut reader = match format {
+            Some(format) => ImageReader::with_format(Cursor::new(image_data), format),
+            None => ImageReader::new(Cursor::new(image_data)).with_guessed_format()?,
+        };
+        reader.limits(Self::decoder_limits(limits));
+
+        // Apply the EXIF orientation here so every mode handler works on the
+        // upright image; a 90/270 rotation swaps width and height, which changes
+        // the Cover/Contain aspect math.
+        let mut decoder = reader.into_decoder()?;
+        let orientation = decoder.orientation()?;
+        let icc_profile = decoder.icc_profile()?;
+        let mut img = DynamicImage::from_decoder(decoder)?;
+        img.apply_orientation(orientation);
+        Ok((img, icc_profile))
+    }
+
+    /// Converts pixels tagged with `icc_profile` (e.g. Display P3 or Adobe RGB)
+    /// to sRGB. A profile that can't be parsed or doesn't describe RGB data,
+    /// such as a CMYK profile, leaves the pixels as decoded.
+    fn convert_to_srgb(img: DynamicImage, icc_profile: &[u8]) -> DynamicImage {
+        let transform = moxcms::ColorProfile::new_from_slice(icc_profile).and_then(|source| {
+            source.create_transform_8bit(
+                Layout::Rgba,
+                &moxcms::ColorProfile::new_srgb(),
+                Layout::Rgba,
+                TransformOptions::default(),
+            )
+        });
+        let transform = match transform {
+            Ok(transform) => transform,
+            Err(e) => {
+                tracing::warn!(
+                    "Ignoring embedded ICC profile that can't be converted to sRGB: {}",
+                    e
+                );
+                return img;
+            }
+        };
+
+        let source = img.to_rgba8();
+        let mut converted = RgbaImage::new(source.width(), source.height());
+        if let Err(e) = transform.transform(source.as_raw(), &mut converted) {
+            tracing::warn!(
+                "Failed to convert pixels to sRGB, keeping them as decoded: {}",
+                e
+            );
+            return DynamicImage::ImageRgba8(source);
+        }
+
+        DynamicImage::ImageRgba8(converted)
+    }
+
+    /// Drops trailing padding and any dangling marker byte, then closes the
+    /// stream with an end-of-image marker.
+    fn repair_truncated_jpeg(image_data: &[u8]) -> Vec<u8> {
+        let mut end = image_data.len();
+        while end > 0 && image_data[end - 1] == 0x00 {
+            end -= 1;
+        }
+        if end > 0 && image_data[end - 1] == 0xFF {
+            end -= 1;
         }
 
-        img.resize(width, height, image::imageops::FilterType::Lanczos3)
+        let mut repaired = Vec::with_capacity(end + JPEG_EOI.len());
+        repaired.extend_from_slice(&image_data[..end]);
+        repaired.extend_from_slice(&JPEG_EOI);
+        repaired
+    }
+
+    fn encode(img: &DynamicImage, options: &EncodeOptions) -> Result<ProcessedImage, AppError> {
+        Self::encode_with_profile(img, options, None)
+    }
+
+    /// `icc_profile` is embedded in JPEG output; AVIF output never carries one.
+    fn encode_with_profile(
+        img: &DynamicImage,
+        options: &EncodeOptions,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<ProcessedImage, AppError> {
+        let (width, height) = img.dimensions();
+        let data = match options.format {
+            OutputFormat::Jpeg => Self::encode_jpeg(img, options, icc_profile)?,
+            OutputFormat::Avif => Self::encode_avif(img, options)?,
+            OutputFormat::Png => Self::encode_png(img, options.dpi)?,
+            OutputFormat::Webp => Self::encode_lossless(img, ImageFormat::WebP)?,
+            OutputFormat::Auto => {
+                return Err(AppError::InternalError(
+                    "auto output must be resolved to a candidate format before encoding"
+                        .to_string(),
+                ))
+            }
+        };
+
+        Ok(ProcessedImage {
+            data: Bytes::from(data),
+            content_type: options.format.content_type().to_string(),
+            format: options.format,
+            width,
+            height,
+            fallback: None,
+            face_detected: None,
+            phash: None,
+            blurhash: None,
+            timings: StageTimings::default(),
+            source_kept: false,
+        })
+    }
+
+    /// Encodes in `options.format`, and when that fails in each of `fallback`
+    /// in turn until one succeeds. The format that was produced is the one on
+    /// the result; the last error is returned when every format fails.
+    fn encode_with_fallback(
+        img: &DynamicImage,
+        options: &EncodeOptions,
+        fallback: &[OutputFormat],
+        icc_profile: Option<&[u8]>,
+    ) -> Result<ProcessedImage, AppError> {
+        let mut error = match Self::encode_with_profile(img, options, icc_profile) {
+            Ok(processed) => return Ok(processed),
+            Err(e) => e,
+        };
+        let mut failed = options.format;
+        for &format in fallback {
+            tracing::warn!(
+                "{} encode failed, falling back to {}: {}",
+                failed.as_str(),
+                format.as_str(),
+                error
+            );
+            match Self::encode_with_profile(img, &EncodeOptions { format, ..*options }, icc_profile)
+            {
+                Ok(processed) => return Ok(processed),
+                Err(e) => {
+                    error = e;
+                    failed = format;
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Encodes `img` in each candidate format and keeps the smallest result,
+    /// the earlier candidate winning a tie. Candidates don't carry an ICC
+    /// profile, since only JPEG could embed it.
+    fn encode_smallest(
+        img: &DynamicImage,
+        options: &EncodeOptions,
+        candidates: &[OutputFormat],
+    ) -> Result<ProcessedImage, AppError> {
+        let mut smallest: Option<ProcessedImage> = None;
+        for &format in candidates {
+            let candidate = Self::encode(img, &EncodeOptions { format, ..*options })?;
+            tracing::debug!(
+                "auto candidate {} is {} bytes",
+                format.as_str(),
+                candidate.data.len()
+            );
+            if smallest
+                .as_ref()
+                .is_none_or(|smallest| candidate.data.len() < smallest.data.len())
+            {
+                smallest = Some(candidate);
+            }
+        }
+
+        smallest.ok_or_else(|| {
+            AppError::InternalError("No candidate formats configured for auto output".to_string())
+        })
+    }
+
+    fn encode_jpeg(
+        img: &DynamicImage,
+        options: &EncodeOptions,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<Vec<u8>, AppError> {
+        let (width, height) = img.dimensions();
+        // JPEG stores each side in 16 bits.
+        let (Ok(jpeg_width), Ok(jpeg_height)) = (u16::try_from(width), u16::try_from(height))
+        else {
+            return Err(AppError::DimensionTooLarge(format!(
+                "JPEG output can be at most {}px per side, got {}x{}",
+                u16::MAX,
+                width,
+                height
+            )));
+        };
+        if options.jpeg_encoder == JpegEncoder::Mozjpeg {
+            let rgb = Self::flatten_alpha(img, options.alpha_background);
+            return jpeg::encode_mozjpeg(&rgb, width, height, options, icc_profile);
+        }
+
+        let sampling = match options.chroma_subsampling {
+            ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+        };
+
+        let mut buffer = Vec::new();
+        let mut encoder = Encoder::new(&mut buffer, options.quality);
+        encoder.set_sampling_factor(sampling);
+        encoder.set_progressive(options.progressive);
+        if let Some(dpi) = options.dpi {
+            encoder.set_density(PixelDensity::dpi(dpi));
+        }
+        if let Some(icc_profile) = icc_profile {
+            encoder.add_icc_profile(icc_profile).map_err(|e| {
+                AppError::ImageProcessingError(format!("Failed to embed ICC profile: {}", e))
+            })?;
+        }
+        encoder
+            .encode(
+                &Self::flatten_alpha(img, options.alpha_background),
+                jpeg_width,
+                jpeg_height,
+                ColorType::Rgb,
+            )
+            .map_err(|e| {
+                AppError::ImageProcessingError(format!("Failed to encode image: {}", e))
+            })?;
+
+        Ok(buffer)
+    }
+
+    /// Lossless and keeps transparency, so the alpha background doesn't apply.
+    /// PNG goes through `encode_png` instead, which can record a density.
+    fn encode_lossless(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, AppError> {
+        let img = if img.color().has_alpha() {
+            DynamicImage::ImageRgba8(img.to_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(img.to_rgb8())
+        };
+
+        let mut buffer = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buffer), format)
+            .map_err(|e| {
+                AppError::ImageProcessingError(format!("Failed to encode {:?}: {}", format, e))
+            })?;
+
+        Ok(buffer)
+    }
+
+    /// Indexed when the output has few enough colors, e.g. pixel art or a
+    /// palette source resized with `nearest`; truecolor otherwise. `dpi` is
+    /// written as a pHYs chunk.
+    fn encode_png(img: &DynamicImage, dpi: Option<u16>) -> Result<Vec<u8>, AppError> {
+        match Self::encode_indexed_png(img, dpi) {
+            Some(encoded) => encoded,
+            None => Self::encode_truecolor_png(img, dpi),
+        }
+    }
+
+    /// pHYs only knows pixels per meter.
+    fn png_pixel_dims(dpi: Option<u16>) -> Option<png::PixelDimensions> {
+        dpi.map(|dpi| {
+            let per_meter = (dpi as f64 / 0.0254).round() as u32;
+            png::PixelDimensions {
+                xppu: per_meter,
+                yppu: per_meter,
+                unit: png::Unit::Meter,
+            }
+        })
+    }
+
+    /// RGB or RGBA at 8 bits, with the compression and filter `image`'s own
+    /// PNG encoder uses.
+    fn encode_truecolor_png(img: &DynamicImage, dpi: Option<u16>) -> Result<Vec<u8>, AppError> {
+        let (color, samples) = if img.color().has_alpha() {
+            (png::ColorType::Rgba, img.to_rgba8().into_raw())
+        } else {
+            (png::ColorType::Rgb, img.to_rgb8().into_raw())
+        };
+
+        let encode = || -> Result<Vec<u8>, png::EncodingError> {
+            let mut buffer = Vec::new();
+            let mut encoder = png::Encoder::new(&mut buffer, img.width(), img.height());
+            encoder.set_color(color);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(png::Compression::Balanced);
+            encoder.set_filter(png::Filter::Adaptive);
+            encoder.set_pixel_dims(Self::png_pixel_dims(dpi));
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&samples)?;
+            writer.finish()?;
+            Ok(buffer)
+        };
+
+        encode().map_err(|e| AppError::ImageProcessingError(format!("Failed to encode Png: {}", e)))
+    }
+
+    /// Writes `img` with a palette of its exact colors at the smallest bit
+    /// depth that holds them, or `None` when it has more than
+    /// `PNG_PALETTE_SIZE` colors. Lossless, transparency included.
+    fn encode_indexed_png(
+        img: &DynamicImage,
+        dpi: Option<u16>,
+    ) -> Option<Result<Vec<u8>, AppError>> {
+        let rgba = img.to_rgba8();
+        let mut palette: Vec<[u8; 4]> = Vec::new();
+        let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(rgba.width() as usize * rgba.height() as usize);
+        for pixel in rgba.pixels() {
+            let index = match lookup.get(&pixel.0) {
+                Some(&index) => index,
+                None => {
+                    if palette.len() == PNG_PALETTE_SIZE {
+                        return None;
+                    }
+                    let index = palette.len() as u8;
+                    palette.push(pixel.0);
+                    lookup.insert(pixel.0, index);
+                    index
+                }
+            };
+            indices.push(index);
+        }
+
+        let (bits, depth) = match palette.len() {
+            0..=2 => (1, png::BitDepth::One),
+            3..=4 => (2, png::BitDepth::Two),
+            5..=16 => (4, png::BitDepth::Four),
+            _ => (8, png::BitDepth::Eight),
+        };
+        // Rows are packed most significant bits first and padded to a byte.
+        let width = rgba.width() as usize;
+        let mut data = Vec::with_capacity((width * bits).div_ceil(8) * rgba.height() as usize);
+        for row in indices.chunks(width.max(1)) {
+            let mut byte = 0u8;
+            let mut filled = 0;
+            for &index in row {
+                byte |= index << (8 - bits - filled);
+                filled += bits;
+                if filled == 8 {
+                    data.push(byte);
+                    byte = 0;
+                    filled = 0;
+                }
+            }
+            if filled > 0 {
+                data.push(byte);
+            }
+        }
+
+        let rgb: Vec<u8> = palette
+            .iter()
+            .flat_map(|color| color[..3].to_vec())
+            .collect();
+        // tRNS may stop after the last entry that isn't opaque.
+        let trns_len = palette
+            .iter()
+            .rposition(|color| color[3] < 255)
+            .map_or(0, |last| last + 1);
+        let alpha: Vec<u8> = palette[..trns_len].iter().map(|color| color[3]).collect();
+
+        let encode = || -> Result<Vec<u8>, png::EncodingError> {
+            let mut buffer = Vec::new();
+            let mut encoder = png::Encoder::new(&mut buffer, rgba.width(), rgba.height());
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(depth);
+            encoder.set_palette(rgb);
+            if !alpha.is_empty() {
+                encoder.set_trns(alpha);
+            }
+            encoder.set_pixel_dims(Self::png_pixel_dims(dpi));
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&data)?;
+            writer.finish()?;
+            Ok(buffer)
+        };
+
+        Some(encode().map_err(|e| {
+            AppError::ImageProcessingError(format!("Failed to encode indexed PNG: {}", e))
+        }))
+    }
+
+    /// Composites the image over `background`. Dropping the alpha channel
+    /// instead would expose whatever color the transparent pixels happen to
+    /// hold, usually black.
+    fn flatten_alpha(img: &DynamicImage, background: HexColor) -> RgbImage {
+        if !img.color().has_alpha() {
+            return img.to_rgb8();
+        }
+
+        let [bg_r, bg_g, bg_b, _] = background.0;
+        let rgba = img.to_rgba8();
+        RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            let blend = |channel: u8, bg: u8| {
+                ((channel as u32 * a as u32 + bg as u32 * (255 - a as u32) + 127) / 255) as u8
+            };
+            Rgb([blend(r, bg_r), blend(g, bg_g), blend(b, bg_b)])
+        })
+    }
+
+    #[cfg(feature = "avif")]
+    fn encode_avif(img: &DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, AppError> {
+        use image::codecs::avif::AvifEncoder;
+        use image::{ExtendedColorType, ImageEncoder};
+
+        let rgb = Self::flatten_alpha(img, options.alpha_background);
+        let mut buffer = Vec::new();
+        AvifEncoder::new_with_speed_quality(&mut buffer, AVIF_SPEED, options.quality)
+            .write_image(
+                rgb.as_raw(),
+                rgb.width(),
+                rgb.height(),
+                ExtendedColorType::Rgb8,
+            )
+            .map_err(|e| AppError::ImageProcessingError(format!("Failed to encode AVIF: {}", e)))?;
+
+        Ok(buffer)
+    }
+
+    /// Handlers reject AVIF up front in builds without the encoder; this only
+    /// guards callers that skip that check.
+    #[cfg(not(feature = "avif"))]
+    fn encode_avif(_img: &DynamicImage, _options: &EncodeOptions) -> Result<Vec<u8>, AppError> {
+        Err(AppError::UnsupportedOperation(
+            "AVIF output requires the service to be built with the `avif` feature".to_string(),
+        ))
+    }
+
+    /// Picks the crop offset whose window covers the most edge energy, sliding
+    /// only along the axis that is being trimmed.
+    fn smart_crop_window(img: &DynamicImage, crop_width: u32, crop_height: u32) -> (u32, u32) {
+        let (img_width, img_height) = img.dimensions();
+        if crop_width >= img_width && crop_height >= img_height {
+            return (0, 0);
+        }
+
+        let sample = img
+            .thumbnail(ENERGY_SAMPLE_SIZE, ENERGY_SAMPLE_SIZE)
+            .to_luma8();
+        let (sample_width, sample_height) = sample.dimensions();
+
+        if crop_width < img_width {
+            let mut columns = vec![0u64; sample_width as usize];
+            for y in 0..sample_height {
+                for x in 0..sample_width {
+                    columns[x as usize] += Self::pixel_energy(&sample, x, y);
+                }
+            }
+
+            let window = crop_width as f64 / img_width as f64 * sample_width as f64;
+            let start = Self::best_window(&columns, window.round() as usize);
+            let x = (start as f64 * img_width as f64 / sample_width as f64).round() as u32;
+            (x.min(img_width - crop_width), 0)
+        } else {
+            let mut rows = vec![0u64; sample_height as usize];
+            for y in 0..sample_height {
+                for x in 0..sample_width {
+                    rows[y as usize] += Self::pixel_energy(&sample, x, y);
+                }
+            }
+
+            let window = crop_height as f64 / img_height as f64 * sample_height as f64;
+            let start = Self::best_window(&rows, window.round() as usize);
+            let y = (start as f64 * img_height as f64 / sample_height as f64).round() as u32;
+            (0, y.min(img_height - crop_height))
+        }
+    }
+
+    fn pixel_energy(img: &GrayImage, x: u32, y: u32) -> u64 {
+        let (width, height) = img.dimensions();
+        let value = img.get_pixel(x, y)[0] as i32;
+        let right = if x + 1 < width {
+            img.get_pixel(x + 1, y)[0] as i32
+        } else {
+            value
+        };
+        let below = if y + 1 < height {
+            img.get_pixel(x, y + 1)[0] as i32
+        } else {
+            value
+        };
+
+        ((value - right).abs() + (value - below).abs()) as u64
+    }
+
+    fn best_window(sums: &[u64], window: usize) -> usize {
+        let window = window.clamp(1, sums.len());
+        let mut current: u64 = sums[..window].iter().sum();
+        let mut best = current;
+        let mut best_start = 0;
+
+        for start in 1..=(sums.len() - window) {
+            current = current + sums[start + window - 1] - sums[start - 1];
+            if current > best {
+                best = current;
+                best_start = start;
+            }
+        }
+
+        best_start
+    }
+
+    fn place_on_canvas(img: DynamicImage, canvas: &CanvasOptions) -> DynamicImage {
+        let mut base =
+            RgbaImage::from_pixel(canvas.width, canvas.height, Rgba(canvas.background.0));
+
+        let (img_width, img_height) = img.dimensions();
+        let free_width = canvas.width as i64 - img_width as i64;
+        let free_height = canvas.height as i64 - img_height as i64;
+
+        let gravity_x = match canvas.gravity {
+            Gravity::West | Gravity::NorthWest | Gravity::SouthWest => 0,
+            Gravity::East | Gravity::NorthEast | Gravity::SouthEast => free_width,
+            Gravity::Center | Gravity::North | Gravity::South => free_width / 2,
+        };
+        let gravity_y = match canvas.gravity {
+            Gravity::North | Gravity::NorthEast | Gravity::NorthWest => 0,
+            Gravity::South | Gravity::SouthEast | Gravity::SouthWest => free_height,
+            Gravity::Center | Gravity::East | Gravity::West => free_height / 2,
+        };
+
+        let x = canvas.x.unwrap_or(gravity_x);
+        let y = canvas.y.unwrap_or(gravity_y);
+
+        image::imageops::overlay(&mut base, &img.to_rgba8(), x, y);
+        DynamicImage::ImageRgba8(base)
+    }
+
+    /// Draws the text centered and rotated about the image center, blended with
+    /// the watermark color at the requested opacity. The text is rendered into a
+    /// coverage mask as wide as the image diagonal, so it can't clip while rotating.
+    fn draw_text_watermark(img: DynamicImage, watermark: &Watermark) -> DynamicImage {
+        let options = &watermark.options;
+        let font = &watermark.font;
+        let mut base = img.to_rgba8();
+        let (width, height) = base.dimensions();
+        let diagonal = (width as f32).hypot(height as f32).ceil() as u32;
+
+        let size = options.size.unwrap_or_else(|| {
+            let (reference_width, _) = text_size(PxScale::from(100.0), font, &options.text);
+            let target_width = diagonal as f32 * WATERMARK_DIAGONAL_SHARE;
+            100.0 * target_width / reference_width.max(1) as f32
+        });
+        let scale = PxScale::from(size);
+        let (text_width, _) = text_size(scale, font, &options.text);
+        let line_height = font.as_scaled(scale).height();
+
+        let mut mask = GrayImage::new(diagonal, diagonal);
+        draw_text_mut(
+            &mut mask,
+            Luma([255]),
+            (diagonal as i32 - text_width as i32) / 2,
+            ((diagonal as f32 - line_height) / 2.0).round() as i32,
+            scale,
+            font,
+            &options.text,
+        );
+
+        let angle = options
+            .angle
+            .unwrap_or_else(|| (height as f32).atan2(width as f32).to_degrees());
+        // rotate_about_center turns clockwise; the angle is counter-clockwise.
+        let mask = rotate_about_center(
+            &mask,
+            -angle.to_radians(),
+            Interpolation::Bilinear,
+            Luma([0]),
+        );
+
+        let [r, g, b, a] = options.color.0;
+        let strength = options.opacity * a as f32 / 255.0;
+        let offset_x = (diagonal - width) / 2;
+        let offset_y = (diagonal - height) / 2;
+
+        for (x, y, pixel) in base.enumerate_pixels_mut() {
+            let coverage = mask.get_pixel(x + offset_x, y + offset_y)[0];
+            if coverage == 0 {
+                continue;
+            }
+
+            let alpha = strength * coverage as f32 / 255.0;
+            for (channel, target) in pixel.0.iter_mut().zip([r, g, b]) {
+                *channel = (*channel as f32 * (1.0 - alpha) + target as f32 * alpha).round() as u8;
+            }
+        }
+
+        DynamicImage::ImageRgba8(base)
+    }
+
+    fn filter_type(filter: ResizeFilter) -> FilterType {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 | ResizeFilter::Auto => FilterType::Lanczos3,
+        }
+    }
+
+    /// With `auto`, a reduction of at least `AUTO_PRESCALE_RATIO` first
+    /// box-filters the source down to `AUTO_PRESCALE_MARGIN` times the target
+    /// size, leaving Lanczos3 only the last, small step. `exact` resizes may
+    /// change the aspect ratio, so both axes must shrink that much; fits keep
+    /// it, so the more-reduced axis decides.
+    fn prescale(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        exact: bool,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        if filter != ResizeFilter::Auto {
+            return img;
+        }
+
+        let (img_width, img_height) = img.dimensions();
+        let (ratio_x, ratio_y) = (
+            img_width as f64 / width as f64,
+            img_height as f64 / height as f64,
+        );
+        let ratio = if exact {
+            ratio_x.min(ratio_y)
+        } else {
+            ratio_x.max(ratio_y)
+        };
+        if ratio < AUTO_PRESCALE_RATIO {
+            return img;
+        }
+
+        let (box_width, box_height) = (
+            width.saturating_mul(AUTO_PRESCALE_MARGIN),
+            height.saturating_mul(AUTO_PRESCALE_MARGIN),
+        );
+        if exact {
+            img.thumbnail_exact(box_width, box_height)
+        } else {
+            img.thumbnail(box_width, box_height)
+        }
+    }
+
+    fn resample_exact(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        resample::resize_exact(
+            &Self::prescale(img, width, height, true, filter),
+            width,
+            height,
+            Self::filter_type(filter),
+        )
+    }
+
+    fn resample_within(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        resample::resize(
+            &Self::prescale(img, width, height, false, filter),
+            width,
+            height,
+            Self::filter_type(filter),
+        )
+    }
+
+    /// `focus` is the point to center the crop on, as fractions of the source
+    /// size; the crop is centered on the image without one.
+    fn resize_cover(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+        focus: Option<(f32, f32)>,
+    ) -> DynamicImage {
+        let (img_width, img_height) = img.dimensions();
+        let img_aspect = img_width as f64 / img_height as f64;
+        let target_aspect = width as f64 / height as f64;
+
+        let (scale_width, scale_height) = if img_aspect > target_aspect {
+            (((height as f64) * img_aspect) as u32, height)
+        } else {
+            (width, ((width as f64) / img_aspect) as u32)
+        };
+
+        let scaled = Self::resample_exact(img, scale_width, scale_height, filter);
+
+        let x_offset = Self::crop_offset(scale_width, width, focus.map(|(x, _)| x));
+        let y_offset = Self::crop_offset(scale_height, height, focus.map(|(_, y)| y));
+
+        DynamicImage::ImageRgba8(
+            image::imageops::crop_imm(&scaled.to_rgba8(), x_offset, y_offset, width, height)
+                .to_image(),
+        )
+    }
+
+    /// Offset of a `crop`-long window within `scaled` that puts `focus` (a
+    /// fraction of `scaled`) in the middle, as far as the edges allow.
+    fn crop_offset(scaled: u32, crop: u32, focus: Option<f32>) -> u32 {
+        let max_offset = scaled.saturating_sub(crop);
+        match focus {
+            Some(focus) => {
+                let centered = focus as f64 * scaled as f64 - crop as f64 / 2.0;
+                (centered.round().max(0.0) as u32).min(max_offset)
+            }
+            None => max_offset / 2,
+        }
+    }
+
+    fn resize_contain(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        Self::resample_within(img, width, height, filter)
+    }
+
+    /// How much `Fill` stretches a `source` (width, height) into `target`: the
+    /// larger aspect ratio over the smaller, minus one. `0.0` keeps the
+    /// proportions, `0.5` stretches one axis 50% more than the other.
+    pub fn aspect_distortion(source: (u32, u32), target: (u32, u32)) -> f64 {
+        let source = source.0 as f64 / source.1 as f64;
+        let target = target.0 as f64 / target.1 as f64;
+        source.max(target) / source.min(target) - 1.0
+    }
+
+    fn check_distortion(
+        source: (u32, u32),
+        target: (u32, u32),
+        guard: DistortionGuard,
+    ) -> Result<(), AppError> {
+        let distortion = Self::aspect_distortion(source, target);
+        if distortion <= guard.threshold {
+            return Ok(());
+        }
+
+        let message = format!(
+            "fill stretches the {}x{} source to {}x{}, distorting its aspect ratio by {:.1}%",
+            source.0,
+            source.1,
+            target.0,
+            target.1,
+            distortion * 100.0
+        );
+        if guard.reject {
+            return Err(AppError::InvalidParameter(message));
+        }
+        tracing::warn!("{}", message);
+        Ok(())
+    }
+
+    fn resize_fill(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        Self::resample_exact(img, width, height, filter)
+    }
+
+    fn resize_scale_down(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> DynamicImage {
+        let (img_width, img_height) = img.dimensions();
+
+        if img_width <= width && img_height <= height {
+            return img;
+        }
+
+        Self::resample_within(img, width, height, filter)
+    }
+
+    /// Cover's scaling without the crop: the smaller constraint is met exactly
+    /// and the other side overflows the box. Only the box is checked against
+    /// the limits up front, so the overflowing side is checked here, before
+    /// anything is allocated: a panorama in a square box grows without bound.
+    fn resize_outside(
+        img: DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, AppError> {
+        let (img_width, img_height) = img.dimensions();
+        let img_aspect = img_width as f64 / img_height as f64;
+        let target_aspect = width as f64 / height as f64;
+
+        let (scale_width, scale_height) = if img_aspect > target_aspect {
+            (
+                ((height as f64) * img_aspect).round().max(width as f64),
+                height as f64,
+            )
+        } else {
+            (
+                width as f64,
+                ((width as f64) / img_aspect).round().max(height as f64),
+            )
+        };
+
+        let max_dimension = limits.max_dimension as f64;
+        if scale_width > max_dimension || scale_height > max_dimension {
+            return Err(AppError::DimensionTooLarge(format!(
+                "outside scales the {}x{} source to {}x{}, over the maximum of {}px per side",
+                img_width, img_height, scale_width, scale_height, limits.max_dimension
+            )));
+        }
+
+        Ok(Self::resample_exact(
+            img,
+            scale_width as u32,
+            scale_height as u32,
+            filter,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    /// JPEG artifacts shift channels a little, especially near color edges.
+    const TOLERANCE: u8 = 48;
+
+    fn limits() -> DecodeLimits {
+        DecodeLimits {
+            max_dimension: 4096,
+            max_alloc_bytes: 64 * 1024 * 1024,
+            format_retry: true,
+        }
+    }
+
+    fn options(width: u32, height: u32, object_mode: ObjectMode) -> ResizeOptions {
+        ResizeOptions {
+            width,
+            height,
+            object_mode,
+            filter: ResizeFilter::default(),
+            min_source_dimension: None,
+            crop: None,
+            distortion_guard: None,
+            canvas: None,
+            watermark: None,
+            color_profile: ColorProfile::default(),
+            encode: EncodeOptions::default(),
+            auto_formats: Vec::new(),
+            avif_fallback: Vec::new(),
+            jpeg_fallback: false,
+            face_crop: false,
+            phash: false,
+            blurhash: false,
+            frame_index: None,
+            operations: None,
+            reject_if_larger: false,
+        }
+    }
+
+    /// Lossless PNG split into quadrants: red, green on top; blue, white below.
+    fn quadrants(width: u32, height: u32) -> Bytes {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            match (x < width / 2, y < height / 2) {
+                (true, true) => Rgb(RED),
+                (false, true) => Rgb(GREEN),
+                (true, false) => Rgb(BLUE),
+                (false, false) => Rgb(WHITE),
+            }
+        });
+
+        let mut buffer = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
+            .unwrap();
+        Bytes::from(buffer)
+    }
+
+    /// Baseline JPEG with an EXIF APP1 segment carrying `orientation`; the left
+    /// half is red and the right half blue.
+    fn jpeg_with_orientation(width: u16, height: u16, orientation: u8) -> Bytes {
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+        for _ in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(if x < width / 2 { &RED } else { &BLUE });
+            }
+        }
+
+        let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut buffer = Vec::new();
+        let mut encoder = Encoder::new(&mut buffer, 95);
+        encoder.add_app_segment(1, exif).unwrap();
+        encoder
+            .encode(&pixels, width, height, ColorType::Rgb)
+            .unwrap();
+        Bytes::from(buffer)
+    }
+
+    fn decode_output(processed: &ProcessedImage) -> RgbImage {
+        image::load_from_memory(&processed.data).unwrap().to_rgb8()
+    }
+
+    fn assert_pixel(img: &RgbImage, x: u32, y: u32, expected: [u8; 3]) {
+        let actual = img.get_pixel(x, y).0;
+        let close = actual
+            .iter()
+            .zip(expected.iter())
+            .all(|(a, e)| a.abs_diff(*e) <= TOLERANCE);
+        assert!(
+            close,
+            "pixel ({}, {}) is {:?}, expected {:?} within {}",
+            x, y, actual, expected, TOLERANCE
+        );
+    }
+
+    #[test]
+    fn cover_produces_exact_dimensions_and_crops_the_center() {
+        // 200x100 -> 100x100 trims 50px from each side, leaving a 2x2 quadrant layout.
+        let processed = ImageProcessor::resize(
+            quadrants(200, 100),
+            &options(100, 100, ObjectMode::Cover),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (100, 100));
+
+        let img = decode_output(&processed);
+        assert_eq!(img.dimensions(), (100, 100));
+        assert_pixel(&img, 10, 10, RED);
+        assert_pixel(&img, 90, 10, GREEN);
+        assert_pixel(&img, 10, 90, BLUE);
+        assert_pixel(&img, 90, 90, WHITE);
+    }
+
+    #[test]
+    fn fill_stretches_to_exact_dimensions() {
+        let processed = ImageProcessor::resize(
+            quadrants(200, 100),
+            &options(60, 120, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (60, 120));
+
+        let img = decode_output(&processed);
+        assert_eq!(img.dimensions(), (60, 120));
+        assert_pixel(&img, 5, 5, RED);
+        assert_pixel(&img, 55, 115, WHITE);
+    }
+
+    #[test]
+    fn fill_distortion_past_the_threshold_is_rejected_on_request() {
+        assert_eq!(
+            ImageProcessor::aspect_distortion((200, 100), (100, 50)),
+            0.0
+        );
+        assert_eq!(
+            ImageProcessor::aspect_distortion((200, 100), (100, 100)),
+            1.0
+        );
+
+        let mut guarded = options(100, 100, ObjectMode::Fill);
+        guarded.distortion_guard = Some(DistortionGuard {
+            threshold: 0.05,
+            reject: true,
+        });
+        assert!(matches!(
+            ImageProcessor::resize(quadrants(200, 100), &guarded, &limits()),
+            Err(AppError::InvalidParameter(_))
+        ));
+        // Within the threshold, and past it without `reject`, the resize goes ahead.
+        assert!(ImageProcessor::resize(quadrants(104, 100), &guarded, &limits()).is_ok());
+        guarded.distortion_guard = Some(DistortionGuard {
+            threshold: 0.05,
+            reject: false,
+        });
+        assert!(ImageProcessor::resize(quadrants(200, 100), &guarded, &limits()).is_ok());
+    }
+
+    #[test]
+    fn contain_fits_inside_and_keeps_aspect_ratio() {
+        let processed = ImageProcessor::resize(
+            quadrants(200, 100),
+            &options(100, 100, ObjectMode::Contain),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (100, 50));
+
+        let img = decode_output(&processed);
+        assert_pixel(&img, 5, 5, RED);
+        assert_pixel(&img, 95, 45, WHITE);
+    }
+
+    #[test]
+    fn scale_down_leaves_small_sources_unchanged() {
+        let processed = ImageProcessor::resize(
+            quadrants(40, 20),
+            &options(100, 100, ObjectMode::ScaleDown),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (40, 20));
+    }
+
+    #[test]
+    fn scale_down_shrinks_large_sources_to_fit() {
+        let processed = ImageProcessor::resize(
+            quadrants(400, 200),
+            &options(100, 100, ObjectMode::ScaleDown),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (100, 50));
+    }
+
+    #[test]
+    fn outside_covers_the_box_without_cropping() {
+        let processed = ImageProcessor::resize(
+            quadrants(200, 100),
+            &options(80, 80, ObjectMode::Outside),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((processed.width, processed.height), (160, 80));
+
+        let img = decode_output(&processed);
+        assert_pixel(&img, 5, 5, RED);
+        assert_pixel(&img, 155, 5, GREEN);
+    }
+
+    #[test]
+    fn outside_rejects_an_overflowing_side_before_resampling() {
+        // A 400:1 panorama in the largest allowed square would be 1,638,400
+        // pixels wide.
+        let panorama = quadrants(4000, 10);
+        let result = ImageProcessor::resize(
+            panorama.clone(),
+            &options(4096, 4096, ObjectMode::Outside),
+            &limits(),
+        );
+        assert!(matches!(result, Err(AppError::DimensionTooLarge(_))));
+
+        let mut steps = options(4096, 4096, ObjectMode::Fill);
+        steps.operations = Some(vec![Step::Resize {
+            width: 4096,
+            height: 4096,
+            object_mode: ObjectMode::Outside,
+        }]);
+        let result = ImageProcessor::resize(panorama, &steps, &limits());
+        assert!(matches!(result, Err(AppError::DimensionTooLarge(_))));
+    }
+
+    #[test]
+    fn jpeg_rejects_sides_over_16_bits() {
+        let wide = DynamicImage::ImageRgb8(RgbImage::new(u16::MAX as u32 + 1, 1));
+        let result = ImageProcessor::encode_jpeg(&wide, &EncodeOptions::default(), None);
+        assert!(matches!(result, Err(AppError::DimensionTooLarge(_))));
+    }
+
+    #[test]
+    fn cover_uses_exif_rotated_dimensions() {
+        // Orientation 6 rotates 90° clockwise: the 40x20 red|blue source is
+        // 20x40 upright, red on top and blue below.
+        let source = jpeg_with_orientation(40, 20, 6);
+        assert_eq!(ImageProcessor::dimensions(&source).unwrap(), (20, 40));
+
+        let processed =
+            ImageProcessor::resize(source, &options(10, 20, ObjectMode::Cover), &limits()).unwrap();
+
+        let img = decode_output(&processed);
+        assert_eq!(img.dimensions(), (10, 20));
+        assert_pixel(&img, 5, 2, RED);
+        assert_pixel(&img, 5, 17, BLUE);
+    }
+
+    #[test]
+    fn identity_resize_passes_source_bytes_through() {
+        let source = jpeg_with_orientation(40, 20, 1);
+        let processed = ImageProcessor::resize(
+            source.clone(),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!(processed.data, source);
+
+        let mut reencoded = options(40, 20, ObjectMode::Fill);
+        reencoded.encode.quality = 90;
+        let processed = ImageProcessor::resize(source.clone(), &reencoded, &limits()).unwrap();
+        assert_ne!(processed.data, source);
+    }
+
+    #[test]
+    fn min_source_dimension_rejects_small_sources() {
+        let mut options = options(10, 10, ObjectMode::Cover);
+        options.min_source_dimension = Some(50);
+
+        let result = ImageProcessor::resize(quadrants(100, 40), &options, &limits());
+        assert!(matches!(result, Err(AppError::SourceTooSmall(_))));
+    }
+
+    #[test]
+    fn oversized_sources_are_rejected_before_decoding() {
+        let limits = DecodeLimits {
+            max_dimension: 50,
+            ..limits()
+        };
+
+        let result = ImageProcessor::resize(
+            quadrants(100, 40),
+            &options(10, 10, ObjectMode::Cover),
+            &limits,
+        );
+        assert!(matches!(result, Err(AppError::DimensionTooLarge(_))));
+    }
+
+    /// A PNG whose IHDR claims `width`x`height` RGB pixels but whose IDAT
+    /// holds only a few bytes, as a crafted upload would.
+    fn png_claiming(width: u32, height: u32) -> Bytes {
+        fn crc32(bytes: &[u8]) -> u32 {
+            let mut crc = !0u32;
+            for &byte in bytes {
+                crc ^= byte as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 {
+                        (crc >> 1) ^ 0xEDB8_8320
+                    } else {
+                        crc >> 1
+                    };
+                }
+            }
+            !crc
+        }
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        for (kind, data) in [
+            (b"IHDR", ihdr.as_slice()),
+            (b"IDAT", &[0x78, 0x9c, 0x03, 0x00][..]),
+            (b"IEND", &[][..]),
+        ] {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let crc = crc32(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        }
+        Bytes::from(png)
+    }
+
+    #[test]
+    fn crafted_huge_headers_are_rejected_before_allocating() {
+        // 10 gigapixels of RGB, about 30 GB decoded, from a few dozen bytes.
+        let crafted = png_claiming(100_000, 100_000);
+        assert!(crafted.len() < 64);
+        assert_eq!(
+            ImageProcessor::dimensions(&crafted).unwrap(),
+            (100_000, 100_000)
+        );
+
+        let decoded = ImageProcessor::decode(&crafted, &limits());
+        assert!(matches!(decoded, Err(AppError::DimensionTooLarge(_))));
+
+        let resized =
+            ImageProcessor::resize(crafted, &options(10, 10, ObjectMode::Cover), &limits());
+        assert!(matches!(resized, Err(AppError::DimensionTooLarge(_))));
+    }
+
+    #[test]
+    fn truncated_jpegs_decode_and_garbage_does_not() {
+        let source = jpeg_with_orientation(256, 256, 1);
+        // Cut halfway through the entropy-coded scan after the SOS marker.
+        let scan_start = source
+            .windows(2)
+            .position(|pair| pair == [0xFF, 0xDA])
+            .unwrap();
+        let cut = (scan_start + source.len()) / 2;
+        let truncated = Bytes::copy_from_slice(&source[..cut]);
+        let img = ImageProcessor::decode(&truncated, &limits()).unwrap();
+        assert_eq!(img.dimensions(), (256, 256));
+
+        // A cut inside a marker leaves a dangling 0xFF, which is dropped
+        // before the end-of-image marker is appended.
+        let mut mid_marker = source[..cut].to_vec();
+        mid_marker.push(0xFF);
+        let repaired = ImageProcessor::repair_truncated_jpeg(&mid_marker);
+        assert_eq!(&repaired[mid_marker.len() - 1..], &JPEG_EOI);
+        let img = ImageProcessor::decode(&Bytes::from(mid_marker), &limits()).unwrap();
+        assert_eq!(img.dimensions(), (256, 256));
+
+        let mut garbage = b"\xFF\xD8".to_vec();
+        garbage.extend_from_slice(&[0x55; 256]);
+        let result = ImageProcessor::decode(&Bytes::from(garbage), &limits());
+        assert!(matches!(result, Err(AppError::ImageProcessingError(_))));
+    }
+
+    #[test]
+    fn canvas_places_the_image_with_gravity() {
+        let mut options = options(20, 20, ObjectMode::Fill);
+        options.canvas = Some(CanvasOptions {
+            width: 60,
+            height: 20,
+            background: crate::models::HexColor([0, 0, 0, 255]),
+            gravity: Gravity::West,
+            x: None,
+            y: None,
+        });
+
+        let processed = ImageProcessor::resize(quadrants(40, 40), &options, &limits()).unwrap();
+        assert_eq!((processed.width, processed.height), (60, 20));
+
+        let img = decode_output(&processed);
+        assert_pixel(&img, 3, 3, RED);
+        assert_pixel(&img, 50, 10, [0, 0, 0]);
+    }
+
+    #[test]
+    fn dpi_is_written_as_jfif_density() {
+        let encode = EncodeOptions {
+            dpi: Some(300),
+            ..EncodeOptions::default()
+        };
+        let processed = ImageProcessor::encode(&DynamicImage::new_rgb8(8, 8), &encode).unwrap();
+
+        // APP0 JFIF: units (1 = dots per inch) followed by big-endian X/Y density.
+        assert_eq!(&processed.data[6..11], b"JFIF\0");
+        assert_eq!(&processed.data[13..18], &[1, 0x01, 0x2C, 0x01, 0x2C]);
+    }
+
+    #[test]
+    fn dpi_is_written_as_png_phys() {
+        let encode = EncodeOptions {
+            format: OutputFormat::Png,
+            dpi: Some(300),
+            ..EncodeOptions::default()
+        };
+        let gradient = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, 0])
+        }));
+        // 300 dpi is 11811 pixels per meter (0x2E23), unit 1 = meter.
+        let phys = [0, 0, 0x2E, 0x23, 0, 0, 0x2E, 0x23, 1];
+
+        for img in [gradient, DynamicImage::new_rgb8(8, 8)] {
+            let processed = ImageProcessor::encode(&img, &encode).unwrap();
+            let chunk = processed
+                .data
+                .windows(4)
+                .position(|window| window == b"pHYs")
+                .expect("pHYs chunk");
+            assert_eq!(&processed.data[chunk + 4..chunk + 13], &phys);
+            assert_eq!(decode_output(&processed).dimensions(), img.dimensions());
+        }
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn mozjpeg_backend_writes_the_same_pixels_and_density() {
+        let encode = EncodeOptions {
+            dpi: Some(300),
+            jpeg_encoder: JpegEncoder::Mozjpeg,
+            ..EncodeOptions::default()
+        };
+        let source = image::load_from_memory(&quadrants(40, 40)).unwrap();
+        let processed = ImageProcessor::encode(&source, &encode).unwrap();
+
+        assert_eq!(&processed.data[13..18], &[1, 0x01, 0x2C, 0x01, 0x2C]);
+        let img = decode_output(&processed);
+        assert_eq!(img.dimensions(), (40, 40));
+        assert_pixel(&img, 5, 5, RED);
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    #[test]
+    fn mozjpeg_backend_honors_progressive() {
+        let source = image::load_from_memory(&quadrants(40, 40)).unwrap();
+        let frame_marker = |progressive| {
+            let encode = EncodeOptions {
+                progressive,
+                jpeg_encoder: JpegEncoder::Mozjpeg,
+                ..EncodeOptions::default()
+            };
+            let data = ImageProcessor::encode(&source, &encode).unwrap().data;
+            // SOF0 marks a baseline frame, SOF2 a progressive one.
+            data.windows(2)
+                .find(|w| w[0] == 0xFF && matches!(w[1], 0xC0 | 0xC2))
+                .unwrap()[1]
+        };
+
+        assert_eq!(frame_marker(false), 0xC0);
+        assert_eq!(frame_marker(true), 0xC2);
+    }
+
+    #[test]
+    fn similarity_scores_identical_close_and_mismatched_images() {
+        let source = quadrants(40, 40);
+        let encoded = ImageProcessor::resize(
+            source.clone(),
+            &options(20, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap()
+        .data;
+
+        assert_eq!(
+            ImageProcessor::similarity(&encoded, &encoded, &limits()).unwrap(),
+            1.0
+        );
+
+        let mut low_quality = options(20, 20, ObjectMode::Fill);
+        low_quality.encode.quality = 20;
+        let degraded = ImageProcessor::resize(source, &low_quality, &limits())
+            .unwrap()
+            .data;
+        let similarity = ImageProcessor::similarity(&encoded, &degraded, &limits()).unwrap();
+        assert!(
+            similarity > 0.9 && similarity < 1.0,
+            "similarity {}",
+            similarity
+        );
+
+        let other_size = quadrants(20, 10);
+        assert_eq!(
+            ImageProcessor::similarity(&encoded, &other_size, &limits()).unwrap(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn fit_megapixels_keeps_aspect_and_never_upscales() {
+        assert_eq!(
+            ImageProcessor::fit_megapixels(6000, 4000, 2.0),
+            (1732, 1154)
+        );
+        assert_eq!(ImageProcessor::fit_megapixels(800, 600, 2.0), (800, 600));
+    }
+
+    #[test]
+    fn transparent_pixels_are_flattened_onto_the_background() {
+        // Transparent black corners, as most editors store them, around an
+        // opaque red center.
+        let img = RgbaImage::from_fn(100, 100, |x, y| {
+            let corner = !(25..75).contains(&x) && !(25..75).contains(&y);
+            if corner {
+                Rgba([0, 0, 0, 0])
+            } else {
+                Rgba([255, 0, 0, 255])
+            }
+        });
+        let mut buffer = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
+            .unwrap();
+        let png = Bytes::from(buffer);
+
+        let processed =
+            ImageProcessor::resize(png.clone(), &options(100, 100, ObjectMode::Fill), &limits())
+                .unwrap();
+        let output = decode_output(&processed);
+        assert_pixel(&output, 5, 5, WHITE);
+        assert_pixel(&output, 94, 94, WHITE);
+        assert_pixel(&output, 50, 50, RED);
+
+        let mut blue_background = options(100, 100, ObjectMode::Fill);
+        blue_background.encode.alpha_background = HexColor([0, 0, 255, 255]);
+        let processed = ImageProcessor::resize(png, &blue_background, &limits()).unwrap();
+        assert_pixel(&decode_output(&processed), 5, 5, BLUE);
+    }
+
+    #[test]
+    fn estimate_work_scales_with_pixels_outputs_and_filter() {
+        let lanczos = ImageProcessor::estimate_work(24_000_000, 1, ResizeFilter::Lanczos3);
+        assert_eq!(lanczos, 24.0);
+        assert_eq!(
+            ImageProcessor::estimate_work(24_000_000, 3, ResizeFilter::Lanczos3),
+            72.0
+        );
+        assert!(ImageProcessor::estimate_work(24_000_000, 1, ResizeFilter::Triangle) < lanczos);
+    }
+
+    #[test]
+    fn auto_filter_matches_lanczos_sizes_on_large_reductions() {
+        for object_mode in [ObjectMode::Contain, ObjectMode::Cover, ObjectMode::Outside] {
+            let lanczos = options(40, 30, object_mode);
+            let mut auto = options(40, 30, object_mode);
+            auto.filter = ResizeFilter::Auto;
+            let lanczos = ImageProcessor::resize(quadrants(800, 400), &lanczos, &limits()).unwrap();
+            let processed = ImageProcessor::resize(quadrants(800, 400), &auto, &limits()).unwrap();
+
+            assert_eq!(
+                (processed.width, processed.height),
+                (lanczos.width, lanczos.height)
+            );
+            let output = decode_output(&processed);
+            let (width, height) = output.dimensions();
+            assert_pixel(&output, width / 4, height / 4, RED);
+            assert_pixel(&output, width * 3 / 4, height * 3 / 4, WHITE);
+        }
+    }
+
+    #[test]
+    fn fit_within_respects_both_sides_and_never_upscales() {
+        assert_eq!(
+            ImageProcessor::fit_within(4000, 3000, Some(400), Some(400)),
+            (400, 300)
+        );
+        assert_eq!(
+            ImageProcessor::fit_within(3000, 4000, Some(400), Some(400)),
+            (300, 400)
+        );
+        assert_eq!(
+            ImageProcessor::fit_within(4000, 3000, None, Some(150)),
+            (200, 150)
+        );
+        assert_eq!(
+            ImageProcessor::fit_within(4000, 3000, Some(1000), None),
+            (1000, 750)
+        );
+        assert_eq!(
+            ImageProcessor::fit_within(300, 200, Some(400), Some(400)),
+            (300, 200)
+        );
+    }
+
+    #[test]
+    fn text_watermark_changes_the_output() {
+        let plain = ImageProcessor::resize(
+            quadrants(200, 100),
+            &options(200, 100, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+
+        let mut watermarked_options = options(200, 100, ObjectMode::Fill);
+        watermarked_options.watermark = Some(Watermark {
+            options: TextWatermark {
+                text: "PREVIEW".to_string(),
+                size: None,
+                color: HexColor([0, 0, 0, 255]),
+                opacity: 0.5,
+                angle: None,
+            },
+            font: ImageProcessor::default_watermark_font(),
+        });
+        let watermarked =
+            ImageProcessor::resize(quadrants(200, 100), &watermarked_options, &limits()).unwrap();
+
+        assert_eq!(
+            (watermarked.width, watermarked.height),
+            (plain.width, plain.height)
+        );
+        assert_ne!(decode_output(&watermarked), decode_output(&plain));
+    }
+
+    /// Solid-color JPEG tagged with the Display P3 profile.
+    fn display_p3_jpeg(color: [u8; 3]) -> Bytes {
+        let pixels = color.repeat(64 * 64);
+        let profile = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+
+        let mut buffer = Vec::new();
+        let mut encoder = Encoder::new(&mut buffer, 95);
+        encoder.add_icc_profile(&profile).unwrap();
+        encoder.encode(&pixels, 64, 64, ColorType::Rgb).unwrap();
+        Bytes::from(buffer)
+    }
+
+    fn output_icc_profile(processed: &ProcessedImage) -> Option<Vec<u8>> {
+        ImageReader::new(Cursor::new(&processed.data))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap()
+            .icc_profile()
+            .unwrap()
+    }
+
+    #[test]
+    fn display_p3_source_is_converted_to_srgb_unless_preserved() {
+        let color = [200, 100, 60];
+
+        let converted = ImageProcessor::resize(
+            display_p3_jpeg(color),
+            &options(32, 32, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!(output_icc_profile(&converted), None);
+        let [r, g, b] = decode_output(&converted).get_pixel(16, 16).0;
+        assert!(
+            r > color[0] + 10 && g < color[1] && b < color[2],
+            "got {:?}",
+            [r, g, b]
+        );
+
+        let mut preserve_options = options(32, 32, ObjectMode::Fill);
+        preserve_options.color_profile = ColorProfile::Preserve;
+        let preserved =
+            ImageProcessor::resize(display_p3_jpeg(color), &preserve_options, &limits()).unwrap();
+        assert!(output_icc_profile(&preserved).is_some());
+        assert_pixel(&decode_output(&preserved), 16, 16, color);
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn svg_is_rendered_at_the_output_size_per_object_mode() {
+        // 2:1 viewBox with no width/height: red left half, blue right half.
+        let svg = Bytes::from_static(
+            br##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 200 100">
+  <rect x="0" y="0" width="100" height="100" fill="#ff0000"/>
+  <rect x="100" y="0" width="100" height="100" fill="#0000ff"/>
+</svg>"##,
+        );
+        assert_eq!(ImageProcessor::dimensions(&svg).unwrap(), (200, 100));
+
+        let contained = ImageProcessor::resize(
+            svg.clone(),
+            &options(400, 400, ObjectMode::Contain),
+            &limits(),
+        )
+        .unwrap();
+        assert_eq!((contained.width, contained.height), (400, 200));
+        let output = decode_output(&contained);
+        assert_pixel(&output, 10, 100, RED);
+        assert_pixel(&output, 390, 100, BLUE);
+
+        let mut png = options(100, 100, ObjectMode::Cover);
+        png.encode.format = OutputFormat::Png;
+        let covered = ImageProcessor::resize(svg, &png, &limits()).unwrap();
+        assert_eq!(covered.content_type, "image/png");
+        assert_eq!((covered.width, covered.height), (100, 100));
+        let output = decode_output(&covered);
+        assert_pixel(&output, 10, 50, RED);
+        assert_pixel(&output, 90, 50, BLUE);
+    }
+
+    /// 4-bit indexed PNG in quadrants: red, green on top; blue and fully
+    /// transparent below.
+    fn indexed_quadrants(width: u32, height: u32) -> Bytes {
+        let mut data = Vec::new();
+        for y in 0..height {
+            let mut row = Vec::new();
+            for x in 0..width {
+                row.push((x >= width / 2) as u8 + 2 * (y >= height / 2) as u8);
+            }
+            data.extend(
+                row.chunks(2)
+                    .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)),
+            );
+        }
+
+        let mut buffer = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Four);
+        encoder.set_palette([RED, GREEN, BLUE, [0, 0, 0]].concat());
+        encoder.set_trns(vec![255, 255, 255, 0]);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+        writer.finish().unwrap();
+        Bytes::from(buffer)
+    }
+
+    #[test]
+    fn indexed_sources_keep_their_colors() {
+        let mut png = options(40, 20, ObjectMode::Fill);
+        png.filter = ResizeFilter::Nearest;
+        png.encode.format = OutputFormat::Png;
+        let processed = ImageProcessor::resize(indexed_quadrants(80, 40), &png, &limits()).unwrap();
+        // IHDR color type 3 is indexed.
+        assert_eq!(processed.data[25], 3);
+        let output = image::load_from_memory(&processed.data).unwrap().to_rgba8();
+        assert_eq!(output.get_pixel(5, 5).0, [255, 0, 0, 255]);
+        assert_eq!(output.get_pixel(35, 5).0, [0, 255, 0, 255]);
+        assert_eq!(output.get_pixel(5, 15).0, [0, 0, 255, 255]);
+        assert_eq!(output.get_pixel(35, 15).0[3], 0);
+
+        // More colors than a palette holds stay truecolor (type 2).
+        let gradient = RgbImage::from_fn(80, 40, |x, y| Rgb([x as u8 * 3, y as u8 * 6, 128]));
+        let mut gradient_png = Vec::new();
+        DynamicImage::ImageRgb8(gradient)
+            .write_to(&mut Cursor::new(&mut gradient_png), ImageFormat::Png)
+            .unwrap();
+        let truecolor = ImageProcessor::resize(Bytes::from(gradient_png), &png, &limits()).unwrap();
+        assert_eq!(truecolor.data[25], 2);
+
+        let jpeg = ImageProcessor::resize(
+            indexed_quadrants(80, 40),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        let output = decode_output(&jpeg);
+        assert_pixel(&output, 5, 5, RED);
+        assert_pixel(&output, 35, 5, GREEN);
+        assert_pixel(&output, 5, 15, BLUE);
+        assert_pixel(&output, 35, 15, WHITE);
+    }
+
+    #[test]
+    fn prefixed_sources_decode_by_their_sniffed_signature() {
+        let mut prefixed = b"\xEF\xBB\xBFuploaded-by: cms\n".to_vec();
+        prefixed.extend_from_slice(&quadrants(80, 40));
+        let prefixed = Bytes::from(prefixed);
+
+        let processed = ImageProcessor::resize(
+            prefixed.clone(),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        assert_pixel(&decode_output(&processed), 5, 5, RED);
+
+        let strict = DecodeLimits {
+            format_retry: false,
+            ..limits()
+        };
+        let result = ImageProcessor::resize(prefixed, &options(40, 20, ObjectMode::Fill), &strict);
+        assert!(matches!(result, Err(AppError::ImageProcessingError(_))));
+
+        // Both attempts are reported when the retry fails too.
+        let garbage = Bytes::from_static(b"junk\x89PNG\r\n\x1a\n not really a png");
+        match ImageProcessor::resize(garbage, &options(40, 20, ObjectMode::Fill), &limits()) {
+            Err(AppError::ImageProcessingError(message)) => {
+                assert!(
+                    message.contains("; retried as Png from byte 4: "),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("expected a decode error, got {:?}", other.map(|p| p.format)),
+        }
+    }
+
+    #[test]
+    fn auto_output_keeps_the_smallest_candidate() {
+        let mut auto = options(64, 64, ObjectMode::Fill);
+        auto.encode.format = OutputFormat::Auto;
+        auto.auto_formats = vec![OutputFormat::Jpeg, OutputFormat::Webp];
+
+        // Flat color compresses far better losslessly.
+        let flat = ImageProcessor::resize(quadrants(64, 64), &auto, &limits()).unwrap();
+        assert_eq!(flat.format, OutputFormat::Webp);
+        assert_eq!(flat.content_type, "image/webp");
+        assert_pixel(&decode_output(&flat), 10, 10, RED);
+
+        // Noise is where lossless loses.
+        let mut seed = 0x2545_f491u32;
+        let noise = RgbImage::from_fn(64, 64, |_, _| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let [r, g, b, _] = seed.to_le_bytes();
+            Rgb([r, g, b])
+        });
+        let mut noise_png = Vec::new();
+        DynamicImage::ImageRgb8(noise)
+            .write_to(&mut Cursor::new(&mut noise_png), ImageFormat::Png)
+            .unwrap();
+        let noisy = ImageProcessor::resize(Bytes::from(noise_png), &auto, &limits()).unwrap();
+        assert_eq!(noisy.format, OutputFormat::Jpeg);
+        assert_eq!(noisy.content_type, "image/jpeg");
+    }
+
+    #[test]
+    fn jpeg_fallback_is_encoded_from_the_same_pixels() {
+        let mut webp = options(40, 20, ObjectMode::Fill);
+        webp.encode.format = OutputFormat::Webp;
+        webp.jpeg_fallback = true;
+        let processed = ImageProcessor::resize(quadrants(80, 40), &webp, &limits()).unwrap();
+        assert_eq!(processed.content_type, "image/webp");
+
+        let fallback = processed.fallback.expect("fallback requested");
+        assert_eq!(fallback.format, OutputFormat::Jpeg);
+        assert_eq!((fallback.width, fallback.height), (40, 20));
+        let output = decode_output(&fallback);
+        assert_pixel(&output, 5, 5, RED);
+        assert_pixel(&output, 35, 15, WHITE);
+    }
+
+    #[test]
+    fn perceptual_hash_survives_resizing_but_not_a_different_image() {
+        let hash = |data: Bytes| ImageProcessor::perceptual_hash(&data, &limits()).unwrap();
+        let distance = |a: u64, b: u64| (a ^ b).count_ones();
+        let png = |img: RgbImage| {
+            let mut data = Vec::new();
+            DynamicImage::ImageRgb8(img)
+                .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+                .unwrap();
+            Bytes::from(data)
+        };
+        let waves = RgbImage::from_fn(400, 300, |x, y| {
+            let v = 128.0 + 100.0 * (x as f32 / 40.0).sin() * (y as f32 / 30.0).cos();
+            Rgb([v as u8; 3])
+        });
+
+        let original = hash(png(waves.clone()));
+        let resized =
+            ImageProcessor::resize(png(waves), &options(120, 90, ObjectMode::Fill), &limits())
+                .unwrap();
+        assert!(distance(original, hash(resized.data)) <= 4);
+
+        assert!(distance(original, hash(quadrants(400, 300))) > 16);
+    }
+
+    #[test]
+    fn blurhash_describes_the_source_rather_than_the_output() {
+        let source = ImageProcessor::blurhash(&quadrants(80, 40), &limits()).unwrap();
+        assert_eq!(source.len(), 28);
+
+        let mut cropped = options(20, 20, ObjectMode::Cover);
+        cropped.blurhash = true;
+        let processed = ImageProcessor::resize(quadrants(80, 40), &cropped, &limits()).unwrap();
+        assert_eq!(processed.blurhash.as_deref(), Some(source.as_str()));
+    }
+
+    #[test]
+    fn cover_crop_centers_on_the_focus_within_the_edges() {
+        assert_eq!(ImageProcessor::crop_offset(300, 100, None), 100);
+        assert_eq!(ImageProcessor::crop_offset(300, 100, Some(0.5)), 100);
+        assert_eq!(ImageProcessor::crop_offset(300, 100, Some(0.3)), 40);
+        assert_eq!(ImageProcessor::crop_offset(300, 100, Some(0.05)), 0);
+        assert_eq!(ImageProcessor::crop_offset(300, 100, Some(0.95)), 200);
+        assert_eq!(ImageProcessor::crop_offset(100, 100, Some(0.9)), 0);
+    }
+
+    #[test]
+    fn frame_index_selects_an_animation_frame_and_clamps_to_the_last() {
+        let mut buffer = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut buffer);
+            for color in [RED, GREEN, BLUE] {
+                let [r, g, b] = color;
+                let frame = RgbaImage::from_pixel(20, 20, Rgba([r, g, b, 255]));
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        let gif = Bytes::from(buffer);
+
+        for (frame_index, expected) in [(None, RED), (Some(1), GREEN), (Some(7), BLUE)] {
+            let mut frame = options(10, 10, ObjectMode::Fill);
+            frame.frame_index = frame_index;
+            let processed = ImageProcessor::resize(gif.clone(), &frame, &limits()).unwrap();
+            assert_pixel(&decode_output(&processed), 5, 5, expected);
+        }
+
+        let mut still = options(40, 20, ObjectMode::Fill);
+        still.frame_index = Some(3);
+        let processed = ImageProcessor::resize(quadrants(80, 40), &still, &limits()).unwrap();
+        assert_pixel(&decode_output(&processed), 5, 5, RED);
+    }
+
+    #[test]
+    fn raw_output_is_unencoded_rgba_within_the_limit() {
+        let raw = ImageProcessor::resize_raw(
+            quadrants(80, 40),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+            40 * 20 * 4,
+        )
+        .unwrap();
+
+        assert_eq!((raw.width, raw.height), (40, 20));
+        assert_eq!(raw.data.len(), 40 * 20 * 4);
+        assert_eq!(&raw.data[..4], &[255, 0, 0, 255]);
+
+        let too_large = ImageProcessor::resize_raw(
+            quadrants(80, 40),
+            &options(40, 40, ObjectMode::Outside),
+            &limits(),
+            40 * 40 * 4,
+        );
+        assert!(matches!(too_large, Err(AppError::DimensionTooLarge(_))));
+    }
+
+    #[cfg(feature = "face-detect")]
+    #[test]
+    fn face_crop_without_a_face_falls_back_to_the_center() {
+        let mut face_crop = options(40, 40, ObjectMode::Cover);
+        face_crop.face_crop = true;
+        let processed = ImageProcessor::resize(quadrants(80, 40), &face_crop, &limits()).unwrap();
+
+        assert_eq!(processed.face_detected, Some(false));
+        let output = decode_output(&processed);
+        assert_pixel(&output, 5, 5, RED);
+        assert_pixel(&output, 35, 5, GREEN);
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_output_is_tagged_and_sized() {
+        let mut avif = options(40, 20, ObjectMode::Fill);
+        avif.encode.format = OutputFormat::Avif;
+        let processed = ImageProcessor::resize(quadrants(80, 40), &avif, &limits()).unwrap();
+
+        assert_eq!(processed.content_type, "image/avif");
+        assert_eq!((processed.width, processed.height), (40, 20));
+        assert_eq!(&processed.data[4..12], b"ftypavif");
+    }
+
+    /// Without the `avif` feature every AVIF encode fails, which stands in for
+    /// an encoder error here.
+    #[cfg(not(feature = "avif"))]
+    #[test]
+    fn failed_avif_encode_falls_back_in_order() {
+        let mut avif = options(40, 20, ObjectMode::Fill);
+        avif.encode.format = OutputFormat::Avif;
+        assert!(ImageProcessor::resize(quadrants(80, 40), &avif, &limits()).is_err());
+
+        avif.avif_fallback = vec![OutputFormat::Webp, OutputFormat::Jpeg];
+        let processed = ImageProcessor::resize(quadrants(80, 40), &avif, &limits()).unwrap();
+        assert_eq!(processed.format, OutputFormat::Webp);
+        assert_eq!(processed.content_type, "image/webp");
+        assert_pixel(&decode_output(&processed), 5, 5, RED);
+    }
+
+    #[test]
+    fn convert_keeps_the_source_size() {
+        let webp = EncodeOptions {
+            format: OutputFormat::Webp,
+            ..EncodeOptions::default()
+        };
+        let processed = ImageProcessor::convert(
+            &quadrants(80, 40),
+            &webp,
+            ColorProfile::Srgb,
+            &[],
+            &limits(),
+        )
+        .unwrap();
+
+        assert_eq!(processed.format, OutputFormat::Webp);
+        assert_eq!((processed.width, processed.height), (80, 40));
+        // Lossless WebP keeps the pixels exactly.
+        let output = decode_output(&processed);
+        assert_eq!(output.dimensions(), (80, 40));
+        assert_pixel(&output, 10, 10, RED);
+        assert_pixel(&output, 70, 30, WHITE);
+    }
+
+    #[test]
+    fn resize_reports_its_stage_timings() {
+        let processed = ImageProcessor::resize(
+            quadrants(80, 40),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        assert!(processed.timings.decode > Duration::ZERO);
+        assert!(processed.timings.resize > Duration::ZERO);
+        assert!(processed.timings.encode > Duration::ZERO);
+
+        let jpeg = ImageProcessor::resize(
+            jpeg_with_orientation(40, 20, 1),
+            &options(40, 20, ObjectMode::Fill),
+            &limits(),
+        )
+        .unwrap();
+        // Passed through untouched, so no stage ran.
+        assert_eq!(jpeg.timings.encode, Duration::ZERO);
+    }
+
+    #[test]
+    fn tile_pyramid_halves_down_to_one_pixel() {
+        let png = EncodeOptions {
+            format: OutputFormat::Png,
+            ..EncodeOptions::default()
+        };
+        let pyramid =
+            ImageProcessor::tile_pyramid(&quadrants(600, 300), 256, 1, &png, &limits()).unwrap();
+
+        // ceil(log2(600)) = 10, so eleven levels from 1x1 up to 600x300.
+        assert_eq!(pyramid.levels.len(), 11);
+        let top = pyramid.levels.last().unwrap();
+        assert_eq!(
+            (top.width, top.height, top.columns, top.rows),
+            (600, 300, 3, 2)
+        );
+        let level_8 = &pyramid.levels[8];
+        assert_eq!(
+            (level_8.width, level_8.height, level_8.columns),
+            (150, 75, 1)
+        );
+        assert_eq!((pyramid.levels[0].width, pyramid.levels[0].height), (1, 1));
+
+        let expected: u32 = pyramid.levels.iter().map(|l| l.columns * l.rows).sum();
+        assert_eq!(pyramid.tiles.len() as u32, expected);
+        // Interior tiles gain the overlap on each shared edge; the last
+        // column is whatever is left.
+        let tile = |column, row| {
+            let tile = pyramid
+                .tiles
+                .iter()
+                .find(|t| t.level == 10 && t.column == column && t.row == row)
+                .unwrap();
+            image::load_from_memory(&tile.data).unwrap().to_rgb8()
+        };
+        assert_eq!(tile(0, 0).dimensions(), (257, 257));
+        assert_eq!(tile(1, 0).dimensions(), (258, 257));
+        assert_eq!(tile(2, 1).dimensions(), (89, 45));
+        assert_pixel(&tile(0, 0), 10, 10, RED);
+        assert_pixel(&tile(2, 1), 80, 40, WHITE);
+
+        let manifest = ImageProcessor::dzi_manifest(600, 300, 256, 1, "png");
+        assert!(manifest.contains("TileSize=\"256\""));
+        assert_eq!(ImageProcessor::dzi_size(&manifest), Some((600, 300)));
+    }
+
+    #[test]
+    fn operations_apply_in_order() {
+        let mut chained = options(20, 40, ObjectMode::Fill);
+        chained.operations = Some(vec![
+            Step::Rotate(90),
+            Step::Crop(CropRect {
+                x: 0,
+                y: 0,
+                width: 20,
+                height: 80,
+                on_invalid: Default::default(),
+            }),
+            Step::Resize {
+                width: 20,
+                height: 40,
+                object_mode: ObjectMode::Fill,
+            },
+        ]);
+        let processed = ImageProcessor::resize(quadrants(80, 40), &chained, &limits()).unwrap();
+        assert_eq!((processed.width, processed.height), (20, 40));
+        // Turned clockwise the blue quadrant is top left, and the crop keeps
+        // only the left half of the 40x80 image: blue above white.
+        let output = decode_output(&processed);
+        assert_pixel(&output, 10, 5, BLUE);
+        assert_pixel(&output, 10, 35, WHITE);
+
+        chained.operations = Some(vec![
+            Step::Resize {
+                width: 40,
+                height: 20,
+                object_mode: ObjectMode::Fill,
+            },
+            Step::Grayscale,
+        ]);
+        let gray =
+            decode_output(&ImageProcessor::resize(quadrants(80, 40), &chained, &limits()).unwrap());
+        let [r, g, b] = gray.get_pixel(30, 15).0;
+        assert!(r.abs_diff(g) < 8 && g.abs_diff(b) < 8);
+    }
+
+    #[test]
+    fn reject_if_larger_keeps_a_smaller_source_in_the_same_format() {
+        let source = jpeg_with_orientation(64, 64, 1);
+        let mut options = options(60, 60, ObjectMode::Fill);
+        options.encode.quality = 100;
+        options.reject_if_larger = true;
+
+        let processed = ImageProcessor::resize(source.clone(), &options, &limits()).unwrap();
+        assert!(processed.source_kept);
+        assert_eq!(processed.data, source);
+        assert_eq!((processed.width, processed.height), (64, 64));
+
+        // A PNG source can't stand in for JPEG output, however small.
+        let processed = ImageProcessor::resize(quadrants(64, 64), &options, &limits()).unwrap();
+        assert!(!processed.source_kept);
+        assert_eq!(processed.content_type, "image/jpeg");
+        assert_eq!((processed.width, processed.height), (60, 60));
+    }
+
+    #[test]
+    fn content_type_mismatch_prefers_the_sniffed_format() {
+        let png = quadrants(8, 8);
+        assert_eq!(
+            ImageProcessor::content_type_mismatch(Some("image/png"), &png),
+            None
+        );
+        assert_eq!(
+            ImageProcessor::content_type_mismatch(Some("image/jpeg; charset=binary"), &png)
+                .as_deref(),
+            Some("Source is labeled image/jpeg but its bytes are image/png; decoding as Png")
+        );
+        assert_eq!(ImageProcessor::content_type_mismatch(None, &png), None);
+        assert_eq!(
+            ImageProcessor::content_type_mismatch(Some("binary/octet-stream"), &png),
+            None
+        );
+        assert_eq!(
+            ImageProcessor::content_type_mismatch(Some("image/png"), b"not an image"),
+            None
+        );
+    }
+
+    #[test]
+    fn animation_sprite_sheet_takes_evenly_spaced_frames() {
+        let mut buffer = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut buffer);
+            for color in [RED, GREEN, BLUE, WHITE] {
+                let [r, g, b] = color;
+                let frame = RgbaImage::from_pixel(40, 20, Rgba([r, g, b, 255]));
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        let gif = Bytes::from(buffer);
+        let png = EncodeOptions {
+            format: OutputFormat::Png,
+            ..EncodeOptions::default()
+        };
+
+        let (sheet, frames) =
+            ImageProcessor::animation_sprite_sheet(&gif, Some(2), 100, 10, 10, 3, &png, &limits())
+                .unwrap();
+        assert_eq!(frames, [0, 2]);
+        // Only two cells, so the sheet is two columns wide rather than three.
+        assert_eq!((sheet.width, sheet.height), (20, 10));
+        let output = decode_output(&sheet);
+        assert_pixel(&output, 5, 5, RED);
+        assert_pixel(&output, 15, 5, BLUE);
+
+        let still = ImageProcessor::animation_sprite_sheet(
+            &quadrants(40, 20),
+            None,
+            100,
+            10,
+            10,
+            3,
+            &png,
+            &limits(),
+        );
+        assert!(matches!(still, Err(AppError::InvalidParameter(_))));
     }
 }
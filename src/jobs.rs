@@ -0,0 +1,139 @@
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    Json,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::handlers;
+use crate::models::{JobResponse, JobStatus, ResizeQuery, ResizeRequest, ResizeResponse};
+use crate::state::AppState;
+
+#[derive(Debug, Clone)]
+struct JobRecord {
+    status: JobStatus,
+    result: Option<ResizeResponse>,
+    error: Option<String>,
+    finished_at: Option<Instant>,
+}
+
+type Job = (Uuid, ResizeRequest);
+
+/// In-process queue for `POST /jobs`. Jobs are handed to a fixed pool of
+/// workers through a bounded channel; statuses live in memory and finished
+/// jobs are forgotten after `retention`.
+#[derive(Debug)]
+pub struct JobQueue {
+    sender: mpsc::Sender<Job>,
+    receiver: Arc<tokio::sync::Mutex<mpsc::Receiver<Job>>>,
+    jobs: Mutex<HashMap<Uuid, JobRecord>>,
+    retention: Duration,
+}
+
+impl JobQueue {
+    pub fn new(capacity: usize, retention: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+
+        Self {
+            sender,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+            jobs: Mutex::new(HashMap::new()),
+            retention,
+        }
+    }
+
+    pub fn enqueue(&self, request: ResizeRequest) -> Result<Uuid, AppError> {
+        let id = Uuid::new_v4();
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished_at| finished_at.elapsed() < self.retention)
+        });
+
+        self.sender.try_send((id, request)).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                AppError::ServiceUnavailable("Job queue is full, retry later".to_string())
+            }
+            mpsc::error::TrySendError::Closed(_) => {
+                AppError::InternalError("Job workers are not running".to_string())
+            }
+        })?;
+
+        jobs.insert(
+            id,
+            JobRecord {
+                status: JobStatus::Pending,
+                result: None,
+                error: None,
+                finished_at: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<JobResponse> {
+        self.jobs.lock().unwrap().get(&id).map(|job| JobResponse {
+            id,
+            status: job.status,
+            result: job.result.clone(),
+            error: job.error.clone(),
+        })
+    }
+
+    fn update(&self, id: Uuid, update: impl FnOnce(&mut JobRecord)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            update(job);
+        }
+    }
+}
+
+/// Starts `workers` tasks that pull jobs off the queue and run them through
+/// the same code path as `POST /resize`.
+pub fn spawn_workers(state: AppState, workers: usize) {
+    for _ in 0..workers.max(1) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = state.jobs.receiver.lock().await.recv().await;
+                let Some((id, request)) = next else {
+                    break;
+                };
+
+                state.jobs.update(id, |job| job.status = JobStatus::Running);
+                tracing::debug!("Running job {}", id);
+
+                // Run in its own task so a panicking job fails alone instead of
+                // taking the worker down with it.
+                // Jobs carry no caller deadline, so each gets the global timeout.
+                let outcome = tokio::spawn(handlers::resize_image(
+                    State(state.clone()),
+                    Query(ResizeQuery { debug: false }),
+                    HeaderMap::new(),
+                    Json(request),
+                ))
+                .await
+                .unwrap_or_else(|e| Err(AppError::InternalError(format!("Job panicked: {}", e))));
+
+                state.jobs.update(id, |job| {
+                    job.finished_at = Some(Instant::now());
+                    match outcome {
+                        Ok(Json(response)) => {
+                            job.status = JobStatus::Done;
+                            job.result = Some(response);
+                        }
+                        Err(e) => {
+                            tracing::warn!("Job {} failed: {}", id, e);
+                            job.status = JobStatus::Failed;
+                            job.error = Some(e.to_string());
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
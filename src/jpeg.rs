@@ -0,0 +1,77 @@
+//! JPEG encoding through mozjpeg, for builds with the `mozjpeg` feature.
+
+#[cfg(feature = "mozjpeg")]
+mod encoder {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use mozjpeg::{ColorSpace, Compress, PixelDensity, PixelDensityUnit};
+
+    use crate::error::AppError;
+    use crate::image_processor::EncodeOptions;
+    use crate::models::ChromaSubsampling;
+
+    /// Encodes packed RGB8 rows with mozjpeg's trellis quantization and
+    /// optimized Huffman tables. mozjpeg defaults to progressive output; a
+    /// baseline request turns the scan optimization, and with it the
+    /// progressive scans, off.
+    pub fn encode(
+        rgb: &[u8],
+        width: u32,
+        height: u32,
+        options: &EncodeOptions,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<Vec<u8>, AppError> {
+        // libjpeg reports errors by unwinding out of the C code.
+        catch_unwind(AssertUnwindSafe(|| {
+            let mut compress = Compress::new(ColorSpace::JCS_RGB);
+            compress.set_size(width as usize, height as usize);
+            compress.set_quality(options.quality as f32);
+            let (cb, cr) = match options.chroma_subsampling {
+                ChromaSubsampling::Yuv444 => ((1, 1), (1, 1)),
+                ChromaSubsampling::Yuv422 => ((2, 1), (2, 1)),
+                ChromaSubsampling::Yuv420 => ((2, 2), (2, 2)),
+            };
+            compress.set_chroma_sampling_pixel_sizes(cb, cr);
+            if !options.progressive {
+                compress.set_optimize_scans(false);
+            }
+            if let Some(dpi) = options.dpi {
+                compress.set_pixel_density(PixelDensity {
+                    unit: PixelDensityUnit::Inches,
+                    x: dpi,
+                    y: dpi,
+                });
+            }
+
+            let mut started = compress.start_compress(Vec::new())?;
+            if let Some(icc_profile) = icc_profile {
+                started.write_icc_profile(icc_profile);
+            }
+            started.write_scanlines(rgb)?;
+            started.finish()
+        }))
+        .map_err(|_| {
+            AppError::ImageProcessingError("mozjpeg failed to encode the image".to_string())
+        })?
+        .map_err(|e| AppError::ImageProcessingError(format!("Failed to encode image: {}", e)))
+    }
+}
+
+#[cfg(feature = "mozjpeg")]
+pub use encoder::encode as encode_mozjpeg;
+
+/// `Config` falls back to the built-in encoder in builds without mozjpeg,
+/// so this is never reached there.
+#[cfg(not(feature = "mozjpeg"))]
+pub fn encode_mozjpeg(
+    _rgb: &[u8],
+    _width: u32,
+    _height: u32,
+    _options: &crate::image_processor::EncodeOptions,
+    _icc_profile: Option<&[u8]>,
+) -> Result<Vec<u8>, crate::error::AppError> {
+    Err(crate::error::AppError::InternalError(
+        "JPEG_ENCODER=mozjpeg requires the service to be built with the `mozjpeg` feature"
+            .to_string(),
+    ))
+}
@@ -0,0 +1,27 @@
+//! The service's modules, shared by the server binary and the benchmarks in
+//! `benches/`.
+
+pub mod circuit_breaker;
+pub mod config;
+pub mod deadline;
+pub mod error;
+pub mod face;
+#[cfg(feature = "gcs")]
+pub mod gcs;
+pub mod handlers;
+pub mod image_processor;
+pub mod jobs;
+pub mod jpeg;
+pub mod log_sampling;
+pub mod models;
+pub mod openapi;
+pub mod rate_limit;
+pub mod readiness;
+pub mod resample;
+pub mod s3;
+pub mod single_flight;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod svg;
+pub mod video;
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Decides how routine success logs are emitted. One in every `every` successes
+/// is logged at info and the rest at debug, while anything slower than
+/// `slow_threshold` is always logged at warn.
+#[derive(Debug)]
+pub struct LogSampler {
+    every: u64,
+    slow_threshold: Duration,
+    counter: AtomicU64,
+}
+
+impl LogSampler {
+    pub fn new(every: u64, slow_threshold: Duration) -> Self {
+        Self {
+            every: every.max(1),
+            slow_threshold,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        elapsed >= self.slow_threshold
+    }
+
+    pub fn should_log(&self) -> bool {
+        self.counter
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every)
+    }
+}
+
+/// Logs a request's success message through a `LogSampler`: warn when the
+/// request was slow, info when sampled, debug otherwise. Accepts the same
+/// fields and format arguments as the `tracing` macros.
+macro_rules! log_sampled {
+    ($sampler:expr, $elapsed:expr, $($arg:tt)+) => {{
+        let elapsed: std::time::Duration = $elapsed;
+        let elapsed_ms = elapsed.as_millis() as u64;
+        if $sampler.is_slow(elapsed) {
+            tracing::warn!(elapsed_ms, slow = true, $($arg)+);
+        } else if $sampler.should_log() {
+            tracing::info!(elapsed_ms, $($arg)+);
+        } else {
+            tracing::debug!(elapsed_ms, $($arg)+);
+        }
+    }};
+}
+
+pub(crate) use log_sampled;
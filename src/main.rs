@@ -1,35 +1,182 @@
-mod handlers;
-mod models;
-mod s3;
-mod image_processor;
-mod error;
-
 use axum::{
-    routing::post,
+    extract::DefaultBodyLimit,
+    http::{header, Extensions, HeaderMap, StatusCode, Version},
+    middleware,
+    routing::{get, post},
     Router,
 };
+use std::net::SocketAddr;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[tokio::main]
-async fn main() {
+use image_resizer::config::Config;
+use image_resizer::image_processor::JpegEncoder;
+use image_resizer::state::AppState;
+use image_resizer::{handlers, jobs, rate_limit, resample, s3};
+
+/// JSON bodies smaller than this aren't worth the compression overhead.
+const MIN_COMPRESSED_RESPONSE_BYTES: u16 = 1024;
+
+fn main() {
+    // RUST_LOG takes full filter directives; LOG_LEVEL is the simpler knob,
+    // e.g. LOG_LEVEL=info to drop the debug-level per-operation logs.
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "image_resizer=debug,tower_http=debug".into()),
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                match std::env::var("LOG_LEVEL") {
+                    Ok(level) => format!("image_resizer={},tower_http={}", level, level).into(),
+                    Err(_) => "image_resizer=debug,tower_http=debug".into(),
+                }
+            }),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Built by hand rather than with #[tokio::main] so the pool sizes come from
+    // the environment. The blocking pool bounds how many resizes decode at once.
+    let config = Config::from_env();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .max_blocking_threads(config.max_blocking_threads)
+        .enable_all()
+        .build()
+        .expect("Failed to build the Tokio runtime");
+    tracing::info!(
+        "Tokio runtime with {} worker threads and up to {} blocking threads",
+        config.worker_threads,
+        config.max_blocking_threads
+    );
+    if config.resize_threads > 1 {
+        resample::set_threads(config.resize_threads);
+        tracing::info!(
+            "Splitting each resample across {} shared threads",
+            config.resize_threads
+        );
+    }
+
+    runtime.block_on(serve(config));
+}
+
+async fn serve(config: Config) {
+    // Before anything parses a source URL.
+    if !config.custom_domain_buckets.is_empty() {
+        s3::set_custom_domains(config.custom_domain_buckets.clone());
+        tracing::info!(
+            "Reading source URLs on {} custom domains",
+            config.custom_domain_buckets.len()
+        );
+    }
+    let state = AppState::new(config);
+    match &state.config.s3_file {
+        Some(file) => tracing::info!(
+            "Using S3 settings from S3_CONFIG_FILE: region {}, endpoint {}",
+            file.region,
+            file.endpoint.as_deref().unwrap_or("AWS default")
+        ),
+        None => tracing::info!("Using S3 region {}", s3::default_region()),
+    }
+    if let Some(version) = &state.config.cache_version {
+        tracing::info!("Derivative keys are tagged with CACHE_VERSION {}", version);
+    }
+    if let Some(decodes) = state.config.max_concurrent_decodes {
+        tracing::info!("Running at most {} decodes at once", decodes);
+    }
+    if state.config.jpeg_encoder == JpegEncoder::Mozjpeg {
+        tracing::info!("Encoding JPEG output with mozjpeg");
+    }
+    if !state.config.avif_fallback.is_empty() {
+        let formats: Vec<_> = state
+            .config
+            .avif_fallback
+            .iter()
+            .map(|f| f.as_str())
+            .collect();
+        tracing::info!("Failed AVIF encodes fall back to {}", formats.join(", "));
+    }
+    if let Some(rps) = state.config.rate_limit_rps {
+        tracing::info!(
+            "Rate limiting each client to {} requests/s with bursts of {}",
+            rps,
+            state.config.rate_limit_burst
+        );
+    }
+    jobs::spawn_workers(state.clone(), state.config.job_workers);
+    if let (true, Some(bucket)) = (
+        state.config.readiness_write_check,
+        state.config.readiness_bucket.clone(),
+    ) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let s3_client = state.s3_client().await;
+            state.readiness.run_write_check(&s3_client, &bucket).await;
+        });
+    }
+    // Leave headroom over the image part for the multipart framing and form fields.
+    let upload_body_limit = state.config.max_upload_bytes + 64 * 1024;
+
     let app = Router::new()
-        .route("/resize", post(handlers::resize_image))
-        .layer(TraceLayer::new_for_http());
+        .route("/health", get(handlers::health))
+        .route("/ready", get(handlers::ready))
+        .route("/stats", get(handlers::stats))
+        .route("/openapi.json", get(handlers::openapi))
+        .route(
+            "/resize",
+            post(handlers::resize_image).get(handlers::resize_inline),
+        )
+        .route("/resize/raw", get(handlers::resize_raw))
+        .route("/resize-multi-dest", post(handlers::resize_multi_dest))
+        .route("/resize-multi-size", post(handlers::resize_multi_size))
+        .route("/phash", post(handlers::phash))
+        .route("/aspect-crop", post(handlers::aspect_crop))
+        .route("/convert", post(handlers::convert))
+        .route("/collage", post(handlers::collage))
+        .route("/montage", post(handlers::montage))
+        .route("/sprite", post(handlers::sprite))
+        .route("/tiles", post(handlers::tiles))
+        .route("/verify", post(handlers::verify))
+        .route("/jobs", post(handlers::create_job))
+        .route("/jobs/:id", get(handlers::get_job))
+        .route("/parse-url", post(handlers::parse_url))
+        .route(
+            "/resize/upload",
+            post(handlers::resize_upload).layer(DefaultBodyLimit::max(upload_body_limit)),
+        )
+        .layer(
+            CompressionLayer::new()
+                .compress_when(SizeAbove::new(MIN_COMPRESSED_RESPONSE_BYTES).and(is_json_response)),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::enforce,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .unwrap();
-    
     tracing::info!("Server listening on {}", listener.local_addr().unwrap());
-    
-    axum::serve(listener, app).await.unwrap();
+
+    // The peer address keys the rate limiter for clients without an API key.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Only JSON responses are compressed; the image bytes returned by
+/// `GET /resize` and `POST /resize/upload` are already compressed.
+fn is_json_response(
+    _status: StatusCode,
+    _version: Version,
+    headers: &HeaderMap,
+    _extensions: &Extensions,
+) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
 }
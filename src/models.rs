@@ -1,32 +1,2067 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
-#[derive(Debug, Deserialize)]
+use crate::circuit_breaker::BreakerState;
+use crate::error::{reject_problems, AppError};
+
+const MAX_DPR: u32 = 4;
+const MAX_LQIP_SIZE: u32 = 64;
+const MAX_WATERMARK_TEXT_LENGTH: usize = 200;
+const MAX_OPERATIONS: usize = 20;
+const MAX_OUTPUT_NAME_LENGTH: usize = 128;
+const MAX_SNAP_TO: u32 = 256;
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
 pub struct ResizeRequest {
     pub s3_url: String,
-    pub width: u32,
-    pub height: u32,
-    #[serde(default = "default_object_mode")]
-    pub object_mode: ObjectMode,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Alternative to `width`/`height`: the largest size under this many
+    /// megapixels that keeps the source aspect ratio.
+    pub max_megapixels: Option<f64>,
+    /// Alternative to `width`/`height`: a box the output fits within, keeping
+    /// the source aspect ratio. Either side may be left open.
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Shorthand for `width` and `height` both set to this, e.g. for avatars.
+    pub square: Option<u32>,
+    /// Rounds the output width and height to multiples of this many pixels,
+    /// so nearby sizes share one variant.
+    pub snap_to: Option<u32>,
+    /// With `square`, fits the whole source inside the square and pads the
+    /// rest with `square_background` instead of cropping it.
+    #[serde(default)]
+    pub square_pad: bool,
+    /// Padding color for `square_pad`; white when unset.
+    pub square_background: Option<HexColor>,
+    /// Defaults to `DEFAULT_OBJECT_MODE`.
+    pub object_mode: Option<ObjectMode>,
+    /// With `fill`, logs a warning when the output's aspect ratio differs
+    /// from the source's by more than this share, e.g. `0.05` for 5%.
+    pub warn_on_distortion: Option<f64>,
+    /// Rejects such resizes with a `400` instead of logging them.
+    #[serde(default)]
+    pub reject_distortion: bool,
+    pub dpr: Option<u32>,
+    #[serde(default)]
+    pub include_stats: bool,
+    /// Adds the source's perceptual hash to the response, as `POST /phash`.
+    #[serde(default)]
+    pub include_phash: bool,
+    /// Adds a BlurHash placeholder string of the source to the response.
+    #[serde(default)]
+    pub include_blurhash: bool,
+    #[serde(default)]
+    pub content_hash: bool,
+    #[serde(default)]
+    pub write_sidecar: bool,
+    pub min_source_dimension: Option<u32>,
+    /// A rectangle of the source to resize instead of the whole image.
+    pub crop: Option<CropRect>,
+    pub canvas: Option<CanvasOptions>,
+    /// Bucket to write the derivative to, as `s3://bucket` or `gs://bucket`.
+    /// Defaults to the source bucket.
+    pub output_bucket: Option<String>,
+    #[serde(default)]
+    pub chroma_subsampling: ChromaSubsampling,
+    pub preset: Option<Preset>,
+    pub filter: Option<ResizeFilter>,
+    pub quality: Option<u8>,
+    pub progressive: Option<bool>,
+    /// Pixel density written to the output's metadata, for print workflows:
+    /// JFIF density in JPEG, pHYs in PNG. Doesn't change the pixels.
+    pub dpi: Option<u16>,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Centers `cover` crops on the largest detected face. Needs the
+    /// `face-detect` feature.
+    #[serde(default)]
+    pub face_crop: bool,
+    /// With a `webp` or `avif` output format, also stores a JPEG of the same
+    /// pixels under the `.jpg` key, for `<picture>` fallbacks.
+    #[serde(default)]
+    pub with_fallback: bool,
+    /// Keeps the source key's extension whatever the output format, for
+    /// consumers that key off it. The object's Content-Type stays correct.
+    #[serde(default)]
+    pub preserve_extension: bool,
+    /// File name for the derivative in place of the source's, e.g. an SEO
+    /// slug. Characters other than letters, digits, `-` and `_` become `-`.
+    pub output_name: Option<String>,
+    #[serde(default)]
+    pub color_profile: ColorProfile,
+    /// Color transparent source pixels are composited over, since the output
+    /// formats have no alpha channel. Its own alpha is ignored.
+    #[serde(default = "default_alpha_background")]
+    pub alpha_background: HexColor,
+    /// Also returns a tiny blurred placeholder of the derivative as a data URI.
+    pub lqip: Option<LqipOptions>,
+    /// Text drawn over the derivative. Off by default.
+    pub text_watermark: Option<TextWatermark>,
+    /// Steps applied in order instead of the size, `object_mode`, `crop`,
+    /// `canvas` and `text_watermark` fields, e.g. rotating before cropping.
+    pub operations: Option<Vec<Operation>>,
+    /// Seconds into a video source to take the frame from; the first frame
+    /// when unset.
+    pub video_timestamp: Option<f64>,
+    /// Zero-based frame of an animated GIF, PNG or WebP source to resize
+    /// instead of the first; past the end selects the last frame. Still
+    /// images have only frame 0.
+    pub frame_index: Option<u32>,
+    /// S3 object tags applied to the derivative after upload.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Skips the cache shortcut and overwrites any existing derivative.
+    #[serde(default)]
+    pub force: bool,
+    /// Stores the source bytes instead when the encoded derivative would be
+    /// larger and the source is already in the output format.
+    #[serde(default)]
+    pub reject_if_larger: bool,
+    /// Canned ACL for the uploaded derivative; overrides `UPLOAD_ACL`.
+    pub acl: Option<ObjectAcl>,
+    /// S3 storage class for the derivative; overrides `STORAGE_CLASS`.
+    pub storage_class: Option<StorageClass>,
+    /// Pays for reading the source, for requester-pays buckets; overrides
+    /// `S3_REQUESTER_PAYS`.
+    pub requester_pays: Option<bool>,
 }
 
-fn default_object_mode() -> ObjectMode {
-    ObjectMode::Cover
+impl ResizeRequest {
+    /// Explicit `filter` wins over the preset, which wins over the default.
+    pub fn resolved_filter(&self) -> ResizeFilter {
+        self.filter
+            .or(self.preset.map(|p| p.filter()))
+            .unwrap_or_default()
+    }
+
+    pub fn resolved_quality(&self, default: u8) -> u8 {
+        self.quality
+            .or(self.preset.map(|p| p.quality()))
+            .unwrap_or(default)
+    }
+
+    pub fn resolved_progressive(&self) -> bool {
+        self.progressive
+            .or(self.preset.map(|p| p.progressive()))
+            .unwrap_or(false)
+    }
+
+    /// Rewrites `square` as the `width`/`height` request it stands for; with
+    /// `square_pad`, a `contain` resize centered on a square canvas. Done
+    /// after validation, which checks `square` against the other parameters.
+    pub fn expand_square(mut self) -> Self {
+        let Some(size) = self.square.take() else {
+            return self;
+        };
+        self.width = Some(size);
+        self.height = Some(size);
+        if self.square_pad {
+            self.object_mode = Some(ObjectMode::Contain);
+            self.canvas = Some(CanvasOptions {
+                width: size,
+                height: size,
+                background: self
+                    .square_background
+                    .unwrap_or_else(default_canvas_background),
+                gravity: Gravity::Center,
+                x: None,
+                y: None,
+            });
+        }
+        self
+    }
+
+    /// `output_name` as it appears in the key, or `None` when unset.
+    pub fn output_stem(&self) -> Option<String> {
+        self.output_name.as_deref().map(sanitize_output_name)
+    }
+
+    /// `width`x`height` rounded to `snap_to`. An explicit size or `square` is a
+    /// box, so both sides round to the nearest multiple (halves up), never
+    /// below one step. `max_megapixels` and `max_width`/`max_height` give the
+    /// source's fitted size: only its long side rounds, down so the caps still
+    /// hold, and the short side follows it to keep the aspect ratio.
+    pub fn snapped(&self, width: u32, height: u32) -> (u32, u32) {
+        let Some(step) = self.snap_to.filter(|&step| step > 1) else {
+            return (width, height);
+        };
+        if self.width.is_some() || self.square.is_some() {
+            let snap = |value: u32| (value.saturating_add(step / 2) / step * step).max(step);
+            return (snap(width), snap(height));
+        }
+
+        // A long side under one step can't round down, so it is kept.
+        let long = width.max(height).max(1);
+        let snapped = match long / step * step {
+            0 => long,
+            snapped => snapped,
+        };
+        let follow = |value: u32| {
+            ((value as u64 * snapped as u64 + long as u64 / 2) / long as u64).max(1) as u32
+        };
+        if width >= height {
+            (snapped, follow(height))
+        } else {
+            (follow(width), snapped)
+        }
+    }
+
+    /// True when none of the ways of choosing the output size was used.
+    pub fn omits_size(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.square.is_none()
+            && self.max_megapixels.is_none()
+            && self.max_width.is_none()
+            && self.max_height.is_none()
+            && self.operations.is_none()
+    }
+
+    /// The output size `operations` asks for: the last `resize` or `crop`,
+    /// turned by any rotation after it. `None` without either.
+    pub fn operations_size(&self) -> Option<(u32, u32)> {
+        self.operations
+            .iter()
+            .flatten()
+            .fold(None, |size, operation| match operation {
+                Operation::Resize(resize) => Some((resize.width, resize.height)),
+                Operation::Crop(crop) => Some((crop.width, crop.height)),
+                Operation::Rotate(90 | 270) => size.map(|(width, height)| (height, width)),
+                _ => size,
+            })
+    }
+
+    /// Object mode of the last `resize` in `operations`.
+    pub fn operations_object_mode(&self) -> Option<ObjectMode> {
+        self.operations
+            .iter()
+            .flatten()
+            .rev()
+            .find_map(|operation| match operation {
+                Operation::Resize(resize) => Some(resize.object_mode),
+                _ => None,
+            })?
+    }
+
+    /// Takes the size and any other fields the request left out from the
+    /// source's `resize-*` user metadata, e.g. `x-amz-meta-resize-width`.
+    /// Only used when the request `omits_size`, so a size in the metadata
+    /// never mixes with one in the request. Values that don't parse are
+    /// reported together; unknown keys are ignored.
+    pub fn with_metadata_defaults(
+        mut self,
+        metadata: &HashMap<String, String>,
+    ) -> Result<Self, AppError> {
+        let mut problems = Vec::new();
+        self.width = metadata_value(metadata, "resize-width", &mut problems, parse_number);
+        self.height = metadata_value(metadata, "resize-height", &mut problems, parse_number);
+        self.max_width = metadata_value(metadata, "resize-max-width", &mut problems, parse_number);
+        self.max_height =
+            metadata_value(metadata, "resize-max-height", &mut problems, parse_number);
+        // Explicit fields win, and then their metadata isn't even parsed.
+        self.object_mode = self
+            .object_mode
+            .or_else(|| metadata_value(metadata, "resize-object-mode", &mut problems, parse_name));
+        self.filter = self
+            .filter
+            .or_else(|| metadata_value(metadata, "resize-filter", &mut problems, parse_name));
+        self.quality = self
+            .quality
+            .or_else(|| metadata_value(metadata, "resize-quality", &mut problems, parse_number));
+
+        reject_problems(problems)?;
+        Ok(self)
+    }
+
+    /// Checks ranges and rejects parameters that contradict each other, so
+    /// a request never silently loses one of them. Every problem is reported
+    /// in one error; checks that depend on the build or the configuration are
+    /// left to the handlers.
+    pub fn validate(&self) -> Result<(), AppError> {
+        reject_problems(self.problems())
+    }
+
+    /// Everything `validate` would reject, in field order.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match &self.operations {
+            Some(operations) => self.operation_problems(operations, &mut problems),
+            None => self.size_problems(&mut problems),
+        }
+
+        if self
+            .warn_on_distortion
+            .is_some_and(|threshold| !threshold.is_finite() || threshold < 0.0)
+        {
+            problems.push("warn_on_distortion must be a non-negative number".to_string());
+        }
+        if self.reject_distortion && self.warn_on_distortion.is_none() {
+            problems.push("reject_distortion requires warn_on_distortion".to_string());
+        }
+        if self.warn_on_distortion.is_some()
+            && self
+                .object_mode
+                .is_some_and(|mode| !matches!(mode, ObjectMode::Fill))
+        {
+            problems.push(
+                "warn_on_distortion only applies to object_mode fill, the only mode that stretches"
+                    .to_string(),
+            );
+        }
+        if self.dpr.is_some_and(|dpr| dpr == 0 || dpr > MAX_DPR) {
+            problems.push(format!("dpr must be between 1 and {}", MAX_DPR));
+        }
+        if self
+            .quality
+            .is_some_and(|quality| !(1..=100).contains(&quality))
+        {
+            problems.push("quality must be between 1 and 100".to_string());
+        }
+        if self.dpi == Some(0) {
+            problems.push("dpi must be greater than 0".to_string());
+        }
+        if self
+            .video_timestamp
+            .is_some_and(|timestamp| !timestamp.is_finite() || timestamp < 0.0)
+        {
+            problems.push("video_timestamp must be a non-negative number of seconds".to_string());
+        }
+        if self.video_timestamp.is_some() && self.frame_index.is_some() {
+            problems.push(
+                "video_timestamp and frame_index can't be combined; use video_timestamp for videos"
+                    .to_string(),
+            );
+        }
+        if let Some(crop) = &self.crop {
+            if crop.width == 0 || crop.height == 0 {
+                problems.push("crop width and height must be greater than 0".to_string());
+            }
+        }
+        if let Some(canvas) = &self.canvas {
+            if canvas.width == 0 || canvas.height == 0 {
+                problems.push("Canvas width and height must be greater than 0".to_string());
+            }
+        }
+        if let Some(lqip) = &self.lqip {
+            lqip.problems(&mut problems);
+        }
+        if let Some(step) = self.snap_to {
+            if step == 0 || step > MAX_SNAP_TO {
+                problems.push(format!("snap_to must be between 1 and {}", MAX_SNAP_TO));
+            }
+            if self.operations.is_some() || self.canvas.is_some() || self.square_pad {
+                problems.push(
+                    "snap_to can't be combined with operations, canvas or square_pad, which need their exact sizes".to_string(),
+                );
+            }
+        }
+        if let Some(name) = &self.output_name {
+            if name.contains(['/', '\\']) {
+                problems.push("output_name is a file name and cannot contain / or \\".to_string());
+            } else if name.chars().count() > MAX_OUTPUT_NAME_LENGTH {
+                problems.push(format!(
+                    "output_name must be at most {} characters",
+                    MAX_OUTPUT_NAME_LENGTH
+                ));
+            } else if sanitize_output_name(name).is_empty() {
+                problems.push("output_name must contain a letter or digit".to_string());
+            }
+        }
+        if let Some(watermark) = &self.text_watermark {
+            watermark.problems(&mut problems);
+        }
+
+        if self.face_crop && !matches!(self.object_mode, None | Some(ObjectMode::Cover)) {
+            problems.push(
+                "face_crop positions a cover crop and cannot be combined with another object_mode"
+                    .to_string(),
+            );
+        }
+        if self.reject_if_larger
+            && (self.crop.is_some()
+                || self.canvas.is_some()
+                || self.square_pad
+                || self.text_watermark.is_some()
+                || self.operations.is_some()
+                || self.frame_index.is_some())
+        {
+            problems.push(
+                "reject_if_larger can't be combined with crop, canvas, square_pad, text_watermark, operations or frame_index, which the source bytes wouldn't show".to_string(),
+            );
+        }
+        if self.with_fallback
+            && !matches!(self.output_format, OutputFormat::Webp | OutputFormat::Avif)
+        {
+            problems.push(
+                "with_fallback pairs a webp or avif output_format with a JPEG; set output_format to one of those".to_string(),
+            );
+        }
+        if self.output_format != OutputFormat::Jpeg
+            && (self.progressive == Some(true)
+                || self.chroma_subsampling != ChromaSubsampling::default()
+                || self.color_profile != ColorProfile::default())
+        {
+            problems.push(
+                "progressive, chroma_subsampling and color_profile=preserve only apply to JPEG output".to_string(),
+            );
+        }
+        if self.dpi.is_some()
+            && !matches!(self.output_format, OutputFormat::Jpeg | OutputFormat::Png)
+        {
+            problems.push("dpi only applies to JPEG and PNG output".to_string());
+        }
+
+        problems
+    }
+
+    /// Exactly one way of choosing the output size: `width` and `height`,
+    /// `square`, `max_megapixels`, or `max_width`/`max_height`. The last two
+    /// keep the source aspect ratio, so they also rule out `object_mode`,
+    /// `dpr` and `face_crop`.
+    fn size_problems(&self, problems: &mut Vec<String>) {
+        self.square_problems(problems);
+        let exact = self.width.is_some() || self.height.is_some() || self.square.is_some();
+        let megapixels = self.max_megapixels.is_some();
+        let bounded = self.max_width.is_some() || self.max_height.is_some();
+
+        let conflict = match (exact, megapixels, bounded) {
+            (true, true, _) => Some("max_megapixels cannot be combined with width/height"),
+            (true, _, true) => Some("max_width/max_height cannot be combined with width/height"),
+            (_, true, true) => Some("max_megapixels cannot be combined with max_width/max_height"),
+            (false, false, false) => {
+                Some("Provide width and height, square, max_megapixels, or max_width/max_height")
+            }
+            _ => None,
+        };
+        // The remaining checks assume a single way of sizing.
+        if let Some(conflict) = conflict {
+            problems.push(conflict.to_string());
+            return;
+        }
+
+        if let Some(square) = self.square {
+            if self.width.is_some() || self.height.is_some() {
+                problems.push("square cannot be combined with width/height".to_string());
+            } else if square == 0 {
+                problems.push("square must be greater than 0".to_string());
+            }
+            return;
+        }
+        if exact {
+            match (self.width, self.height) {
+                (Some(width), Some(height)) if width > 0 && height > 0 => {}
+                (Some(_), Some(_)) => {
+                    problems.push("width and height must be greater than 0".to_string())
+                }
+                _ => problems.push("width and height must be given together".to_string()),
+            }
+            return;
+        }
+
+        let name = if megapixels {
+            if self
+                .max_megapixels
+                .is_some_and(|max| !max.is_finite() || max <= 0.0)
+            {
+                problems.push("max_megapixels must be a positive number".to_string());
+            }
+            "max_megapixels"
+        } else {
+            if self.max_width == Some(0) || self.max_height == Some(0) {
+                problems.push("max_width and max_height must be greater than 0".to_string());
+            }
+            "max_width/max_height"
+        };
+        if self.object_mode.is_some() {
+            problems.push(format!(
+                "object_mode cannot be combined with {}, which keeps the source aspect ratio",
+                name
+            ));
+        }
+        if self.dpr.is_some_and(|dpr| dpr > 1) {
+            problems.push(format!("dpr cannot be combined with {}", name));
+        }
+        if self.warn_on_distortion.is_some() {
+            problems.push(format!(
+                "warn_on_distortion cannot be combined with {}, which keeps the source aspect ratio",
+                name
+            ));
+        }
+        if self.crop.is_some() {
+            problems.push(format!(
+                "crop cannot be combined with {}, which is measured on the whole source",
+                name
+            ));
+        }
+        if self.face_crop {
+            problems.push(format!(
+                "face_crop cannot be combined with {}, which never crops",
+                name
+            ));
+        }
+    }
+
+    /// `operations` stands in for the size and the steps around the resize,
+    /// so it rules those fields out, and it must resize somewhere.
+    fn operation_problems(&self, operations: &[Operation], problems: &mut Vec<String>) {
+        let replaced: Vec<&str> = [
+            ("width", self.width.is_some()),
+            ("height", self.height.is_some()),
+            ("square", self.square.is_some()),
+            ("max_megapixels", self.max_megapixels.is_some()),
+            ("max_width", self.max_width.is_some()),
+            ("max_height", self.max_height.is_some()),
+            ("object_mode", self.object_mode.is_some()),
+            ("crop", self.crop.is_some()),
+            ("canvas", self.canvas.is_some()),
+            ("text_watermark", self.text_watermark.is_some()),
+            ("face_crop", self.face_crop),
+            ("warn_on_distortion", self.warn_on_distortion.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if !replaced.is_empty() {
+            problems.push(format!(
+                "{} can't be combined with operations; express them as operations instead",
+                replaced.join(", ")
+            ));
+        }
+        if self.dpr.is_some_and(|dpr| dpr > 1) {
+            problems.push("dpr cannot be combined with operations".to_string());
+        }
+
+        if operations.is_empty() || operations.len() > MAX_OPERATIONS {
+            problems.push(format!(
+                "operations must list between 1 and {} operations",
+                MAX_OPERATIONS
+            ));
+        }
+        if !operations
+            .iter()
+            .any(|operation| matches!(operation, Operation::Resize(_)))
+        {
+            problems.push("operations must include a resize".to_string());
+        }
+        for (index, operation) in operations.iter().enumerate() {
+            match operation {
+                Operation::Rotate(degrees) if !matches!(degrees, 90 | 180 | 270) => problems.push(
+                    format!("operations[{}].rotate must be 90, 180 or 270", index),
+                ),
+                Operation::Crop(crop) if crop.width == 0 || crop.height == 0 => {
+                    problems.push(format!(
+                        "operations[{}].crop width and height must be greater than 0",
+                        index
+                    ))
+                }
+                Operation::Resize(resize) if resize.width == 0 || resize.height == 0 => problems
+                    .push(format!(
+                        "operations[{}].resize width and height must be greater than 0",
+                        index
+                    )),
+                Operation::Watermark(watermark) => watermark.problems(problems),
+                _ => {}
+            }
+        }
+    }
+
+    /// `square_pad` chooses the fit and the canvas itself, so it rules out
+    /// the parameters it would override.
+    fn square_problems(&self, problems: &mut Vec<String>) {
+        if !self.square_pad {
+            if self.square_background.is_some() {
+                problems.push("square_background only applies with square_pad".to_string());
+            }
+            return;
+        }
+        if self.square.is_none() {
+            problems.push("square_pad requires square".to_string());
+        }
+        if self.object_mode.is_some() || self.face_crop {
+            problems.push(
+                "square_pad fits the whole source and cannot be combined with object_mode or face_crop"
+                    .to_string(),
+            );
+        }
+        if self.canvas.is_some() {
+            problems.push("square_pad cannot be combined with canvas".to_string());
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+/// Reduces `name` to letters, digits, `-` and `_`, so it can't add a
+/// directory, an extension or a character that needs escaping in a URL:
+/// `Red Running Shoe!` becomes `Red-Running-Shoe`. Runs of other characters
+/// become a single `-`, and separators are trimmed from the ends.
+fn sanitize_output_name(name: &str) -> String {
+    let mut stem = String::with_capacity(name.len());
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            stem.push(c);
+        } else if !stem.ends_with('-') {
+            stem.push('-');
+        }
+    }
+    stem.trim_matches(['-', '_']).to_string()
+}
+
+/// Parses user metadata value `name`, noting it in `problems` when it's
+/// present but invalid.
+fn metadata_value<T>(
+    metadata: &HashMap<String, String>,
+    name: &str,
+    problems: &mut Vec<String>,
+    parse: fn(&str) -> Option<T>,
+) -> Option<T> {
+    let value = metadata.get(name)?.trim();
+    let parsed = parse(value);
+    if parsed.is_none() {
+        problems.push(format!(
+            "source metadata x-amz-meta-{} has an invalid value '{}'",
+            name, value
+        ));
+    }
+    parsed
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+/// Parses a value the way the same field is read from a JSON body.
+fn parse_name<T: DeserializeOwned>(value: &str) -> Option<T> {
+    T::deserialize(value.into_deserializer())
+        .map_err(|_: serde::de::value::Error| ())
+        .ok()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum ObjectMode {
     Cover,
     Contain,
     Fill,
     ScaleDown,
+    /// Scales to cover the box like `Cover` but skips the crop, so the output is
+    /// at least `width x height` and larger on one axis when the aspects differ.
+    Outside,
+}
+
+impl ObjectMode {
+    /// Key suffix for the derivative. `outside` outputs are larger than the
+    /// requested box, so they get their own variant; the other modes keep the
+    /// historical key.
+    pub fn key_suffix(self) -> Option<&'static str> {
+        match self {
+            ObjectMode::Outside => Some("outside"),
+            ObjectMode::Cover | ObjectMode::Contain | ObjectMode::Fill | ObjectMode::ScaleDown => {
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+    /// Lanczos3, preceded by a box-filter pass for large reductions.
+    Auto,
+}
+
+impl ResizeFilter {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmull_rom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+            ResizeFilter::Auto => "auto",
+        }
+    }
+
+    /// CPU cost per pixel relative to Lanczos3, following the kernel's support
+    /// radius (Nearest has no kernel at all). `Auto` is costed as Lanczos3,
+    /// which it is for modest reductions; the estimate doesn't know the ratio.
+    pub fn relative_cost(self) -> f64 {
+        match self {
+            ResizeFilter::Nearest => 0.1,
+            ResizeFilter::Triangle => 1.0 / 3.0,
+            ResizeFilter::CatmullRom => 2.0 / 3.0,
+            ResizeFilter::Gaussian | ResizeFilter::Lanczos3 | ResizeFilter::Auto => 1.0,
+        }
+    }
+}
+
+/// Named bundles of filter and encoder settings for callers that don't want
+/// to tune each knob.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Fast,
+    Balanced,
+    Best,
+}
+
+impl Preset {
+    pub fn filter(self) -> ResizeFilter {
+        match self {
+            Preset::Fast => ResizeFilter::Triangle,
+            Preset::Balanced => ResizeFilter::CatmullRom,
+            Preset::Best => ResizeFilter::Lanczos3,
+        }
+    }
+
+    pub fn quality(self) -> u8 {
+        match self {
+            Preset::Fast => 60,
+            Preset::Balanced => 80,
+            Preset::Best => 90,
+        }
+    }
+
+    pub fn progressive(self) -> bool {
+        matches!(self, Preset::Best)
+    }
+}
+
+/// Encoding of the derivative. AVIF needs a build with the `avif` feature.
+/// PNG and WebP are lossless and keep transparency, e.g. for icons rendered
+/// from SVG. `auto` encodes each of `AUTO_FORMAT_CANDIDATES` and keeps the
+/// smallest.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Avif,
+    Png,
+    Webp,
+    Auto,
+}
+
+impl OutputFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Auto => "auto",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            // Resolved to one of the candidates before anything is stored.
+            OutputFormat::Auto => "application/octet-stream",
+        }
+    }
+}
+
+/// What happens to the source's embedded ICC profile.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorProfile {
+    /// Converts the pixels to sRGB and drops the profile, so browsers that
+    /// assume sRGB show the intended colors.
+    #[default]
+    Srgb,
+    /// Keeps the source pixels and embeds the original profile in the output.
+    Preserve,
+}
+
+impl ColorProfile {
+    /// Key suffix for the derivative. sRGB conversion is the default and keeps
+    /// the historical key.
+    pub fn key_suffix(self) -> Option<&'static str> {
+        match self {
+            ColorProfile::Srgb => None,
+            ColorProfile::Preserve => Some("icc"),
+        }
+    }
+}
+
+/// Chroma subsampling used for JPEG output. 4:4:4 keeps full color resolution,
+/// which avoids color bleeding around text and sharp edges at the cost of size.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "4:4:4")]
+    Yuv444,
+    #[serde(rename = "4:2:2")]
+    Yuv422,
+    #[default]
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+}
+
+impl ChromaSubsampling {
+    /// Key suffix for the derivative. 4:2:0 is the default and keeps its
+    /// historical key; other modes get their own variant.
+    pub fn key_suffix(self) -> Option<&'static str> {
+        match self {
+            ChromaSubsampling::Yuv444 => Some("444"),
+            ChromaSubsampling::Yuv422 => Some("422"),
+            ChromaSubsampling::Yuv420 => None,
+        }
+    }
+}
+
+/// Canned ACL applied to uploaded objects, named as in the S3 API.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ObjectAcl {
+    Private,
+    PublicRead,
+    PublicReadWrite,
+    AuthenticatedRead,
+    BucketOwnerRead,
+    BucketOwnerFullControl,
+}
+
+impl ObjectAcl {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectAcl::Private => "private",
+            ObjectAcl::PublicRead => "public-read",
+            ObjectAcl::PublicReadWrite => "public-read-write",
+            ObjectAcl::AuthenticatedRead => "authenticated-read",
+            ObjectAcl::BucketOwnerRead => "bucket-owner-read",
+            ObjectAcl::BucketOwnerFullControl => "bucket-owner-full-control",
+        }
+    }
+}
+
+/// S3 storage classes derivatives can be written with. Archive classes
+/// (GLACIER, DEEP_ARCHIVE) are left out on purpose: their objects can't be read
+/// back without a restore, which would break cached responses.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StorageClass {
+    Standard,
+    StandardIa,
+    OnezoneIa,
+    IntelligentTiering,
+    GlacierIr,
+    ReducedRedundancy,
+}
+
+impl StorageClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::StandardIa => "STANDARD_IA",
+            StorageClass::OnezoneIa => "ONEZONE_IA",
+            StorageClass::IntelligentTiering => "INTELLIGENT_TIERING",
+            StorageClass::GlacierIr => "GLACIER_IR",
+            StorageClass::ReducedRedundancy => "REDUCED_REDUNDANCY",
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
+/// A rectangle of the source, in pixels of the upright image (after the EXIF
+/// orientation is applied), that is cut out before resizing.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// What happens when the rectangle doesn't fit inside the source.
+    #[serde(default)]
+    pub on_invalid: InvalidCropPolicy,
+}
+
+/// Handling of a crop rectangle that reaches past the source, e.g. one drawn
+/// on an earlier upload of a different size.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidCropPolicy {
+    /// Rejects the request.
+    #[default]
+    Error,
+    /// Keeps the part of the rectangle that overlaps the source.
+    Clamp,
+    /// Ignores the rectangle and resizes the whole source, so `cover`
+    /// crops around the center as if no rectangle had been given.
+    FallbackGravity,
+}
+
+impl CropRect {
+    /// The `(x, y, width, height)` to cut from a `source_width x
+    /// source_height` source, or `None` to use the whole source.
+    ///
+    /// Clamping intersects the rectangle with the source: the left and top
+    /// edges become `min(x, source_width)` and `min(y, source_height)`, the
+    /// right and bottom edges `min(x + width, source_width)` and
+    /// `min(y + height, source_height)`. A rectangle entirely outside the
+    /// source has nothing left and is rejected even when clamping.
+    pub fn resolve(
+        &self,
+        source_width: u32,
+        source_height: u32,
+    ) -> Result<Option<(u32, u32, u32, u32)>, AppError> {
+        let right = self.x as u64 + self.width as u64;
+        let bottom = self.y as u64 + self.height as u64;
+        if right <= source_width as u64 && bottom <= source_height as u64 {
+            return Ok(Some((self.x, self.y, self.width, self.height)));
+        }
+
+        let outside = || {
+            AppError::InvalidParameter(format!(
+                "crop {}x{} at ({}, {}) reaches past the {}x{} source",
+                self.width, self.height, self.x, self.y, source_width, source_height
+            ))
+        };
+        match self.on_invalid {
+            InvalidCropPolicy::Error => Err(outside()),
+            InvalidCropPolicy::Clamp => {
+                let (left, top) = (self.x.min(source_width), self.y.min(source_height));
+                let right = right.min(source_width as u64) as u32;
+                let bottom = bottom.min(source_height as u64) as u32;
+                if right <= left || bottom <= top {
+                    return Err(outside());
+                }
+                Ok(Some((left, top, right - left, bottom - top)))
+            }
+            InvalidCropPolicy::FallbackGravity => Ok(None),
+        }
+    }
+
+    /// Key suffix, e.g. `crop10-20-300x200`; a non-default policy is tagged
+    /// too, as it can change the output.
+    pub fn key_suffix(&self) -> String {
+        let suffix = format!("crop{}-{}-{}x{}", self.x, self.y, self.width, self.height);
+        match self.on_invalid {
+            InvalidCropPolicy::Error => suffix,
+            InvalidCropPolicy::Clamp => format!("{}-clamp", suffix),
+            InvalidCropPolicy::FallbackGravity => format!("{}-fallback", suffix),
+        }
+    }
+}
+
+/// One step of `ResizeRequest::operations`, e.g. `{"rotate": 90}` or
+/// `{"resize": {"width": 800, "height": 600}}`. Each step sees the image
+/// as the previous one left it.
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Clockwise, in degrees: 90, 180 or 270.
+    Rotate(u32),
+    /// Measured on the image as it is at this step.
+    Crop(CropRect),
+    Resize(ResizeOperation),
+    /// `true` drops the color; `false` does nothing.
+    Grayscale(bool),
+    Watermark(TextWatermark),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, ToSchema)]
+pub struct ResizeOperation {
+    pub width: u32,
+    pub height: u32,
+    /// Defaults to `DEFAULT_OBJECT_MODE`.
+    pub object_mode: Option<ObjectMode>,
+}
+
+/// Places the resized image on a larger fixed-size canvas.
+#[derive(Debug, Deserialize, Clone, ToSchema)]
+pub struct CanvasOptions {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_canvas_background")]
+    pub background: HexColor,
+    #[serde(default)]
+    pub gravity: Gravity,
+    /// Explicit top-left offset of the image; overrides `gravity` when both are set.
+    pub x: Option<i64>,
+    pub y: Option<i64>,
+}
+
+fn default_canvas_background() -> HexColor {
+    HexColor([255, 255, 255, 255])
+}
+
+pub fn default_alpha_background() -> HexColor {
+    HexColor([255, 255, 255, 255])
+}
+
+/// Low-quality image placeholder generated from the derivative.
+#[derive(Debug, Deserialize, Clone, Copy, ToSchema)]
+pub struct LqipOptions {
+    /// Longest side of the placeholder in pixels.
+    #[serde(default = "default_lqip_size")]
+    pub size: u32,
+    /// Gaussian blur sigma applied after downscaling.
+    #[serde(default = "default_lqip_blur")]
+    pub blur: f32,
+    #[serde(default = "default_lqip_quality")]
+    pub quality: u8,
+}
+
+fn default_lqip_size() -> u32 {
+    20
+}
+
+fn default_lqip_blur() -> f32 {
+    1.5
+}
+
+fn default_lqip_quality() -> u8 {
+    40
+}
+
+impl LqipOptions {
+    fn problems(&self, problems: &mut Vec<String>) {
+        if self.size == 0 || self.size > MAX_LQIP_SIZE {
+            problems.push(format!("lqip.size must be between 1 and {}", MAX_LQIP_SIZE));
+        }
+        if !(1..=100).contains(&self.quality) {
+            problems.push("lqip.quality must be between 1 and 100".to_string());
+        }
+        if !self.blur.is_finite() || self.blur < 0.0 {
+            problems.push("lqip.blur must be a non-negative number".to_string());
+        }
+    }
+}
+
+/// Text drawn across the derivative, e.g. a diagonal "PREVIEW" on drafts.
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct TextWatermark {
+    pub text: String,
+    /// Font size in CSS pixels; scaled by `dpr`. Defaults to a size that spans
+    /// about 60% of the image diagonal.
+    pub size: Option<f32>,
+    #[serde(default = "default_watermark_color")]
+    pub color: HexColor,
+    /// 0.0 (invisible) to 1.0 (opaque), multiplied with the color's alpha.
+    #[serde(default = "default_watermark_opacity")]
+    pub opacity: f32,
+    /// Counter-clockwise rotation in degrees. Defaults to the angle of the
+    /// bottom-left to top-right diagonal.
+    pub angle: Option<f32>,
+}
+
+fn default_watermark_color() -> HexColor {
+    HexColor([255, 255, 255, 255])
+}
+
+fn default_watermark_opacity() -> f32 {
+    0.35
+}
+
+impl TextWatermark {
+    fn problems(&self, problems: &mut Vec<String>) {
+        if self.text.trim().is_empty() {
+            problems.push("text_watermark.text must not be empty".to_string());
+        }
+        if self.text.chars().count() > MAX_WATERMARK_TEXT_LENGTH {
+            problems.push(format!(
+                "text_watermark.text must be at most {} characters",
+                MAX_WATERMARK_TEXT_LENGTH
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            problems.push("text_watermark.opacity must be between 0 and 1".to_string());
+        }
+        if self
+            .size
+            .is_some_and(|size| !size.is_finite() || size <= 0.0)
+        {
+            problems.push("text_watermark.size must be a positive number".to_string());
+        }
+        if self.angle.is_some_and(|angle| !angle.is_finite()) {
+            problems.push("text_watermark.angle must be a number of degrees".to_string());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Gravity {
+    #[default]
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+/// An RGBA color parsed from `#rrggbb` or `#rrggbbaa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexColor(pub [u8; 4]);
+
+impl HexColor {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let hex = value.trim_start_matches('#');
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Invalid color '{}', expected #rrggbb or #rrggbbaa",
+                value
+            ));
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(255);
+        let alpha = if hex.len() == 8 { channel(6) } else { 255 };
+
+        Ok(Self([channel(0), channel(2), channel(4), alpha]))
+    }
+
+    pub fn to_hex(self) -> String {
+        let [r, g, b, a] = self.0;
+        format!("{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// Documented as the string it is parsed from.
+impl utoipa::PartialSchema for HexColor {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        utoipa::openapi::ObjectBuilder::new()
+            .schema_type(utoipa::openapi::schema::Type::String)
+            .pattern(Some("^#?([0-9a-fA-F]{6}|[0-9a-fA-F]{8})$"))
+            .description(Some("`#rrggbb` or `#rrggbbaa`"))
+            .into()
+    }
+}
+
+impl ToSchema for HexColor {}
+
+impl Serialize for HexColor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("#{}", self.to_hex()))
+    }
+}
+
+impl<'de> Deserialize<'de> for HexColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        HexColor::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ResizeResponse {
     pub original_url: String,
     pub resized_url: String,
     pub width: u32,
     pub height: u32,
     pub object_mode: ObjectMode,
+    pub dpr: u32,
+    /// Format of the stored derivative; with `auto`, the candidate that won.
+    pub output_format: OutputFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<ResizeStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sidecar_url: Option<String>,
+    /// The JPEG stored alongside, when `with_fallback` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_url: Option<String>,
+    /// With `face_crop`, whether a face positioned the crop. Unknown, and
+    /// left out, for cached derivatives.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub face_detected: Option<bool>,
+    /// With `reject_if_larger`, whether the source bytes were stored because
+    /// the encoded derivative was larger. Left out for cached derivatives.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_kept: Option<bool>,
+    /// The source's perceptual hash, when `include_phash` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    /// BlurHash of the source, when `include_blurhash` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// `data:image/jpeg;base64,...` placeholder, when `lqip` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lqip: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// `Content-Type` S3 reported for the source, for spotting mislabeled
+    /// uploads. Left out when the source wasn't downloaded, e.g. on most
+    /// cache hits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_content_type: Option<String>,
+    /// One entry per bucket for `/resize-multi-dest`; `resized_url` is then the
+    /// first destination that was written.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<DestinationResult>,
+    /// Per-stage timings, with `?debug=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ResizeTimings>,
+}
+
+/// Query parameters for `POST /resize`.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ResizeQuery {
+    /// Adds `timings` to the response.
+    #[serde(default)]
+    pub debug: bool,
+}
+
+/// Milliseconds spent in each stage of a resize. Stages that didn't run, such
+/// as all but the download on a cache hit, report 0.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ResizeTimings {
+    pub download_ms: u64,
+    pub decode_ms: u64,
+    pub resize_ms: u64,
+    pub encode_ms: u64,
+    pub upload_ms: u64,
+    pub total_ms: u64,
+}
+
+/// `POST /resize-multi-dest`: a resize request plus the buckets, as `s3://bucket`
+/// or `gs://bucket`, to store the derivative in under the same key.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MultiDestResizeRequest {
+    #[serde(flatten)]
+    pub request: ResizeRequest,
+    pub destinations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DestinationResult {
+    pub bucket: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resized_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sidecar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `POST /resize-multi-size`: a resize request whose `width`/`height` come
+/// from each entry of `sizes`, or from `srcset`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MultiSizeResizeRequest {
+    #[serde(flatten)]
+    pub request: ResizeRequest,
+    #[serde(default)]
+    pub sizes: Vec<RequestedSize>,
+    pub srcset: Option<SrcsetOptions>,
+}
+
+/// Widths sharing one aspect ratio, for a responsive image's `srcset`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SrcsetOptions {
+    pub widths: Vec<u32>,
+    /// Ratio the heights follow, as `W:H` (e.g. `16:9`); the source's own
+    /// when unset.
+    pub aspect_ratio: Option<String>,
+}
+
+impl SrcsetOptions {
+    /// Each width with the height, rounded to the nearest pixel, that keeps
+    /// the `ratio_width:ratio_height` aspect ratio.
+    pub fn sizes(&self, ratio_width: u32, ratio_height: u32) -> Vec<RequestedSize> {
+        self.widths
+            .iter()
+            .map(|&width| {
+                let height = (width as u64 * ratio_height as u64 + ratio_width as u64 / 2)
+                    / ratio_width as u64;
+                RequestedSize {
+                    width,
+                    height: height.clamp(1, u32::MAX as u64) as u32,
+                }
+            })
+            .collect()
+    }
+}
+
+/// One output size, as `width`/`height` on `POST /resize`.
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+pub struct RequestedSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Outcome of one entry of `sizes`, in request order.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SizeResult {
+    Ok(Box<ResizeResponse>),
+    /// The size failed; `code` is the HTTP status a `POST /resize` for it
+    /// would have returned.
+    Error {
+        width: u32,
+        height: u32,
+        code: u16,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ResizeStats {
+    pub source_bytes: usize,
+    pub output_bytes: usize,
+    /// Source size divided by output size; above 1.0 means the output is smaller.
+    pub compression_ratio: f64,
+    /// Output pixels per encoded output byte.
+    pub pixels_per_byte: f64,
+}
+
+impl ResizeStats {
+    pub fn new(source_bytes: usize, output_bytes: usize, width: u32, height: u32) -> Self {
+        let output = output_bytes.max(1) as f64;
+
+        Self {
+            source_bytes,
+            output_bytes,
+            compression_ratio: source_bytes as f64 / output,
+            pixels_per_byte: (width as u64 * height as u64) as f64 / output,
+        }
+    }
+}
+
+/// Query parameters for `GET /resize`, which returns the image bytes directly.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct InlineResizeQuery {
+    pub s3_url: String,
+    pub width: u32,
+    pub height: u32,
+    /// Defaults to `DEFAULT_OBJECT_MODE`.
+    pub object_mode: Option<ObjectMode>,
+    /// Sends `Content-Disposition: attachment` so browsers download the image.
+    /// `true` (or an empty value) names the file after `filename` or the
+    /// derivative; any other value except `false` is the filename itself.
+    pub download: Option<String>,
+    /// Filename for `download=true`; defaults to the derivative's file name.
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Overrides `STORAGE_CLASS` for the cached derivative.
+    pub storage_class: Option<StorageClass>,
+    /// Overrides `S3_REQUESTER_PAYS` for the source.
+    pub requester_pays: Option<bool>,
+}
+
+/// Query parameters for `GET /resize/raw`, which returns uncompressed pixels.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RawResizeQuery {
+    pub s3_url: String,
+    pub width: u32,
+    pub height: u32,
+    /// Defaults to `DEFAULT_OBJECT_MODE`.
+    pub object_mode: Option<ObjectMode>,
+    #[serde(default)]
+    pub filter: ResizeFilter,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PhashRequest {
+    pub s3_url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PhashResponse {
+    pub original_url: String,
+    /// Hex, most significant bit first.
+    pub phash: String,
+    pub algorithm: &'static str,
+    pub bits: u32,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AspectCropRequest {
+    pub s3_url: String,
+    pub aspect_ratio: String,
+    pub max_width: Option<u32>,
+    /// Overrides `STORAGE_CLASS` for the cropped derivative.
+    pub storage_class: Option<StorageClass>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AspectCropResponse {
+    pub original_url: String,
+    pub cropped_url: String,
+    pub aspect_ratio: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// `POST /convert`: re-encodes the source in another format at its own size.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ConvertRequest {
+    pub s3_url: String,
+    /// A concrete format; `auto` isn't accepted.
+    pub output_format: OutputFormat,
+    /// JPEG and AVIF only; defaults to the resize default.
+    pub quality: Option<u8>,
+    /// EXIF and XMP are never copied. `preserve` also keeps the ICC profile,
+    /// JPEG output only.
+    #[serde(default)]
+    pub color_profile: ColorProfile,
+    #[serde(default = "default_alpha_background")]
+    pub alpha_background: HexColor,
+    /// Skips the cache shortcut and overwrites any existing conversion.
+    #[serde(default)]
+    pub force: bool,
+    /// Overrides `STORAGE_CLASS` for the converted object.
+    pub storage_class: Option<StorageClass>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ConvertResponse {
+    pub original_url: String,
+    pub converted_url: String,
+    /// Format actually stored; differs from the request when AVIF fell back.
+    pub output_format: OutputFormat,
+    /// Left out for cached conversions whose header can't be read, e.g. AVIF.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// Several sources tiled into one image, e.g. a 2x2 listing thumbnail.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CollageRequest {
+    /// Filled row by row; must hold exactly `columns * rows` URLs.
+    pub s3_urls: Vec<String>,
+    pub columns: u32,
+    pub rows: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Overrides `STORAGE_CLASS` for the collage.
+    pub storage_class: Option<StorageClass>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CollageResponse {
+    pub source_urls: Vec<String>,
+    pub collage_url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Contact sheet of many sources at a fixed cell size.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MontageRequest {
+    /// Filled row by row; at most `columns * rows` URLs.
+    pub s3_urls: Vec<String>,
+    pub columns: u32,
+    /// Defaults to as many rows as the sources need.
+    pub rows: Option<u32>,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// Overrides `STORAGE_CLASS` for the montage.
+    pub storage_class: Option<StorageClass>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MontageResponse {
+    pub montage_url: String,
+    pub width: u32,
+    pub height: u32,
+    /// Sources drawn as placeholder cells because they couldn't be fetched or read.
+    pub failed_sources: Vec<String>,
+}
+
+/// Thumbnails laid out on one sheet, e.g. for scrubbing previews of a video.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SpriteRequest {
+    /// One thumbnail per URL, in order. Use either this or `s3_url`.
+    #[serde(default)]
+    pub s3_urls: Vec<String>,
+    /// An animated GIF, PNG or WebP whose frames become the thumbnails.
+    pub s3_url: Option<String>,
+    /// With `s3_url`, how many evenly spaced frames to take; every frame, up
+    /// to the cell limit, when unset.
+    pub frames: Option<u32>,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+    pub columns: u32,
+    /// `jpeg`, `png` or `webp`.
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// JPEG only; defaults to the resize default.
+    pub quality: Option<u8>,
+    /// Skips the cache shortcut and overwrites any existing sheet.
+    #[serde(default)]
+    pub force: bool,
+    /// Overrides `STORAGE_CLASS` for the sheet and its map.
+    pub storage_class: Option<StorageClass>,
+}
+
+/// Also stored as the sheet's map, `{sprite_url}.json`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct SpriteResponse {
+    pub sprite_url: String,
+    pub map_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+    pub columns: u32,
+    pub rows: u32,
+    pub output_format: OutputFormat,
+    /// In order, row by row from the top left.
+    pub cells: Vec<SpriteCell>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct SpriteCell {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The source drawn here, for sheets built from `s3_urls`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// Zero-based frame of `s3_url` drawn here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<u32>,
+}
+
+/// A Deep Zoom tile pyramid for pan-and-zoom viewers such as OpenSeadragon.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TileRequest {
+    pub s3_url: String,
+    /// Side of the square tiles, 64-2048.
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+    /// Pixels each tile shares with its neighbours, at most 8.
+    #[serde(default)]
+    pub overlap: u32,
+    /// `jpeg`, `png` or `webp`.
+    #[serde(default = "default_tile_format")]
+    pub output_format: OutputFormat,
+    /// JPEG only; defaults to the resize default.
+    pub quality: Option<u8>,
+    /// Skips the cache shortcut and regenerates every tile.
+    #[serde(default)]
+    pub force: bool,
+    /// Overrides `STORAGE_CLASS` for the tiles and the manifest.
+    pub storage_class: Option<StorageClass>,
+}
+
+fn default_tile_size() -> u32 {
+    256
+}
+
+fn default_tile_format() -> OutputFormat {
+    OutputFormat::Jpeg
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TileResponse {
+    pub original_url: String,
+    /// The `.dzi` descriptor to point the viewer at.
+    pub manifest_url: String,
+    /// Prefix holding the tiles as `{level}/{column}_{row}.{format}`.
+    pub tiles_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: u32,
+    pub overlap: u32,
+    pub output_format: OutputFormat,
+    /// Smallest first; level 0 is a single pixel.
+    pub levels: Vec<TileLevel>,
+    pub tile_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TileLevel {
+    pub level: u32,
+    pub width: u32,
+    pub height: u32,
+    pub columns: u32,
+    pub rows: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Status of a `POST /jobs` resize; `result` is the `POST /resize` response
+/// once the job is done.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobResponse {
+    pub id: Uuid,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ResizeResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `POST /verify`: the same fields as `POST /resize`, plus where the
+/// stored variant lives and how close it has to be.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct VerifyRequest {
+    #[serde(flatten)]
+    pub resize: ResizeRequest,
+    /// Stored object to compare against; defaults to the key `POST /resize` would use.
+    pub variant_url: Option<String>,
+    /// Minimum similarity (0-1) for the variant to count as a match.
+    pub threshold: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VerifyResponse {
+    pub original_url: String,
+    pub variant_url: String,
+    pub exists: bool,
+    /// 1.0 means pixel-identical; `None` when the variant doesn't exist.
+    pub similarity: Option<f64>,
+    pub threshold: f64,
+    pub matches: bool,
+    pub identical_bytes: bool,
+    pub expected_width: u32,
+    pub expected_height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_height: Option<u32>,
+}
+
+/// Metadata written next to a derivative as `{key}.json` when `write_sidecar` is set.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResizeSidecar {
+    pub source_url: String,
+    pub resized_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    pub object_mode: ObjectMode,
+    pub dpr: u32,
+    pub content_type: String,
+    pub stats: ResizeStats,
+    /// Unix timestamp (seconds) of when the derivative was generated.
+    pub generated_at: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadResizeResponse {
+    pub resized_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub object_mode: ObjectMode,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ParseUrlRequest {
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ParseUrlResponse {
+    pub url: String,
+    pub bucket: String,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    pub s3_circuit: BreakerState,
+}
+
+/// In-process counters since startup, from `GET /stats`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatsResponse {
+    pub uptime_secs: u64,
+    /// Derivatives produced, whether stored or returned directly.
+    pub resizes: u64,
+    /// Requests answered with an already stored derivative.
+    pub cache_hits: u64,
+    /// `cache_hits` over all resize requests; absent before the first one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_hit_ratio: Option<f64>,
+    /// Source bytes fetched from storage.
+    pub bytes_downloaded: u64,
+    /// Derivative bytes written to storage, once per destination.
+    pub bytes_uploaded: u64,
+    /// Resizes per output format, including `raw`.
+    pub formats: BTreeMap<String, u64>,
+    /// Mean decode-to-encode time of `resizes`; absent before the first one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_resize_ms: Option<f64>,
+    /// Mean encode time per output format, for formats encoded at least once.
+    /// An `auto` output counts every candidate encode towards the winner.
+    pub average_encode_ms: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadyResponse {
+    pub status: &'static str,
+    pub checks: Vec<PermissionCheck>,
+}
+
+/// One permission exercised by the readiness probe, named as in IAM policies.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PermissionCheck {
+    pub permission: &'static str,
+    pub bucket: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PermissionCheck {
+    pub fn new<T>(permission: &'static str, bucket: &str, result: &Result<T, AppError>) -> Self {
+        Self {
+            permission,
+            bucket: bucket.to_string(),
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(fields: serde_json::Value) -> ResizeRequest {
+        let mut body = serde_json::json!({ "s3_url": "s3://bucket/photo.jpg" });
+        body.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn problems(fields: serde_json::Value) -> Vec<String> {
+        match request(fields).validate() {
+            Err(AppError::ValidationFailed(problems)) => problems,
+            other => panic!("expected a validation failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_conflicting_size_parameters() {
+        assert!(
+            request(serde_json::json!({ "width": 400, "height": 300, "dpr": 2 }))
+                .validate()
+                .is_ok()
+        );
+        assert!(request(serde_json::json!({ "max_width": 400 }))
+            .validate()
+            .is_ok());
+
+        assert_eq!(
+            problems(serde_json::json!({ "width": 400, "height": 300, "max_megapixels": 2.0 })),
+            ["max_megapixels cannot be combined with width/height"]
+        );
+        assert_eq!(
+            problems(serde_json::json!({ "width": 400, "max_height": 300 })),
+            ["max_width/max_height cannot be combined with width/height"]
+        );
+        assert_eq!(
+            problems(serde_json::json!({ "width": 400 })),
+            ["width and height must be given together"]
+        );
+        assert_eq!(
+            problems(serde_json::json!({ "max_width": 400, "object_mode": "cover", "dpr": 2 })),
+            [
+                "object_mode cannot be combined with max_width/max_height, which keeps the source aspect ratio",
+                "dpr cannot be combined with max_width/max_height",
+            ]
+        );
+        assert_eq!(
+            problems(serde_json::json!({})),
+            ["Provide width and height, square, max_megapixels, or max_width/max_height"]
+        );
+    }
+
+    #[test]
+    fn output_name_is_sanitized_to_a_file_stem() {
+        let named = |name: &str| {
+            request(serde_json::json!({ "width": 800, "height": 600, "output_name": name }))
+        };
+        assert_eq!(
+            named("Red Running Shoe!").output_stem().as_deref(),
+            Some("Red-Running-Shoe")
+        );
+        assert_eq!(
+            named("summer.sale_2024").output_stem().as_deref(),
+            Some("summer-sale_2024")
+        );
+        assert!(named("red-running-shoe").validate().is_ok());
+
+        assert_eq!(
+            problems(
+                serde_json::json!({ "width": 800, "height": 600, "output_name": "../secrets" })
+            ),
+            ["output_name is a file name and cannot contain / or \\"]
+        );
+        assert_eq!(
+            problems(serde_json::json!({ "width": 800, "height": 600, "output_name": " .. " })),
+            ["output_name must contain a letter or digit"]
+        );
+    }
+
+    #[test]
+    fn snap_to_rounds_exact_sizes_to_nearest_and_caps_down() {
+        let exact = request(serde_json::json!({ "width": 804, "height": 595, "snap_to": 10 }));
+        assert!(exact.validate().is_ok());
+        assert_eq!(exact.snapped(804, 595), (800, 600));
+        assert_eq!(exact.snapped(3, 4), (10, 10));
+
+        let capped = request(serde_json::json!({ "max_width": 809, "snap_to": 10 }));
+        assert_eq!(capped.snapped(809, 607), (800, 600));
+        assert_eq!(capped.snapped(304, 1009), (301, 1000));
+        assert_eq!(capped.snapped(1000, 333), (1000, 333));
+
+        // A cap under one step is kept rather than rounded up past it.
+        let small = request(serde_json::json!({ "max_width": 50, "snap_to": 64 }));
+        assert_eq!(small.snapped(50, 30), (50, 30));
+        assert_eq!(small.snapped(50, 200), (48, 192));
+
+        assert_eq!(
+            problems(serde_json::json!({ "width": 800, "height": 600, "snap_to": 0 })),
+            ["snap_to must be between 1 and 256"]
+        );
+        assert_eq!(
+            problems(serde_json::json!({ "square": 96, "square_pad": true, "snap_to": 10 })),
+            ["snap_to can't be combined with operations, canvas or square_pad, which need their exact sizes"]
+        );
+    }
+
+    #[test]
+    fn metadata_fills_in_what_the_request_leaves_out() {
+        let metadata: HashMap<String, String> = [
+            ("resize-width", "320"),
+            ("resize-height", "240"),
+            ("resize-object-mode", "contain"),
+            ("resize-quality", "70"),
+            ("owner", "uploader"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+        let bare = request(serde_json::json!({ "object_mode": "fill" }));
+        assert!(bare.omits_size());
+        let completed = bare.with_metadata_defaults(&metadata).unwrap();
+        assert_eq!((completed.width, completed.height), (Some(320), Some(240)));
+        assert!(matches!(completed.object_mode, Some(ObjectMode::Fill)));
+        assert_eq!(completed.quality, Some(70));
+        assert!(completed.validate().is_ok());
+
+        let invalid: HashMap<String, String> = [
+            ("resize-width", "wide".to_string()),
+            ("resize-filter", "sharp".to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+        match request(serde_json::json!({})).with_metadata_defaults(&invalid) {
+            Err(AppError::ValidationFailed(problems)) => assert_eq!(
+                problems,
+                [
+                    "source metadata x-amz-meta-resize-width has an invalid value 'wide'",
+                    "source metadata x-amz-meta-resize-filter has an invalid value 'sharp'",
+                ]
+            ),
+            other => panic!("expected a validation failure, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let problems = problems(serde_json::json!({
+            "width": 0,
+            "height": 300,
+            "quality": 0,
+            "dpr": 9,
+            "lqip": { "size": 0, "quality": 200 },
+            "with_fallback": true,
+        }));
+
+        assert_eq!(
+            problems,
+            [
+                "width and height must be greater than 0",
+                "dpr must be between 1 and 4",
+                "quality must be between 1 and 100",
+                "lqip.size must be between 1 and 64",
+                "lqip.quality must be between 1 and 100",
+                "with_fallback pairs a webp or avif output_format with a JPEG; set output_format to one of those",
+            ]
+        );
+    }
+
+    #[test]
+    fn size_results_are_tagged_with_their_status() {
+        let ok = SizeResult::Ok(Box::new(ResizeResponse {
+            original_url: "s3://bucket/photo.jpg".to_string(),
+            resized_url: "s3://bucket/photo_400x.jpg".to_string(),
+            width: 400,
+            height: 300,
+            object_mode: ObjectMode::Contain,
+            dpr: 1,
+            output_format: OutputFormat::Jpeg,
+            stats: None,
+            sidecar_url: None,
+            fallback_url: None,
+            face_detected: None,
+            source_kept: None,
+            phash: None,
+            blurhash: None,
+            lqip: None,
+            warnings: Vec::new(),
+            source_content_type: None,
+            destinations: Vec::new(),
+            timings: None,
+        }));
+        let error = SizeResult::Error {
+            width: 4000,
+            height: 3000,
+            code: 422,
+            message: "Source is too small".to_string(),
+        };
+
+        let ok = serde_json::to_value(ok).unwrap();
+        assert_eq!(ok["status"], "ok");
+        assert_eq!(ok["resized_url"], "s3://bucket/photo_400x.jpg");
+        assert_eq!(
+            serde_json::to_value(error).unwrap(),
+            serde_json::json!({
+                "status": "error",
+                "width": 4000,
+                "height": 3000,
+                "code": 422,
+                "message": "Source is too small",
+            })
+        );
+    }
+
+    #[test]
+    fn square_expands_to_a_square_box_or_a_padded_canvas() {
+        let cropped = request(serde_json::json!({ "square": 256 }));
+        assert!(cropped.validate().is_ok());
+        let cropped = cropped.expand_square();
+        assert_eq!((cropped.width, cropped.height), (Some(256), Some(256)));
+        assert!(cropped.object_mode.is_none() && cropped.canvas.is_none());
+
+        let padded = request(serde_json::json!({
+            "square": 256,
+            "square_pad": true,
+            "square_background": "#000000",
+        }));
+        assert!(padded.validate().is_ok());
+        let padded = padded.expand_square();
+        assert!(matches!(padded.object_mode, Some(ObjectMode::Contain)));
+        let canvas = padded.canvas.unwrap();
+        assert_eq!((canvas.width, canvas.height), (256, 256));
+        assert_eq!(canvas.background.0, [0, 0, 0, 255]);
+
+        assert_eq!(
+            problems(serde_json::json!({ "square": 256, "width": 100, "square_pad": true, "object_mode": "cover" })),
+            [
+                "square_pad fits the whole source and cannot be combined with object_mode or face_crop",
+                "square cannot be combined with width/height",
+            ]
+        );
+    }
+
+    #[test]
+    fn operations_replace_the_size_fields() {
+        let chained = request(serde_json::json!({
+            "operations": [
+                { "crop": { "x": 0, "y": 0, "width": 400, "height": 300 } },
+                { "resize": { "width": 200, "height": 150, "object_mode": "fill" } },
+                { "rotate": 90 },
+                { "grayscale": true },
+            ],
+        }));
+        assert!(chained.validate().is_ok());
+        assert!(!chained.omits_size());
+        assert_eq!(chained.operations_size(), Some((150, 200)));
+        assert!(matches!(
+            chained.operations_object_mode(),
+            Some(ObjectMode::Fill)
+        ));
+
+        assert_eq!(
+            problems(serde_json::json!({
+                "width": 100,
+                "height": 100,
+                "operations": [{ "rotate": 45 }],
+            })),
+            [
+                "width, height can't be combined with operations; express them as operations instead",
+                "operations must include a resize",
+                "operations[0].rotate must be 90, 180 or 270",
+            ]
+        );
+    }
+
+    #[test]
+    fn srcset_heights_follow_the_aspect_ratio() {
+        let srcset = SrcsetOptions {
+            widths: vec![320, 640, 1000, 1],
+            aspect_ratio: None,
+        };
+        let sizes: Vec<_> = srcset
+            .sizes(16, 9)
+            .iter()
+            .map(|size| (size.width, size.height))
+            .collect();
+
+        assert_eq!(sizes, [(320, 180), (640, 360), (1000, 563), (1, 1)]);
+    }
+
+    #[test]
+    fn crop_rects_outside_the_source_follow_on_invalid() {
+        let crop = |x, y, on_invalid| CropRect {
+            x,
+            y,
+            width: 300,
+            height: 200,
+            on_invalid,
+        };
+        let inside = crop(100, 50, InvalidCropPolicy::Error);
+        assert_eq!(inside.resolve(800, 600).unwrap(), Some((100, 50, 300, 200)));
+
+        // 600..900 x 500..700 overhangs an 800x600 source on two sides.
+        let partial = |on_invalid| crop(600, 500, on_invalid).resolve(800, 600);
+        assert!(matches!(
+            partial(InvalidCropPolicy::Error),
+            Err(AppError::InvalidParameter(_))
+        ));
+        assert_eq!(
+            partial(InvalidCropPolicy::Clamp).unwrap(),
+            Some((600, 500, 200, 100))
+        );
+        assert_eq!(partial(InvalidCropPolicy::FallbackGravity).unwrap(), None);
+
+        let outside = |on_invalid| crop(900, 0, on_invalid).resolve(800, 600);
+        assert!(outside(InvalidCropPolicy::Error).is_err());
+        assert!(outside(InvalidCropPolicy::Clamp).is_err());
+        assert_eq!(outside(InvalidCropPolicy::FallbackGravity).unwrap(), None);
+
+        assert_eq!(
+            crop(600, 500, InvalidCropPolicy::Clamp).key_suffix(),
+            "crop600-500-300x200-clamp"
+        );
+    }
 }
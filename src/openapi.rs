@@ -0,0 +1,56 @@
+use utoipa::openapi::schema::{KnownFormat, ObjectBuilder, Schema, SchemaFormat, Type};
+use utoipa::openapi::RefOr;
+use utoipa::{OpenApi, PartialSchema, ToSchema};
+
+use crate::error::ErrorBody;
+use crate::handlers;
+
+/// The HTTP contract, generated from the handlers' `#[utoipa::path]`
+/// annotations and the request and response types, and served as
+/// `GET /openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Image Resizer",
+        description = "Resizes images stored in S3 and stores the derivatives next to them.",
+        license(name = "MIT")
+    ),
+    paths(
+        handlers::resize_image,
+        handlers::resize_inline,
+        handlers::resize_raw,
+        handlers::resize_multi_dest,
+        handlers::resize_multi_size,
+        handlers::resize_upload,
+        handlers::phash,
+        handlers::aspect_crop,
+        handlers::convert,
+        handlers::collage,
+        handlers::montage,
+        handlers::sprite,
+        handlers::tiles,
+        handlers::verify,
+        handlers::create_job,
+        handlers::get_job,
+        handlers::parse_url,
+        handlers::health,
+        handlers::ready,
+        handlers::stats,
+    ),
+    components(schemas(ErrorBody))
+)]
+pub struct ApiDoc;
+
+/// Response body of the endpoints that return the image itself.
+pub struct ImageBytes;
+
+impl PartialSchema for ImageBytes {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+            .into()
+    }
+}
+
+impl ToSchema for ImageBytes {}
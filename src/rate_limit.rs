@@ -0,0 +1,142 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+use crate::state::AppState;
+
+/// Header naming the caller; requests without it are limited per client IP.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Probes must keep answering while a client is being throttled.
+const EXEMPT_PATHS: [&str; 3] = ["/health", "/ready", "/metrics"];
+
+/// Above this many tracked clients, idle ones are dropped on the next check.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets per client: each holds up to `burst` requests and refills at
+/// `per_second`, so short bursts pass while the sustained rate is capped.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self {
+            per_second,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A bucket that has refilled completely is indistinguishable from a new one.
+            let full_after = Duration::from_secs_f64(self.burst / self.per_second);
+            buckets.retain(|_, bucket| now.duration_since(bucket.refilled_at) < full_after);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// Rejects requests over `RATE_LIMIT_RPS` with a `429` and `Retry-After`.
+pub async fn enforce(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let client = client_key(&request, peer, state.config.rate_limit_trust_forwarded);
+    match limiter.check(&client) {
+        Ok(()) => next.run(request).await,
+        // The key itself stays out of the message, which is logged.
+        Err(retry_after) => {
+            AppError::RateLimited("Rate limit exceeded".to_string(), retry_after).into_response()
+        }
+    }
+}
+
+/// The API key when one is sent, otherwise the client IP: the peer address,
+/// or the first `X-Forwarded-For` hop when the service sits behind a proxy.
+fn client_key(request: &Request, peer: SocketAddr, trust_forwarded: bool) -> String {
+    let headers = request.headers();
+    if let Some(key) = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+    {
+        return format!("key:{}", key);
+    }
+
+    let forwarded = trust_forwarded
+        .then(|| headers.get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty());
+    match forwarded {
+        Some(ip) => format!("ip:{}", ip),
+        None => format!("ip:{}", peer.ip()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_rejects_until_refilled() {
+        let limiter = RateLimiter::new(1.0, 3);
+        for _ in 0..3 {
+            assert!(limiter.check("ip:10.0.0.1").is_ok());
+        }
+
+        let wait = limiter.check("ip:10.0.0.1").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // Other clients have their own bucket.
+        assert!(limiter.check("key:other").is_ok());
+    }
+}
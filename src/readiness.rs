@@ -0,0 +1,63 @@
+use bytes::Bytes;
+use std::sync::Mutex;
+
+use crate::models::PermissionCheck;
+use crate::s3::S3Client;
+
+/// Prefix of the throwaway objects written by the write check.
+const WRITE_CHECK_PREFIX: &str = ".readiness-check/";
+
+/// Holds the outcome of the write check. Writing on every probe would fill the
+/// bucket's request metrics and versioning history with test objects, so it
+/// runs once at startup and `/ready` reports that result; only a failed check
+/// is retried by later probes, so a transient error doesn't stick.
+#[derive(Default)]
+pub struct Readiness {
+    write_checks: Mutex<Option<Vec<PermissionCheck>>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` while the startup write check is still running.
+    pub fn write_checks(&self) -> Option<Vec<PermissionCheck>> {
+        self.write_checks.lock().unwrap().clone()
+    }
+
+    /// Puts and deletes a tiny object in `bucket`, recording which of the two
+    /// permissions is missing.
+    pub async fn run_write_check(
+        &self,
+        s3_client: &S3Client,
+        bucket: &str,
+    ) -> Vec<PermissionCheck> {
+        let key = format!("{}{}", WRITE_CHECK_PREFIX, uuid::Uuid::new_v4());
+
+        let put = s3_client
+            .put_object(bucket, &key, Bytes::from_static(b"ok"))
+            .await;
+        let mut checks = vec![PermissionCheck::new("s3:PutObject", bucket, &put)];
+        if put.is_ok() {
+            let delete = s3_client.delete_object(bucket, &key).await;
+            checks.push(PermissionCheck::new("s3:DeleteObject", bucket, &delete));
+        }
+
+        for check in checks.iter().filter(|check| !check.ok) {
+            tracing::error!(
+                "Readiness write check failed for {} on {}: {}",
+                check.permission,
+                bucket,
+                check.error.as_deref().unwrap_or_default()
+            );
+        }
+        *self.write_checks.lock().unwrap() = Some(checks.clone());
+        checks
+    }
+}
+
+/// Checks `s3:ListBucket` on `bucket`; cheap enough to run on every probe.
+pub async fn check_read(s3_client: &S3Client, bucket: &str) -> PermissionCheck {
+    PermissionCheck::new("s3:ListBucket", bucket, &s3_client.list_one(bucket).await)
+}
@@ -0,0 +1,415 @@
+//! Multi-threaded resampling for `RESIZE_THREADS`.
+//!
+//! Mirrors the separable resampler behind `DynamicImage::resize_exact` (a
+//! vertical pass into `f32` rows, then a horizontal pass back to 8 bits) with
+//! the same weights and summation order, so the output is bit-for-bit the
+//! same; only the rows of each pass are split across a rayon pool.
+//!
+//! Builds with the `fast-resize` feature hand 8-bit gray, RGB and RGBA
+//! resamples to `fast_image_resize` instead, ahead of the pool. Its output has
+//! the same dimensions but not the same bytes.
+
+use std::f32::consts::PI;
+use std::sync::OnceLock;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, Pixel};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+
+/// Set once at startup by `set_threads`; resampling stays on the calling
+/// thread until then.
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// Filter weight at a distance from the sample center, in source pixels.
+type Kernel = fn(f32) -> f32;
+
+/// Splits every resample across a pool of `threads` threads shared by all
+/// requests. Values below 2 keep resampling single-threaded. Only the first
+/// call has an effect.
+pub fn set_threads(threads: usize) {
+    if threads < 2 {
+        return;
+    }
+    match rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("resample-{}", index))
+        .build()
+    {
+        Ok(pool) => {
+            let _ = POOL.set(pool);
+        }
+        Err(e) => tracing::warn!(
+            "Failed to start {} resize threads, resampling single-threaded: {}",
+            threads,
+            e
+        ),
+    }
+}
+
+/// `DynamicImage::resize_exact`, on the pool when one is set. Nearest-neighbor
+/// sampling and color types other than 8-bit gray, RGB and RGBA take the
+/// single-threaded path.
+pub fn resize_exact(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    if img.width() == 0 || img.height() == 0 || (width, height) == (img.width(), img.height()) {
+        return img.resize_exact(width, height, filter);
+    }
+    #[cfg(feature = "fast-resize")]
+    if let Some(resized) = fast::resize_exact(img, width, height, filter) {
+        return resized;
+    }
+    let Some(pool) = POOL.get() else {
+        return img.resize_exact(width, height, filter);
+    };
+    let Some((kernel, support)) = kernel(filter) else {
+        return img.resize_exact(width, height, filter);
+    };
+
+    pool.install(|| match img {
+        DynamicImage::ImageLuma8(buffer) => {
+            DynamicImage::ImageLuma8(resample::<_, 1>(buffer, width, height, kernel, support))
+        }
+        DynamicImage::ImageLumaA8(buffer) => {
+            DynamicImage::ImageLumaA8(resample::<_, 2>(buffer, width, height, kernel, support))
+        }
+        DynamicImage::ImageRgb8(buffer) => {
+            DynamicImage::ImageRgb8(resample::<_, 3>(buffer, width, height, kernel, support))
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            DynamicImage::ImageRgba8(resample::<_, 4>(buffer, width, height, kernel, support))
+        }
+        _ => img.resize_exact(width, height, filter),
+    })
+}
+
+/// `DynamicImage::resize`: the largest size within `width`x`height` that
+/// keeps the aspect ratio, through `resize_exact`.
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    let alternative = POOL.get().is_some() || cfg!(feature = "fast-resize");
+    if !alternative || (width, height) == (img.width(), img.height()) {
+        return img.resize(width, height, filter);
+    }
+    let ratio = f64::min(
+        width as f64 / img.width() as f64,
+        height as f64 / img.height() as f64,
+    );
+    let fit_width = ((img.width() as f64 * ratio).round() as u32).max(1);
+    let fit_height = ((img.height() as f64 * ratio).round() as u32).max(1);
+
+    resize_exact(img, fit_width, fit_height, filter)
+}
+
+#[cfg(feature = "fast-resize")]
+mod fast {
+    use fast_image_resize::images::{Image, ImageRef};
+    use fast_image_resize::{
+        FilterType as FastFilter, PixelType, ResizeAlg, ResizeOptions, Resizer,
+    };
+    use image::imageops::FilterType;
+    use image::{DynamicImage, ImageBuffer};
+
+    /// `ResizeFilter` arrives here as the `image` filter `filter_type` maps it
+    /// to; `auto` is Lanczos3 by then.
+    pub fn algorithm(filter: FilterType) -> ResizeAlg {
+        match filter {
+            FilterType::Nearest => ResizeAlg::Nearest,
+            FilterType::Triangle => ResizeAlg::Convolution(FastFilter::Bilinear),
+            FilterType::CatmullRom => ResizeAlg::Convolution(FastFilter::CatmullRom),
+            FilterType::Gaussian => ResizeAlg::Convolution(FastFilter::Gaussian),
+            FilterType::Lanczos3 => ResizeAlg::Convolution(FastFilter::Lanczos3),
+        }
+    }
+
+    /// `None` for color types other than 8-bit gray, RGB and RGBA, which stay
+    /// with `image`.
+    pub fn resize_exact(
+        img: &DynamicImage,
+        width: u32,
+        height: u32,
+        filter: FilterType,
+    ) -> Option<DynamicImage> {
+        let (samples, pixel_type) = match img {
+            DynamicImage::ImageLuma8(buffer) => (buffer.as_raw(), PixelType::U8),
+            DynamicImage::ImageLumaA8(buffer) => (buffer.as_raw(), PixelType::U8x2),
+            DynamicImage::ImageRgb8(buffer) => (buffer.as_raw(), PixelType::U8x3),
+            DynamicImage::ImageRgba8(buffer) => (buffer.as_raw(), PixelType::U8x4),
+            _ => return None,
+        };
+        let source = ImageRef::new(img.width(), img.height(), samples, pixel_type).ok()?;
+        let mut target = Image::new(width, height, pixel_type);
+        let options = ResizeOptions::new().resize_alg(algorithm(filter));
+        Resizer::new().resize(&source, &mut target, &options).ok()?;
+
+        let samples = target.into_vec();
+        Some(match img {
+            DynamicImage::ImageLuma8(_) => {
+                DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, samples)?)
+            }
+            DynamicImage::ImageLumaA8(_) => {
+                DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, samples)?)
+            }
+            DynamicImage::ImageRgb8(_) => {
+                DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, samples)?)
+            }
+            _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, samples)?),
+        })
+    }
+}
+
+fn kernel(filter: FilterType) -> Option<(Kernel, f32)> {
+    match filter {
+        FilterType::Nearest => None,
+        FilterType::Triangle => Some((triangle, 1.0)),
+        FilterType::CatmullRom => Some((catmull_rom, 2.0)),
+        FilterType::Gaussian => Some((gaussian, 3.0)),
+        FilterType::Lanczos3 => Some((lanczos3, 3.0)),
+    }
+}
+
+fn sinc(t: f32) -> f32 {
+    if t == 0.0 {
+        1.0
+    } else {
+        let a = t * PI;
+        a.sin() / a
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+fn gaussian(x: f32) -> f32 {
+    const SIGMA: f32 = 0.5;
+    ((2.0 * PI).sqrt() * SIGMA).recip() * (-x.powi(2) / (2.0 * SIGMA.powi(2))).exp()
+}
+
+/// The Mitchell-Netravali cubic with B = 0, C = 0.5.
+fn catmull_rom(x: f32) -> f32 {
+    let (b, c) = (0.0f32, 0.5f32);
+    let a = x.abs();
+    let k = if a < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * a.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * a.powi(2)
+            + (6.0 - 2.0 * b)
+    } else if a < 2.0 {
+        (-b - 6.0 * c) * a.powi(3)
+            + (6.0 * b + 30.0 * c) * a.powi(2)
+            + (-12.0 * b - 48.0 * c) * a
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    k / 6.0
+}
+
+fn triangle(x: f32) -> f32 {
+    if x.abs() < 1.0 {
+        1.0 - x.abs()
+    } else {
+        0.0
+    }
+}
+
+/// For each output position along an axis, the first source position it
+/// reads and the normalized weights of it and the following positions.
+fn axis_weights(source: u32, target: u32, kernel: Kernel, support: f32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = source as f32 / target as f32;
+    let scale = ratio.max(1.0);
+    let source_support = support * scale;
+
+    (0..target)
+        .map(|out| {
+            let center = (out as f32 + 0.5) * ratio;
+            let left = ((center - source_support).floor() as i64).clamp(0, source as i64 - 1);
+            let right = ((center + source_support).ceil() as i64).clamp(left + 1, source as i64);
+            let center = center - 0.5;
+
+            let mut weights: Vec<f32> = (left..right)
+                .map(|i| kernel((i as f32 - center) / scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            for weight in weights.iter_mut() {
+                *weight /= sum;
+            }
+            (left as usize, weights)
+        })
+        .collect()
+}
+
+/// Vertical pass into `f32` rows of the source width, then a horizontal pass
+/// into the output; each output row is computed on its own.
+fn resample<P, const N: usize>(
+    source: &ImageBuffer<P, Vec<u8>>,
+    width: u32,
+    height: u32,
+    kernel: Kernel,
+    support: f32,
+) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8>,
+{
+    let (source_width, source_height) = source.dimensions();
+    let samples = source.as_raw();
+    let source_row = source_width as usize * N;
+
+    let rows = axis_weights(source_height, height, kernel, support);
+    let mut vertical = vec![0.0f32; source_row * height as usize];
+    vertical
+        .par_chunks_mut(source_row)
+        .zip(rows.par_iter())
+        .for_each(|(row, (top, weights))| {
+            for x in 0..source_width as usize {
+                let mut sums = [0.0f32; N];
+                for (i, weight) in weights.iter().enumerate() {
+                    let offset = (top + i) * source_row + x * N;
+                    for (sum, &sample) in sums.iter_mut().zip(&samples[offset..offset + N]) {
+                        *sum += sample as f32 * weight;
+                    }
+                }
+                row[x * N..x * N + N].copy_from_slice(&sums);
+            }
+        });
+
+    let columns = axis_weights(source_width, width, kernel, support);
+    let target_row = width as usize * N;
+    let mut output = vec![0u8; target_row * height as usize];
+    output
+        .par_chunks_mut(target_row)
+        .zip(vertical.par_chunks(source_row))
+        .for_each(|(row, source)| {
+            for (x, (left, weights)) in columns.iter().enumerate() {
+                let mut sums = [0.0f32; N];
+                for (i, weight) in weights.iter().enumerate() {
+                    let offset = (left + i) * N;
+                    for (sum, &sample) in sums.iter_mut().zip(&source[offset..offset + N]) {
+                        *sum += sample * weight;
+                    }
+                }
+                for (channel, sum) in row[x * N..x * N + N].iter_mut().zip(sums) {
+                    *channel = sum.clamp(0.0, 255.0).round() as u8;
+                }
+            }
+        });
+
+    ImageBuffer::from_raw(width, height, output).expect("output buffer matches its dimensions")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    // fast_image_resize rounds differently, so the pool is only compared
+    // with `image` in builds without it.
+    #[cfg(not(feature = "fast-resize"))]
+    #[test]
+    fn matches_the_single_threaded_resampler() {
+        set_threads(4);
+        let rgb = DynamicImage::ImageRgb8(RgbImage::from_fn(97, 61, |x, y| {
+            Rgb([
+                (x * 7 % 256) as u8,
+                (y * 13 % 256) as u8,
+                ((x ^ y) * 5 % 256) as u8,
+            ])
+        }));
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::from_fn(40, 90, |x, y| {
+            Rgba([
+                (x * 11 % 256) as u8,
+                200,
+                (y * 3 % 256) as u8,
+                ((x + y) * 9 % 256) as u8,
+            ])
+        }));
+
+        for filter in [
+            FilterType::Triangle,
+            FilterType::CatmullRom,
+            FilterType::Gaussian,
+            FilterType::Lanczos3,
+        ] {
+            for (img, width, height) in [(&rgb, 30, 20), (&rgb, 200, 45), (&rgba, 17, 128)] {
+                assert_eq!(
+                    resize_exact(img, width, height, filter),
+                    img.resize_exact(width, height, filter),
+                    "{:?} to {}x{}",
+                    filter,
+                    width,
+                    height
+                );
+            }
+            assert_eq!(resize(&rgb, 50, 50, filter), rgb.resize(50, 50, filter));
+        }
+    }
+
+    #[cfg(feature = "fast-resize")]
+    #[test]
+    fn fast_resize_keeps_the_dimensions() {
+        let gradient = DynamicImage::ImageRgb8(RgbImage::from_fn(97, 61, |x, y| {
+            Rgb([(x * 2) as u8, (y * 3) as u8, (x + y) as u8])
+        }));
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::from_fn(40, 90, |x, y| {
+            Rgba([
+                (x * 11 % 256) as u8,
+                200,
+                (y * 3 % 256) as u8,
+                ((x + y) * 9 % 256) as u8,
+            ])
+        }));
+
+        for filter in [
+            FilterType::Nearest,
+            FilterType::Triangle,
+            FilterType::CatmullRom,
+            FilterType::Gaussian,
+            FilterType::Lanczos3,
+        ] {
+            for (img, width, height) in
+                [(&gradient, 30, 20), (&gradient, 200, 45), (&rgba, 17, 128)]
+            {
+                let fast = resize_exact(img, width, height, filter);
+                let reference = img.resize_exact(width, height, filter);
+                assert_eq!(
+                    (fast.width(), fast.height()),
+                    (width, height),
+                    "{:?}",
+                    filter
+                );
+                assert_eq!(fast.color(), reference.color(), "{:?}", filter);
+            }
+            let (fast, reference) = (
+                resize(&gradient, 50, 50, filter),
+                gradient.resize(50, 50, filter),
+            );
+            assert_eq!(
+                (fast.width(), fast.height()),
+                (reference.width(), reference.height())
+            );
+
+            // A smooth gradient should come out nearly the same from both.
+            if filter != FilterType::Nearest {
+                let (fast, reference) = (
+                    resize_exact(&gradient, 30, 20, filter).into_rgb8(),
+                    gradient.resize_exact(30, 20, filter).into_rgb8(),
+                );
+                let difference: u32 = fast
+                    .as_raw()
+                    .iter()
+                    .zip(reference.as_raw())
+                    .map(|(a, b)| a.abs_diff(*b) as u32)
+                    .sum();
+                let mean = difference as f64 / fast.as_raw().len() as f64;
+                assert!(mean < 2.0, "{:?} differs by {:.2} on average", filter, mean);
+            }
+        }
+    }
+}
@@ -1,109 +1,633 @@
-use aws_sdk_s3::Client;
 use aws_config::{self, Region};
 use aws_credential_types::Credentials;
-use bytes::Bytes;
-use url::Url;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, ObjectCannedAcl, RequestPayer, StorageClass, Tag, Tagging,
+};
+use aws_sdk_s3::Client;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::sync::{Arc, OnceLock};
+use url::Url;
 
+use crate::circuit_breaker::CircuitBreaker;
 use crate::error::AppError;
+use crate::storage::{UploadChecksum, UploadOptions};
+
+/// Downloads reserve their buffer from the reported `Content-Length` up to
+/// this size; larger sources grow it as chunks arrive.
+const MAX_PREALLOCATED_SOURCE_BYTES: usize = 256 * 1024 * 1024;
 
+/// Cheap to clone: clones share the SDK client's connection pool and the breaker.
+#[derive(Clone)]
 pub struct S3Client {
     client: Client,
+    breaker: Option<Arc<CircuitBreaker>>,
+    /// Send `x-amz-request-payer: requester` with object requests, which
+    /// requester-pays buckets require and other buckets ignore.
+    requester_pays: bool,
+}
+
+/// S3 settings from the JSON file named by `S3_CONFIG_FILE`, for sandboxes
+/// without IMDS or credential variables. When present it replaces the
+/// `TT_AWS_*` variables and the region lookup entirely.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3FileConfig {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    #[serde(default)]
+    pub session_token: Option<String>,
+    /// S3-compatible endpoint such as MinIO, e.g. `http://minio:9000`.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Address buckets as `{endpoint}/{bucket}`, which most S3-compatible
+    /// stores need.
+    #[serde(default)]
+    pub force_path_style: bool,
+}
+
+impl S3FileConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let config: Self = serde_json::from_str(&text)
+            .map_err(|e| format!("{} is not a valid S3 config: {}", path, e))?;
+
+        for (field, value) in [
+            ("region", &config.region),
+            ("access_key_id", &config.access_key_id),
+            ("secret_access_key", &config.secret_access_key),
+        ] {
+            if value.trim().is_empty() {
+                return Err(format!("{} has an empty `{}`", path, field));
+            }
+        }
+        if let Some(endpoint) = &config.endpoint {
+            let url = Url::parse(endpoint)
+                .map_err(|e| format!("{} has an invalid `endpoint`: {}", path, e))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(format!("{} has an `endpoint` that isn't http(s)://", path));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// One client per region, built on first use and then shared, so a bucket
+/// outside the configured region is reached at its own endpoint instead of
+/// failing with a `PermanentRedirect`.
+pub struct RegionalClients {
+    file_config: Option<S3FileConfig>,
+    clients: tokio::sync::Mutex<HashMap<String, S3Client>>,
+}
+
+impl RegionalClients {
+    pub fn new(file_config: Option<S3FileConfig>) -> Self {
+        Self {
+            file_config,
+            clients: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The client for `region`, or for the configured region when `None`.
+    /// A custom endpoint from `S3_CONFIG_FILE` isn't AWS, so its configured
+    /// region is always used.
+    pub async fn get(&self, region: Option<&str>) -> S3Client {
+        let region = match (&self.file_config, region) {
+            (Some(file), _) if file.endpoint.is_some() => file.region.clone(),
+            (_, Some(region)) => region.to_string(),
+            (Some(file), None) => file.region.clone(),
+            (None, None) => default_region(),
+        };
+
+        let mut clients = self.clients.lock().await;
+        if let Some(client) = clients.get(&region) {
+            return client.clone();
+        }
+        tracing::debug!("Creating S3 client for region {}", region);
+        let client = S3Client::new(self.file_config.as_ref(), &region).await;
+        clients.insert(region, client.clone());
+        client
+    }
+}
+
+/// Leaves the keys out of logs and panics.
+impl std::fmt::Debug for S3FileConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3FileConfig")
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("force_path_style", &self.force_path_style)
+            .finish_non_exhaustive()
+    }
 }
 
 impl S3Client {
-    pub async fn new() -> Self {
-        let access_key = env::var("TT_AWS_ACCESS_KEY_ID")
-            .expect("TT_AWS_ACCESS_KEY_ID must be set");
-        let secret_key = env::var("TT_AWS_SECRET_ACCESS_KEY")
-            .expect("TT_AWS_SECRET_ACCESS_KEY must be set");
-        let region = env::var("TT_AWS_REGION")
-            .unwrap_or_else(|_| "us-east-1".to_string());
-
-        let credentials = Credentials::new(
-            access_key,
-            secret_key,
-            None,
-            None,
-            "custom-env",
-        );
+    /// `region` is where requests are sent; the credentials and endpoint come
+    /// from `file_config` or the `TT_AWS_*` variables as usual.
+    pub async fn new(file_config: Option<&S3FileConfig>, region: &str) -> Self {
+        let credentials = match file_config {
+            Some(file) => Credentials::new(
+                file.access_key_id.clone(),
+                file.secret_access_key.clone(),
+                file.session_token.clone(),
+                None,
+                "s3-config-file",
+            ),
+            None => {
+                let access_key =
+                    env::var("TT_AWS_ACCESS_KEY_ID").expect("TT_AWS_ACCESS_KEY_ID must be set");
+                let secret_key = env::var("TT_AWS_SECRET_ACCESS_KEY")
+                    .expect("TT_AWS_SECRET_ACCESS_KEY must be set");
+                Credentials::new(access_key, secret_key, None, None, "custom-env")
+            }
+        };
 
         let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-            .region(Region::new(region))
+            .region(Region::new(region.to_string()))
             .credentials_provider(credentials)
             .load()
             .await;
 
-        let client = Client::new(&config);
-        Self { client }
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&config);
+        if let Some(file) = file_config {
+            if let Some(endpoint) = &file.endpoint {
+                s3_config = s3_config.endpoint_url(endpoint);
+            }
+            s3_config = s3_config.force_path_style(file.force_path_style);
+        }
+
+        let client = Client::from_conf(s3_config.build());
+        Self {
+            client,
+            breaker: None,
+            requester_pays: false,
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn with_requester_pays(mut self, requester_pays: bool) -> Self {
+        self.requester_pays = requester_pays;
+        self
+    }
+
+    fn request_payer(&self) -> Option<RequestPayer> {
+        self.requester_pays.then_some(RequestPayer::Requester)
+    }
+
+    fn check_breaker(&self) -> Result<(), AppError> {
+        match &self.breaker {
+            Some(breaker) => breaker.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds the outcome of an S3 call into the breaker. Only failures that look
+    /// like an S3 outage (timeouts, connection errors, 5xx) count; a missing key
+    /// or a denied request says nothing about S3's health.
+    fn record_outcome<T, E>(&self, result: &Result<T, SdkError<E>>) {
+        let Some(breaker) = &self.breaker else {
+            return;
+        };
+
+        match result {
+            Ok(_) => breaker.record_success(),
+            Err(SdkError::ServiceError(e)) if !e.raw().status().is_server_error() => {
+                breaker.record_success()
+            }
+            Err(SdkError::ConstructionFailure(_)) => {}
+            Err(_) => breaker.record_failure(),
+        }
     }
 
     pub async fn download_image(&self, s3_url: &str) -> Result<Bytes, AppError> {
+        let (data, _) = self.download_with_content_type(s3_url).await?;
+        Ok(data)
+    }
+
+    /// Like `download_image`, also returning the `Content-Type` S3 reported
+    /// for the object. It is only what the uploader claimed; decoding goes by
+    /// the bytes.
+    pub async fn download_with_content_type(
+        &self,
+        s3_url: &str,
+    ) -> Result<(Bytes, Option<String>), AppError> {
         let (bucket, key) = parse_s3_url(s3_url)?;
-        
-        tracing::info!("Downloading from S3: bucket={}, key={}", bucket, key);
-        
-        let response = self
+
+        tracing::debug!("Downloading from S3: bucket={}, key={}", bucket, key);
+
+        self.check_breaker()?;
+
+        let result = self
             .client
             .get_object()
             .bucket(&bucket)
             .key(&key)
+            .set_request_payer(self.request_payer())
             .send()
-            .await
-            .map_err(|e| AppError::S3Error(format!("Failed to download from S3: {}", e)))?;
+            .await;
+        self.record_outcome(&result);
+
+        let response = result.map_err(|e| match &e {
+            SdkError::ServiceError(service) if service.err().is_no_such_key() => {
+                AppError::NotFound(format!("Source s3://{}/{} does not exist", bucket, key))
+            }
+            _ => AppError::S3Error(format!("Failed to download from S3: {}", e)),
+        })?;
 
-        let data = response
-            .body
-            .collect()
+        let content_type = response.content_type().map(str::to_string);
+        tracing::debug!(
+            "S3 reports Content-Type {} for bucket={}, key={}",
+            content_type.as_deref().unwrap_or("(none)"),
+            bucket,
+            key
+        );
+        // Each chunk is copied into one buffer sized from Content-Length and
+        // dropped as soon as it arrives. Collecting the body first would hold
+        // every chunk and then copy them all into a contiguous buffer, briefly
+        // keeping the source in memory twice.
+        let capacity = response
+            .content_length()
+            .and_then(|length| usize::try_from(length).ok())
+            .unwrap_or(0)
+            .min(MAX_PREALLOCATED_SOURCE_BYTES);
+        let mut body = response.body;
+        let mut buffer = BytesMut::with_capacity(capacity);
+        while let Some(chunk) = body
+            .try_next()
             .await
-            .map_err(|e| AppError::S3Error(format!("Failed to read S3 response body: {}", e)))?;
+            .map_err(|e| AppError::S3Error(format!("Failed to read S3 response body: {}", e)))?
+        {
+            buffer.extend_from_slice(&chunk);
+        }
+        let data = buffer.freeze();
+        check_source_len(&bucket, &key, data.len())?;
 
-        Ok(data.into_bytes())
+        Ok((data, content_type))
+    }
+
+    /// The object's user metadata (`x-amz-meta-*`), keyed by lowercase name
+    /// without the prefix.
+    pub async fn object_metadata(&self, s3_url: &str) -> Result<HashMap<String, String>, AppError> {
+        let (bucket, key) = parse_s3_url(s3_url)?;
+
+        tracing::debug!("Reading metadata from S3: bucket={}, key={}", bucket, key);
+
+        self.check_breaker()?;
+
+        let result = self
+            .client
+            .head_object()
+            .bucket(&bucket)
+            .key(&key)
+            .set_request_payer(self.request_payer())
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        let head = result.map_err(|e| match &e {
+            SdkError::ServiceError(service) if service.err().is_not_found() => {
+                AppError::NotFound(format!("Source s3://{}/{} does not exist", bucket, key))
+            }
+            _ => AppError::S3Error(format!("Failed to read S3 object metadata: {}", e)),
+        })?;
+
+        Ok(head
+            .metadata()
+            .map(|metadata| {
+                metadata
+                    .iter()
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// The size stored with a derivative by an upload with
+    /// `UploadOptions::dimensions`, or `None` for objects written without it.
+    pub async fn stored_dimensions(&self, s3_url: &str) -> Result<Option<(u32, u32)>, AppError> {
+        let metadata = self.object_metadata(s3_url).await?;
+        let dimension = |name: &str| metadata.get(name).and_then(|value| value.parse().ok());
+        Ok(dimension("width").zip(dimension("height")))
     }
 
     pub async fn check_object_exists(&self, bucket: &str, key: &str) -> bool {
-        tracing::info!("Checking if object exists: bucket={}, key={}", bucket, key);
-        
-        match self.client.head_object().bucket(bucket).key(key).send().await {
+        tracing::debug!("Checking if object exists: bucket={}, key={}", bucket, key);
+
+        // While the breaker is open, report a miss; the download that follows fails fast.
+        if self.check_breaker().is_err() {
+            return false;
+        }
+
+        let result = self
+            .client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .set_request_payer(self.request_payer())
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        match result {
+            // A zero-byte object is a failed upload; treating it as a miss
+            // renders a derivative instead of serving the empty one.
+            Ok(head) if head.content_length() == Some(0) => {
+                tracing::warn!(
+                    "Object is empty, treating it as missing: bucket={}, key={}",
+                    bucket,
+                    key
+                );
+                false
+            }
             Ok(_) => {
-                tracing::info!("Object exists: bucket={}, key={}", bucket, key);
+                tracing::debug!("Object exists: bucket={}, key={}", bucket, key);
                 true
             }
             Err(_) => {
-                tracing::info!("Object does not exist: bucket={}, key={}", bucket, key);
+                tracing::debug!("Object does not exist: bucket={}, key={}", bucket, key);
                 false
             }
         }
     }
 
+    async fn is_empty_object(&self, bucket: &str, key: &str) -> bool {
+        let result = self
+            .client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .set_request_payer(self.request_payer())
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        matches!(result, Ok(head) if head.content_length() == Some(0))
+    }
+
     pub async fn upload_image(
         &self,
         bucket: &str,
         key: &str,
         data: Bytes,
         content_type: &str,
+        options: &UploadOptions,
     ) -> Result<String, AppError> {
-        tracing::info!("Uploading to S3: bucket={}, key={}", bucket, key);
-        
-        self.client
+        tracing::debug!("Uploading to S3: bucket={}, key={}", bucket, key);
+
+        self.check_breaker()?;
+
+        // The SDK computes a CRC32 as it sends; a SHA-256 is taken of the
+        // bytes as they left the encoder.
+        let (checksum_algorithm, checksum_sha256) = match options.checksum {
+            UploadChecksum::Crc32 => (ChecksumAlgorithm::Crc32, None),
+            UploadChecksum::Sha256 => (
+                ChecksumAlgorithm::Sha256,
+                Some(BASE64_STANDARD.encode(Sha256::digest(&data))),
+            ),
+        };
+
+        let mut retried = false;
+        let result = loop {
+            let result = self
+                .client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(data.clone().into())
+                .content_type(content_type)
+                .set_acl(options.acl.map(|acl| ObjectCannedAcl::from(acl.as_str())))
+                .set_storage_class(
+                    options
+                        .storage_class
+                        .map(|class| StorageClass::from(class.as_str())),
+                )
+                .set_if_none_match(options.if_absent.then(|| "*".to_string()))
+                .set_metadata(options.dimensions.map(|(width, height)| {
+                    HashMap::from([
+                        ("width".to_string(), width.to_string()),
+                        ("height".to_string(), height.to_string()),
+                    ])
+                }))
+                .checksum_algorithm(checksum_algorithm.clone())
+                .set_checksum_sha256(checksum_sha256.clone())
+                .set_request_payer(self.request_payer())
+                .send()
+                .await;
+            self.record_outcome(&result);
+
+            // S3 answers a conditional put that overlaps another write with a
+            // 409 and asks for a retry, which then sees the finished object.
+            match &result {
+                Err(e) if !retried && e.code() == Some("ConditionalRequestConflict") => {
+                    tracing::debug!("Conditional put of {}/{} conflicted, retrying", bucket, key);
+                    retried = true;
+                }
+                _ => break result,
+            }
+        };
+
+        if let Err(e) = &result {
+            if options.if_absent && e.code() == Some("PreconditionFailed") {
+                // `check_object_exists` reports an empty object as missing, so
+                // the derivative is rendered again; it replaces the empty one.
+                if self.is_empty_object(bucket, key).await {
+                    tracing::warn!("Replacing empty object s3://{}/{}", bucket, key);
+                    let options = UploadOptions {
+                        if_absent: false,
+                        ..*options
+                    };
+                    return Box::pin(self.upload_image(bucket, key, data, content_type, &options))
+                        .await;
+                }
+                tracing::debug!(
+                    "s3://{}/{} was already written by a concurrent request, keeping it",
+                    bucket,
+                    key
+                );
+                return Ok(format!("s3://{}/{}", bucket, key));
+            }
+        }
+
+        result.map_err(|e| match (options.acl, e.code()) {
+            (Some(acl), Some("AccessControlListNotSupported")) => AppError::InvalidParameter(format!(
+                "Bucket {} has ACLs disabled and rejected the '{}' ACL; remove the acl option or UPLOAD_ACL",
+                bucket,
+                acl.as_str()
+            )),
+            // The body was damaged on the way; sending it again usually works.
+            (_, Some("BadDigest" | "InvalidDigest" | "XAmzContentChecksumMismatch")) => {
+                AppError::ServiceUnavailable(format!(
+                    "Upload of s3://{}/{} failed its integrity check, retry the request: {}",
+                    bucket, key, e
+                ))
+            }
+            _ => AppError::S3Error(format!("Failed to upload to S3: {}", e)),
+        })?;
+
+        let url = format!("s3://{}/{}", bucket, key);
+        Ok(url)
+    }
+
+    /// Lists at most one key, to check `s3:ListBucket` on `bucket`.
+    pub async fn list_one(&self, bucket: &str) -> Result<(), AppError> {
+        self.check_breaker()?;
+
+        let result = self
+            .client
+            .list_objects_v2()
+            .bucket(bucket)
+            .max_keys(1)
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        result.map(|_| ()).map_err(|e| {
+            AppError::S3Error(format!(
+                "Failed to list bucket {}: {}",
+                bucket,
+                DisplayErrorContext(&e)
+            ))
+        })
+    }
+
+    /// Writes a small object with no ACL, storage class or tags, so a failure
+    /// comes down to `s3:PutObject` itself.
+    pub async fn put_object(&self, bucket: &str, key: &str, data: Bytes) -> Result<(), AppError> {
+        self.check_breaker()?;
+
+        let result = self
+            .client
             .put_object()
             .bucket(bucket)
             .key(key)
             .body(data.into())
-            .content_type(content_type)
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        result.map(|_| ()).map_err(|e| {
+            AppError::S3Error(format!(
+                "Failed to write {}/{}: {}",
+                bucket,
+                key,
+                DisplayErrorContext(&e)
+            ))
+        })
+    }
+
+    pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), AppError> {
+        self.check_breaker()?;
+
+        let result = self
+            .client
+            .delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await;
+        self.record_outcome(&result);
+
+        result.map(|_| ()).map_err(|e| {
+            AppError::S3Error(format!(
+                "Failed to delete {}/{}: {}",
+                bucket,
+                key,
+                DisplayErrorContext(&e)
+            ))
+        })
+    }
+
+    pub async fn tag_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: &BTreeMap<String, String>,
+    ) -> Result<(), AppError> {
+        tracing::debug!(
+            "Tagging S3 object: bucket={}, key={}, tags={}",
+            bucket,
+            key,
+            tags.len()
+        );
+
+        let tag_set = tags
+            .iter()
+            .map(|(k, v)| Tag::builder().key(k).value(v).build())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| AppError::S3Error(format!("Invalid object tag: {}", e)))?;
+
+        let tagging = Tagging::builder()
+            .set_tag_set(Some(tag_set))
+            .build()
+            .map_err(|e| AppError::S3Error(format!("Invalid object tagging: {}", e)))?;
+
+        self.client
+            .put_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .tagging(tagging)
+            .set_request_payer(self.request_payer())
             .send()
             .await
-            .map_err(|e| AppError::S3Error(format!("Failed to upload to S3: {}", e)))?;
+            .map_err(|e| AppError::S3Error(format!("Failed to tag S3 object: {}", e)))?;
 
-        let url = format!("s3://{}/{}", bucket, key);
-        Ok(url)
+        Ok(())
     }
 }
 
+/// Resolves the S3 region from the environment. `S3_REGION` wins so a deployment
+/// can pin the bucket region without touching the credentials setup, followed by
+/// `TT_AWS_REGION`, then `AWS_REGION`, then `us-east-1`.
+pub fn default_region() -> String {
+    ["S3_REGION", "TT_AWS_REGION", "AWS_REGION"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| "us-east-1".to_string())
+}
+
+/// Hosts from `CUSTOM_DOMAIN_BUCKET_MAP` and the bucket each one serves.
+static CUSTOM_DOMAINS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// Lets `parse_s3_url` read `https://{host}/{key}` as `key` in the mapped
+/// bucket, for buckets served behind a custom domain. Set once at startup;
+/// later calls are ignored.
+pub fn set_custom_domains(domains: BTreeMap<String, String>) {
+    let _ = CUSTOM_DOMAINS.set(domains);
+}
+
 pub fn parse_s3_url(s3_url: &str) -> Result<(String, String), AppError> {
+    parse_s3_url_with_region(s3_url).map(|(bucket, key, _)| (bucket, key))
+}
+
+/// Like `parse_s3_url`, plus the region named by a regional endpoint such as
+/// `bucket.s3.us-west-2.amazonaws.com`. `s3://` URLs and global endpoints
+/// (`s3.amazonaws.com`, Transfer Acceleration) name none.
+pub fn parse_s3_url_with_region(
+    s3_url: &str,
+) -> Result<(String, String, Option<String>), AppError> {
+    parse_with_custom_domains(s3_url, CUSTOM_DOMAINS.get())
+}
+
+fn parse_with_custom_domains(
+    s3_url: &str,
+    custom_domains: Option<&BTreeMap<String, String>>,
+) -> Result<(String, String, Option<String>), AppError> {
     let url = Url::parse(s3_url)
         .map_err(|e| AppError::InvalidS3Url(format!("Invalid URL format: {}", e)))?;
 
+    let mut region = None;
     let (bucket, key) = match url.scheme() {
         "s3" => {
             let bucket = url
@@ -119,28 +643,33 @@ pub fn parse_s3_url(s3_url: &str) -> Result<(String, String), AppError> {
                 .host_str()
                 .ok_or_else(|| AppError::InvalidS3Url("Missing host".to_string()))?;
 
-            if host.starts_with("s3.") || host.starts_with("s3-") {
-                let path = url.path().trim_start_matches('/');
-                let parts: Vec<&str> = path.splitn(2, '/').collect();
-                
-                if parts.len() < 2 {
-                    return Err(AppError::InvalidS3Url(
-                        "Invalid path-style S3 URL format".to_string(),
-                    ));
-                }
-                
-                (parts[0].to_string(), parts[1].to_string())
-            } else if host.contains(".s3.") || host.contains(".s3-") {
-                let bucket = host.split('.').next()
-                    .ok_or_else(|| AppError::InvalidS3Url("Cannot extract bucket name".to_string()))?
-                    .to_string();
-                
-                let key = url.path().trim_start_matches('/').to_string();
-                (bucket, key)
+            if let Some(bucket) = custom_domains.and_then(|domains| domains.get(host)) {
+                // The domain stands for the bucket, so the whole path is the key.
+                (
+                    bucket.clone(),
+                    url.path().trim_start_matches('/').to_string(),
+                )
             } else {
-                return Err(AppError::InvalidS3Url(
-                    "URL does not appear to be a valid S3 URL".to_string(),
-                ));
+                let (bucket, endpoint_region) = parse_endpoint(host).ok_or_else(|| {
+                    AppError::InvalidS3Url("URL does not appear to be a valid S3 URL".to_string())
+                })?;
+                region = endpoint_region.map(str::to_string);
+
+                if bucket.is_empty() {
+                    let path = url.path().trim_start_matches('/');
+                    let parts: Vec<&str> = path.splitn(2, '/').collect();
+
+                    if parts.len() < 2 {
+                        return Err(AppError::InvalidS3Url(
+                            "Invalid path-style S3 URL format".to_string(),
+                        ));
+                    }
+
+                    (parts[0].to_string(), parts[1].to_string())
+                } else {
+                    let key = url.path().trim_start_matches('/').to_string();
+                    (bucket.to_string(), key)
+                }
             }
         }
         _ => {
@@ -149,15 +678,293 @@ pub fn parse_s3_url(s3_url: &str) -> Result<(String, String), AppError> {
             ));
         }
     };
-    
+
     if key.is_empty() {
         return Err(AppError::InvalidS3Url("Missing object key".to_string()));
     }
 
-    Ok((bucket, key))
+    Ok((bucket, key, region))
+}
+
+/// Splits an S3 endpoint host at its service label (`s3`, `s3-{region}`,
+/// `s3-accelerate`, ...) into the bucket in front of it and the region after
+/// it, e.g. `my.bucket` and `us-east-1` for
+/// `my.bucket.s3.dualstack.us-east-1.amazonaws.com`. The bucket is empty for
+/// path-style hosts; `None` means the host has no service label. The
+/// rightmost label wins, since bucket names may themselves contain dots or
+/// start with `s3-` while the labels after the service (`dualstack`, the
+/// region) never do.
+fn parse_endpoint(host: &str) -> Option<(&str, Option<&str>)> {
+    let labels: Vec<&str> = host.split('.').collect();
+    let service = labels
+        .iter()
+        .rposition(|label| *label == "s3" || label.starts_with("s3-"))?;
+    let bucket_len = labels[..service]
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum::<usize>();
+
+    let region = labels[service]
+        .strip_prefix("s3-")
+        .into_iter()
+        .chain(labels[service + 1..].iter().copied())
+        .find(|label| is_region(label));
+
+    Some((&host[..bucket_len.saturating_sub(1)], region))
+}
+
+/// Matches AWS region names such as `us-west-2` or `us-gov-east-1`, and not
+/// the other endpoint labels (`dualstack`, `accelerate`, `external-1`).
+fn is_region(label: &str) -> bool {
+    let parts: Vec<&str> = label.split('-').collect();
+    parts.len() >= 3
+        && parts[..parts.len() - 1]
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase()))
+        && parts[parts.len() - 1].chars().all(|c| c.is_ascii_digit())
+        && !parts[parts.len() - 1].is_empty()
+}
+
+/// `cache_version` is `CACHE_VERSION`; the generators below take it too, so
+/// bumping it moves every derivative to a new key.
+pub fn generate_resized_key(
+    original_key: &str,
+    width: u32,
+    height: u32,
+    cache_version: Option<&str>,
+) -> String {
+    derive_key(
+        original_key,
+        &format!("{}x{}", width, height),
+        cache_version,
+    )
+}
+
+pub fn generate_dpr_key(
+    original_key: &str,
+    width: u32,
+    height: u32,
+    dpr: u32,
+    cache_version: Option<&str>,
+) -> String {
+    derive_key(
+        original_key,
+        &format!("{}x{}@{}x", width, height, dpr),
+        cache_version,
+    )
+}
+
+pub fn generate_aspect_crop_key(
+    original_key: &str,
+    ratio_width: u32,
+    ratio_height: u32,
+    max_width: Option<u32>,
+    cache_version: Option<&str>,
+) -> String {
+    let suffix = match max_width {
+        Some(max_width) => format!("ar{}-{}_w{}", ratio_width, ratio_height, max_width),
+        None => format!("ar{}-{}", ratio_width, ratio_height),
+    };
+
+    derive_key(original_key, &suffix, cache_version)
+}
+
+/// Conversions keep the source's dimensions, so the key names the format
+/// instead: `photo.png` converted to WebP is `photo_converted.webp`.
+pub fn generate_convert_key(
+    original_key: &str,
+    extension: &str,
+    encoding_suffix: Option<&str>,
+    cache_version: Option<&str>,
+) -> String {
+    let suffix = match encoding_suffix {
+        Some(encoding) => format!("converted_{}", encoding),
+        None => "converted".to_string(),
+    };
+
+    replace_extension(&derive_key(original_key, &suffix, cache_version), extension)
+}
+
+/// Collage keys sit next to the first source. The hash of all source URLs keeps
+/// grids with the same first image apart.
+pub fn generate_collage_key(
+    first_key: &str,
+    columns: u32,
+    rows: u32,
+    width: u32,
+    height: u32,
+    source_urls: &[String],
+    cache_version: Option<&str>,
+) -> String {
+    let suffix = format!(
+        "collage{}x{}_{}x{}_{}",
+        columns,
+        rows,
+        width,
+        height,
+        sources_digest(source_urls)
+    );
+
+    derive_key(first_key, &suffix, cache_version)
+}
+
+/// Like `generate_collage_key`, keyed by cell size instead of output size.
+pub fn generate_montage_key(
+    first_key: &str,
+    columns: u32,
+    rows: u32,
+    cell_width: u32,
+    cell_height: u32,
+    source_urls: &[String],
+    cache_version: Option<&str>,
+) -> String {
+    let suffix = format!(
+        "montage{}x{}_{}x{}_{}",
+        columns,
+        rows,
+        cell_width,
+        cell_height,
+        sources_digest(source_urls)
+    );
+
+    derive_key(first_key, &suffix, cache_version)
+}
+
+/// Sprite sheets sit next to their first source, like collages. `frames` is
+/// the frame count taken from an animated source.
+pub fn generate_sprite_key(
+    first_key: &str,
+    columns: u32,
+    thumb_width: u32,
+    thumb_height: u32,
+    frames: Option<u32>,
+    source_urls: &[String],
+    cache_version: Option<&str>,
+) -> String {
+    let mut suffix = format!(
+        "sprite{}c_{}x{}_{}",
+        columns,
+        thumb_width,
+        thumb_height,
+        sources_digest(source_urls)
+    );
+    if let Some(frames) = frames {
+        suffix.push_str(&format!("_{}f", frames));
+    }
+
+    derive_key(first_key, &suffix, cache_version)
+}
+
+/// The `.dzi` manifest of a tile pyramid, e.g. `photo_dzi256-o1_jpg.dzi`.
+/// The tiles live under the manifest's stem plus `_files/`, which is where
+/// Deep Zoom viewers look for them.
+pub fn generate_tile_manifest_key(
+    original_key: &str,
+    tile_size: u32,
+    overlap: u32,
+    extension: &str,
+    encoding_suffix: Option<&str>,
+    cache_version: Option<&str>,
+) -> String {
+    let mut suffix = format!("dzi{}", tile_size);
+    if overlap > 0 {
+        suffix.push_str(&format!("-o{}", overlap));
+    }
+    suffix.push_str(&format!("_{}", extension));
+    if let Some(encoding) = encoding_suffix {
+        suffix.push_str(&format!("_{}", encoding));
+    }
+
+    replace_extension(&derive_key(original_key, &suffix, cache_version), "dzi")
+}
+
+/// `{manifest stem}_files/{level}/{column}_{row}.{extension}`.
+pub fn generate_tile_key(
+    manifest_key: &str,
+    level: u32,
+    column: u32,
+    row: u32,
+    extension: &str,
+) -> String {
+    format!(
+        "{}/{}/{}_{}.{}",
+        tiles_prefix(manifest_key),
+        level,
+        column,
+        row,
+        extension
+    )
+}
+
+pub fn tiles_prefix(manifest_key: &str) -> String {
+    format!(
+        "{}_files",
+        manifest_key.strip_suffix(".dzi").unwrap_or(manifest_key)
+    )
+}
+
+fn sources_digest(source_urls: &[String]) -> String {
+    let digest = format!("{:x}", Sha256::digest(source_urls.join("\n").as_bytes()));
+    digest[..8].to_string()
+}
+
+/// Inserts the first `length` hex chars of the SHA-256 of `data` before the
+/// key's extension, e.g. `photo_800x600.jpg` -> `photo_800x600.1a2b3c4d.jpg`.
+pub fn append_content_hash(key: &str, data: &[u8], length: usize) -> String {
+    let digest = format!("{:x}", Sha256::digest(data));
+    let hash = &digest[..length.min(digest.len())];
+
+    insert_before_extension(key, &format!(".{}", hash))
+}
+
+/// Appends `_{suffix}` to the key's stem, e.g. `photo_800x600.jpg` -> `photo_800x600_{suffix}.jpg`.
+pub fn append_key_suffix(key: &str, suffix: &str) -> String {
+    insert_before_extension(key, &format!("_{}", suffix))
+}
+
+/// Swaps the key's file name for `stem`, keeping its directory and
+/// extension: `uploads/IMG_0042.jpg` with `red-running-shoe` becomes
+/// `uploads/red-running-shoe.jpg`.
+pub fn replace_key_stem(key: &str, stem: &str) -> String {
+    let (parent, file_name) = match key.rsplit_once('/') {
+        Some((parent, file_name)) => (Some(parent), file_name),
+        None => (None, key),
+    };
+    let file_name = match file_name.rsplit_once('.') {
+        Some((_, extension)) => format!("{}.{}", stem, extension),
+        None => stem.to_string(),
+    };
+
+    match parent {
+        Some(parent) => format!("{}/{}", parent, file_name),
+        None => file_name,
+    }
+}
+
+/// Swaps the key's extension, e.g. `photo_800x600.jpg` -> `photo_800x600.avif`.
+pub fn replace_extension(key: &str, extension: &str) -> String {
+    match key.rsplit_once('.') {
+        Some((base, existing)) if !existing.contains('/') => format!("{}.{}", base, extension),
+        _ => format!("{}.{}", key, extension),
+    }
+}
+
+fn insert_before_extension(key: &str, insert: &str) -> String {
+    match key.rsplit_once('.') {
+        Some((base, extension)) if !extension.contains('/') => {
+            format!("{}{}.{}", base, insert, extension)
+        }
+        _ => format!("{}{}", key, insert),
+    }
+}
+
+pub fn generate_sidecar_key(key: &str) -> String {
+    format!("{}.json", key)
 }
 
-pub fn generate_resized_key(original_key: &str, width: u32, height: u32) -> String {
+/// Names a derivative `{stem}_{suffix}.{extension}` next to its source, with
+/// a `_v{cache_version}` tag after the suffix when one is configured.
+fn derive_key(original_key: &str, suffix: &str, cache_version: Option<&str>) -> String {
     let extension = std::path::Path::new(original_key)
         .extension()
         .and_then(|e| e.to_str())
@@ -173,11 +980,323 @@ pub fn generate_resized_key(original_key: &str, width: u32, height: u32) -> Stri
         .and_then(|p| p.to_str())
         .unwrap_or("");
 
-    let filename = format!("{}_{}x{}.{}", stem, width, height, extension);
-    
+    let filename = match cache_version {
+        Some(version) => format!("{}_{}_v{}.{}", stem, suffix, version, extension),
+        None => format!("{}_{}.{}", stem, suffix, extension),
+    };
+
     if parent.is_empty() {
         filename
     } else {
         format!("{}/{}", parent, filename)
     }
 }
+
+/// Rejects an empty download, which otherwise surfaces as an unhelpful
+/// decode error. There is no useful minimum above zero: a 1x1 binary PBM is
+/// only 8 bytes, so anything shorter is left to the decoder.
+fn check_source_len(bucket: &str, key: &str, len: usize) -> Result<(), AppError> {
+    if len == 0 {
+        return Err(AppError::EmptySource(format!(
+            "Source s3://{}/{} is empty (0 bytes); re-upload it",
+            bucket, key
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(url: &str) -> (String, String) {
+        parse_s3_url(url).unwrap_or_else(|e| panic!("{} failed to parse: {}", url, e))
+    }
+
+    fn owned(bucket: &str, key: &str) -> (String, String) {
+        (bucket.to_string(), key.to_string())
+    }
+
+    #[test]
+    fn parses_regional_and_path_style_urls() {
+        assert_eq!(
+            parsed("s3://my-bucket/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://my-bucket.s3.us-west-2.amazonaws.com/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://my-bucket.s3-us-west-2.amazonaws.com/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://s3.us-west-2.amazonaws.com/my-bucket/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://my.dotted.bucket.s3.amazonaws.com/a.jpg"),
+            owned("my.dotted.bucket", "a.jpg")
+        );
+        assert_eq!(
+            parsed("https://s3-backups.s3.amazonaws.com/a.jpg"),
+            owned("s3-backups", "a.jpg")
+        );
+        assert!(parse_s3_url("https://example.com/my-bucket/a.jpg").is_err());
+    }
+
+    #[test]
+    fn resolves_mapped_custom_domains() {
+        let domains: BTreeMap<String, String> =
+            [("cdn.example.com".to_string(), "my-bucket".to_string())].into();
+        let with_domains = |url: &str| parse_with_custom_domains(url, Some(&domains));
+
+        assert_eq!(
+            with_domains("https://cdn.example.com/images/photo.jpg").unwrap(),
+            (
+                "my-bucket".to_string(),
+                "images/photo.jpg".to_string(),
+                None
+            )
+        );
+        assert!(matches!(
+            with_domains("https://cdn.example.com/"),
+            Err(AppError::InvalidS3Url(_))
+        ));
+        // Other hosts parse as before.
+        assert_eq!(
+            with_domains("https://other.s3.amazonaws.com/a.jpg")
+                .unwrap()
+                .0,
+            "other"
+        );
+        assert!(matches!(
+            with_domains("https://img.example.org/images/photo.jpg"),
+            Err(AppError::InvalidS3Url(_))
+        ));
+        assert!(matches!(
+            parse_with_custom_domains("https://cdn.example.com/images/photo.jpg", None),
+            Err(AppError::InvalidS3Url(_))
+        ));
+    }
+
+    #[test]
+    fn parses_accelerate_urls() {
+        assert_eq!(
+            parsed("https://my-bucket.s3-accelerate.amazonaws.com/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://my-bucket.s3-accelerate.dualstack.amazonaws.com/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://my.bucket.s3-accelerate.amazonaws.com/a.jpg"),
+            owned("my.bucket", "a.jpg")
+        );
+    }
+
+    #[test]
+    fn parses_dualstack_urls() {
+        assert_eq!(
+            parsed("https://my-bucket.s3.dualstack.eu-central-1.amazonaws.com/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert_eq!(
+            parsed("https://s3.dualstack.eu-central-1.amazonaws.com/my-bucket/photos/a.jpg"),
+            owned("my-bucket", "photos/a.jpg")
+        );
+        assert!(parse_s3_url("https://s3.dualstack.eu-central-1.amazonaws.com/my-bucket").is_err());
+    }
+
+    #[test]
+    fn reads_the_region_from_regional_endpoints() {
+        let region = |url: &str| parse_s3_url_with_region(url).unwrap().2;
+
+        assert_eq!(
+            region("https://b.s3.us-west-2.amazonaws.com/a.jpg").as_deref(),
+            Some("us-west-2")
+        );
+        assert_eq!(
+            region("https://b.s3-eu-west-1.amazonaws.com/a.jpg").as_deref(),
+            Some("eu-west-1")
+        );
+        assert_eq!(
+            region("https://b.s3.dualstack.ap-southeast-2.amazonaws.com/a.jpg").as_deref(),
+            Some("ap-southeast-2")
+        );
+        assert_eq!(
+            region("https://s3.us-gov-west-1.amazonaws.com/b/a.jpg").as_deref(),
+            Some("us-gov-west-1")
+        );
+        assert_eq!(region("s3://b/a.jpg"), None);
+        assert_eq!(region("https://b.s3.amazonaws.com/a.jpg"), None);
+        assert_eq!(region("https://b.s3-accelerate.amazonaws.com/a.jpg"), None);
+        assert_eq!(region("https://b.s3-external-1.amazonaws.com/a.jpg"), None);
+    }
+
+    #[test]
+    fn replaces_the_stem_and_keeps_directory_and_extension() {
+        assert_eq!(
+            replace_key_stem("uploads/2024/IMG_0042.jpg", "red-running-shoe"),
+            "uploads/2024/red-running-shoe.jpg"
+        );
+        assert_eq!(replace_key_stem("IMG_0042", "shoe"), "shoe");
+        assert_eq!(
+            generate_resized_key(&replace_key_stem("a/IMG.png", "shoe"), 800, 600, None),
+            "a/shoe_800x600.png"
+        );
+    }
+
+    #[test]
+    fn rejects_only_empty_sources() {
+        let empty = check_source_len("b", "a.jpg", 0).unwrap_err();
+        assert!(matches!(empty, AppError::EmptySource(_)));
+        assert!(empty.to_string().contains("s3://b/a.jpg is empty"));
+
+        let pbm = b"P4\n1 1\n\x00";
+        let decoded = image::load_from_memory(pbm).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1, 1));
+        assert!(check_source_len("b", "a.pbm", pbm.len()).is_ok());
+    }
+
+    /// Body and `x-amz-meta-*` headers of each object, keyed by path.
+    type FakeObjects = Arc<std::sync::Mutex<HashMap<String, (Vec<u8>, axum::http::HeaderMap)>>>;
+
+    /// Just enough of S3 for HEAD and (conditional) PUT, keyed by path.
+    async fn fake_s3(
+        axum::extract::State(objects): axum::extract::State<FakeObjects>,
+        method: axum::http::Method,
+        uri: axum::http::Uri,
+        headers: axum::http::HeaderMap,
+        body: Bytes,
+    ) -> axum::response::Response {
+        use axum::http::{header, StatusCode};
+        use axum::response::IntoResponse;
+
+        let mut objects = objects.lock().unwrap();
+        let key = uri.path().to_string();
+        match method {
+            axum::http::Method::HEAD => match objects.get(&key) {
+                Some((data, metadata)) => (
+                    [(header::CONTENT_LENGTH, data.len().to_string())],
+                    metadata.clone(),
+                    (),
+                )
+                    .into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+            axum::http::Method::PUT
+                if headers.contains_key(header::IF_NONE_MATCH) && objects.contains_key(&key) =>
+            {
+                (
+                    StatusCode::PRECONDITION_FAILED,
+                    "<Error><Code>PreconditionFailed</Code><Message>At least one of the pre-conditions you specified did not hold</Message></Error>",
+                )
+                    .into_response()
+            }
+            axum::http::Method::PUT => {
+                let metadata = headers
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((name?, value)))
+                    .filter(|(name, _)| name.as_str().starts_with("x-amz-meta-"))
+                    .collect();
+                objects.insert(key, (body.to_vec(), metadata));
+                StatusCode::OK.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    /// A client talking to a fresh `fake_s3`, with the objects it serves.
+    async fn fake_s3_client() -> (S3Client, FakeObjects) {
+        let objects = FakeObjects::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new()
+            .fallback(fake_s3)
+            .with_state(objects.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let file_config = S3FileConfig {
+            region: "us-east-1".to_string(),
+            access_key_id: "test".to_string(),
+            secret_access_key: "test".to_string(),
+            session_token: None,
+            endpoint: Some(endpoint),
+            force_path_style: true,
+        };
+        let client = S3Client::new(Some(&file_config), "us-east-1").await;
+        (client, objects)
+    }
+
+    #[tokio::test]
+    async fn replaces_an_empty_object_on_a_conditional_upload() {
+        let (client, objects) = fake_s3_client().await;
+        objects
+            .lock()
+            .unwrap()
+            .insert("/b/a_800x600.jpg".to_string(), Default::default());
+        objects.lock().unwrap().insert(
+            "/b/kept_800x600.jpg".to_string(),
+            (b"kept".to_vec(), Default::default()),
+        );
+
+        let options = UploadOptions {
+            if_absent: true,
+            ..Default::default()
+        };
+
+        assert!(!client.check_object_exists("b", "a_800x600.jpg").await);
+        client
+            .upload_image(
+                "b",
+                "a_800x600.jpg",
+                Bytes::from_static(b"resized"),
+                "image/jpeg",
+                &options,
+            )
+            .await
+            .unwrap();
+        assert!(!objects.lock().unwrap()["/b/a_800x600.jpg"].0.is_empty());
+        assert!(client.check_object_exists("b", "a_800x600.jpg").await);
+
+        // A non-empty object written first is still kept.
+        client
+            .upload_image(
+                "b",
+                "kept_800x600.jpg",
+                Bytes::from_static(b"resized"),
+                "image/jpeg",
+                &options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(objects.lock().unwrap()["/b/kept_800x600.jpg"].0, b"kept");
+    }
+
+    #[tokio::test]
+    async fn stores_dimensions_as_object_metadata() {
+        let (client, _) = fake_s3_client().await;
+
+        for (key, dimensions) in [("cropped.jpg", Some((1600, 900))), ("plain.jpg", None)] {
+            let options = UploadOptions {
+                dimensions,
+                ..Default::default()
+            };
+            client
+                .upload_image(
+                    "b",
+                    key,
+                    Bytes::from_static(b"jpeg"),
+                    "image/jpeg",
+                    &options,
+                )
+                .await
+                .unwrap();
+            let url = format!("s3://b/{}", key);
+            assert_eq!(client.stored_dimensions(&url).await.unwrap(), dimensions);
+        }
+    }
+}
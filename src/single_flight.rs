@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Tracks derivatives currently being produced, so concurrent identical
+/// requests (e.g. a CDN cold start) wait for the first one instead of each
+/// downloading and resizing the same source.
+#[derive(Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, watch::Receiver<bool>>>,
+}
+
+/// Held by the request producing a derivative. Waiters are released when it
+/// is dropped; they reuse the stored derivative only if `succeeded` was called
+/// first, otherwise one of them takes over.
+pub struct FlightGuard {
+    flights: Arc<SingleFlight>,
+    key: String,
+    done: watch::Sender<bool>,
+}
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a guard when the caller should produce the derivative itself,
+    /// or `None` once an identical request has stored it.
+    pub async fn join(self: &Arc<Self>, key: &str) -> Option<FlightGuard> {
+        loop {
+            let mut done = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(key) {
+                    Some(done) => done.clone(),
+                    None => {
+                        let (sender, receiver) = watch::channel(false);
+                        in_flight.insert(key.to_string(), receiver);
+                        return Some(FlightGuard {
+                            flights: self.clone(),
+                            key: key.to_string(),
+                            done: sender,
+                        });
+                    }
+                }
+            };
+
+            tracing::debug!("Waiting for an identical in-flight request for {}", key);
+            // Errs when the leader gave up without storing anything.
+            if done.wait_for(|succeeded| *succeeded).await.is_ok() {
+                return None;
+            }
+        }
+    }
+}
+
+impl FlightGuard {
+    /// Marks the derivative as stored, so waiters can take the cached path.
+    pub fn succeeded(self) {
+        self.done.send_replace(true);
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.flights.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
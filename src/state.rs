@@ -0,0 +1,115 @@
+use ab_glyph::FontArc;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::Config;
+use crate::image_processor::ImageProcessor;
+use crate::jobs::JobQueue;
+use crate::log_sampling::LogSampler;
+use crate::rate_limit::RateLimiter;
+use crate::readiness::Readiness;
+use crate::s3::{parse_s3_url_with_region, RegionalClients, S3Client};
+use crate::single_flight::SingleFlight;
+use crate::stats::ServiceStats;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub s3_breaker: Arc<CircuitBreaker>,
+    pub log_sampler: Arc<LogSampler>,
+    pub jobs: Arc<JobQueue>,
+    pub flights: Arc<SingleFlight>,
+    pub readiness: Arc<Readiness>,
+    pub watermark_font: FontArc,
+    /// Caps concurrent decodes at `MAX_CONCURRENT_DECODES`; `None` is unlimited.
+    pub decode_permits: Option<Arc<Semaphore>>,
+    pub s3_clients: Arc<RegionalClients>,
+    pub stats: Arc<ServiceStats>,
+    /// Per-client request limit from `RATE_LIMIT_RPS`; `None` when disabled.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl AppState {
+    pub fn new(config: Config) -> Self {
+        let s3_breaker = CircuitBreaker::new(
+            "S3",
+            config.breaker_failure_threshold,
+            config.breaker_window,
+            config.breaker_cooldown,
+        );
+        let log_sampler = LogSampler::new(config.log_sample_rate, config.slow_request_threshold);
+        let jobs = JobQueue::new(config.job_queue_capacity, config.job_retention);
+        let watermark_font = match &config.watermark_font_path {
+            Some(path) => {
+                let data = std::fs::read(path).unwrap_or_else(|e| {
+                    panic!("WATERMARK_FONT_PATH {} is not readable: {}", path, e)
+                });
+                FontArc::try_from_vec(data).unwrap_or_else(|e| {
+                    panic!(
+                        "WATERMARK_FONT_PATH {} is not a TrueType/OpenType font: {}",
+                        path, e
+                    )
+                })
+            }
+            None => ImageProcessor::default_watermark_font(),
+        };
+
+        let decode_permits = config
+            .max_concurrent_decodes
+            .map(|permits| Arc::new(Semaphore::new(permits)));
+
+        let s3_clients = RegionalClients::new(config.s3_file.clone());
+
+        let rate_limiter = config
+            .rate_limit_rps
+            .map(|rps| Arc::new(RateLimiter::new(rps, config.rate_limit_burst)));
+
+        Self {
+            config: Arc::new(config),
+            s3_breaker: Arc::new(s3_breaker),
+            log_sampler: Arc::new(log_sampler),
+            jobs: Arc::new(jobs),
+            flights: Arc::new(SingleFlight::new()),
+            readiness: Arc::new(Readiness::new()),
+            watermark_font,
+            decode_permits,
+            s3_clients: Arc::new(s3_clients),
+            stats: Arc::new(ServiceStats::new()),
+            rate_limiter,
+        }
+    }
+
+    /// An S3 client for the configured region whose downloads and uploads go
+    /// through the shared breaker, paying for requests with `S3_REQUESTER_PAYS`.
+    pub async fn s3_client(&self) -> S3Client {
+        self.s3_clients
+            .get(None)
+            .await
+            .with_circuit_breaker(self.s3_breaker.clone())
+            .with_requester_pays(self.config.requester_pays)
+    }
+
+    /// Like `s3_client`, but in the region `s3_url`'s endpoint names, if any.
+    /// An unparseable URL gets the default client and fails where it's used.
+    pub async fn s3_client_for(&self, s3_url: &str) -> S3Client {
+        let region = parse_s3_url_with_region(s3_url)
+            .ok()
+            .and_then(|(_, _, region)| region);
+        self.s3_clients
+            .get(region.as_deref())
+            .await
+            .with_circuit_breaker(self.s3_breaker.clone())
+            .with_requester_pays(self.config.requester_pays)
+    }
+
+    /// `s3_client_for` a request's source, with the request's
+    /// `requester_pays` overriding `S3_REQUESTER_PAYS` when given.
+    pub async fn source_client(&self, s3_url: &str, requester_pays: Option<bool>) -> S3Client {
+        let client = self.s3_client_for(s3_url).await;
+        match requester_pays {
+            Some(requester_pays) => client.with_requester_pays(requester_pays),
+            None => client,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::models::StatsResponse;
+
+/// Output formats counted in `GET /stats`; `raw` is `GET /resize/raw`.
+const FORMATS: [&str; 5] = ["jpeg", "avif", "png", "webp", "raw"];
+
+/// Resize counters since startup, for a quick look at what an instance has
+/// been doing. Updates are relaxed, so a snapshot taken mid-request may be a
+/// request apart between counters.
+pub struct ServiceStats {
+    started: Instant,
+    resizes: AtomicU64,
+    cache_hits: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    resize_micros: AtomicU64,
+    formats: [AtomicU64; FORMATS.len()],
+    encodes: [AtomicU64; FORMATS.len()],
+    encode_micros: [AtomicU64; FORMATS.len()],
+}
+
+impl ServiceStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            resizes: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            bytes_uploaded: AtomicU64::new(0),
+            resize_micros: AtomicU64::new(0),
+            formats: Default::default(),
+            encodes: Default::default(),
+            encode_micros: Default::default(),
+        }
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A source fetched to be resized.
+    pub fn downloaded(&self, bytes: usize) {
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A derivative written to storage.
+    pub fn uploaded(&self, bytes: usize) {
+        self.bytes_uploaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A resize producing `format` (one of `FORMATS`) that took `elapsed`
+    /// from decode to encode.
+    pub fn resized(&self, format: &str, elapsed: Duration) {
+        self.resizes.fetch_add(1, Ordering::Relaxed);
+        self.resize_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if let Some(index) = FORMATS.iter().position(|name| *name == format) {
+            self.formats[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The encode stage of a resize producing `format`, which took `elapsed`.
+    /// Zero means nothing was encoded, e.g. a source passed through as is,
+    /// and isn't counted.
+    pub fn encoded(&self, format: &str, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        if let Some(index) = FORMATS.iter().position(|name| *name == format) {
+            self.encodes[index].fetch_add(1, Ordering::Relaxed);
+            self.encode_micros[index].fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsResponse {
+        let resizes = self.resizes.load(Ordering::Relaxed);
+        let cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        let resize_micros = self.resize_micros.load(Ordering::Relaxed);
+
+        StatsResponse {
+            uptime_secs: self.started.elapsed().as_secs(),
+            resizes,
+            cache_hits,
+            cache_hit_ratio: (resizes + cache_hits > 0)
+                .then(|| cache_hits as f64 / (resizes + cache_hits) as f64),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            formats: FORMATS
+                .iter()
+                .zip(&self.formats)
+                .map(|(name, count)| (name.to_string(), count.load(Ordering::Relaxed)))
+                .collect::<BTreeMap<_, _>>(),
+            average_resize_ms: (resizes > 0)
+                .then(|| resize_micros as f64 / resizes as f64 / 1000.0),
+            average_encode_ms: FORMATS
+                .iter()
+                .zip(self.encodes.iter().zip(&self.encode_micros))
+                .filter_map(|(name, (encodes, micros))| {
+                    let encodes = encodes.load(Ordering::Relaxed);
+                    (encodes > 0).then(|| {
+                        let micros = micros.load(Ordering::Relaxed);
+                        (name.to_string(), micros as f64 / encodes as f64 / 1000.0)
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for ServiceStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,235 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use url::Url;
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::models::{ObjectAcl, StorageClass};
+use crate::s3::S3Client;
+
+/// Per-object settings applied when a derivative is uploaded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadOptions {
+    pub acl: Option<ObjectAcl>,
+    /// S3 only; `None` leaves the bucket default (STANDARD). GCS outputs use
+    /// the bucket's default class.
+    pub storage_class: Option<StorageClass>,
+    /// Fail the upload when tagging the object afterwards fails.
+    pub strict_tagging: bool,
+    /// Only create the object, atomically: when the key already exists, e.g.
+    /// because a concurrent request for the same derivative wrote it first,
+    /// that object is kept and the upload succeeds without writing.
+    pub if_absent: bool,
+    /// Checksum S3 verifies the uploaded bytes against. GCS outputs ignore it.
+    pub checksum: UploadChecksum,
+    /// Size of the image, stored as `width` and `height` user metadata so a
+    /// cache hit can report it from a HEAD. GCS outputs ignore it.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// How S3 uploads are protected against corruption in transit, from
+/// `UPLOAD_CHECKSUM`. S3 rejects a put whose body doesn't match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UploadChecksum {
+    /// CRC32 computed by the SDK as it streams the body.
+    #[default]
+    Crc32,
+    /// SHA-256 of the encoded output, computed before the upload starts and
+    /// sent as `x-amz-checksum-sha256`.
+    Sha256,
+}
+
+impl UploadOptions {
+    /// The service-wide defaults from the environment.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            acl: config.default_acl,
+            storage_class: config.default_storage_class,
+            strict_tagging: config.strict_tagging,
+            if_absent: false,
+            checksum: config.upload_checksum,
+            dimensions: None,
+        }
+    }
+}
+
+/// Where derivatives are written. Sources are always read from S3, but the
+/// output can land in any backend implementing this trait.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn check_object_exists(&self, bucket: &str, key: &str) -> bool;
+
+    async fn upload_image(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        content_type: &str,
+        options: &UploadOptions,
+    ) -> Result<String, AppError>;
+
+    async fn download_object(&self, _bucket: &str, _key: &str) -> Result<Bytes, AppError> {
+        Err(AppError::InvalidParameter(
+            "Downloading derivatives is not supported by this storage backend".to_string(),
+        ))
+    }
+
+    async fn tag_object(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _tags: &BTreeMap<String, String>,
+    ) -> Result<(), AppError> {
+        Err(AppError::InvalidParameter(
+            "Object tagging is not supported by this storage backend".to_string(),
+        ))
+    }
+
+    /// Uploads and then tags the object. The put is what matters: a tagging
+    /// failure after a successful put is returned as a warning unless
+    /// `options.strict_tagging` is set, in which case it fails the whole upload.
+    async fn upload_image_with_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        content_type: &str,
+        options: &UploadOptions,
+        tags: &BTreeMap<String, String>,
+    ) -> Result<UploadOutcome, AppError> {
+        let url = self
+            .upload_image(bucket, key, data, content_type, options)
+            .await?;
+        let mut warnings = Vec::new();
+
+        if !tags.is_empty() {
+            if let Err(e) = self.tag_object(bucket, key, tags).await {
+                if options.strict_tagging {
+                    return Err(e);
+                }
+                tracing::warn!("Uploaded {} but tagging failed: {}", url, e);
+                warnings.push(format!("Object uploaded but tagging failed: {}", e));
+            }
+        }
+
+        Ok(UploadOutcome { url, warnings })
+    }
+}
+
+pub struct UploadOutcome {
+    pub url: String,
+    pub warnings: Vec<String>,
+}
+
+#[async_trait]
+impl Storage for S3Client {
+    async fn check_object_exists(&self, bucket: &str, key: &str) -> bool {
+        S3Client::check_object_exists(self, bucket, key).await
+    }
+
+    async fn upload_image(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        content_type: &str,
+        options: &UploadOptions,
+    ) -> Result<String, AppError> {
+        S3Client::upload_image(self, bucket, key, data, content_type, options).await
+    }
+
+    async fn download_object(&self, bucket: &str, key: &str) -> Result<Bytes, AppError> {
+        self.download_image(&format!("s3://{}/{}", bucket, key))
+            .await
+    }
+
+    async fn tag_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: &BTreeMap<String, String>,
+    ) -> Result<(), AppError> {
+        S3Client::tag_object(self, bucket, key, tags).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageScheme {
+    S3,
+    Gcs,
+}
+
+impl StorageScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StorageScheme::S3 => "s3",
+            StorageScheme::Gcs => "gs",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputLocation {
+    pub scheme: StorageScheme,
+    pub bucket: String,
+}
+
+impl OutputLocation {
+    pub fn s3(bucket: &str) -> Self {
+        Self {
+            scheme: StorageScheme::S3,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    pub fn object_url(&self, key: &str) -> String {
+        format!("{}://{}/{}", self.scheme.as_str(), self.bucket, key)
+    }
+
+    /// The bucket as it is written in requests, e.g. `gs://derivatives`.
+    pub fn bucket_url(&self) -> String {
+        format!("{}://{}", self.scheme.as_str(), self.bucket)
+    }
+}
+
+/// Parses an output bucket URL such as `s3://derivatives` or `gs://derivatives`.
+pub fn parse_output_bucket(output_url: &str) -> Result<OutputLocation, AppError> {
+    let url = Url::parse(output_url)
+        .map_err(|e| AppError::InvalidParameter(format!("Invalid output bucket URL: {}", e)))?;
+
+    let scheme = match url.scheme() {
+        "s3" => StorageScheme::S3,
+        "gs" => StorageScheme::Gcs,
+        _ => {
+            return Err(AppError::InvalidParameter(
+                "Output bucket must use the s3:// or gs:// scheme".to_string(),
+            ));
+        }
+    };
+
+    let bucket = url
+        .host_str()
+        .ok_or_else(|| AppError::InvalidParameter("Missing output bucket name".to_string()))?
+        .to_string();
+
+    if !url.path().trim_matches('/').is_empty() {
+        return Err(AppError::InvalidParameter(
+            "Output bucket URL must not contain a path".to_string(),
+        ));
+    }
+
+    Ok(OutputLocation { scheme, bucket })
+}
+
+#[cfg(feature = "gcs")]
+pub async fn gcs_storage() -> Result<Box<dyn Storage>, AppError> {
+    Ok(Box::new(crate::gcs::GcsClient::new().await?))
+}
+
+#[cfg(not(feature = "gcs"))]
+pub async fn gcs_storage() -> Result<Box<dyn Storage>, AppError> {
+    Err(AppError::UnsupportedOperation(
+        "gs:// output requires the service to be built with the `gcs` feature".to_string(),
+    ))
+}
@@ -0,0 +1,166 @@
+use crate::error::AppError;
+
+/// True for keys ending in `.svg`.
+pub fn is_svg_key(key: &str) -> bool {
+    key.rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case("svg"))
+}
+
+/// Sniffs SVG markup: text starting with a tag, with an `<svg` element near
+/// the top (after any XML declaration, doctype or comments).
+pub fn is_svg_data(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with('<') && head.contains("<svg")
+}
+
+pub fn unsupported() -> AppError {
+    AppError::UnsupportedOperation(
+        "SVG sources require the service to be built with the `svg` feature".to_string(),
+    )
+}
+
+#[cfg(feature = "svg")]
+mod raster {
+    use image::{DynamicImage, RgbaImage};
+    use resvg::tiny_skia::{Pixmap, Transform};
+    use resvg::usvg::{roxmltree, Options, Tree};
+
+    use crate::error::AppError;
+    use crate::image_processor::DecodeLimits;
+    use crate::models::ObjectMode;
+
+    /// Elements this build renders incorrectly rather than not at all: text
+    /// needs fonts, and embedded images need raster decoders the renderer
+    /// isn't built with.
+    const UNSUPPORTED_ELEMENTS: [(&str, &str); 3] = [
+        ("text", "convert text to paths before uploading"),
+        ("image", "embedded raster images are not rendered"),
+        ("foreignObject", "embedded HTML is not rendered"),
+    ];
+
+    fn parse(data: &[u8]) -> Result<Tree, AppError> {
+        let text = std::str::from_utf8(data).map_err(|_| {
+            AppError::ImageProcessingError("SVG source is not valid UTF-8".to_string())
+        })?;
+        let document = roxmltree::Document::parse(text)
+            .map_err(|e| AppError::ImageProcessingError(format!("Failed to parse SVG: {}", e)))?;
+        for node in document.descendants().filter(|node| node.is_element()) {
+            let name = node.tag_name().name();
+            if let Some((_, hint)) = UNSUPPORTED_ELEMENTS
+                .iter()
+                .find(|(element, _)| *element == name)
+            {
+                return Err(AppError::UnsupportedOperation(format!(
+                    "SVG <{}> elements are not supported: {}",
+                    name, hint
+                )));
+            }
+        }
+
+        Tree::from_str(text, &Options::default())
+            .map_err(|e| AppError::ImageProcessingError(format!("Failed to parse SVG: {}", e)))
+    }
+
+    /// The SVG's own size from `width`/`height`, or its viewBox when those are
+    /// missing, rounded up to whole pixels.
+    pub fn size(data: &[u8]) -> Result<(u32, u32), AppError> {
+        let size = parse(data)?.size();
+
+        Ok((
+            (size.width().ceil() as u32).max(1),
+            (size.height().ceil() as u32).max(1),
+        ))
+    }
+
+    /// Renders the SVG straight at the size `object_mode` produces for
+    /// `width` x `height`, so vector sources are never scaled as pixels. The
+    /// viewBox keeps its aspect ratio except in `fill` mode; `cover` crops
+    /// around the center like raster sources.
+    pub fn rasterize(
+        data: &[u8],
+        width: u32,
+        height: u32,
+        object_mode: ObjectMode,
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, AppError> {
+        let tree = parse(data)?;
+        let (source_width, source_height) = (tree.size().width(), tree.size().height());
+        let scale_x = width as f32 / source_width;
+        let scale_y = height as f32 / source_height;
+        let fitted = |scale: f32| {
+            (
+                ((source_width * scale).round() as u32).max(1),
+                ((source_height * scale).round() as u32).max(1),
+            )
+        };
+
+        let ((pixmap_width, pixmap_height), scale_x, scale_y) = match object_mode {
+            ObjectMode::Fill => ((width, height), scale_x, scale_y),
+            ObjectMode::Cover => {
+                let scale = scale_x.max(scale_y);
+                ((width, height), scale, scale)
+            }
+            ObjectMode::Contain => {
+                let scale = scale_x.min(scale_y);
+                (fitted(scale), scale, scale)
+            }
+            ObjectMode::ScaleDown => {
+                let scale = scale_x.min(scale_y).min(1.0);
+                (fitted(scale), scale, scale)
+            }
+            ObjectMode::Outside => {
+                let scale = scale_x.max(scale_y);
+                (fitted(scale), scale, scale)
+            }
+        };
+
+        if pixmap_width > limits.max_dimension || pixmap_height > limits.max_dimension {
+            return Err(AppError::DimensionTooLarge(format!(
+                "Rendering the SVG at {}x{} exceeds the maximum of {}px per side",
+                pixmap_width, pixmap_height, limits.max_dimension
+            )));
+        }
+
+        let mut pixmap = Pixmap::new(pixmap_width, pixmap_height).ok_or_else(|| {
+            AppError::ImageProcessingError(format!(
+                "Failed to allocate {}x{} canvas for SVG",
+                pixmap_width, pixmap_height
+            ))
+        })?;
+        let offset_x = (pixmap_width as f32 - source_width * scale_x) / 2.0;
+        let offset_y = (pixmap_height as f32 - source_height * scale_y) / 2.0;
+        resvg::render(
+            &tree,
+            Transform::from_row(scale_x, 0.0, 0.0, scale_y, offset_x, offset_y),
+            &mut pixmap.as_mut(),
+        );
+
+        let pixels = RgbaImage::from_raw(pixmap_width, pixmap_height, pixmap.take_demultiplied())
+            .ok_or_else(|| {
+            AppError::InternalError("SVG render has an unexpected size".to_string())
+        })?;
+
+        Ok(DynamicImage::ImageRgba8(pixels))
+    }
+}
+
+#[cfg(feature = "svg")]
+pub use raster::{rasterize, size};
+
+#[cfg(not(feature = "svg"))]
+pub fn size(_data: &[u8]) -> Result<(u32, u32), AppError> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn rasterize(
+    _data: &[u8],
+    _width: u32,
+    _height: u32,
+    _object_mode: crate::models::ObjectMode,
+    _limits: &crate::image_processor::DecodeLimits,
+) -> Result<image::DynamicImage, AppError> {
+    Err(unsupported())
+}
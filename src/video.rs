@@ -0,0 +1,108 @@
+use bytes::Bytes;
+
+use crate::error::AppError;
+
+/// Extensions treated as video before the source is downloaded, so the key and
+/// the feature check don't depend on the bytes.
+const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "m4v", "mov", "webm", "mkv"];
+
+/// True for keys with a video extension, e.g. `previews/clip.mp4`.
+pub fn is_video_key(key: &str) -> bool {
+    key.rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .is_some_and(|extension| VIDEO_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Sniffs MP4/QuickTime (`ftyp` box) and Matroska/WebM (EBML header) sources
+/// whose key doesn't give them away.
+pub fn is_video_data(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp".as_slice()) && !is_heif_brand(data)
+        || data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3])
+}
+
+/// HEIF/AVIF stills share the `ftyp` box with MP4 and are images.
+fn is_heif_brand(data: &[u8]) -> bool {
+    matches!(
+        data.get(8..12),
+        Some(b"avif" | b"avis" | b"heic" | b"heix" | b"mif1" | b"msf1")
+    )
+}
+
+/// Grabs the frame at `timestamp` seconds (the first frame when `None`) as a PNG
+/// by running ffmpeg on a temporary copy of the video. MP4 files often keep
+/// their index at the end, so ffmpeg needs a seekable file rather than a pipe.
+#[cfg(feature = "video")]
+pub async fn extract_frame(
+    data: Bytes,
+    timestamp: Option<f64>,
+    ffmpeg_path: &str,
+) -> Result<Bytes, AppError> {
+    let input = TempFile::new();
+    tokio::fs::write(&input.0, &data)
+        .await
+        .map_err(|e| AppError::InternalError(format!("Failed to stage video for ffmpeg: {}", e)))?;
+
+    let mut command = tokio::process::Command::new(ffmpeg_path);
+    command.args(["-v", "error", "-nostdin"]);
+    if let Some(timestamp) = timestamp {
+        command.args(["-ss", &timestamp.to_string()]);
+    }
+    command
+        .arg("-i")
+        .arg(&input.0)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"])
+        .kill_on_drop(true);
+
+    let output = command.output().await.map_err(|e| {
+        AppError::InternalError(format!("Failed to run ffmpeg at {}: {}", ffmpeg_path, e))
+    })?;
+
+    if !output.status.success() {
+        return Err(AppError::ImageProcessingError(format!(
+            "Failed to extract video frame: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    if output.stdout.is_empty() {
+        return Err(AppError::ImageProcessingError(format!(
+            "Video has no frame at {}s",
+            timestamp.unwrap_or(0.0)
+        )));
+    }
+
+    Ok(Bytes::from(output.stdout))
+}
+
+#[cfg(not(feature = "video"))]
+pub async fn extract_frame(
+    _data: Bytes,
+    _timestamp: Option<f64>,
+    _ffmpeg_path: &str,
+) -> Result<Bytes, AppError> {
+    Err(unsupported())
+}
+
+pub fn unsupported() -> AppError {
+    AppError::UnsupportedOperation(
+        "Video sources require the service to be built with the `video` feature".to_string(),
+    )
+}
+
+/// Scratch file removed on drop, including when the deadline abandons the
+/// extraction halfway.
+#[cfg(feature = "video")]
+struct TempFile(std::path::PathBuf);
+
+#[cfg(feature = "video")]
+impl TempFile {
+    fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("image-resizer-{}.video", uuid::Uuid::new_v4())))
+    }
+}
+
+#[cfg(feature = "video")]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}